@@ -2,13 +2,160 @@
 //!
 //! This module defines the `DBEntry` enum and its serialization/deserialization implementations,
 //! which represent the different types of entries that can exist in the database file.
+//!
+//! Every entry starts with a one-byte tag. Tags below [`EXTENSION_TAG_START`] are core entries
+//! whose layout every reader knows. Tags from [`EXTENSION_TAG_START`] upwards are reserved for
+//! extension entries, which are always written as the tag followed by a length-prefixed payload,
+//! so a reader that doesn't know a newer extension tag can still skip over the entry.
+//!
+//! Every entry is written inside a frame: an extension entry whose payload is the CRC32 of the
+//! framed entry followed by the entry itself. A final frame whose checksum doesn't match was torn
+//! by a crash part way through the write, and readers treat it like a truncated final entry.
+//! Entries written before framing was introduced are read as they are.
 
 use serde::{
     de::{self, SeqAccess, Visitor},
-    ser::SerializeTuple,
+    ser::{self, SerializeTuple},
     Deserialize, Deserializer, Serialize, Serializer,
 };
 
+use crate::{structures::checksum::crc32, StructureError};
+
+/// The first tag of the range reserved for length-framed extension entries.
+pub const EXTENSION_TAG_START: u8 = 128;
+
+/// The tag of `DBEntry::ExternalHashMapEntry`, the first extension entry.
+const EXTERNAL_HASH_MAP_ENTRY_TAG: u8 = EXTENSION_TAG_START;
+
+/// The tag of `DBEntry::EntryTimestamp`.
+const ENTRY_TIMESTAMP_TAG: u8 = EXTENSION_TAG_START + 1;
+
+/// The tag of `DBEntry::EndOfLog`.
+const END_OF_LOG_TAG: u8 = EXTENSION_TAG_START + 2;
+
+/// The tag of `DBEntry::KeyAlias`.
+const KEY_ALIAS_TAG: u8 = EXTENSION_TAG_START + 3;
+
+/// The tag of `DBEntry::EntryChecksum`.
+const ENTRY_CHECKSUM_TAG: u8 = EXTENSION_TAG_START + 4;
+
+/// The tag of `DBEntry::MultiMapEntry`.
+const MULTI_MAP_ENTRY_TAG: u8 = EXTENSION_TAG_START + 5;
+
+/// The tag of `DBEntry::RemoveMultiMapValue`.
+const REMOVE_MULTI_MAP_VALUE_TAG: u8 = EXTENSION_TAG_START + 6;
+
+/// The tag of the frame every entry is written in.
+const FRAMED_ENTRY_TAG: u8 = EXTENSION_TAG_START + 7;
+
+/// The tag of `DBEntry::LogFormat`.
+const LOG_FORMAT_TAG: u8 = EXTENSION_TAG_START + 8;
+
+/// The tag of `DBEntry::EntryExpiry`.
+const ENTRY_EXPIRY_TAG: u8 = EXTENSION_TAG_START + 9;
+
+/// The tag of `DBEntry::FileHeader`.
+const FILE_HEADER_TAG: u8 = EXTENSION_TAG_START + 10;
+
+/// The magic sequence at the start of the payload of `DBEntry::FileHeader`.
+const FILE_MAGIC: [u8; 8] = *b"RUSTMAP\0";
+
+/// The version of the file format written by this version of rustmap-db, recorded in the
+/// `DBEntry::FileHeader` of new database files. Files recording a later version aren't opened.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The error message of a frame whose checksum doesn't match its entry.
+const FRAME_CHECKSUM_MISMATCH: &str = "entry frame checksum mismatch";
+
+/// The byte range of the checksum in a serialized frame, after the frame tag and the length
+/// of its payload.
+pub(crate) const FRAME_CHECKSUM: std::ops::Range<usize> = 9..13;
+
+/// Returns whether `error` means an entry's frame checksum doesn't match the entry.
+pub(crate) fn is_frame_checksum_mismatch(error: &bincode::ErrorKind) -> bool {
+    matches!(error, bincode::ErrorKind::Custom(message) if message == FRAME_CHECKSUM_MISMATCH)
+}
+
+/// Returns the length of the frame `header` starts, from the frame tag and the length of its
+/// payload, or None if `header` doesn't start a frame.
+pub(crate) fn frame_len(header: &[u8]) -> Option<u64> {
+    if header.len() < FRAME_CHECKSUM.start || header[0] != FRAMED_ENTRY_TAG {
+        return None;
+    }
+    let payload = u64::from_le_bytes(header[1..FRAME_CHECKSUM.start].try_into().ok()?);
+    (FRAME_CHECKSUM.start as u64).checked_add(payload)
+}
+
+/// Returns the checksum recorded in the serialized `frame` and the checksum of the entry it
+/// holds, or None if `frame` isn't a complete frame.
+pub(crate) fn frame_checksums(frame: &[u8]) -> Option<(u32, u32)> {
+    if frame.len() < FRAME_CHECKSUM.end || frame[0] != FRAMED_ENTRY_TAG {
+        return None;
+    }
+    let recorded = u32::from_le_bytes(frame[FRAME_CHECKSUM].try_into().ok()?);
+    Some((recorded, crc32(&frame[FRAME_CHECKSUM.end..])))
+}
+
+/// The location of a value stored outside the log, in a structure's sidecar value file.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ValueLocation {
+    /// The name of the sidecar file, relative to the structure's large value directory.
+    pub file: String,
+    /// The offset of the value within the sidecar file.
+    pub offset: u64,
+    /// The length of the serialized value in bytes.
+    pub len: u64,
+}
+
+/// How a structure treats extension entries it doesn't recognise while loading.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownEntryPolicy {
+    /// Skip the entry, so files written by newer versions can still be opened.
+    #[default]
+    Skip,
+    /// Fail the load with `StructureError::UnknownEntry`.
+    Reject,
+}
+
+/// How a structure treats a record it can't read in the middle of the file while loading.
+///
+/// A truncated or torn final record is always ignored, as it's what a crash part way through
+/// an append leaves behind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// Fail the load with the error reading the record.
+    #[default]
+    Strict,
+    /// Skip the record and carry on from the next complete record whose frame checksum
+    /// matches. Records written before framing was introduced can't be found again this way,
+    /// so everything from the bad record up to the next framed one is skipped. The skipped
+    /// bytes are reported as [`SkippedRecord`]s by the structure or database that loaded them.
+    Lenient,
+}
+
+/// A range of the file skipped while loading in [`RecoveryMode::Lenient`], because the record
+/// at its start couldn't be read.
+#[derive(Debug)]
+pub struct SkippedRecord {
+    /// The offset of the unreadable record in the file.
+    pub offset: u64,
+    /// The number of bytes skipped, up to the next intact record or the end of the file.
+    pub len: u64,
+    /// The error reading the record, or None if it looked truncated, as a corrupted length
+    /// makes it look.
+    pub error: Option<StructureError>,
+}
+
+impl Clone for SkippedRecord {
+    fn clone(&self) -> Self {
+        Self {
+            offset: self.offset,
+            len: self.len,
+            error: self.error.as_ref().map(StructureError::duplicate),
+        }
+    }
+}
+
 /// Represents an entry in the database.
 ///
 /// `DBEntry` is an enum that can represent different types of entries within the database,
@@ -23,6 +170,100 @@ pub enum DBEntry {
     HashSetEntry(Vec<u8>, Vec<u8>),
     /// Represents the removal of an entry from a hashset.
     RemoveHashSetEntry(Vec<u8>, Vec<u8>),
+    /// Records the fingerprint of the key/value types a structure was created with.
+    TypeFingerprint(Vec<u8>, u64),
+    /// Represents a key-value pair entry in a hashmap whose value is stored in a sidecar file.
+    ExternalHashMapEntry(Vec<u8>, Vec<u8>, ValueLocation),
+    /// Records when the preceding write of a hashmap key happened, in milliseconds since the
+    /// Unix epoch.
+    EntryTimestamp(Vec<u8>, Vec<u8>, u64),
+    /// Marks the logical end of the log. Readers stop here and ignore anything after it, such
+    /// as zero padding left by preallocation.
+    EndOfLog,
+    /// Defines an alias for the serialized key of a hashmap. The map's records refer to the
+    /// key by the alias from this entry onwards.
+    KeyAlias(Vec<u8>, Vec<u8>, Vec<u8>),
+    /// Records the CRC32 of the serialized value of the preceding write of a hashmap key.
+    EntryChecksum(Vec<u8>, Vec<u8>, u32),
+    /// Appends a value to the values of a key in a multimap.
+    MultiMapEntry(Vec<u8>, Vec<u8>, Vec<u8>),
+    /// Removes the first occurrence of a value from the values of a key in a multimap.
+    RemoveMultiMapValue(Vec<u8>, Vec<u8>, Vec<u8>),
+    /// Records the serialization format of the keys and values in the file, as the byte of a
+    /// `SerializationFormat`. Written as the first entry of files that don't use bincode.
+    LogFormat(u8),
+    /// Records when the preceding write of a hashmap key expires, in milliseconds since the
+    /// Unix epoch.
+    EntryExpiry(Vec<u8>, Vec<u8>, u64),
+    /// Identifies a database file, recording the version of its format and the serialization
+    /// format of its keys and values, as the byte of a `SerializationFormat`. Written as the
+    /// first entry of every new database file, and serialized after a magic sequence so that
+    /// other files fail to read as one.
+    FileHeader(u32, u8),
+    /// An extension entry with a tag in the reserved range and its raw payload.
+    ///
+    /// Readers keep extension entries they don't understand in this form.
+    Extension(u8, Vec<u8>),
+}
+
+impl DBEntry {
+    /// Returns the id of the structure the entry belongs to, or None for entries that don't
+    /// belong to a structure or whose payload isn't understood.
+    pub fn id(&self) -> Option<&[u8]> {
+        match self {
+            DBEntry::HashMapEntry(id, _, _)
+            | DBEntry::RemoveHashMapEntry(id, _)
+            | DBEntry::HashSetEntry(id, _)
+            | DBEntry::RemoveHashSetEntry(id, _)
+            | DBEntry::TypeFingerprint(id, _)
+            | DBEntry::ExternalHashMapEntry(id, _, _)
+            | DBEntry::EntryTimestamp(id, _, _)
+            | DBEntry::EntryExpiry(id, _, _)
+            | DBEntry::KeyAlias(id, _, _)
+            | DBEntry::EntryChecksum(id, _, _)
+            | DBEntry::MultiMapEntry(id, _, _)
+            | DBEntry::RemoveMultiMapValue(id, _, _) => Some(id),
+            DBEntry::EndOfLog
+            | DBEntry::LogFormat(_)
+            | DBEntry::FileHeader(_, _)
+            | DBEntry::Extension(_, _) => None,
+        }
+    }
+
+    /// Returns a mutable reference to the id of the structure the entry belongs to.
+    pub(crate) fn id_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            DBEntry::HashMapEntry(id, _, _)
+            | DBEntry::RemoveHashMapEntry(id, _)
+            | DBEntry::HashSetEntry(id, _)
+            | DBEntry::RemoveHashSetEntry(id, _)
+            | DBEntry::TypeFingerprint(id, _)
+            | DBEntry::ExternalHashMapEntry(id, _, _)
+            | DBEntry::EntryTimestamp(id, _, _)
+            | DBEntry::EntryExpiry(id, _, _)
+            | DBEntry::KeyAlias(id, _, _)
+            | DBEntry::EntryChecksum(id, _, _)
+            | DBEntry::MultiMapEntry(id, _, _)
+            | DBEntry::RemoveMultiMapValue(id, _, _) => Some(id),
+            DBEntry::EndOfLog
+            | DBEntry::LogFormat(_)
+            | DBEntry::FileHeader(_, _)
+            | DBEntry::Extension(_, _) => None,
+        }
+    }
+}
+
+/// Serializes a known extension entry as its tag followed by its bincode-encoded payload.
+fn serialize_extension<S, T>(serializer: S, tag: u8, payload: &T) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: Serialize,
+{
+    let payload = bincode::serialize(payload).map_err(ser::Error::custom)?;
+    let mut tuple = serializer.serialize_tuple(2)?;
+    tuple.serialize_element(&tag)?;
+    tuple.serialize_element(&payload)?;
+    tuple.end()
 }
 
 impl Serialize for DBEntry {
@@ -30,7 +271,26 @@ impl Serialize for DBEntry {
     where
         S: Serializer,
     {
-        match *self {
+        let entry = bincode::serialize(&Unframed(self)).map_err(ser::Error::custom)?;
+        let mut payload = Vec::with_capacity(4 + entry.len());
+        payload.extend_from_slice(&crc32(&entry).to_le_bytes());
+        payload.extend_from_slice(&entry);
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&FRAMED_ENTRY_TAG)?;
+        tuple.serialize_element(&payload)?;
+        tuple.end()
+    }
+}
+
+/// Serializes an entry without its frame.
+struct Unframed<'a>(&'a DBEntry);
+
+impl Serialize for Unframed<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match *self.0 {
             DBEntry::HashMapEntry(ref id, ref key, ref value) => {
                 let mut tuple = serializer.serialize_tuple(4)?;
                 tuple.serialize_element(&0u8)?; // 0 indicates HashMapEntry
@@ -60,6 +320,53 @@ impl Serialize for DBEntry {
                 tuple.serialize_element(key)?;
                 tuple.end()
             }
+            DBEntry::TypeFingerprint(ref id, fingerprint) => {
+                let mut tuple = serializer.serialize_tuple(3)?;
+                tuple.serialize_element(&4u8)?; // 4 indicates TypeFingerprint
+                tuple.serialize_element(id)?; // id
+                tuple.serialize_element(&fingerprint)?;
+                tuple.end()
+            }
+            DBEntry::ExternalHashMapEntry(ref id, ref key, ref location) => serialize_extension(
+                serializer,
+                EXTERNAL_HASH_MAP_ENTRY_TAG,
+                &(id, key, location),
+            ),
+            DBEntry::EntryTimestamp(ref id, ref key, timestamp) => {
+                serialize_extension(serializer, ENTRY_TIMESTAMP_TAG, &(id, key, timestamp))
+            }
+            DBEntry::EndOfLog => serialize_extension(serializer, END_OF_LOG_TAG, &()),
+            DBEntry::KeyAlias(ref id, ref alias, ref key) => {
+                serialize_extension(serializer, KEY_ALIAS_TAG, &(id, alias, key))
+            }
+            DBEntry::EntryChecksum(ref id, ref key, checksum) => {
+                serialize_extension(serializer, ENTRY_CHECKSUM_TAG, &(id, key, checksum))
+            }
+            DBEntry::MultiMapEntry(ref id, ref key, ref value) => {
+                serialize_extension(serializer, MULTI_MAP_ENTRY_TAG, &(id, key, value))
+            }
+            DBEntry::RemoveMultiMapValue(ref id, ref key, ref value) => {
+                serialize_extension(serializer, REMOVE_MULTI_MAP_VALUE_TAG, &(id, key, value))
+            }
+            DBEntry::LogFormat(format) => serialize_extension(serializer, LOG_FORMAT_TAG, &format),
+            DBEntry::EntryExpiry(ref id, ref key, expiry) => {
+                serialize_extension(serializer, ENTRY_EXPIRY_TAG, &(id, key, expiry))
+            }
+            DBEntry::FileHeader(version, format) => {
+                serialize_extension(serializer, FILE_HEADER_TAG, &(FILE_MAGIC, version, format))
+            }
+            DBEntry::Extension(tag, ref payload) => {
+                if tag < EXTENSION_TAG_START {
+                    return Err(ser::Error::custom(format!(
+                        "extension tag {} is in the core range",
+                        tag
+                    )));
+                }
+                let mut tuple = serializer.serialize_tuple(2)?;
+                tuple.serialize_element(&tag)?;
+                tuple.serialize_element(payload)?; // length-prefixed, so it can be skipped
+                tuple.end()
+            }
         }
     }
 }
@@ -135,6 +442,81 @@ impl<'de> Visitor<'de> for DBEntryVisitor {
                     .ok_or_else(|| de::Error::invalid_length(2, &self))?;
                 Ok(DBEntry::RemoveHashSetEntry(id, key))
             }
+            4 => {
+                let id = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let fingerprint = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                Ok(DBEntry::TypeFingerprint(id, fingerprint))
+            }
+            EXTENSION_TAG_START.. => {
+                let payload: Vec<u8> = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                match tag {
+                    EXTERNAL_HASH_MAP_ENTRY_TAG => {
+                        let (id, key, location) =
+                            bincode::deserialize(&payload).map_err(de::Error::custom)?;
+                        Ok(DBEntry::ExternalHashMapEntry(id, key, location))
+                    }
+                    ENTRY_TIMESTAMP_TAG => {
+                        let (id, key, timestamp) =
+                            bincode::deserialize(&payload).map_err(de::Error::custom)?;
+                        Ok(DBEntry::EntryTimestamp(id, key, timestamp))
+                    }
+                    END_OF_LOG_TAG => Ok(DBEntry::EndOfLog),
+                    FRAMED_ENTRY_TAG => {
+                        if payload.len() < 4 {
+                            return Err(de::Error::invalid_length(payload.len(), &self));
+                        }
+                        let (checksum, entry) = payload.split_at(4);
+                        if crc32(entry).to_le_bytes() != checksum {
+                            return Err(de::Error::custom(FRAME_CHECKSUM_MISMATCH));
+                        }
+                        bincode::deserialize(entry).map_err(de::Error::custom)
+                    }
+                    KEY_ALIAS_TAG => {
+                        let (id, alias, key) =
+                            bincode::deserialize(&payload).map_err(de::Error::custom)?;
+                        Ok(DBEntry::KeyAlias(id, alias, key))
+                    }
+                    ENTRY_CHECKSUM_TAG => {
+                        let (id, key, checksum) =
+                            bincode::deserialize(&payload).map_err(de::Error::custom)?;
+                        Ok(DBEntry::EntryChecksum(id, key, checksum))
+                    }
+                    MULTI_MAP_ENTRY_TAG => {
+                        let (id, key, value) =
+                            bincode::deserialize(&payload).map_err(de::Error::custom)?;
+                        Ok(DBEntry::MultiMapEntry(id, key, value))
+                    }
+                    REMOVE_MULTI_MAP_VALUE_TAG => {
+                        let (id, key, value) =
+                            bincode::deserialize(&payload).map_err(de::Error::custom)?;
+                        Ok(DBEntry::RemoveMultiMapValue(id, key, value))
+                    }
+                    LOG_FORMAT_TAG => {
+                        let format = bincode::deserialize(&payload).map_err(de::Error::custom)?;
+                        Ok(DBEntry::LogFormat(format))
+                    }
+                    ENTRY_EXPIRY_TAG => {
+                        let (id, key, expiry) =
+                            bincode::deserialize(&payload).map_err(de::Error::custom)?;
+                        Ok(DBEntry::EntryExpiry(id, key, expiry))
+                    }
+                    FILE_HEADER_TAG => {
+                        let (magic, version, format): ([u8; 8], _, _) =
+                            bincode::deserialize(&payload).map_err(de::Error::custom)?;
+                        if magic != FILE_MAGIC {
+                            return Err(de::Error::custom("bad file header magic"));
+                        }
+                        Ok(DBEntry::FileHeader(version, format))
+                    }
+                    _ => Ok(DBEntry::Extension(tag, payload)),
+                }
+            }
             _ => Err(de::Error::invalid_value(
                 de::Unexpected::Unsigned(tag as u64),
                 &self,
@@ -148,7 +530,7 @@ impl<'de> Deserialize<'de> for DBEntry {
     where
         D: Deserializer<'de>,
     {
-        const FIELDS: &'static [&'static str] = &["tag", "id", "key", "value"];
+        const FIELDS: &[&str] = &["tag", "id", "key", "value"];
         deserializer.deserialize_tuple_struct("DBEntry", FIELDS.len(), DBEntryVisitor::new())
     }
 }
@@ -162,6 +544,13 @@ mod db_entry_tests {
         bincode::serialize(entry).expect("Serialization should succeed")
     }
 
+    // Helper function to return the tag of the entry inside a serialized frame
+    fn inner_tag(serialized: &[u8]) -> u8 {
+        assert_eq!(serialized[0], FRAMED_ENTRY_TAG);
+        // The frame tag, the payload length and the checksum come before the entry.
+        serialized[1 + 8 + 4]
+    }
+
     // Helper function to deserialize a DBEntry
     fn deserialize_entry(data: &[u8]) -> DBEntry {
         bincode::deserialize(data).expect("Deserialization should succeed")
@@ -183,6 +572,154 @@ mod db_entry_tests {
         assert_eq!(entry, deserialized);
     }
 
+    #[test]
+    fn test_serialize_deserialize_type_fingerprint() {
+        let entry = DBEntry::TypeFingerprint(vec![1], 0xdead_beef);
+        let serialized = serialize_entry(&entry);
+        let deserialized = deserialize_entry(&serialized);
+        assert_eq!(entry, deserialized);
+    }
+
+    #[test]
+    fn test_serialize_deserialize_extension() {
+        let entry = DBEntry::Extension(200, vec![1, 2, 3]);
+        let serialized = serialize_entry(&entry);
+        let deserialized = deserialize_entry(&serialized);
+        assert_eq!(entry, deserialized);
+    }
+
+    #[test]
+    fn test_serialize_deserialize_external_hashmap_entry() {
+        let location = ValueLocation {
+            file: "values".to_string(),
+            offset: 10,
+            len: 20,
+        };
+        let entry = DBEntry::ExternalHashMapEntry(vec![1], vec![2], location);
+        let serialized = serialize_entry(&entry);
+        assert_eq!(inner_tag(&serialized), EXTERNAL_HASH_MAP_ENTRY_TAG);
+        let deserialized = deserialize_entry(&serialized);
+        assert_eq!(entry, deserialized);
+    }
+
+    #[test]
+    fn test_serialize_deserialize_entry_timestamp() {
+        let entry = DBEntry::EntryTimestamp(vec![1], vec![2], 1_700_000_000_000);
+        let serialized = serialize_entry(&entry);
+        assert_eq!(inner_tag(&serialized), ENTRY_TIMESTAMP_TAG);
+        let deserialized = deserialize_entry(&serialized);
+        assert_eq!(entry, deserialized);
+    }
+
+    #[test]
+    fn test_serialize_deserialize_end_of_log() {
+        let serialized = serialize_entry(&DBEntry::EndOfLog);
+        assert_eq!(inner_tag(&serialized), END_OF_LOG_TAG);
+        assert_eq!(deserialize_entry(&serialized), DBEntry::EndOfLog);
+    }
+
+    #[test]
+    fn test_serialize_deserialize_log_format() {
+        let serialized = serialize_entry(&DBEntry::LogFormat(1));
+        assert_eq!(inner_tag(&serialized), LOG_FORMAT_TAG);
+        assert_eq!(deserialize_entry(&serialized), DBEntry::LogFormat(1));
+    }
+
+    #[test]
+    fn test_serialize_deserialize_file_header() {
+        let serialized = serialize_entry(&DBEntry::FileHeader(FORMAT_VERSION, 1));
+        assert_eq!(inner_tag(&serialized), FILE_HEADER_TAG);
+        assert_eq!(
+            deserialize_entry(&serialized),
+            DBEntry::FileHeader(FORMAT_VERSION, 1)
+        );
+    }
+
+    #[test]
+    fn test_serialize_deserialize_entry_expiry() {
+        let entry = DBEntry::EntryExpiry(vec![1], vec![2], 1_700_000_060_000);
+        let serialized = serialize_entry(&entry);
+        assert_eq!(inner_tag(&serialized), ENTRY_EXPIRY_TAG);
+        let deserialized = deserialize_entry(&serialized);
+        assert_eq!(entry, deserialized);
+    }
+
+    #[test]
+    fn test_serialize_deserialize_key_alias() {
+        let entry = DBEntry::KeyAlias(vec![1], vec![2; 13], vec![3; 100]);
+        let serialized = serialize_entry(&entry);
+        assert_eq!(inner_tag(&serialized), KEY_ALIAS_TAG);
+        let deserialized = deserialize_entry(&serialized);
+        assert_eq!(entry, deserialized);
+    }
+
+    #[test]
+    fn test_serialize_deserialize_entry_checksum() {
+        let entry = DBEntry::EntryChecksum(vec![1], vec![2], 0xCBF4_3926);
+        let serialized = serialize_entry(&entry);
+        assert_eq!(inner_tag(&serialized), ENTRY_CHECKSUM_TAG);
+        let deserialized = deserialize_entry(&serialized);
+        assert_eq!(entry, deserialized);
+    }
+
+    #[test]
+    fn test_serialize_deserialize_multi_map_entries() {
+        for (entry, tag) in [
+            (
+                DBEntry::MultiMapEntry(vec![1], vec![2], vec![3]),
+                MULTI_MAP_ENTRY_TAG,
+            ),
+            (
+                DBEntry::RemoveMultiMapValue(vec![1], vec![2], vec![3]),
+                REMOVE_MULTI_MAP_VALUE_TAG,
+            ),
+        ] {
+            let serialized = serialize_entry(&entry);
+            assert_eq!(inner_tag(&serialized), tag);
+            assert_eq!(deserialize_entry(&serialized), entry);
+        }
+    }
+
+    #[test]
+    fn test_unframed_entry_is_read() {
+        let entry = DBEntry::HashMapEntry(vec![1], vec![2], vec![3]);
+        let serialized = bincode::serialize(&Unframed(&entry)).unwrap();
+        assert_eq!(serialized[0], 0);
+        assert_eq!(deserialize_entry(&serialized), entry);
+    }
+
+    #[test]
+    fn test_frame_checksum_mismatch_is_detected() {
+        let mut serialized = serialize_entry(&DBEntry::HashSetEntry(vec![1], vec![2]));
+        *serialized.last_mut().unwrap() ^= 0xFF;
+        let error = bincode::deserialize::<DBEntry>(&serialized).unwrap_err();
+        assert!(is_frame_checksum_mismatch(&error));
+        let (recorded, found) = frame_checksums(&serialized).unwrap();
+        assert_ne!(recorded, found);
+    }
+
+    #[test]
+    fn test_extension_with_core_tag_fails_to_serialize() {
+        let entry = DBEntry::Extension(EXTENSION_TAG_START - 1, vec![1]);
+        assert!(bincode::serialize(&entry).is_err());
+    }
+
+    #[test]
+    fn test_unknown_extension_is_skipped_between_entries() {
+        let mut data = serialize_entry(&DBEntry::HashSetEntry(vec![1], vec![2]));
+        // A record from a newer version: an unknown tag followed by a length-prefixed payload.
+        data.push(250);
+        data.extend_from_slice(&bincode::serialize(&vec![9u8; 5]).unwrap());
+        data.extend_from_slice(&serialize_entry(&DBEntry::HashSetEntry(vec![1], vec![3])));
+
+        let mut cursor = std::io::Cursor::new(&data);
+        let entries = (0..3)
+            .map(|_| bincode::deserialize_from::<_, DBEntry>(&mut cursor).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(entries[1], DBEntry::Extension(250, vec![9; 5]));
+        assert_eq!(entries[2], DBEntry::HashSetEntry(vec![1], vec![3]));
+    }
+
     #[test]
     #[should_panic(expected = "Deserialization should succeed")]
     fn test_deserialization_failure() {
@@ -9,15 +9,27 @@
 //! and manipulation of data in a persistent manner.
 
 pub mod db_entry;
+pub mod transaction;
 
+use bincode::Options;
 use serde::{Deserialize, Serialize};
 use std::fs::{File, OpenOptions};
 use std::hash::Hash;
-use std::io::{self, Write};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, TryLockError};
+use std::time::Duration;
 
-use crate::{HashMap, HashMapConfig, HashSet, HashSetConfig, StructureError};
+use crate::{
+    structures::{
+        encode_id, group_commit::GroupCommit, lock_file, log_end, pending::PendingWrites, read_log,
+        replace_log, scan_file, writer::Writer, FileLock, LogPath,
+    },
+    AsyncHashMap, HashMap, HashMapConfig, HashSet, HashSetConfig, MultiMap, OrderedMap,
+    RecoveryMode, SerializationFormat, SkippedRecord, SnapshotHashMap, StructureError,
+};
+
+use self::db_entry::{DBEntry, FORMAT_VERSION};
 
 /// A builder for creating a new `Database` instance.
 ///
@@ -27,6 +39,11 @@ use crate::{HashMap, HashMapConfig, HashSet, HashSetConfig, StructureError};
 /// opening or creation of the database file.
 pub struct DBMaker {
     path: PathBuf,
+    group_commit: Option<Duration>,
+    read_only: bool,
+    shared: bool,
+    recovery: RecoveryMode,
+    format: Option<SerializationFormat>,
 }
 
 impl DBMaker {
@@ -36,7 +53,80 @@ impl DBMaker {
     ///
     /// * `path` - A `PathBuf` that points to the desired database file location.
     pub fn file_db<P: Into<PathBuf>>(path: P) -> Self {
-        Self { path: path.into() }
+        Self {
+            path: path.into(),
+            group_commit: None,
+            read_only: false,
+            shared: false,
+            recovery: RecoveryMode::Strict,
+            format: None,
+        }
+    }
+
+    /// Shares the syncs of the database's structures through a group commit.
+    ///
+    /// Synced writes (see `Durability::Sync`) from any hashmap opened through the database
+    /// are collected for `window` and made durable together by a single `sync_all`, instead of
+    /// one per write. Each write still completes only once it is durable, so this trades up
+    /// to `window` of extra latency per write for far fewer syncs under concurrent load.
+    ///
+    /// # Arguments
+    ///
+    /// * `window` - How long to collect writes before syncing them.
+    pub fn group_commit(mut self, window: Duration) -> Self {
+        self.group_commit = Some(window);
+        self
+    }
+
+    /// Opens the database read-only, for example to ship a prebuilt database as an asset.
+    ///
+    /// The file is opened without write or create access, so opening a missing file fails
+    /// with `io::ErrorKind::NotFound` and a file the process may only read can be opened.
+    /// Every mutating method of the hashmaps, hashsets and multimaps opened through the
+    /// database, and of the database itself, returns `StructureError::ReadOnly`, while reads
+    /// work as usual. Other structures fail to write with an I/O error.
+    /// Prepared logs left by an interrupted `MultiDbTransaction` are left untouched.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Lets other databases open the file at the same time, for read-only multi-reader use.
+    ///
+    /// By default a database takes an exclusive advisory lock on its file, so a second
+    /// database opening the same file, in this process or another, fails with
+    /// `io::ErrorKind::WouldBlock` instead of interleaving its appends. With this the database
+    /// takes a shared lock instead: any number of databases opened with `allow_shared` can
+    /// hold the file together, while one opened without it still can't. Combine it with
+    /// [`read_only`](#method.read_only) so the readers can't corrupt each other's view.
+    pub fn allow_shared(mut self) -> Self {
+        self.shared = true;
+        self
+    }
+
+    /// Recovers what it can from a damaged file instead of failing to open it.
+    ///
+    /// By default, a record in the middle of the file that can't be read, for example because
+    /// a byte of it was flipped on disk, fails the load of the structures it could belong to.
+    /// With this the hashmaps and hashsets opened through the database, and the database
+    /// itself, skip such a record and carry on from the next intact record (see
+    /// [`RecoveryMode::Lenient`]). The records that were skipped are lost, and are reported by
+    /// [`Database::skipped_records`].
+    pub fn lenient(mut self) -> Self {
+        self.recovery = RecoveryMode::Lenient;
+        self
+    }
+
+    /// Serializes the keys and values of the hashmaps and hashsets opened through the database
+    /// in `format`, for example JSON to keep them readable in the file while debugging.
+    ///
+    /// The format is recorded at the start of a new file, so reopening the database picks it
+    /// up without this being set again. Opening an existing file with a format other than the
+    /// one it was written in fails with `io::ErrorKind::InvalidInput`. Without this, new files
+    /// use bincode. Multimaps, async and snapshot hashmaps always use bincode.
+    pub fn format(mut self, format: SerializationFormat) -> Self {
+        self.format = Some(format);
+        self
     }
 
     /// Consumes the `DBMaker`, attempting to create a `Database`.
@@ -45,7 +135,18 @@ impl DBMaker {
     /// returning a `Database` instance on success. It encapsulates the logic required for
     /// the initialization of a `Database`, handling the creation or opening of the database file.
     pub fn make(self) -> io::Result<Database> {
-        Database::open(self.path)
+        let lock = match self.shared {
+            true => FileLock::Shared,
+            false => FileLock::Exclusive,
+        };
+        Database::open(
+            self.path,
+            self.group_commit,
+            self.read_only,
+            lock,
+            self.recovery,
+            self.format,
+        )
     }
 }
 
@@ -56,9 +157,83 @@ impl DBMaker {
 /// of data to and from the database file, encapsulating the file I/O logic required
 /// for persistent storage. This struct is central to the `rustmap-db` library, as it
 /// provides the mechanisms for reading from and writing to the database.
+///
+/// The hashmaps, hashsets and multimaps opened through a database append their writes through
+/// a single background writer, each structure's in the order its writes were applied in
+/// memory, so replaying the log always reproduces the last write to each key. A write only
+/// waits for the other writes of its own structure, so holding a reference into one structure
+/// while writing to another structure of the same database is fine.
 #[derive(Clone)]
 pub struct Database {
     pub(crate) file: Arc<Mutex<File>>,
+    path: PathBuf,
+    lock: FileLock,
+    recovery: RecoveryMode,
+    format: SerializationFormat,
+    group_commit: Option<Arc<GroupCommit>>,
+    pending: PendingWrites,
+    writer: Arc<Writer>,
+    skipped: Arc<Vec<SkippedRecord>>,
+    _sync_on_drop: Arc<SyncOnDrop>,
+}
+
+/// Syncs the database file to disk when the last handle to the database is dropped.
+///
+/// Errors are ignored, since they can't be returned from `drop`, and the sync is skipped if
+/// the file lock is held, so dropping a database never panics or deadlocks. Writes still in
+/// flight are not waited for; use [`Database::close_async`] for that.
+struct SyncOnDrop(Arc<Mutex<File>>);
+
+impl Drop for SyncOnDrop {
+    fn drop(&mut self) {
+        let file = match self.0.try_lock() {
+            Ok(file) => file,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return,
+        };
+        let _ = file.sync_data();
+    }
+}
+
+/// The outcome of [`Database::repair`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RepairReport {
+    /// The number of entries read from the file.
+    pub scanned_entries: usize,
+    /// The number of hashmap tombstones rewritten as hashset tombstones.
+    pub set_tombstones_fixed: usize,
+    /// The number of hashset tombstones rewritten as hashmap tombstones.
+    pub map_tombstones_fixed: usize,
+}
+
+impl RepairReport {
+    /// Returns true if any entry was rewritten.
+    pub fn repaired(&self) -> bool {
+        self.set_tombstones_fixed + self.map_tombstones_fixed > 0
+    }
+}
+
+/// The kind of a structure found in a database file by [`Database::list_structures`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StructureKind {
+    /// A `HashMap`, or a structure built on one such as an `AsyncHashMap`.
+    HashMap,
+    /// A `HashSet`.
+    HashSet,
+    /// A `MultiMap`.
+    MultiMap,
+}
+
+/// A structure found in a database file by [`Database::list_structures`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructureInfo {
+    /// The identifier the structure was created with.
+    pub id: String,
+    /// The kind of the structure.
+    pub kind: StructureKind,
+    /// The number of entries the structure holds: keys for hashmaps and multimaps, and
+    /// elements for hashsets.
+    pub len: usize,
 }
 
 impl Database {
@@ -71,28 +246,548 @@ impl Database {
     /// # Arguments
     ///
     /// * `path` - A `PathBuf` that points to the database file.
+    /// * `group_commit` - The window of the group commit, if the database uses one.
+    /// * `read_only` - Whether to open the file read-only, refusing every write.
+    /// * `lock` - The advisory lock to take on the file.
+    /// * `recovery` - How the structures opened through the database treat unreadable records.
+    /// * `format` - The serialization format requested for the file, if any. An empty file
+    ///   records it, and an existing file must already use it.
     ///
     /// # Errors
     ///
-    /// Will return an `io::Error` if the file cannot be created or opened.
-    fn open(path: PathBuf) -> io::Result<Self> {
-        let file = Arc::new(Mutex::new(
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(path)?,
-        ));
-        Ok(Self { file })
+    /// If the file contains an `EndOfLog` marker, for example after it was preallocated,
+    /// it is truncated to the marker so new entries are appended at the logical end of the
+    /// log rather than after the padding. Otherwise a partially written final entry, left by
+    /// a crash part way through an append, is cut off so new entries don't land after it.
+    /// Read-only databases leave the file as it is.
+    ///
+    /// Prepared logs left next to the file by an interrupted `MultiDbTransaction` are then
+    /// applied if their transaction committed, and discarded otherwise.
+    ///
+    /// Will return an `io::Error` if the file cannot be created or opened, and one of kind
+    /// `io::ErrorKind::WouldBlock` if another database holds a conflicting lock on it.
+    fn open(
+        path: PathBuf,
+        group_commit: Option<Duration>,
+        read_only: bool,
+        lock: FileLock,
+        recovery: RecoveryMode,
+        format: Option<SerializationFormat>,
+    ) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .create(!read_only)
+            .truncate(false)
+            .open(&path)?;
+        lock.acquire(&file, &path)?;
+        let file = Arc::new(Mutex::new(file));
+        // Checked before anything is cut off the file, so an unrelated file is left as it is.
+        let recorded = read_format(&file)?;
+        let mut writer = Writer::start()?;
+        let mut skipped = Vec::new();
+        if read_only {
+            writer = writer.read_only();
+            if recovery == RecoveryMode::Lenient {
+                skipped = log_end(&file, recovery).map_err(io::Error::other)?.1;
+            }
+        } else {
+            let end;
+            (end, skipped) = log_end(&file, recovery).map_err(io::Error::other)?;
+            {
+                let file = lock_file(&file).map_err(io::Error::other)?;
+                if end < file.metadata()?.len() {
+                    file.set_len(end)?;
+                }
+            }
+        }
+        let format = resolve_format(&file, recorded, format, read_only)?;
+        if !read_only {
+            transaction::recover(&path, &file).map_err(io::Error::other)?;
+        }
+        let group_commit =
+            group_commit.map(|window| Arc::new(GroupCommit::new(file.clone(), window)));
+        Ok(Self {
+            _sync_on_drop: Arc::new(SyncOnDrop(file.clone())),
+            file,
+            path,
+            lock,
+            recovery,
+            format,
+            group_commit,
+            pending: PendingWrites::default(),
+            writer: Arc::new(writer),
+            skipped: Arc::new(skipped),
+        })
     }
 
-    /// Flushes the database to disk.
+    /// Returns the path of the file and the lock held on it, for structures that replace it.
+    fn log_path(&self) -> LogPath {
+        LogPath {
+            path: self.path.clone(),
+            lock: self.lock,
+        }
+    }
+
+    /// Returns the records that couldn't be read when the database was opened.
+    ///
+    /// Only a database opened with [`DBMaker::lenient`] skips records; otherwise an unreadable
+    /// record fails the open, and this is empty. The skipped records are lost from the
+    /// structures loaded from the file.
+    pub fn skipped_records(&self) -> &[SkippedRecord] {
+        &self.skipped
+    }
+
+    /// Returns true if a database file exists at `path`, without opening or creating it.
+    pub fn exists<P: AsRef<Path>>(path: P) -> bool {
+        path.as_ref().is_file()
+    }
+
+    /// Returns the current size of the database file in bytes.
+    pub fn file_size(&self) -> io::Result<u64> {
+        Ok(lock_file(&self.file)
+            .map_err(io::Error::other)?
+            .metadata()?
+            .len())
+    }
+
+    /// Returns the number of times the group commit has synced the file, or 0 if the database
+    /// doesn't use one.
+    pub fn group_syncs(&self) -> u64 {
+        self.group_commit
+            .as_ref()
+            .map_or(0, |group_commit| group_commit.syncs())
+    }
+
+    /// Flushes the database's userspace write buffers to the operating system.
     ///
-    /// This method ensures that all buffered writes to the database file are committed
-    /// to disk. It is crucial for maintaining data integrity, especially after a series
-    /// of write operations.
+    /// This hands any buffered writes to the OS, but does not force the OS to write them to
+    /// the storage device, so they can still be lost on a power failure or OS crash. Use
+    /// [`sync`](#method.sync) when the data must be durable.
     pub fn flush(&self) -> io::Result<()> {
-        self.file.lock().unwrap().flush()?;
+        lock_file(&self.file).map_err(io::Error::other)?.flush()?;
+        Ok(())
+    }
+
+    /// Flushes the database and waits for the OS to write it to the storage device.
+    ///
+    /// This calls `File::sync_all`, which fsyncs both the file's contents and its metadata.
+    /// Once it returns, all writes that had completed before the call are durable.
+    pub fn sync(&self) -> io::Result<()> {
+        let mut file = lock_file(&self.file).map_err(io::Error::other)?;
+        file.flush()?;
+        file.sync_all()
+    }
+
+    /// Closes the database once every write spawned by its structures has finished, then syncs
+    /// it.
+    ///
+    /// Inserts and removals on the hashmaps, hashsets and multimaps opened through the
+    /// database are written by spawned tasks, which [`sync`](#method.sync) doesn't wait for.
+    /// This waits for all of them, including fire-and-forget writes whose handles were
+    /// dropped, so every write made before the call is durable once it resolves.
+    pub async fn close_async(self) -> Result<(), StructureError> {
+        self.pending.idle().await;
+        self.sync()?;
+        Ok(())
+    }
+
+    /// Reads the whole file once so the OS page cache is warm before structures are opened.
+    ///
+    /// The file is read sequentially in large blocks and the data discarded, which reduces the
+    /// latency of the first loads and lookups after a cold start. The file lock is held for
+    /// the duration of the read.
+    pub fn prefetch(&self) -> io::Result<()> {
+        let mut file = lock_file(&self.file).map_err(io::Error::other)?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut buffer = vec![0; 1 << 20];
+        while file.read(&mut buffer)? > 0 {}
+        Ok(())
+    }
+
+    /// Creates an isolated copy of the database at `dest` and opens it.
+    ///
+    /// The current contents of the file are copied to `dest` (replacing any existing file
+    /// there) while holding the file lock, so the fork sees a consistent snapshot. Changes made
+    /// through the returned `Database` don't affect this one, and vice versa.
+    ///
+    /// # Arguments
+    ///
+    /// * `dest` - The path of the new database file.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StructureError` if the file can't be read, copied, or opened.
+    pub fn fork(&self, dest: &Path) -> Result<Database, StructureError> {
+        {
+            let mut file = lock_file(&self.file)?;
+            let mut fork = File::create(dest)?;
+            file.seek(SeekFrom::Start(0))?;
+            io::copy(&mut *file, &mut fork)?;
+            fork.sync_all()?;
+        }
+        let group_commit = self
+            .group_commit
+            .as_ref()
+            .map(|group_commit| group_commit.window());
+        Ok(Database::open(
+            dest.to_path_buf(),
+            group_commit,
+            false,
+            FileLock::Exclusive,
+            self.recovery,
+            None,
+        )?)
+    }
+
+    /// Looks up the latest raw value of a key in a hashmap without loading the hashmap.
+    ///
+    /// The log is scanned once, keeping only the most recent `HashMapEntry` or
+    /// `RemoveHashMapEntry` for the key, so memory use doesn't depend on the size of the
+    /// hashmap. This suits occasional lookups in very large maps that aren't worth opening.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The identifier the hashmap was created with through [`hash_map`](#method.hash_map)
+    ///   or [`hash_map_with_config`](#method.hash_map_with_config).
+    /// * `key_bytes` - The bincode-serialized key.
+    ///
+    /// # Returns
+    ///
+    /// Returns the bincode-serialized value, or `None` if the key is absent or was removed.
+    /// Values stored in a sidecar file can't be read this way and return
+    /// `StructureError::LargeValueDirRequired`. Maps with `hash_keys_above` set store their keys
+    /// in another form, so lookups in them find nothing.
+    pub fn point_get(&self, id: &str, key_bytes: &[u8]) -> Result<Option<Vec<u8>>, StructureError> {
+        let ids = [structure_id(id)?, legacy_id(id)];
+        let mut latest = None;
+        let mut external = false;
+        scan_file(&self.file, |entry, _| {
+            match entry {
+                DBEntry::HashMapEntry(entry_id, key, value)
+                    if ids.contains(&entry_id) && key == key_bytes =>
+                {
+                    latest = Some(value);
+                    external = false;
+                }
+                DBEntry::ExternalHashMapEntry(entry_id, key, _)
+                    if ids.contains(&entry_id) && key == key_bytes =>
+                {
+                    latest = None;
+                    external = true;
+                }
+                DBEntry::RemoveHashMapEntry(entry_id, key)
+                    if ids.contains(&entry_id) && key == key_bytes =>
+                {
+                    latest = None;
+                    external = false;
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+        if external {
+            return Err(StructureError::LargeValueDirRequired);
+        }
+        Ok(latest)
+    }
+
+    /// Folds every entry of the log, in order, into a custom state.
+    ///
+    /// This gives full control over how the log is interpreted, for example to build an
+    /// event-sourced aggregate, without opening any typed structure. Entries are decoded one at
+    /// a time, so the log is never held in memory as a whole, and the file lock is held for the
+    /// whole fold. The fold stops after an `EndOfLog` entry, which is passed to `f` like any
+    /// other.
+    ///
+    /// # Arguments
+    ///
+    /// * `init` - The initial state.
+    /// * `f` - Combines the state with the next entry into the new state.
+    pub fn fold_log<S, F>(&self, init: S, mut f: F) -> Result<S, StructureError>
+    where
+        F: FnMut(S, &DBEntry) -> S,
+    {
+        let mut state = Some(init);
+        scan_file(&self.file, |entry, _| {
+            state = state.take().map(|state| f(state, &entry));
+            Ok(())
+        })?;
+        Ok(state.expect("the state is put back after every entry"))
+    }
+
+    /// Returns how many bytes of the file the records of each structure take up, keyed by the
+    /// id bytes the structure's records are stored under.
+    ///
+    /// The log is scanned once, and every record is counted in full, including overwritten
+    /// records and tombstones, which shows which structure is growing the file. Entries that
+    /// don't belong to a structure aren't counted, so the sizes add up to
+    /// [`file_size`](#method.file_size) less those entries.
+    pub fn size_by_id(&self) -> Result<std::collections::HashMap<Vec<u8>, u64>, StructureError> {
+        let mut sizes = std::collections::HashMap::new();
+        scan_file(&self.file, |entry, span| {
+            if let Some(id) = entry.id() {
+                *sizes.entry(id.to_vec()).or_insert(0) += span.end - span.start;
+            }
+            Ok(())
+        })?;
+        Ok(sizes)
+    }
+
+    /// Lists the structures stored in the file, with their kind and number of live entries.
+    ///
+    /// The log is scanned once, without opening any typed structure, so this works on a
+    /// database whose ids and types aren't known. The live keys of each structure are tracked
+    /// by their serialized form, so memory use grows with the number of keys. A name used by
+    /// several kinds of structure is listed once per kind, and the list is sorted by id and
+    /// then kind.
+    ///
+    /// Only structures with records of their kind are listed, so a structure that was opened
+    /// but never written to is missing. Expired hashmap entries still count until they are
+    /// removed from the file, and records under ids that weren't created through a
+    /// `Database` are skipped.
+    pub fn list_structures(&self) -> Result<Vec<StructureInfo>, StructureError> {
+        let mut keys = std::collections::BTreeMap::new();
+        let mut values = std::collections::HashMap::new();
+        scan_file(&self.file, |entry, _| {
+            let kind = match &entry {
+                DBEntry::HashMapEntry(..)
+                | DBEntry::ExternalHashMapEntry(..)
+                | DBEntry::RemoveHashMapEntry(..) => StructureKind::HashMap,
+                DBEntry::HashSetEntry(..) | DBEntry::RemoveHashSetEntry(..) => {
+                    StructureKind::HashSet
+                }
+                DBEntry::MultiMapEntry(..) | DBEntry::RemoveMultiMapValue(..) => {
+                    StructureKind::MultiMap
+                }
+                _ => return Ok(()),
+            };
+            let Some(name) = entry.id().and_then(structure_name) else {
+                return Ok(());
+            };
+            let live = keys
+                .entry((name, kind))
+                .or_insert_with(std::collections::HashSet::new);
+            match entry {
+                DBEntry::HashMapEntry(_, key, _)
+                | DBEntry::ExternalHashMapEntry(_, key, _)
+                | DBEntry::HashSetEntry(_, key) => {
+                    live.insert(key);
+                }
+                DBEntry::RemoveHashMapEntry(_, key) | DBEntry::RemoveHashSetEntry(_, key) => {
+                    live.remove(&key);
+                }
+                DBEntry::MultiMapEntry(id, key, value) => {
+                    live.insert(key.clone());
+                    let counts: &mut std::collections::HashMap<_, usize> =
+                        values.entry((id, key)).or_default();
+                    *counts.entry(value).or_default() += 1;
+                }
+                DBEntry::RemoveMultiMapValue(id, key, value) => {
+                    let entry = (id, key);
+                    let Some(counts) = values.get_mut(&entry) else {
+                        return Ok(());
+                    };
+                    if let Some(count) = counts.get_mut(&value) {
+                        *count -= 1;
+                        if *count == 0 {
+                            counts.remove(&value);
+                        }
+                    }
+                    if counts.is_empty() {
+                        values.remove(&entry);
+                        live.remove(&entry.1);
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+        Ok(keys
+            .into_iter()
+            .map(|((id, kind), live)| StructureInfo {
+                id,
+                kind,
+                len: live.len(),
+            })
+            .collect())
+    }
+
+    /// Lists the raw keys of every removal recorded for a structure, in log order.
+    ///
+    /// This returns the bincode-serialized key of each `RemoveHashMapEntry` or
+    /// `RemoveHashSetEntry` for `id`, including keys that were later inserted again, which
+    /// makes it possible to audit the structure's deletion history before it is compacted
+    /// away.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The identifier the hashmap or hashset was created with.
+    pub fn tombstones(&self, id: &str) -> Result<Vec<Vec<u8>>, StructureError> {
+        let ids = [structure_id(id)?, legacy_id(id)];
+        let mut tombstones = Vec::new();
+        scan_file(&self.file, |entry, _| {
+            match entry {
+                DBEntry::RemoveHashMapEntry(entry_id, key)
+                | DBEntry::RemoveHashSetEntry(entry_id, key)
+                    if ids.contains(&entry_id) =>
+                {
+                    tombstones.push(key)
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+        Ok(tombstones)
+    }
+
+    /// Finds tombstones of the wrong kind for a structure and rewrites them correctly.
+    ///
+    /// Older versions of `HashSet::remove_batch` recorded removals as `RemoveHashMapEntry`,
+    /// which the set ignores on reload, so the removed elements came back. For each id a
+    /// structure named `id` may be stored under, if the id is only used by a hashset (it has
+    /// set entries and no map entries), its `RemoveHashMapEntry` records are rewritten as
+    /// `RemoveHashSetEntry`, and vice versa for ids only used by a hashmap. Ids used by both
+    /// kinds or by neither are left alone. Since hashmaps and hashsets also load entries stored
+    /// under the unencoded id of older versions, entries under either id count for both.
+    ///
+    /// The file is only rewritten, under its lock, if something needs fixing. The repaired log
+    /// is written to a temporary file and renamed over the database file, so a crash leaves
+    /// either the old log or the repaired one. Structures opened before the repair keep their
+    /// in-memory state; reopen them to see its effect.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The identifier the hashmap or hashset was created with.
+    pub fn repair(&self, id: &str) -> Result<RepairReport, StructureError> {
+        self.writer.check_writable()?;
+        let ids = [legacy_id(id), structure_id(id)?];
+        let mut file = lock_file(&self.file)?;
+        let mut entries = read_log(&mut file, self.recovery)?;
+
+        let mut report = RepairReport {
+            scanned_entries: entries.len(),
+            ..RepairReport::default()
+        };
+        for id in &ids {
+            // A hashmap or hashset loads entries stored under either of its ids.
+            let used_as_map = entries.iter().any(|entry| {
+                matches!(entry, DBEntry::HashMapEntry(entry_id, _, _)
+                    | DBEntry::ExternalHashMapEntry(entry_id, _, _) if ids.contains(entry_id))
+            });
+            let used_as_set = entries.iter().any(
+                |entry| matches!(entry, DBEntry::HashSetEntry(entry_id, _) if ids.contains(entry_id)),
+            );
+            for entry in entries.iter_mut() {
+                match entry {
+                    DBEntry::RemoveHashMapEntry(entry_id, key)
+                        if entry_id == id && used_as_set && !used_as_map =>
+                    {
+                        *entry = DBEntry::RemoveHashSetEntry(entry_id.clone(), key.clone());
+                        report.set_tombstones_fixed += 1;
+                    }
+                    DBEntry::RemoveHashSetEntry(entry_id, key)
+                        if entry_id == id && used_as_map && !used_as_set =>
+                    {
+                        *entry = DBEntry::RemoveHashMapEntry(entry_id.clone(), key.clone());
+                        report.map_tombstones_fixed += 1;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if report.repaired() {
+            replace_log(&mut file, Some(&self.log_path()), &entries)?;
+        }
+        Ok(report)
+    }
+
+    /// Renames a structure by rewriting the id of each of its entries in the file.
+    ///
+    /// Both the hashmap and hashset stored under `old_id` are renamed. The renamed log is
+    /// written to a temporary file that is renamed over the database file under its lock, so
+    /// no writes interleave with the rename and a crash leaves either the old log or the new
+    /// one. Structures opened under either
+    /// id before the rename keep their in-memory state and their old id; reopen them under
+    /// `new_id` afterwards. Renaming a structure that doesn't exist does nothing.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_id` - The identifier the structure was created with.
+    /// * `new_id` - The identifier to store it under from now on.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StructureError::StructureExists` if the file already has entries under
+    /// `new_id`. Hashmaps with values in a sidecar file can't be renamed, as the sidecar file
+    /// is named after the id, and return `StructureError::LargeValueDirRequired`.
+    pub fn rename_structure(&self, old_id: &str, new_id: &str) -> Result<(), StructureError> {
+        self.writer.check_writable()?;
+        if old_id == new_id {
+            return Ok(());
+        }
+        let renames = [
+            (legacy_id(old_id), legacy_id(new_id)),
+            (structure_id(old_id)?, structure_id(new_id)?),
+        ];
+        let mut file = lock_file(&self.file)?;
+        let mut entries = read_log(&mut file, self.recovery)?;
+
+        for entry in &entries {
+            if renames.iter().any(|(_, new)| entry.id() == Some(new)) {
+                return Err(StructureError::StructureExists(new_id.to_string()));
+            }
+            if matches!(entry, DBEntry::ExternalHashMapEntry(id, _, _)
+                if renames.iter().any(|(old, _)| old == id))
+            {
+                return Err(StructureError::LargeValueDirRequired);
+            }
+        }
+        let mut renamed = false;
+        for id in entries.iter_mut().filter_map(DBEntry::id_mut) {
+            if let Some((_, new)) = renames.iter().find(|(old, _)| old == id) {
+                *id = new.clone();
+                renamed = true;
+            }
+        }
+        if renamed {
+            replace_log(&mut file, Some(&self.log_path()), &entries)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes a structure by rewriting the file without any of its entries.
+    ///
+    /// Every entry under `id` is dropped, whatever kind of structure wrote it, so the
+    /// hashmap, hashset and multimap named `id` are all deleted without opening them. The
+    /// remaining entries are written to a temporary file that is renamed over the database
+    /// file under its lock, so no writes interleave with the deletion and a crash leaves
+    /// either the old log or the new one. Structures
+    /// opened under `id` before the deletion keep their in-memory state, and their later writes
+    /// are appended to the file again. Deleting a structure that doesn't exist does nothing.
+    ///
+    /// A hashmap's sidecar value file is named after the id in a directory the database doesn't
+    /// know, so it is left in place.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The identifier the structure was created with.
+    pub fn delete_structure(&self, id: String) -> Result<(), StructureError> {
+        self.writer.check_writable()?;
+        let ids = [legacy_id(&id), structure_id(&id)?];
+        let mut file = lock_file(&self.file)?;
+        let mut entries = read_log(&mut file, self.recovery)?;
+
+        let len = entries.len();
+        entries.retain(|entry| {
+            !entry
+                .id()
+                .is_some_and(|id| ids.iter().any(|ours| ours == id))
+        });
+        if entries.len() < len {
+            replace_log(&mut file, Some(&self.log_path()), &entries)?;
+        }
         Ok(())
     }
 
@@ -116,7 +811,13 @@ impl Database {
         &self,
         id: String,
     ) -> Result<HashMap<K, V>, StructureError> {
-        Ok(HashMap::new(self.file.clone(), to_raw_id(id))?)
+        Ok(
+            HashMap::new_with(self.file.clone(), to_raw_id(id), self.recovery, self.format)?
+                .with_group_commit(self.group_commit.clone())
+                .with_pending_writes(self.pending.clone())
+                .with_writer(Arc::new(self.writer.for_structure()))
+                .with_path(self.log_path()),
+        )
     }
 
     /// Creates a new HashMap with a given capacity and/or shard-amount.
@@ -139,13 +840,66 @@ impl Database {
     >(
         &self,
         id: String,
-        config: HashMapConfig,
+        mut config: HashMapConfig,
     ) -> Result<HashMap<K, V>, StructureError> {
-        Ok(HashMap::with_config(
-            self.file.clone(),
-            to_raw_id(id),
-            config,
-        )?)
+        if self.recovery == RecoveryMode::Lenient {
+            config.recovery = RecoveryMode::Lenient;
+        }
+        if config.format == SerializationFormat::default() {
+            config.format = self.format;
+        }
+        Ok(
+            HashMap::with_config(self.file.clone(), to_raw_id(id), config)?
+                .with_group_commit(self.group_commit.clone())
+                .with_pending_writes(self.pending.clone())
+                .with_writer(Arc::new(self.writer.for_structure()))
+                .with_path(self.log_path()),
+        )
+    }
+
+    /// Creates a new AsyncHashMap.
+    ///
+    /// The map shares the file format and id space of [`hash_map`](#method.hash_map), but keeps
+    /// its contents behind an async read-write lock whose guards can be held across `.await`.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - A `String` identifier for the hashmap, unique within the database.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StructureError` if there is an issue in the creation process.
+    pub fn async_hash_map<
+        K: Serialize + for<'de> Deserialize<'de> + Eq + Hash + Clone + Send + Sync + 'static,
+        V: Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+    >(
+        &self,
+        id: String,
+    ) -> Result<AsyncHashMap<K, V>, StructureError> {
+        AsyncHashMap::new(self.file.clone(), to_raw_id(id))
+    }
+
+    /// Creates a new SnapshotHashMap.
+    ///
+    /// The map shares the file format and id space of [`hash_map`](#method.hash_map), but its
+    /// reads are lock-free and each write publishes a new copy of the map, which suits
+    /// read-heavy workloads.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - A `String` identifier for the hashmap, unique within the database.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StructureError` if there is an issue in the creation process.
+    pub fn snapshot_hash_map<
+        K: Serialize + for<'de> Deserialize<'de> + Eq + Hash + Clone + Send + Sync + 'static,
+        V: Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+    >(
+        &self,
+        id: String,
+    ) -> Result<SnapshotHashMap<K, V>, StructureError> {
+        SnapshotHashMap::new(self.file.clone(), to_raw_id(id))
     }
 
     /// Creates a new HashSet with a capacity of 0.
@@ -174,7 +928,12 @@ impl Database {
         &self,
         id: String,
     ) -> Result<HashSet<K>, StructureError> {
-        Ok(HashSet::new(self.file.clone(), to_raw_id(id))?)
+        Ok(
+            HashSet::new_with(self.file.clone(), to_raw_id(id), self.recovery, self.format)?
+                .with_pending_writes(self.pending.clone())
+                .with_writer(Arc::new(self.writer.for_structure()))
+                .with_path(self.log_path()),
+        )
     }
 
     /// Creates a new HashSet with a given capacity.
@@ -203,16 +962,191 @@ impl Database {
     >(
         &self,
         id: String,
-        config: HashSetConfig,
+        mut config: HashSetConfig,
     ) -> Result<HashSet<K>, StructureError> {
-        Ok(HashSet::with_config(
-            self.file.clone(),
-            to_raw_id(id),
-            config,
-        )?)
+        if self.recovery == RecoveryMode::Lenient {
+            config.recovery = RecoveryMode::Lenient;
+        }
+        if config.format == SerializationFormat::default() {
+            config.format = self.format;
+        }
+        Ok(
+            HashSet::with_config(self.file.clone(), to_raw_id(id), config)?
+                .with_pending_writes(self.pending.clone())
+                .with_writer(Arc::new(self.writer.for_structure()))
+                .with_path(self.log_path()),
+        )
+    }
+
+    /// Creates a new MultiMap, mapping each key to a list of values.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - A `String` identifier for the multimap, unique within the database.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StructureError` if there is an issue in the creation process.
+    pub fn multi_map<K, V>(&self, id: String) -> Result<MultiMap<K, V>, StructureError>
+    where
+        K: Serialize + for<'de> Deserialize<'de> + Eq + Hash + Clone + Send + 'static,
+        V: Serialize + for<'de> Deserialize<'de> + Clone + PartialEq + Send + 'static,
+    {
+        Ok(MultiMap::new(self.file.clone(), to_raw_id(id))?
+            .with_pending_writes(self.pending.clone())
+            .with_writer(Arc::new(self.writer.for_structure())))
+    }
+
+    /// Creates a new OrderedMap, which keeps its keys sorted for range scans.
+    ///
+    /// The map shares the file format and id space of [`hash_map`](#method.hash_map).
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - A `String` identifier for the map, unique within the database.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StructureError` if there is an issue in the creation process.
+    pub fn ordered_map<K, V>(&self, id: String) -> Result<OrderedMap<K, V>, StructureError>
+    where
+        K: Serialize + for<'de> Deserialize<'de> + Ord + Clone + Send + Sync + 'static,
+        V: Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+    {
+        Ok(OrderedMap::new(self.file.clone(), to_raw_id(id))?
+            .with_pending_writes(self.pending.clone())
+            .with_writer(Arc::new(self.writer.for_structure()))
+            .with_path(self.log_path()))
+    }
+}
+
+/// Returns the id bytes stored in the log by a structure created through `Database::hash_map`,
+/// `Database::hash_set` or `Database::multi_map`.
+fn structure_id(id: &str) -> Result<Vec<u8>, StructureError> {
+    encode_id(&to_raw_id(id.to_string()))
+}
+
+/// Reads the serialization format recorded by the first entry of the database file, or returns
+/// None if the file is empty.
+///
+/// New files start with a `FileHeader` entry. Files written before it was introduced are
+/// accepted if the whole file reads as complete entries, up to its end or an `EndOfLog` entry,
+/// and use the format of their first entry if it's a `LogFormat` entry, or bincode otherwise.
+/// Fails with `io::ErrorKind::InvalidData` wrapping `StructureError::BadMagic` if the first
+/// entry can't be read or a file without a header doesn't read as a log, wrapping
+/// `StructureError::UnsupportedVersion` if the header records a newer version of the file
+/// format, and without a wrapped error if the recorded format isn't known.
+fn read_format(file: &Arc<Mutex<File>>) -> io::Result<Option<SerializationFormat>> {
+    let bad_magic = || io::Error::new(io::ErrorKind::InvalidData, StructureError::BadMagic);
+    let mut locked = lock_file(file).map_err(io::Error::other)?;
+    let len = locked.metadata()?.len();
+    if len == 0 {
+        return Ok(None);
+    }
+    locked.seek(SeekFrom::Start(0))?;
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(len);
+    let byte = match options.deserialize_from(io::BufReader::new(&mut *locked)) {
+        Ok(DBEntry::FileHeader(version, _)) if version > FORMAT_VERSION => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                StructureError::UnsupportedVersion(version),
+            ));
+        }
+        Ok(DBEntry::FileHeader(_, byte)) => byte,
+        Ok(first) => {
+            drop(locked);
+            // Any bytes can happen to decode as a single entry, so a file without a header is
+            // only taken for an older log if all of it reads as one, without a truncated end.
+            let mut end = 0;
+            scan_file(file, |entry, span| {
+                end = match entry {
+                    DBEntry::EndOfLog => len,
+                    _ => span.end,
+                };
+                Ok(())
+            })
+            .map_err(|_| bad_magic())?;
+            if end < len {
+                return Err(bad_magic());
+            }
+            match first {
+                DBEntry::LogFormat(byte) => byte,
+                _ => return Ok(Some(SerializationFormat::Bincode)),
+            }
+        }
+        Err(_) => return Err(bad_magic()),
+    };
+    match SerializationFormat::from_byte(byte) {
+        Some(format) => Ok(Some(format)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown serialization format {}", byte),
+        )),
+    }
+}
+
+/// Returns the serialization format of the database file, given the format `recorded` in it
+/// before the database trimmed its end, and writes a `FileHeader` entry recording `requested`
+/// to the file if it is empty.
+///
+/// Fails with `io::ErrorKind::InvalidInput` if `requested` isn't the format of a non-empty file.
+fn resolve_format(
+    file: &Arc<Mutex<File>>,
+    recorded: Option<SerializationFormat>,
+    requested: Option<SerializationFormat>,
+    read_only: bool,
+) -> io::Result<SerializationFormat> {
+    let mut file = lock_file(file).map_err(io::Error::other)?;
+    // A file holding only an end marker is empty once it is trimmed.
+    let recorded = match recorded {
+        Some(recorded) if file.metadata()?.len() > 0 => recorded,
+        _ => {
+            let format = requested.unwrap_or_default();
+            if !read_only {
+                let header =
+                    bincode::serialize(&DBEntry::FileHeader(FORMAT_VERSION, format.to_byte()))
+                        .map_err(io::Error::other)?;
+                file.seek(SeekFrom::Start(0))?;
+                file.write_all(&header)?;
+            }
+            return Ok(format);
+        }
+    };
+    match requested {
+        Some(format) if format != recorded => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the file is serialized as {:?}, not {:?}", recorded, format),
+        )),
+        _ => Ok(recorded),
+    }
+}
+
+/// Returns the identifier a structure was created with from the id bytes of its records,
+/// which are either encoded by `structure_id` or, for hashsets of older versions, unencoded.
+fn structure_name(id: &[u8]) -> Option<String> {
+    fn from_raw(raw: &[u8]) -> Option<String> {
+        let (len, name) = raw.split_first_chunk::<8>()?;
+        if u64::from_be_bytes(*len) != name.len() as u64 {
+            return None;
+        }
+        String::from_utf8(name.to_vec()).ok()
+    }
+    match bincode::deserialize::<Vec<u8>>(id) {
+        Ok(raw) if raw.len() + 8 == id.len() => from_raw(&raw),
+        _ => from_raw(id),
     }
 }
 
+/// Returns the id bytes older versions stored in the log for a hashset created through
+/// `Database::hash_set`, or a hashmap created through `Database::hash_map_with_config`, before
+/// their ids were encoded like the ids of the other structures.
+fn legacy_id(id: &str) -> Vec<u8> {
+    to_raw_id(id.to_string())
+}
+
 /// Converts a string identifier to a raw byte representation.
 ///
 /// This utility function is used to transform a string-based identifier into a byte array
@@ -225,6 +1159,9 @@ impl Database {
 /// # Returns
 ///
 /// Returns a `Vec<u8>` that represents the raw byte format of the identifier.
+///
+/// The structures store this raw id in canonical form, bincode-serialized (see
+/// `structure_id`), so a hashmap, hashset and multimap with the same name share one id.
 pub(crate) fn to_raw_id(id: String) -> Vec<u8> {
     let mut raw_id = Vec::new();
     raw_id.extend_from_slice(&id.len().to_be_bytes());
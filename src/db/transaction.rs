@@ -0,0 +1,401 @@
+//! Multi-database transaction module for rustmap-db.
+//!
+//! This module provides `MultiDbTransaction`, which applies a set of writes to several
+//! databases so that, even across a crash, either every database reflects them or none does.
+//!
+//! The writes are committed in two phases. In the prepare phase, the writes staged for each
+//! database are written to a prepared log next to its file, named
+//! `<database file>.<transaction id>.txn`, and synced. In the commit phase, a commit marker
+//! listing the prepared logs is created next to the first database's file; its creation is
+//! the commit point. The prepared logs are then appended to their databases and removed,
+//! followed by the marker.
+//!
+//! When a database is opened, any prepared logs left next to it by a crash are resolved: if
+//! their transaction's commit marker exists they are applied, otherwise they are discarded.
+//! A database file therefore mustn't be opened a second time while a transaction on it is
+//! prepared but not yet committed, as the second open would discard its prepared log.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read as _, Seek as _, SeekFrom, Write as _},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{
+    structures::{lock_file, sync_dir, write_all_retrying, RetryPolicy},
+    Database, StructureError,
+};
+
+use super::{db_entry::DBEntry, structure_id};
+
+/// The `applied_at` header of a prepared log whose entries haven't been appended yet.
+const NOT_APPLIED: u64 = u64::MAX;
+
+/// The extension of prepared logs.
+const PREPARED_EXTENSION: &str = "txn";
+
+/// Distinguishes transactions started within the same nanosecond.
+static NEXT_TRANSACTION: AtomicU64 = AtomicU64::new(0);
+
+/// A set of writes to several databases that is applied to all of them or to none.
+///
+/// Writes are staged with [`insert`](#method.insert), [`remove`](#method.remove) or
+/// [`stage`](#method.stage), and nothing is written until the transaction is prepared and
+/// committed. The writes are appended to the databases' logs, so structures that are already
+/// open see them only once they are reloaded, for example with
+/// `PersistentStructure::load_from_file`.
+#[derive(Default)]
+pub struct MultiDbTransaction {
+    participants: Vec<(Database, Vec<DBEntry>)>,
+}
+
+impl MultiDbTransaction {
+    /// Creates an empty transaction.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stages an insert into the hashmap `id` of `db`, as opened with `Database::hash_map`.
+    pub fn insert<K: Serialize, V: Serialize>(
+        &mut self,
+        db: &Database,
+        id: &str,
+        key: &K,
+        value: &V,
+    ) -> Result<(), StructureError> {
+        let entry = DBEntry::HashMapEntry(
+            structure_id(id)?,
+            bincode::serialize(key)?,
+            bincode::serialize(value)?,
+        );
+        self.stage(db, entry);
+        Ok(())
+    }
+
+    /// Stages the removal of a key from the hashmap `id` of `db`, as opened with
+    /// `Database::hash_map`.
+    pub fn remove<K: Serialize>(
+        &mut self,
+        db: &Database,
+        id: &str,
+        key: &K,
+    ) -> Result<(), StructureError> {
+        let entry = DBEntry::RemoveHashMapEntry(structure_id(id)?, bincode::serialize(key)?);
+        self.stage(db, entry);
+        Ok(())
+    }
+
+    /// Stages a raw entry to be appended to the log of `db`.
+    pub fn stage(&mut self, db: &Database, entry: DBEntry) {
+        match self
+            .participants
+            .iter_mut()
+            .find(|(participant, _)| Arc::ptr_eq(&participant.file, &db.file))
+        {
+            Some((_, entries)) => entries.push(entry),
+            None => self.participants.push((db.clone(), vec![entry])),
+        }
+    }
+
+    /// Writes and syncs a prepared log for every database, without changing the databases.
+    ///
+    /// If preparing any database fails, the logs already written are removed and the error is
+    /// returned. Dropping the returned `PreparedTransaction` without committing it aborts the
+    /// transaction.
+    pub fn prepare(self) -> Result<PreparedTransaction, StructureError> {
+        let transaction = transaction_id();
+        let mut prepared = PreparedTransaction {
+            marker: PathBuf::new(),
+            participants: Vec::with_capacity(self.participants.len()),
+            finished: false,
+        };
+        for (db, entries) in self.participants {
+            let path = fs::canonicalize(&db.path)?;
+            let log = prepared_log_path(&path, transaction);
+            if prepared.participants.is_empty() {
+                prepared.marker =
+                    path.with_file_name(format!("{}.{}.commit", file_name(&path), transaction));
+            }
+            // Registered before it's written, so a failure part way removes it too.
+            prepared.participants.push((db, log.clone(), entries));
+            let (_, _, entries) = prepared.participants.last().expect("just pushed");
+            write_prepared_log(&log, &prepared.marker, entries)?;
+        }
+        Ok(prepared)
+    }
+}
+
+/// A transaction whose writes have been prepared for every database.
+///
+/// Dropping it without calling [`commit`](#method.commit) aborts the transaction and removes
+/// its prepared logs.
+pub struct PreparedTransaction {
+    marker: PathBuf,
+    participants: Vec<(Database, PathBuf, Vec<DBEntry>)>,
+    finished: bool,
+}
+
+impl PreparedTransaction {
+    /// Commits the transaction, applying its writes to every database.
+    ///
+    /// Once the commit marker has been created the transaction is committed: if applying the
+    /// writes to a database fails afterwards, the error is returned and the remaining writes
+    /// are applied the next time the database is opened.
+    pub fn commit(mut self) -> Result<(), StructureError> {
+        self.finished = true;
+        if self.participants.is_empty() {
+            return Ok(());
+        }
+        self.write_marker()?;
+        for (db, log, entries) in &self.participants {
+            apply(&db.file, log, entries)?;
+        }
+        fs::remove_file(&self.marker)?;
+        sync_dir(&self.marker)?;
+        Ok(())
+    }
+
+    /// Aborts the transaction, removing its prepared logs.
+    pub fn abort(mut self) -> Result<(), StructureError> {
+        self.finished = true;
+        self.remove_logs()
+    }
+
+    /// Creates the commit marker, listing the prepared logs.
+    fn write_marker(&self) -> Result<(), StructureError> {
+        let logs = self
+            .participants
+            .iter()
+            .map(|(_, log, _)| log.clone())
+            .collect::<Vec<_>>();
+        let pending = self.marker.with_extension("pending");
+        let mut file = File::create(&pending)?;
+        bincode::serialize_into(&mut file, &logs)?;
+        file.sync_all()?;
+        fs::rename(&pending, &self.marker)?;
+        sync_dir(&self.marker)?;
+        Ok(())
+    }
+
+    fn remove_logs(&self) -> Result<(), StructureError> {
+        for (_, log, _) in &self.participants {
+            match fs::remove_file(log) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => sync_dir(log)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PreparedTransaction {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.remove_logs();
+        }
+    }
+}
+
+/// Resolves the prepared logs left next to the database file at `path` by an interrupted
+/// transaction, applying those whose transaction committed and discarding the rest.
+pub(crate) fn recover(path: &Path, file: &Arc<Mutex<File>>) -> Result<(), StructureError> {
+    let path = fs::canonicalize(path)?;
+    let prefix = format!("{}.", file_name(&path));
+    let suffix = format!(".{}", PREPARED_EXTENSION);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    for dir_entry in fs::read_dir(dir)? {
+        let name = dir_entry?.file_name();
+        let Some(name) = name.to_str() else { continue };
+        let Some(transaction) = name
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_suffix(&suffix))
+        else {
+            continue;
+        };
+        if transaction.parse::<u128>().is_err() {
+            continue;
+        }
+
+        let log = dir.join(name);
+        match read_prepared_log(&log) {
+            Ok((applied_at, marker, entries)) if marker.exists() => {
+                if applied_at != NOT_APPLIED {
+                    lock_file(file)?.set_len(applied_at)?;
+                }
+                apply(file, &log, &entries)?;
+                remove_marker_if_done(&marker)?;
+            }
+            // A log that can't be read was never fully prepared, so it can't have committed.
+            _ => {
+                fs::remove_file(&log)?;
+                sync_dir(&log)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Appends a prepared log's entries to the database file and removes the log.
+///
+/// The length of the file is recorded in the log first, so an append interrupted by a crash
+/// can be cut off and redone by [`recover`]. The log is removed before the file is unlocked,
+/// so no other write can land after the appended entries while the log still exists.
+fn apply(file: &Arc<Mutex<File>>, log: &Path, entries: &[DBEntry]) -> Result<(), StructureError> {
+    let mut file = lock_file(file)?;
+    let applied_at = file.seek(SeekFrom::End(0))?;
+    let mut prepared = OpenOptions::new().write(true).open(log)?;
+    prepared.write_all(&applied_at.to_le_bytes())?;
+    prepared.sync_all()?;
+
+    let mut buffer = Vec::new();
+    for entry in entries {
+        bincode::serialize_into(&mut buffer, entry)?;
+    }
+    write_all_retrying(&mut *file, &buffer, RetryPolicy::default())?;
+    file.flush()?;
+    file.sync_all()?;
+    fs::remove_file(log)?;
+    sync_dir(log)?;
+    Ok(())
+}
+
+/// Writes and syncs a prepared log: the `applied_at` header, the path of the commit marker and
+/// the entries.
+fn write_prepared_log(
+    log: &Path,
+    marker: &Path,
+    entries: &[DBEntry],
+) -> Result<(), StructureError> {
+    let mut buffer = NOT_APPLIED.to_le_bytes().to_vec();
+    bincode::serialize_into(&mut buffer, marker)?;
+    for entry in entries {
+        bincode::serialize_into(&mut buffer, entry)?;
+    }
+    let mut file = File::create(log)?;
+    file.write_all(&buffer)?;
+    file.sync_all()?;
+    sync_dir(log)?;
+    Ok(())
+}
+
+/// Reads a prepared log written by [`write_prepared_log`].
+fn read_prepared_log(log: &Path) -> Result<(u64, PathBuf, Vec<DBEntry>), StructureError> {
+    let mut buffer = Vec::new();
+    File::open(log)?.read_to_end(&mut buffer)?;
+    let mut header = [0; 8];
+    let mut cursor = io::Cursor::new(&buffer);
+    cursor.read_exact(&mut header)?;
+    let marker = bincode::deserialize_from(&mut cursor)?;
+    let mut entries = Vec::new();
+    while cursor.position() < buffer.len() as u64 {
+        entries.push(bincode::deserialize_from(&mut cursor)?);
+    }
+    Ok((u64::from_le_bytes(header), marker, entries))
+}
+
+/// Removes a commit marker once none of the prepared logs it lists remain.
+fn remove_marker_if_done(marker: &Path) -> Result<(), StructureError> {
+    let logs: Vec<PathBuf> = bincode::deserialize_from(File::open(marker)?)?;
+    if logs.iter().all(|log| !log.exists()) {
+        fs::remove_file(marker)?;
+        sync_dir(marker)?;
+    }
+    Ok(())
+}
+
+fn prepared_log_path(path: &Path, transaction: u128) -> PathBuf {
+    path.with_file_name(format!(
+        "{}.{}.{}",
+        file_name(path),
+        transaction,
+        PREPARED_EXTENSION
+    ))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Returns an id for a new transaction, unique within and across processes in practice.
+fn transaction_id() -> u128 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    let sequence = NEXT_TRANSACTION.fetch_add(1, Ordering::Relaxed) as u128;
+    (nanos << 32) ^ ((std::process::id() as u128) << 16) ^ sequence
+}
+
+#[cfg(test)]
+mod transaction_tests {
+    use super::*;
+    use crate::{DBMaker, RecoveryMode};
+
+    /// Drops a prepared transaction without removing its logs, as a crash would, releasing
+    /// its databases so they can be reopened.
+    fn crash(mut prepared: PreparedTransaction) {
+        prepared.finished = true;
+    }
+
+    #[tokio::test]
+    async fn test_committed_marker_is_applied_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let first_path = dir.path().join("first.db");
+        let second_path = dir.path().join("second.db");
+        let first = DBMaker::file_db(first_path.clone()).make().unwrap();
+        let second = DBMaker::file_db(second_path.clone()).make().unwrap();
+
+        let mut transaction = MultiDbTransaction::new();
+        transaction.insert(&first, "map", &1u32, &10u32).unwrap();
+        transaction.insert(&second, "map", &2u32, &20u32).unwrap();
+        let prepared = transaction.prepare().unwrap();
+        // Crash right after the commit point: the marker exists but nothing was applied.
+        prepared.write_marker().unwrap();
+        crash(prepared);
+        drop((first, second));
+
+        let first = DBMaker::file_db(first_path).make().unwrap();
+        let second = DBMaker::file_db(second_path).make().unwrap();
+        let first_map = first.hash_map::<u32, u32>("map".to_string()).unwrap();
+        let second_map = second.hash_map::<u32, u32>("map".to_string()).unwrap();
+        assert_eq!(*first_map.get(&1).unwrap().value(), 10);
+        assert_eq!(*second_map.get(&2).unwrap().value(), 20);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_apply_is_redone_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("redo.db");
+        let db = DBMaker::file_db(path.clone()).make().unwrap();
+        let mut transaction = MultiDbTransaction::new();
+        transaction.insert(&db, "map", &1u32, &10u32).unwrap();
+        transaction.remove(&db, "map", &2u32).unwrap();
+        let prepared = transaction.prepare().unwrap();
+        prepared.write_marker().unwrap();
+
+        // Crash part way through appending: the header is set and half an entry is written.
+        let (_, log, _) = &prepared.participants[0];
+        let mut header = OpenOptions::new().write(true).open(log).unwrap();
+        header.write_all(&0u64.to_le_bytes()).unwrap();
+        lock_file(&db.file).unwrap().write_all(&[0, 1, 2]).unwrap();
+        crash(prepared);
+        drop(db);
+
+        let db = DBMaker::file_db(path).make().unwrap();
+        let entries =
+            crate::structures::read_log(&mut lock_file(&db.file).unwrap(), RecoveryMode::Strict)
+                .unwrap();
+        assert_eq!(entries.len(), 2);
+        let map = db.hash_map::<u32, u32>("map".to_string()).unwrap();
+        assert_eq!(*map.get(&1).unwrap().value(), 10);
+    }
+}
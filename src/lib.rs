@@ -17,10 +17,27 @@ pub mod db;
 pub mod structures;
 
 // Publicly re-export key components for easy access by library users.
-pub use db::{DBMaker, Database};
+pub use db::{
+    db_entry::{
+        RecoveryMode, SkippedRecord, UnknownEntryPolicy, ValueLocation, EXTENSION_TAG_START,
+    },
+    transaction::{MultiDbTransaction, PreparedTransaction},
+    DBMaker, Database, RepairReport, StructureInfo, StructureKind,
+};
 pub use structures::{
+    async_map::AsyncHashMap,
+    codec::{Codec, SerializationFormat},
+    conflict::ConflictResolver,
+    eviction::EvictionPolicy,
     hashmap::{HashMap, HashMapConfig, HashMapConfigBuilder},
     hashset::{HashSet, HashSetConfig, HashSetConfigBuilder},
+    key_lock::KeyGuard,
+    multimap::MultiMap,
+    ordered_map::OrderedMap,
+    persistent::PersistentStructure,
+    snapshot_map::SnapshotHashMap,
+    stats::{BatchSummary, CompactionEstimate, EffectiveConfig, StructureStats},
     structure_error::StructureError,
     value_ref::{ValueRef, ValueRefPair},
+    Durability,
 };
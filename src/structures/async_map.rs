@@ -0,0 +1,115 @@
+//! Async-aware hashmap module for rustmap-db.
+//!
+//! This module provides `AsyncHashMap`, a file-backed map whose in-memory state sits behind a
+//! single `tokio::sync::RwLock` instead of DashMap's synchronous shard locks. Its guards can be
+//! held across `.await` points without blocking the executor's worker threads, at the cost of
+//! the sharded throughput of [`HashMap`](crate::HashMap).
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap as StdHashMap,
+    fs::File,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{RwLock, RwLockReadGuard};
+
+use crate::{db::db_entry::DBEntry, StructureError};
+
+use super::{encode_id, load_map_entries, serialize_to_file, RetryPolicy};
+
+/// A file-backed hashmap guarded by an async read-write lock.
+///
+/// Writes take the write lock for both the in-memory update and the append to the file, so the
+/// log always records writes in the order they were applied. It shares the file format of
+/// `HashMap`, so a map written by one can be opened by the other with the same id.
+#[derive(Debug)]
+pub struct AsyncHashMap<K, V> {
+    inner: Arc<RwLock<StdHashMap<K, V>>>,
+    file: Arc<Mutex<File>>,
+    id: Vec<u8>,
+}
+
+impl<K, V> AsyncHashMap<K, V>
+where
+    K: Serialize + for<'de> Deserialize<'de> + Hash + Eq + Clone + Send + Sync + 'static,
+    V: Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+{
+    /// Creates a new AsyncHashMap, loading its contents from the file.
+    pub fn new(file: Arc<Mutex<File>>, id: Vec<u8>) -> Result<Self, StructureError> {
+        let id = encode_id(&id)?;
+        let inner = load_map_entries(&file, &id)?;
+        Ok(Self {
+            inner: Arc::new(RwLock::new(inner)),
+            file,
+            id,
+        })
+    }
+
+    /// Inserts a key-value pair and appends it to the file.
+    ///
+    /// Returns the old value (None if new).
+    pub async fn insert(&self, key: K, value: V) -> Result<Option<V>, StructureError> {
+        let entry = DBEntry::HashMapEntry(
+            self.id.clone(),
+            bincode::serialize(&key)?,
+            bincode::serialize(&value)?,
+        );
+        let mut inner = self.inner.write().await;
+        self.append(entry).await?;
+        Ok(inner.insert(key, value))
+    }
+
+    /// Returns a copy of the value corresponding to the given key.
+    ///
+    /// The read lock is released before returning, so the result can be held across `.await`.
+    pub async fn get(&self, key: &K) -> Option<V> {
+        self.inner.read().await.get(key).cloned()
+    }
+
+    /// Returns true if the map contains the given key.
+    pub async fn contains_key(&self, key: &K) -> bool {
+        self.inner.read().await.contains_key(key)
+    }
+
+    /// Acquires the read lock over the whole map.
+    ///
+    /// Unlike a DashMap reference, the returned guard may be held across `.await` points; writers
+    /// wait asynchronously until it is dropped.
+    pub async fn read(&self) -> RwLockReadGuard<'_, StdHashMap<K, V>> {
+        self.inner.read().await
+    }
+
+    /// Removes a key and appends the removal to the file.
+    ///
+    /// Returns the removed value, or None if the key did not exist, in which case nothing is
+    /// written.
+    pub async fn remove(&self, key: &K) -> Result<Option<V>, StructureError> {
+        let entry = DBEntry::RemoveHashMapEntry(self.id.clone(), bincode::serialize(key)?);
+        let mut inner = self.inner.write().await;
+        if !inner.contains_key(key) {
+            return Ok(None);
+        }
+        self.append(entry).await?;
+        Ok(inner.remove(key))
+    }
+
+    /// Appends `entry` to the file on tokio's blocking thread pool.
+    async fn append(&self, entry: DBEntry) -> Result<(), StructureError> {
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || {
+            serialize_to_file(&entry, &file, RetryPolicy::default())
+        })
+        .await?
+    }
+
+    /// Returns the number of key-value pairs in the map.
+    pub async fn len(&self) -> usize {
+        self.inner.read().await.len()
+    }
+
+    /// Returns true if the map contains no key-value pairs.
+    pub async fn is_empty(&self) -> bool {
+        self.inner.read().await.is_empty()
+    }
+}
@@ -0,0 +1,109 @@
+//! Automatic compaction for rustmap-db.
+//!
+//! A hashmap configured with `auto_compact_ratio` compacts the log itself once the file has
+//! grown to more than that many times the size compaction would leave it at. This module
+//! provides `AutoCompact`, which decides when a write should check the file and makes sure
+//! only one check of a map is queued or running at a time.
+
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use super::{
+    stats::CompactionEstimate,
+    writer::{ordered, Writer},
+};
+use crate::StructureError;
+
+/// The automatic compaction state of a hashmap.
+#[derive(Debug, Clone)]
+pub(crate) struct AutoCompact {
+    ratio: f64,
+    state: Arc<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// The size of the file after the last check. The next check waits until the file has
+    /// grown past this times the ratio, so the file isn't scanned after every write.
+    checked_bytes: AtomicU64,
+    /// Whether a check is queued or running.
+    running: AtomicBool,
+}
+
+/// Marks a check as running, clearing the mark when dropped.
+struct Running(Arc<State>);
+
+impl Running {
+    /// Marks a check as running, or returns None if one already is.
+    fn start(state: &Arc<State>) -> Option<Self> {
+        match state.running.swap(true, Ordering::AcqRel) {
+            true => None,
+            false => Some(Self(state.clone())),
+        }
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.running.store(false, Ordering::Release);
+    }
+}
+
+impl AutoCompact {
+    /// Creates the state of a map compacting at `ratio`, whose file is `file_bytes` long.
+    pub(crate) fn new(ratio: f64, file_bytes: u64) -> Self {
+        Self {
+            ratio,
+            state: Arc::new(State {
+                checked_bytes: AtomicU64::new(file_bytes),
+                running: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Checks the file after a write that left it `file_bytes` long, and compacts it with
+    /// `compact` if `estimate` finds it more than `ratio` times the size compaction would leave.
+    ///
+    /// `compact` returns the size of the compacted file. The check runs on `writer`, between
+    /// the writes queued before and after it, or when the returned future is polled if the map
+    /// has no writer. Nothing is done if the file hasn't grown enough since the last check, or
+    /// if a check of the map is already queued or running.
+    pub(crate) fn after_write<E, C>(
+        &self,
+        file_bytes: u64,
+        writer: Option<&Writer>,
+        estimate: E,
+        compact: C,
+    ) -> Result<impl Future<Output = Result<(), StructureError>> + Send + 'static, StructureError>
+    where
+        E: FnOnce() -> Result<CompactionEstimate, StructureError> + Send + 'static,
+        C: FnOnce() -> Result<u64, StructureError> + Send + 'static,
+    {
+        let checked_bytes = self.state.checked_bytes.load(Ordering::Acquire);
+        let running = (file_bytes as f64 > checked_bytes as f64 * self.ratio)
+            .then(|| Running::start(&self.state))
+            .flatten();
+        let slot = match (&running, writer) {
+            (Some(_), Some(writer)) => Some(writer.reserve()?),
+            _ => None,
+        };
+        let ratio = self.ratio;
+        Ok(ordered(slot, move || {
+            let Some(running) = running else {
+                return Ok(());
+            };
+            let estimate = estimate()?;
+            let mut file_bytes = estimate.current_bytes;
+            if file_bytes as f64 > estimate.estimated_bytes.max(1) as f64 * ratio {
+                file_bytes = compact()?;
+            }
+            running.0.checked_bytes.store(file_bytes, Ordering::Release);
+            Ok(())
+        }))
+    }
+}
@@ -0,0 +1,35 @@
+//! Synchronous writes for rustmap-db.
+//!
+//! The structures persist their writes in spawned tasks, which needs a tokio runtime. This
+//! module provides `run`, which the structures' `_sync` methods use to perform a write on the
+//! calling thread instead, so they can be used from code that has no runtime.
+
+use std::future::Future;
+
+use tokio::runtime::{Builder, Runtime};
+
+use crate::StructureError;
+
+thread_local! {
+    /// The runtime the synchronous writes of this thread run on, created by the first one.
+    static RUNTIME: Result<Runtime, std::io::Error> =
+        Builder::new_current_thread().enable_all().build();
+}
+
+/// Starts a write with `write` and blocks the calling thread until it completes.
+///
+/// The write runs on a single-threaded runtime kept for the calling thread, so its tasks run on
+/// the calling thread while it waits. Like other blocking calls, this must not be called from
+/// within an async runtime, where it panics.
+pub(crate) fn run<W, F, T>(write: W) -> Result<T, StructureError>
+where
+    W: FnOnce() -> F,
+    F: Future<Output = Result<Result<T, StructureError>, tokio::task::JoinError>>,
+{
+    RUNTIME.with(|runtime| {
+        let runtime = runtime
+            .as_ref()
+            .map_err(|e| std::io::Error::new(e.kind(), e.to_string()))?;
+        runtime.block_on(async { write().await? })
+    })
+}
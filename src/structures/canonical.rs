@@ -0,0 +1,613 @@
+//! Canonical key encoding module for rustmap-db.
+//!
+//! Compaction, in-place overwrites and raw lookups compare keys by their serialized bytes, so
+//! equal keys have to serialize identically. Plain bincode writes the entries of a map in
+//! iteration order, which for hash-based maps differs between two equal maps. The canonical
+//! encoding is bincode with the entries of every map sorted by their serialized key; it decodes
+//! with plain bincode and is byte-for-byte identical to it for keys without maps.
+//!
+//! Sets are serialized as sequences, which can't be told apart from ordered sequences, so keys
+//! containing hash-based sets should use ordered sets instead.
+
+use serde::ser::{self, Serialize, Serializer};
+
+use super::codec::{Codec, SerializationFormat};
+use crate::StructureError;
+
+/// Serializes `value` with bincode, sorting the entries of every map it contains.
+pub(crate) fn to_canonical_bytes<T: Serialize + ?Sized>(value: &T) -> bincode::Result<Vec<u8>> {
+    let content = value.serialize(ContentSerializer)?;
+    bincode::serialize(&content)
+}
+
+/// Serializes a key for the file in `format`, canonically if `canonical` is set.
+///
+/// Only bincode has a canonical encoding, which structures check when they're configured.
+pub(crate) fn encode_key<K: Serialize + ?Sized>(
+    key: &K,
+    canonical: bool,
+    format: SerializationFormat,
+) -> Result<Vec<u8>, StructureError> {
+    if canonical {
+        Ok(to_canonical_bytes(key)?)
+    } else {
+        format.encode(key)
+    }
+}
+
+/// A captured value in the serde data model.
+enum Content {
+    Bool(bool),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    U128(u128),
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    I128(i128),
+    F32(f32),
+    F64(f64),
+    Char(char),
+    String(String),
+    Bytes(Vec<u8>),
+    None,
+    Some(Box<Content>),
+    Unit,
+    UnitStruct(&'static str),
+    UnitVariant(&'static str, u32, &'static str),
+    NewtypeStruct(&'static str, Box<Content>),
+    NewtypeVariant(&'static str, u32, &'static str, Box<Content>),
+    Seq(Vec<Content>),
+    Tuple(Vec<Content>),
+    TupleStruct(&'static str, Vec<Content>),
+    TupleVariant(&'static str, u32, &'static str, Vec<Content>),
+    Map(Vec<(Content, Content)>),
+    Struct(&'static str, Vec<(&'static str, Content)>),
+    StructVariant(
+        &'static str,
+        u32,
+        &'static str,
+        Vec<(&'static str, Content)>,
+    ),
+}
+
+impl Serialize for Content {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use ser::{
+            SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant, SerializeTuple,
+            SerializeTupleStruct, SerializeTupleVariant,
+        };
+
+        match self {
+            Content::Bool(v) => serializer.serialize_bool(*v),
+            Content::U8(v) => serializer.serialize_u8(*v),
+            Content::U16(v) => serializer.serialize_u16(*v),
+            Content::U32(v) => serializer.serialize_u32(*v),
+            Content::U64(v) => serializer.serialize_u64(*v),
+            Content::U128(v) => serializer.serialize_u128(*v),
+            Content::I8(v) => serializer.serialize_i8(*v),
+            Content::I16(v) => serializer.serialize_i16(*v),
+            Content::I32(v) => serializer.serialize_i32(*v),
+            Content::I64(v) => serializer.serialize_i64(*v),
+            Content::I128(v) => serializer.serialize_i128(*v),
+            Content::F32(v) => serializer.serialize_f32(*v),
+            Content::F64(v) => serializer.serialize_f64(*v),
+            Content::Char(v) => serializer.serialize_char(*v),
+            Content::String(v) => serializer.serialize_str(v),
+            Content::Bytes(v) => serializer.serialize_bytes(v),
+            Content::None => serializer.serialize_none(),
+            Content::Some(v) => serializer.serialize_some(v),
+            Content::Unit => serializer.serialize_unit(),
+            Content::UnitStruct(name) => serializer.serialize_unit_struct(name),
+            Content::UnitVariant(name, index, variant) => {
+                serializer.serialize_unit_variant(name, *index, variant)
+            }
+            Content::NewtypeStruct(name, v) => serializer.serialize_newtype_struct(name, v),
+            Content::NewtypeVariant(name, index, variant, v) => {
+                serializer.serialize_newtype_variant(name, *index, variant, v)
+            }
+            Content::Seq(elements) => {
+                let mut seq = serializer.serialize_seq(Some(elements.len()))?;
+                for element in elements {
+                    seq.serialize_element(element)?;
+                }
+                seq.end()
+            }
+            Content::Tuple(elements) => {
+                let mut tuple = serializer.serialize_tuple(elements.len())?;
+                for element in elements {
+                    tuple.serialize_element(element)?;
+                }
+                tuple.end()
+            }
+            Content::TupleStruct(name, fields) => {
+                let mut tuple = serializer.serialize_tuple_struct(name, fields.len())?;
+                for field in fields {
+                    tuple.serialize_field(field)?;
+                }
+                tuple.end()
+            }
+            Content::TupleVariant(name, index, variant, fields) => {
+                let mut tuple =
+                    serializer.serialize_tuple_variant(name, *index, variant, fields.len())?;
+                for field in fields {
+                    tuple.serialize_field(field)?;
+                }
+                tuple.end()
+            }
+            Content::Map(entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+            Content::Struct(name, fields) => {
+                let mut s = serializer.serialize_struct(name, fields.len())?;
+                for (key, value) in fields {
+                    s.serialize_field(key, value)?;
+                }
+                s.end()
+            }
+            Content::StructVariant(name, index, variant, fields) => {
+                let mut s =
+                    serializer.serialize_struct_variant(name, *index, variant, fields.len())?;
+                for (key, value) in fields {
+                    s.serialize_field(key, value)?;
+                }
+                s.end()
+            }
+        }
+    }
+}
+
+/// Captures a value as `Content`, sorting map entries by their serialized key.
+struct ContentSerializer;
+
+impl Serializer for ContentSerializer {
+    type Ok = Content;
+    type Error = bincode::Error;
+    type SerializeSeq = SerializeElements;
+    type SerializeTuple = SerializeElements;
+    type SerializeTupleStruct = SerializeElements;
+    type SerializeTupleVariant = SerializeElements;
+    type SerializeMap = SerializeEntries;
+    type SerializeStruct = SerializeFields;
+    type SerializeStructVariant = SerializeFields;
+
+    // Types such as addresses serialize differently for human-readable formats, so the
+    // captured form has to match bincode's.
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bool(self, v: bool) -> bincode::Result<Content> {
+        Ok(Content::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> bincode::Result<Content> {
+        Ok(Content::I8(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> bincode::Result<Content> {
+        Ok(Content::I16(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> bincode::Result<Content> {
+        Ok(Content::I32(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> bincode::Result<Content> {
+        Ok(Content::I64(v))
+    }
+
+    fn serialize_i128(self, v: i128) -> bincode::Result<Content> {
+        Ok(Content::I128(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> bincode::Result<Content> {
+        Ok(Content::U8(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> bincode::Result<Content> {
+        Ok(Content::U16(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> bincode::Result<Content> {
+        Ok(Content::U32(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> bincode::Result<Content> {
+        Ok(Content::U64(v))
+    }
+
+    fn serialize_u128(self, v: u128) -> bincode::Result<Content> {
+        Ok(Content::U128(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> bincode::Result<Content> {
+        Ok(Content::F32(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> bincode::Result<Content> {
+        Ok(Content::F64(v))
+    }
+
+    fn serialize_char(self, v: char) -> bincode::Result<Content> {
+        Ok(Content::Char(v))
+    }
+
+    fn serialize_str(self, v: &str) -> bincode::Result<Content> {
+        Ok(Content::String(v.to_owned()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> bincode::Result<Content> {
+        Ok(Content::Bytes(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> bincode::Result<Content> {
+        Ok(Content::None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> bincode::Result<Content> {
+        Ok(Content::Some(Box::new(value.serialize(self)?)))
+    }
+
+    fn serialize_unit(self) -> bincode::Result<Content> {
+        Ok(Content::Unit)
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> bincode::Result<Content> {
+        Ok(Content::UnitStruct(name))
+    }
+
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+    ) -> bincode::Result<Content> {
+        Ok(Content::UnitVariant(name, index, variant))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> bincode::Result<Content> {
+        Ok(Content::NewtypeStruct(
+            name,
+            Box::new(value.serialize(self)?),
+        ))
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> bincode::Result<Content> {
+        Ok(Content::NewtypeVariant(
+            name,
+            index,
+            variant,
+            Box::new(value.serialize(self)?),
+        ))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> bincode::Result<SerializeElements> {
+        Ok(SerializeElements::new(ElementsKind::Seq, len.unwrap_or(0)))
+    }
+
+    fn serialize_tuple(self, len: usize) -> bincode::Result<SerializeElements> {
+        Ok(SerializeElements::new(ElementsKind::Tuple, len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> bincode::Result<SerializeElements> {
+        Ok(SerializeElements::new(ElementsKind::TupleStruct(name), len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> bincode::Result<SerializeElements> {
+        Ok(SerializeElements::new(
+            ElementsKind::TupleVariant(name, index, variant),
+            len,
+        ))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> bincode::Result<SerializeEntries> {
+        Ok(SerializeEntries {
+            entries: Vec::with_capacity(len.unwrap_or(0)),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, name: &'static str, len: usize) -> bincode::Result<SerializeFields> {
+        Ok(SerializeFields::new(FieldsKind::Struct(name), len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> bincode::Result<SerializeFields> {
+        Ok(SerializeFields::new(
+            FieldsKind::StructVariant(name, index, variant),
+            len,
+        ))
+    }
+}
+
+/// The compound a `SerializeElements` is capturing.
+enum ElementsKind {
+    Seq,
+    Tuple,
+    TupleStruct(&'static str),
+    TupleVariant(&'static str, u32, &'static str),
+}
+
+/// Captures the elements of sequences, tuples and tuple structs or variants.
+struct SerializeElements {
+    kind: ElementsKind,
+    elements: Vec<Content>,
+}
+
+impl SerializeElements {
+    fn new(kind: ElementsKind, len: usize) -> Self {
+        Self {
+            kind,
+            elements: Vec::with_capacity(len),
+        }
+    }
+
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> bincode::Result<()> {
+        self.elements.push(value.serialize(ContentSerializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Content {
+        match self.kind {
+            ElementsKind::Seq => Content::Seq(self.elements),
+            ElementsKind::Tuple => Content::Tuple(self.elements),
+            ElementsKind::TupleStruct(name) => Content::TupleStruct(name, self.elements),
+            ElementsKind::TupleVariant(name, index, variant) => {
+                Content::TupleVariant(name, index, variant, self.elements)
+            }
+        }
+    }
+}
+
+impl ser::SerializeSeq for SerializeElements {
+    type Ok = Content;
+    type Error = bincode::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> bincode::Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> bincode::Result<Content> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeTuple for SerializeElements {
+    type Ok = Content;
+    type Error = bincode::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> bincode::Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> bincode::Result<Content> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeElements {
+    type Ok = Content;
+    type Error = bincode::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> bincode::Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> bincode::Result<Content> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeElements {
+    type Ok = Content;
+    type Error = bincode::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> bincode::Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> bincode::Result<Content> {
+        Ok(self.finish())
+    }
+}
+
+/// Captures the entries of a map, which are sorted when the map ends.
+struct SerializeEntries {
+    entries: Vec<(Content, Content)>,
+    key: Option<Content>,
+}
+
+impl ser::SerializeMap for SerializeEntries {
+    type Ok = Content;
+    type Error = bincode::Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> bincode::Result<()> {
+        self.key = Some(key.serialize(ContentSerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> bincode::Result<()> {
+        let key = self.key.take().ok_or_else(|| {
+            <bincode::Error as ser::Error>::custom("map value serialized without a key")
+        })?;
+        self.entries
+            .push((key, value.serialize(ContentSerializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> bincode::Result<Content> {
+        let mut keyed = self
+            .entries
+            .into_iter()
+            .map(|(key, value)| Ok((bincode::serialize(&key)?, key, value)))
+            .collect::<bincode::Result<Vec<_>>>()?;
+        keyed.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(Content::Map(
+            keyed
+                .into_iter()
+                .map(|(_, key, value)| (key, value))
+                .collect(),
+        ))
+    }
+}
+
+/// The compound a `SerializeFields` is capturing.
+enum FieldsKind {
+    Struct(&'static str),
+    StructVariant(&'static str, u32, &'static str),
+}
+
+/// Captures the fields of structs and struct variants.
+struct SerializeFields {
+    kind: FieldsKind,
+    fields: Vec<(&'static str, Content)>,
+}
+
+impl SerializeFields {
+    fn new(kind: FieldsKind, len: usize) -> Self {
+        Self {
+            kind,
+            fields: Vec::with_capacity(len),
+        }
+    }
+
+    fn push<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> bincode::Result<()> {
+        self.fields.push((key, value.serialize(ContentSerializer)?));
+        Ok(())
+    }
+
+    fn finish(self) -> Content {
+        match self.kind {
+            FieldsKind::Struct(name) => Content::Struct(name, self.fields),
+            FieldsKind::StructVariant(name, index, variant) => {
+                Content::StructVariant(name, index, variant, self.fields)
+            }
+        }
+    }
+}
+
+impl ser::SerializeStruct for SerializeFields {
+    type Ok = Content;
+    type Error = bincode::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> bincode::Result<()> {
+        self.push(key, value)
+    }
+
+    fn end(self) -> bincode::Result<Content> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeFields {
+    type Ok = Content;
+    type Error = bincode::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> bincode::Result<()> {
+        self.push(key, value)
+    }
+
+    fn end(self) -> bincode::Result<Content> {
+        Ok(self.finish())
+    }
+}
+
+#[cfg(test)]
+mod canonical_tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use serde::{Deserialize, Serialize};
+
+    use super::to_canonical_bytes;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    enum Shape {
+        Point,
+        Circle(f64),
+        Rect { w: u32, h: u32 },
+        Pair(i8, char),
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Key {
+        name: String,
+        id: u128,
+        shapes: Vec<Shape>,
+        extra: Option<(bool, Vec<u8>)>,
+        ordered: BTreeMap<String, i64>,
+    }
+
+    #[test]
+    fn test_matches_bincode_without_hash_maps() {
+        let key = Key {
+            name: "key".to_string(),
+            id: u128::MAX,
+            shapes: vec![
+                Shape::Point,
+                Shape::Circle(1.5),
+                Shape::Rect { w: 2, h: 3 },
+                Shape::Pair(-1, 'x'),
+            ],
+            extra: Some((true, vec![1, 2, 3])),
+            ordered: [("a".to_string(), 1), ("b".to_string(), -2)].into(),
+        };
+        let bytes = to_canonical_bytes(&key).unwrap();
+        assert_eq!(bytes, bincode::serialize(&key).unwrap());
+        assert_eq!(bincode::deserialize::<Key>(&bytes).unwrap(), key);
+    }
+
+    #[test]
+    fn test_sorts_hash_map_entries() {
+        let pairs = (0..32).map(|i| (format!("k{}", i), i)).collect::<Vec<_>>();
+        let forward = pairs.iter().cloned().collect::<HashMap<_, _>>();
+        let backward = pairs.iter().rev().cloned().collect::<HashMap<_, _>>();
+
+        let bytes = to_canonical_bytes(&forward).unwrap();
+        assert_eq!(bytes, to_canonical_bytes(&backward).unwrap());
+        assert_eq!(
+            bincode::deserialize::<HashMap<String, i32>>(&bytes).unwrap(),
+            forward
+        );
+    }
+}
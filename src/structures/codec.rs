@@ -0,0 +1,107 @@
+//! Serialization format module for rustmap-db.
+//!
+//! The keys and values of hashmaps and hashsets are stored in the log as bytes produced by a
+//! [`Codec`]. This module provides the trait and [`SerializationFormat`], which selects one of
+//! the built-in codecs: bincode, which is compact and fast, or JSON, which leaves keys and
+//! values readable in the file for debugging.
+//!
+//! The entries around the keys and values are always bincode-encoded, as their framing relies
+//! on fixed-size lengths and checksums.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::StructureError;
+
+/// Encodes values to bytes and decodes them back.
+pub trait Codec {
+    /// Serializes `value` to bytes.
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, StructureError>;
+
+    /// Deserializes a value from bytes produced by [`encode`](#tymethod.encode).
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, StructureError>;
+
+    /// Returns the number of bytes `value` encodes to.
+    fn encoded_len<T: Serialize + ?Sized>(&self, value: &T) -> Result<usize, StructureError> {
+        Ok(self.encode(value)?.len())
+    }
+}
+
+/// The format the keys and values of a structure are serialized in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SerializationFormat {
+    /// Bincode, the compact binary format every file written before formats were selectable
+    /// uses.
+    #[default]
+    Bincode,
+    /// JSON, which is larger and slower but readable in the file. Keys containing maps must
+    /// have string keys, as JSON objects do.
+    Json,
+}
+
+impl SerializationFormat {
+    /// Returns the byte recording the format in a file.
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            SerializationFormat::Bincode => 0,
+            SerializationFormat::Json => 1,
+        }
+    }
+
+    /// Returns the format recorded as `byte`, or None if it isn't known.
+    pub(crate) fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(SerializationFormat::Bincode),
+            1 => Some(SerializationFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+impl Codec for SerializationFormat {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, StructureError> {
+        match self {
+            SerializationFormat::Bincode => Ok(bincode::serialize(value)?),
+            SerializationFormat::Json => Ok(serde_json::to_vec(value)?),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, StructureError> {
+        match self {
+            SerializationFormat::Bincode => Ok(bincode::deserialize(bytes)?),
+            SerializationFormat::Json => Ok(serde_json::from_slice(bytes)?),
+        }
+    }
+
+    fn encoded_len<T: Serialize + ?Sized>(&self, value: &T) -> Result<usize, StructureError> {
+        match self {
+            SerializationFormat::Bincode => Ok(bincode::serialized_size(value)? as usize),
+            SerializationFormat::Json => Ok(self.encode(value)?.len()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod codec_tests {
+    use super::*;
+
+    #[test]
+    fn test_formats_round_trip() {
+        let value = (42u32, "value".to_string(), vec![1.5f64, 2.5]);
+        for format in [SerializationFormat::Bincode, SerializationFormat::Json] {
+            let bytes = format.encode(&value).unwrap();
+            assert_eq!(format.encoded_len(&value).unwrap(), bytes.len());
+            assert_eq!(
+                format.decode::<(u32, String, Vec<f64>)>(&bytes).unwrap(),
+                value
+            );
+            assert_eq!(
+                SerializationFormat::from_byte(format.to_byte()),
+                Some(format)
+            );
+        }
+        assert_eq!(
+            SerializationFormat::Json.encode(&value).unwrap(),
+            br#"[42,"value",[1.5,2.5]]"#
+        );
+    }
+}
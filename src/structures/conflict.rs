@@ -0,0 +1,51 @@
+//! Conflict resolution for rustmap-db structures.
+//!
+//! When several processes append to the same file, a key can end up with conflicting records.
+//! This module provides `ConflictResolver`, which decides the value a map keeps for such a key
+//! while it is loaded, instead of the last record in the file winning.
+
+use std::sync::Arc;
+
+/// Chooses the value to keep for a key with more than one record in the file.
+///
+/// Implemented for closures taking the key, the value loaded so far and the value of the
+/// later record.
+pub trait ConflictResolver<K, V>: Send + Sync {
+    /// Returns the value to keep for `key`, given the value of an earlier record and the value
+    /// of a later one.
+    fn resolve(&self, key: &K, old: V, new: V) -> V;
+}
+
+impl<K, V, F> ConflictResolver<K, V> for F
+where
+    F: Fn(&K, V, V) -> V + Send + Sync,
+{
+    fn resolve(&self, key: &K, old: V, new: V) -> V {
+        self(key, old, new)
+    }
+}
+
+/// A shared conflict resolver, held by a map to use on every load.
+pub(crate) struct Resolver<K, V>(Arc<dyn ConflictResolver<K, V>>);
+
+impl<K, V> Resolver<K, V> {
+    pub(crate) fn new(resolver: impl ConflictResolver<K, V> + 'static) -> Self {
+        Self(Arc::new(resolver))
+    }
+
+    pub(crate) fn resolve(&self, key: &K, old: V, new: V) -> V {
+        self.0.resolve(key, old, new)
+    }
+}
+
+impl<K, V> Clone for Resolver<K, V> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<K, V> std::fmt::Debug for Resolver<K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Resolver")
+    }
+}
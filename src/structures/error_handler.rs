@@ -0,0 +1,57 @@
+//! Error reporting for background writes.
+//!
+//! Writes are persisted in spawned tasks whose JoinHandle may be dropped, in which case their
+//! errors would otherwise be lost. This module provides `ErrorReporter`, which passes those
+//! errors to a handler registered by the application.
+
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, RwLock},
+};
+
+use crate::StructureError;
+
+type ErrorHandler = Arc<dyn Fn(StructureError) + Send + Sync>;
+
+/// The slot holding a structure's background write error handler, if one is registered.
+#[derive(Clone, Default)]
+pub(crate) struct ErrorReporter {
+    handler: Arc<RwLock<Option<ErrorHandler>>>,
+}
+
+impl ErrorReporter {
+    /// Registers `handler`, replacing any previous one.
+    pub(crate) fn set(&self, handler: impl Fn(StructureError) + Send + Sync + 'static) {
+        let mut slot = self.handler.write().unwrap_or_else(|e| e.into_inner());
+        *slot = Some(Arc::new(handler));
+    }
+
+    /// Passes a copy of `error` to the handler, if one is registered.
+    ///
+    /// A panic in the handler is caught, so it can't take down the write task or poison the
+    /// slot.
+    pub(crate) fn report(&self, error: &StructureError) {
+        let handler = self
+            .handler
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        if let Some(handler) = handler {
+            let error = error.duplicate();
+            let _ = catch_unwind(AssertUnwindSafe(|| handler(error)));
+        }
+    }
+}
+
+impl std::fmt::Debug for ErrorReporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let registered = self
+            .handler
+            .read()
+            .map(|handler| handler.is_some())
+            .unwrap_or(false);
+        f.debug_struct("ErrorReporter")
+            .field("registered", &registered)
+            .finish()
+    }
+}
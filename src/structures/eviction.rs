@@ -0,0 +1,186 @@
+//! Entry limits for rustmap-db structures.
+//!
+//! This module provides `EvictionPolicy`, which decides what happens when an insert would take
+//! a map past its `max_entries`, and `Eviction`, which tracks the order its entries are evicted
+//! in.
+
+use std::{
+    collections::{BTreeMap, HashMap as StdHashMap},
+    hash::Hash,
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::StructureError;
+
+/// What a map with a `max_entries` limit does with an insert that would take it past the limit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// The least recently used entry is evicted. Both inserts and reads count as uses.
+    #[default]
+    Lru,
+    /// The entry that was inserted first is evicted. Overwriting a key doesn't change its place.
+    Fifo,
+    /// The insert is rejected with `StructureError::MapFull`. Overwrites of existing keys are
+    /// still accepted.
+    RejectNew,
+}
+
+/// The limit of a map's entries, and the order they are evicted in.
+#[derive(Debug)]
+pub(crate) struct Eviction<K> {
+    max_entries: usize,
+    policy: EvictionPolicy,
+    order: Mutex<EvictionOrder<K>>,
+}
+
+impl<K: Hash + Eq + Clone> Eviction<K> {
+    pub(crate) fn new(max_entries: usize, policy: EvictionPolicy) -> Self {
+        Self {
+            max_entries,
+            policy,
+            order: Mutex::new(EvictionOrder::default()),
+        }
+    }
+
+    /// Creates an empty tracker with the same limit and policy as this one.
+    pub(crate) fn sibling(&self) -> Self {
+        Self::new(self.max_entries, self.policy)
+    }
+
+    pub(crate) fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+
+    pub(crate) fn policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+
+    /// Locks the eviction order.
+    ///
+    /// Inserts hold the lock while they change the map, so checking the limit and evicting
+    /// happen atomically with the insert.
+    pub(crate) fn lock(&self) -> Result<MutexGuard<'_, EvictionOrder<K>>, StructureError> {
+        self.order
+            .lock()
+            .map_err(|_| StructureError::MutexLockError)
+    }
+
+    /// Records a read of `key`, which moves it to the back of the order under `Lru`.
+    ///
+    /// Must not be called while holding a reference into the map, since inserts lock the map's
+    /// shards while holding the order.
+    pub(crate) fn touch(&self, key: &K) {
+        if self.policy == EvictionPolicy::Lru {
+            if let Ok(mut order) = self.order.lock() {
+                if order.ticks.contains_key(key) {
+                    order.push(key.clone());
+                }
+            }
+        }
+    }
+
+    /// Forgets a key that was removed from the map.
+    pub(crate) fn forget(&self, key: &K) {
+        if let Ok(mut order) = self.order.lock() {
+            order.remove(key);
+        }
+    }
+
+    /// Replaces the order with `keys`, oldest first.
+    pub(crate) fn reset(&self, keys: impl IntoIterator<Item = K>) -> Result<(), StructureError> {
+        let mut order = self.lock()?;
+        *order = EvictionOrder::default();
+        for key in keys {
+            order.push(key);
+        }
+        Ok(())
+    }
+}
+
+/// The keys of a map, from the next one to be evicted to the last.
+///
+/// Every key has a tick, bumped each time the key moves to the back, so the oldest key is the
+/// first entry of `queue`.
+#[derive(Debug)]
+pub(crate) struct EvictionOrder<K> {
+    ticks: StdHashMap<K, u64>,
+    queue: BTreeMap<u64, K>,
+    next: u64,
+}
+
+impl<K> Default for EvictionOrder<K> {
+    fn default() -> Self {
+        Self {
+            ticks: StdHashMap::new(),
+            queue: BTreeMap::new(),
+            next: 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> EvictionOrder<K> {
+    /// Records an insert of `key` under `policy`.
+    pub(crate) fn inserted(&mut self, key: &K, policy: EvictionPolicy) {
+        if policy == EvictionPolicy::Lru || !self.ticks.contains_key(key) {
+            self.push(key.clone());
+        }
+    }
+
+    /// Removes and returns the next key to be evicted.
+    pub(crate) fn pop(&mut self) -> Option<K> {
+        let (_, key) = self.queue.pop_first()?;
+        self.ticks.remove(&key);
+        Some(key)
+    }
+
+    /// Returns the keys in the order they are evicted in.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &K> {
+        self.queue.values()
+    }
+
+    pub(crate) fn contains(&self, key: &K) -> bool {
+        self.ticks.contains_key(key)
+    }
+
+    pub(crate) fn push(&mut self, key: K) {
+        if let Some(tick) = self.ticks.insert(key.clone(), self.next) {
+            self.queue.remove(&tick);
+        }
+        self.queue.insert(self.next, key);
+        self.next += 1;
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.queue.remove(&tick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod eviction_tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_moves_reinserted_keys_back() {
+        let mut order = EvictionOrder::default();
+        for key in [1, 2, 3] {
+            order.inserted(&key, EvictionPolicy::Lru);
+        }
+        order.inserted(&1, EvictionPolicy::Lru);
+        assert_eq!(order.pop(), Some(2));
+        assert_eq!(order.pop(), Some(3));
+        assert_eq!(order.pop(), Some(1));
+        assert_eq!(order.pop(), None);
+    }
+
+    #[test]
+    fn test_fifo_keeps_first_insert_position() {
+        let mut order = EvictionOrder::default();
+        for key in [1, 2, 3] {
+            order.inserted(&key, EvictionPolicy::Fifo);
+        }
+        order.inserted(&1, EvictionPolicy::Fifo);
+        assert_eq!(order.pop(), Some(1));
+    }
+}
@@ -0,0 +1,135 @@
+//! Entry expiry for rustmap-db.
+//!
+//! This module provides `Expiries`, which keeps the expiry times of the entries a hashmap was
+//! given a TTL for, and runs the background task that removes them once they have expired.
+
+use std::{
+    future::Future,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use dashmap::DashMap;
+
+/// The expiry times of a hashmap's entries, in milliseconds since the Unix epoch.
+#[derive(Debug, Clone)]
+pub(crate) struct Expiries<K: Hash + Eq> {
+    deadlines: Arc<DashMap<K, u64>>,
+    interval: Duration,
+    sweeping: Arc<AtomicBool>,
+}
+
+impl<K: Hash + Eq + Clone> Default for Expiries<K> {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1))
+    }
+}
+
+impl<K: Hash + Eq + Clone> Expiries<K> {
+    /// Creates the expiry times of a map whose expired entries are removed every `interval`.
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            deadlines: Arc::new(DashMap::new()),
+            interval,
+            sweeping: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Creates empty expiry times with the same interval, for a copy of the map.
+    pub(crate) fn sibling(&self) -> Self {
+        Self::new(self.interval)
+    }
+
+    /// Sets the expiry time of `key`, or clears it if `expiry` is None.
+    pub(crate) fn set(&self, key: &K, expiry: Option<u64>) {
+        match expiry {
+            Some(expiry) => {
+                self.deadlines.insert(key.clone(), expiry);
+            }
+            None => {
+                self.deadlines.remove(key);
+            }
+        }
+    }
+
+    /// Returns the expiry time of `key`, if it has one.
+    pub(crate) fn get(&self, key: &K) -> Option<u64> {
+        self.deadlines.get(key).map(|expiry| *expiry)
+    }
+
+    /// Clears the expiry time of `key`.
+    pub(crate) fn remove(&self, key: &K) {
+        self.deadlines.remove(key);
+    }
+
+    /// Clears every expiry time.
+    pub(crate) fn clear(&self) {
+        self.deadlines.clear();
+    }
+
+    /// Clears the expiry times of the keys that had expired at `now`, and returns the keys.
+    ///
+    /// A key given a new expiry time concurrently is left alone.
+    pub(crate) fn take_expired(&self, now: u64) -> Vec<K> {
+        let expired = self
+            .deadlines
+            .iter()
+            .filter(|expiry| *expiry.value() <= now)
+            .map(|expiry| expiry.key().clone())
+            .collect::<Vec<_>>();
+        expired
+            .into_iter()
+            .filter(|key| {
+                self.deadlines
+                    .remove_if(key, |_, expiry| *expiry <= now)
+                    .is_some()
+            })
+            .collect()
+    }
+
+    /// Returns true if there are expiry times but no task removing the expired entries yet.
+    pub(crate) fn needs_sweeper(&self) -> bool {
+        !self.sweeping.load(Ordering::Acquire) && !self.deadlines.is_empty()
+    }
+
+    /// Starts the task that calls `sweep` every `interval` while there are expiry times, unless
+    /// it is already running or there is no tokio runtime to run it on.
+    ///
+    /// `sweep` returns the removal of the expired entries, or None once the map is gone, which
+    /// stops the task.
+    pub(crate) fn start<S, F>(&self, mut sweep: S)
+    where
+        S: FnMut() -> Option<F> + Send + 'static,
+        F: Future<Output = ()> + Send,
+        K: Send + Sync + 'static,
+    {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        if self.sweeping.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let expiries = self.clone();
+        runtime.spawn(async move {
+            loop {
+                tokio::time::sleep(expiries.interval).await;
+                match sweep() {
+                    Some(removal) => removal.await,
+                    None => break,
+                }
+                if expiries.deadlines.is_empty() {
+                    expiries.sweeping.store(false, Ordering::Release);
+                    // An expiry time set just before the flag was cleared didn't start a task.
+                    if !expiries.needs_sweeper() || expiries.sweeping.swap(true, Ordering::AcqRel) {
+                        return;
+                    }
+                }
+            }
+            expiries.sweeping.store(false, Ordering::Release);
+        });
+    }
+}
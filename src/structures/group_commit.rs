@@ -0,0 +1,122 @@
+//! Group commit module for rustmap-db.
+//!
+//! This module provides `GroupCommit`, which lets the structures of a database share the
+//! `sync_all` calls of their synced writes. Writes that finish within a short window of each
+//! other are made durable by a single sync of the file.
+
+use std::{
+    fs::File,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::sync::watch;
+
+use crate::StructureError;
+
+use super::lock_file;
+
+/// The outcome of syncing a batch, shared by every write that joined it.
+type BatchResult = Option<Result<(), Arc<StructureError>>>;
+
+/// The batch writes are currently joining, and whether a task is already waiting to sync it.
+#[derive(Debug)]
+struct State {
+    open: Arc<watch::Sender<BatchResult>>,
+    leader: bool,
+}
+
+/// Coordinates the syncs of writes to a shared file, issuing one `sync_all` per batch.
+///
+/// The first write to join an empty batch becomes its leader: it waits for the window to
+/// elapse, closes the batch, syncs the file once and signals every write in the batch. Writes
+/// arriving while the batch is being synced join the next one.
+#[derive(Debug)]
+pub(crate) struct GroupCommit {
+    file: Arc<Mutex<File>>,
+    window: Duration,
+    state: Mutex<State>,
+    syncs: AtomicU64,
+}
+
+impl GroupCommit {
+    pub(crate) fn new(file: Arc<Mutex<File>>, window: Duration) -> Self {
+        Self {
+            file,
+            window,
+            state: Mutex::new(State {
+                open: Arc::new(watch::channel(None).0),
+                leader: false,
+            }),
+            syncs: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the window writes are collected over before a batch is synced.
+    pub(crate) fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Returns the number of times the file has been synced.
+    pub(crate) fn syncs(&self) -> u64 {
+        self.syncs.load(Ordering::Relaxed)
+    }
+
+    /// Waits until every write appended to the file before the call is durable.
+    pub(crate) async fn sync(self: &Arc<Self>) -> Result<(), StructureError> {
+        let (batch, lead) = {
+            let mut state = self
+                .state
+                .lock()
+                .map_err(|_| StructureError::MutexLockError)?;
+            let lead = !state.leader;
+            state.leader = true;
+            (state.open.clone(), lead)
+        };
+        let mut done = batch.subscribe();
+
+        if lead {
+            // The batch is synced by its own task, so it completes even if this write is
+            // cancelled while waiting.
+            let group = self.clone();
+            tokio::spawn(async move { group.commit(batch).await });
+        }
+
+        let result = done
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| StructureError::MutexLockError)?;
+        match result.as_ref() {
+            Some(Err(e)) => Err(e.duplicate()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits for the window to elapse, then closes `batch`, syncs the file and signals the
+    /// writes in the batch.
+    async fn commit(&self, batch: Arc<watch::Sender<BatchResult>>) {
+        tokio::time::sleep(self.window).await;
+        let closed = self
+            .state
+            .lock()
+            .map(|mut state| {
+                state.open = Arc::new(watch::channel(None).0);
+                state.leader = false;
+            })
+            .map_err(|_| StructureError::MutexLockError);
+        let result = match closed {
+            Ok(()) => {
+                let file = self.file.clone();
+                tokio::task::spawn_blocking(move || Ok(lock_file(&file)?.sync_all()?))
+                    .await
+                    .unwrap_or_else(|e| Err(e.into()))
+            }
+            Err(e) => Err(e),
+        };
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        batch.send_replace(Some(result.map_err(Arc::new)));
+    }
+}
@@ -1,28 +1,728 @@
+use bincode::Options;
 use dashmap::DashMap;
 use derive_builder::Builder;
+use futures::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap as StdHashMap,
     fs::File,
     hash::Hash,
-    io::{Read, Seek, SeekFrom, Write},
-    sync::{Arc, Mutex},
+    io::{BufReader, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio::task::JoinHandle;
+use tokio::{sync::OnceCell, task::JoinHandle};
 
-use crate::{db::db_entry::DBEntry, StructureError};
+use crate::{
+    db::db_entry::{DBEntry, RecoveryMode, SkippedRecord, UnknownEntryPolicy, ValueLocation},
+    StructureError,
+};
 
-use super::{lock_file, serialize_to_file, value_ref::ValueRefPair};
+use super::{
+    append_entries, append_entry,
+    auto_compact::AutoCompact,
+    blocking, check_fingerprint, check_unknown_entry,
+    checksum::crc32,
+    codec::{Codec, SerializationFormat},
+    conflict::{ConflictResolver, Resolver},
+    encode_id,
+    error_handler::ErrorReporter,
+    eviction::{Eviction, EvictionPolicy},
+    expiry::Expiries,
+    group_commit::GroupCommit,
+    key_codec::KeyCodec,
+    key_lock::{KeyGuard, KeyLocks},
+    large_value::LargeValues,
+    lock_file, overwrite_entry,
+    pending::PendingWrites,
+    persistent::{
+        compact_entries, estimate_compaction, structure_stats, PersistentStructure, Record,
+    },
+    read_concurrently, read_log, replace_log, scan_entries, scan_file, serialize_chunks_at,
+    serialize_chunks_to_file, serialize_to_file,
+    spill::{Spill, SpillWrite},
+    stats::{BatchSummary, CompactionEstimate, EffectiveConfig, StructureStats},
+    sync_file, type_fingerprint, validate_file,
+    value_ref::ValueRefPair,
+    write_all_retrying,
+    writer::{ordered, Slot, WriteQueue, Writer},
+    Durability, LogPath, RetryPolicy, DEFAULT_BATCH_CHUNK_SIZE, LOAD_REGION_BYTES,
+};
 
 /// Configuration for creating a `HashMap`.
 ///
 /// This struct defines the parameters for creating a `HashMap`, such as
 /// the number of shards and the initial capacity.
 #[derive(Debug, Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
 pub struct HashMapConfig {
     #[builder(default = "1")]
     pub shard_amount: usize,
     #[builder(default = "0")]
     pub capacity: usize,
+    /// The maximum number of entries serialized and written at once by the batch operations.
+    #[builder(default = "DEFAULT_BATCH_CHUNK_SIZE")]
+    pub batch_chunk_size: usize,
+    /// Whether to record a fingerprint of the structure's types in the file, so reopening it
+    /// with different types fails with `StructureError::TypeMismatch`.
+    #[builder(default = "false")]
+    pub type_fingerprint: bool,
+    /// How extension entries this version doesn't recognise are treated while loading.
+    #[builder(default)]
+    pub unknown_entries: UnknownEntryPolicy,
+    /// How records that can't be read in the middle of the file are treated while loading.
+    #[builder(default)]
+    pub recovery: RecoveryMode,
+    /// Whether writes are synced to disk before their JoinHandle completes.
+    #[builder(default)]
+    pub durability: Durability,
+    /// Whether to record the time of every insert in the file, for
+    /// [`remove_older_than`](HashMap::remove_older_than).
+    #[builder(default = "false")]
+    pub timestamps: bool,
+    /// Whether `insert` overwrites a key's existing record in the file when the new value
+    /// serializes to the same length, instead of appending a new one. This stops the file
+    /// growing for maps of fixed-size values that are updated in place.
+    #[builder(default = "false")]
+    pub overwrite_in_place: bool,
+    /// Serialized values larger than this many bytes are stored in a sidecar file in
+    /// `large_value_dir`, and only their location is kept in the log and read back on access.
+    #[builder(default, setter(strip_option))]
+    pub large_value_threshold: Option<usize>,
+    /// The directory holding the map's sidecar value file. Required when
+    /// `large_value_threshold` is set, and to open a map that stored large values before.
+    #[builder(default, setter(into, strip_option))]
+    pub large_value_dir: Option<PathBuf>,
+    /// How many times a write to the file is retried after a transient I/O error, such as
+    /// `WouldBlock` or `TimedOut`, before the error is returned. Other errors fail immediately.
+    #[builder(default = "0")]
+    pub write_retries: u32,
+    /// The delay before the first retry of a failed write, doubled for every retry after it.
+    #[builder(default = "Duration::from_millis(10)")]
+    pub retry_backoff: Duration,
+    /// The largest serialized value, in bytes, that the inserts accept. Larger values are
+    /// rejected with `StructureError::ValueTooLarge` before anything is changed. Loading a
+    /// file holding a larger value fails the same way, before the value is decoded.
+    #[builder(default, setter(strip_option))]
+    pub max_value_bytes: Option<usize>,
+    /// Whether keys are written in a canonical encoding, in which the entries of any maps inside
+    /// them are sorted, so equal keys always serialize to the same bytes. Needed for keys
+    /// containing hash-based maps, whose entries are otherwise written in iteration order and
+    /// aren't matched up by compaction or in-place overwrites. Keys without maps encode the same
+    /// either way. Should be set when the map is created, as records written without it aren't
+    /// re-encoded.
+    #[builder(default = "false")]
+    pub canonical_keys: bool,
+    /// Serialized keys longer than this many bytes are written in full only once, and referred
+    /// to by a fixed-size alias derived from their hash in every record after that. This keeps
+    /// the log of a map with large keys, such as long paths, from growing by the whole key on
+    /// every overwrite or removal. Changes how every key of the map is written, so it has to be
+    /// set the same way whenever the map is opened.
+    #[builder(default, setter(strip_option))]
+    pub hash_keys_above: Option<usize>,
+    /// How many regions of the file are read at once while the map is loaded. Values above 1
+    /// overlap the latency of the reads, which speeds up loads from high-latency storage such
+    /// as network filesystems. The regions are reassembled in order before any entry is read.
+    #[builder(default = "1")]
+    pub load_concurrency: usize,
+    /// The most entries the map holds. An insert of a new key into a full map is handled as
+    /// `eviction` says: an existing entry is evicted, and its removal persisted, or the insert
+    /// is rejected. Only inserts enforce the limit, so a map loaded from a file holding more
+    /// entries is brought down to it by the next insert.
+    #[builder(default, setter(strip_option))]
+    pub max_entries: Option<usize>,
+    /// What an insert past `max_entries` does.
+    #[builder(default)]
+    pub eviction: EvictionPolicy,
+    /// Whether removing a key whose `insert` hasn't been written yet cancels that write instead
+    /// of appending a tombstone, so keys that are inserted and removed in quick succession
+    /// leave nothing in the file. Only single inserts of keys that weren't in the map are
+    /// tracked; removals of any other key are always written.
+    #[builder(default = "false")]
+    pub skip_unwritten_tombstones: bool,
+    /// The format keys and values are serialized in. Has to be the same whenever the map is
+    /// opened, and can't be combined with `canonical_keys` unless it's bincode.
+    #[builder(default)]
+    pub format: SerializationFormat,
+    /// Whether every value written to the log is followed by a CRC32 checksum of its bytes,
+    /// which [`get_verified`](HashMap::get_verified) checks. Values stored in the sidecar file
+    /// aren't checksummed, and inserts no longer overwrite records in place.
+    #[builder(default = "false")]
+    pub checksums: bool,
+    /// Compacts the log automatically once the file is more than this many times the size
+    /// compaction would leave it at, checked after writes. The check scans the file, so it is
+    /// only made after the file has grown by the ratio since the last one. Compactions run
+    /// in the background, after the writes queued before them, and never concurrently with
+    /// another write. Has to be greater than 1.
+    #[builder(default, setter(strip_option))]
+    pub auto_compact_ratio: Option<f64>,
+    /// The most appends of the map that are queued but haven't started. The append of a write
+    /// made while this many are waiting is only queued once the oldest one starts; its
+    /// JoinHandle waits for that without blocking the calling thread, so a caller awaiting
+    /// writes faster than the file takes them is slowed down. The appends queued after it on
+    /// the same writer wait too. A map created on its own gets a writer thread of its own to
+    /// queue them on; one opened through a database queues them on the database's writer. Has
+    /// to be at least 1.
+    #[builder(default, setter(strip_option))]
+    pub write_queue_depth: Option<usize>,
+    /// How often the entries inserted with [`insert_with_ttl`](HashMap::insert_with_ttl) are
+    /// checked for expiry by the background task that removes them. Expired entries are
+    /// treated as absent by reads as soon as they expire.
+    #[builder(default = "Duration::from_secs(1)")]
+    pub expiry_interval: Duration,
+    /// The most values the map holds in memory. Past it, the least recently used values are
+    /// evicted from memory but stay in the log, and are read back from their record when
+    /// they are accessed again. The offset of every key's latest record is kept in memory for
+    /// that, so a read back doesn't scan the file. Values whose latest write hasn't reached
+    /// the file yet, or that are stored in the sidecar file, stay in memory. Has to be at
+    /// least 1.
+    #[builder(default, setter(strip_option))]
+    pub max_in_memory: Option<usize>,
+}
+
+impl HashMapConfigBuilder {
+    fn validate(&self) -> Result<(), String> {
+        if matches!(self.large_value_threshold, Some(Some(_)))
+            && !matches!(self.large_value_dir, Some(Some(_)))
+        {
+            return Err("large_value_threshold requires large_value_dir".to_string());
+        }
+        if self.canonical_keys == Some(true)
+            && !matches!(self.format, None | Some(SerializationFormat::Bincode))
+        {
+            return Err("canonical_keys requires the bincode format".to_string());
+        }
+        if matches!(self.auto_compact_ratio, Some(Some(ratio)) if ratio <= 1.0 || ratio.is_nan()) {
+            return Err("auto_compact_ratio must be greater than 1".to_string());
+        }
+        if self.write_queue_depth == Some(Some(0)) {
+            return Err("write_queue_depth must be at least 1".to_string());
+        }
+        if self.max_in_memory == Some(Some(0)) {
+            return Err("max_in_memory must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Where the value of a key that isn't held in memory is read from.
+#[derive(Debug, Clone, PartialEq)]
+enum Stored {
+    /// The sidecar file.
+    Sidecar(ValueLocation),
+    /// The key's record at this offset of the log, which it was evicted from memory to keep
+    /// the map within its `max_in_memory`.
+    Log(u64),
+}
+
+/// A value replaced or removed from memory, which may still have to be read from the sidecar
+/// file or the log before it can be returned.
+#[derive(Clone)]
+enum Previous<V> {
+    Value(V),
+    External(Stored),
+}
+
+impl<V: for<'de> Deserialize<'de>> Previous<V> {
+    fn resolve<K: Serialize>(
+        self,
+        key: &K,
+        large_values: Option<&LargeValues>,
+        file: &Arc<Mutex<File>>,
+        id: &[u8],
+        legacy_id: &[u8],
+        key_codec: &KeyCodec,
+    ) -> Result<V, StructureError> {
+        match self {
+            Previous::Value(value) => Ok(value),
+            Previous::External(stored) => {
+                read_stored(&stored, key, large_values, file, id, legacy_id, key_codec)
+            }
+        }
+    }
+}
+
+/// A key changed in memory by a write: the value the write left (None if it removed the key),
+/// and the key's value before the write.
+type Change<K, V> = (K, Option<V>, Option<Previous<V>>);
+
+/// The in-memory changes of a write, undone if its append fails so memory keeps matching the
+/// file.
+struct Undo<K: Hash + Eq, V> {
+    inner: Arc<DashMap<K, V>>,
+    external: Arc<DashMap<K, Stored>>,
+    writer: Option<Arc<Writer>>,
+    changes: Vec<Change<K, V>>,
+}
+
+impl<K: Hash + Eq, V: Serialize> Undo<K, V> {
+    /// Restores the previous value of every changed key, latest change first.
+    ///
+    /// A key is only restored if it still holds what the write left, so later writes to it
+    /// are kept. The database's write order is reserved meanwhile, so no write can change a
+    /// key between that check and the restore.
+    fn apply(self) {
+        let _slot = self.writer.as_ref().map(|writer| writer.reserve());
+        for (key, written, previous) in self.changes.into_iter().rev() {
+            let unchanged = match &written {
+                Some(written) => self
+                    .inner
+                    .get(&key)
+                    .is_some_and(|current| same_value(current.value(), written)),
+                None => !self.inner.contains_key(&key) && !self.external.contains_key(&key),
+            };
+            if !unchanged {
+                continue;
+            }
+            match previous {
+                Some(Previous::Value(value)) => {
+                    self.inner.insert(key, value);
+                }
+                Some(Previous::External(stored)) => {
+                    self.inner.remove(&key);
+                    self.external.insert(key, stored);
+                }
+                None => {
+                    self.inner.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// Returns true if two values serialize to the same bytes.
+fn same_value<V: Serialize>(a: &V, b: &V) -> bool {
+    match (bincode::serialize(a), bincode::serialize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// The shard amount DashMap picks when none is given: four shards per available thread,
+/// rounded up to a power of two.
+fn default_shard_amount() -> usize {
+    (std::thread::available_parallelism().map_or(1, usize::from) * 4).next_power_of_two()
+}
+
+/// The file offsets of the latest record of each key, by serialized key, used to overwrite
+/// records in place.
+type Offsets = Arc<DashMap<Vec<u8>, u64>>;
+
+/// Called with each record applied while a map loads, as described for
+/// [`HashMap::with_on_load`].
+type OnRecord<'a, K, V> = &'a mut dyn FnMut(&K, Option<&V>);
+
+/// The keys whose only record is an insert that hasn't been written yet, used to skip the
+/// tombstones of keys that are removed before they reach the file.
+///
+/// Each tracked insert holds a token, which its write claims before writing the record. A
+/// removal that forgets the key first cancels the write, and needn't write a tombstone.
+#[derive(Debug)]
+struct Unwritten<K> {
+    tokens: Arc<Mutex<StdHashMap<K, u64>>>,
+    next_token: AtomicU64,
+}
+
+impl<K> Default for Unwritten<K> {
+    fn default() -> Self {
+        Self {
+            tokens: Arc::new(Mutex::new(StdHashMap::new())),
+            next_token: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Unwritten<K> {
+    /// Tracks an insert of `key`, which is new if the key wasn't in the map.
+    ///
+    /// Inserts over an existing key are only tracked if the existing value wasn't written
+    /// either, since otherwise the file already holds a record of the key.
+    fn track(&self, key: &K, new: bool) -> Option<PendingInsert<K>> {
+        let mut tokens = lock_tokens(&self.tokens);
+        if !new && !tokens.contains_key(key) {
+            return None;
+        }
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        tokens.insert(key.clone(), token);
+        Some(PendingInsert {
+            tokens: self.tokens.clone(),
+            token,
+        })
+    }
+
+    /// Stops tracking `key`, cancelling its pending insert. Returns true if there was one.
+    fn forget(&self, key: &K) -> bool {
+        lock_tokens(&self.tokens).remove(key).is_some()
+    }
+
+    fn clear(&self) {
+        lock_tokens(&self.tokens).clear();
+    }
+}
+
+/// The token of an insert tracked by `Unwritten`.
+struct PendingInsert<K> {
+    tokens: Arc<Mutex<StdHashMap<K, u64>>>,
+    token: u64,
+}
+
+impl<K: Hash + Eq> PendingInsert<K> {
+    /// Claims the insert for writing. Returns false if it was cancelled or superseded.
+    fn claim(&self, key: &K) -> bool {
+        let mut tokens = lock_tokens(&self.tokens);
+        if tokens.get(key) != Some(&self.token) {
+            return false;
+        }
+        tokens.remove(key);
+        true
+    }
+}
+
+/// Locks the tokens of `Unwritten`, which are left consistent by every critical section.
+fn lock_tokens<K>(tokens: &Mutex<StdHashMap<K, u64>>) -> MutexGuard<'_, StdHashMap<K, u64>> {
+    tokens
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Forgets the offset of a key whose latest record is about to be superseded by an append.
+fn forget_offset(offsets: &Option<Offsets>, key: &[u8]) {
+    if let Some(offsets) = offsets {
+        offsets.remove(key);
+    }
+}
+
+/// Converts a point in time to milliseconds since the Unix epoch, clamping earlier times to 0.
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+/// Makes a completed write as durable as `durability` requires, sharing the sync with other
+/// writes through the database's group commit if there is one.
+async fn sync_write(
+    file: &Arc<Mutex<File>>,
+    durability: Durability,
+    group_commit: Option<&Arc<GroupCommit>>,
+) -> Result<(), StructureError> {
+    match group_commit {
+        Some(group_commit) if durability == Durability::Sync => group_commit.sync().await,
+        _ if durability == Durability::Sync => {
+            let file = file.clone();
+            tokio::task::spawn_blocking(move || sync_file(&file, durability)).await?
+        }
+        _ => sync_file(file, durability),
+    }
+}
+
+/// Reads and deserializes a value stored in the sidecar file.
+fn read_external<V: for<'de> Deserialize<'de>>(
+    large_values: Option<&LargeValues>,
+    location: &ValueLocation,
+    format: SerializationFormat,
+) -> Result<V, StructureError> {
+    let large_values = large_values.ok_or(StructureError::LargeValueDirRequired)?;
+    format.decode(&large_values.read(location)?)
+}
+
+/// Reads and deserializes the value of `key` from where it is stored outside memory.
+fn read_stored<K: Serialize, V: for<'de> Deserialize<'de>>(
+    stored: &Stored,
+    key: &K,
+    large_values: Option<&LargeValues>,
+    file: &Arc<Mutex<File>>,
+    id: &[u8],
+    legacy_id: &[u8],
+    key_codec: &KeyCodec,
+) -> Result<V, StructureError> {
+    match stored {
+        Stored::Sidecar(location) => read_external(large_values, location, key_codec.format()),
+        Stored::Log(offset) => {
+            let key = key_codec.encode(key)?;
+            Ok(read_logged(file, id, legacy_id, &key, *offset, key_codec.format())?.0)
+        }
+    }
+}
+
+/// Reads and deserializes the value of the map's record at `offset`, which holds the latest
+/// write of the (serialized) `key`, and returns it with the offset it was read from.
+///
+/// The record is checked to be a write of `key` first. If it isn't, because the log was
+/// rewritten since the offset was recorded, for example by another structure's `clear`, the
+/// latest write of the key is found by scanning the log instead. Fails with
+/// `StructureError::RecordMissing` if the log holds no live write of the key any more.
+///
+/// Records are the map's if they are stored under `id` or its `legacy_id`.
+fn read_logged<V: for<'de> Deserialize<'de>>(
+    file: &Arc<Mutex<File>>,
+    id: &[u8],
+    legacy_id: &[u8],
+    key: &[u8],
+    offset: u64,
+    format: SerializationFormat,
+) -> Result<(V, u64), StructureError> {
+    let owns = |entry_id: &[u8]| entry_id == id || entry_id == legacy_id;
+    {
+        let mut file = lock_file(file)?;
+        let len = file.metadata()?.len();
+        if offset < len {
+            file.seek(SeekFrom::Start(offset))?;
+            let options = bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .allow_trailing_bytes()
+                .with_limit(len - offset);
+            let entry = options.deserialize_from::<_, DBEntry>(BufReader::new(&mut *file));
+            if let Ok(DBEntry::HashMapEntry(entry_id, entry_key, value)) = entry {
+                if owns(&entry_id) && entry_key == key {
+                    return Ok((format.decode(&value)?, offset));
+                }
+            }
+        }
+    }
+    let mut latest = None;
+    scan_file(file, |entry, span| {
+        match entry {
+            DBEntry::HashMapEntry(entry_id, entry_key, value)
+                if owns(&entry_id) && entry_key == key =>
+            {
+                latest = Some((value, span.start));
+            }
+            DBEntry::ExternalHashMapEntry(entry_id, entry_key, _)
+            | DBEntry::RemoveHashMapEntry(entry_id, entry_key)
+                if owns(&entry_id) && entry_key == key =>
+            {
+                latest = None;
+            }
+            _ => {}
+        }
+        Ok(())
+    })?;
+    let (value, offset) = latest.ok_or(StructureError::RecordMissing)?;
+    Ok((format.decode(&value)?, offset))
+}
+
+/// The previous values of a batch of inserts, the keys evicted to make room for them, and the
+/// writes to report to the map's `max_in_memory` index, if it has one.
+type Inserted<K, V> = (Vec<Option<Previous<V>>>, Vec<K>, Vec<Option<SpillWrite<K>>>);
+
+/// Appends the removals of keys evicted to keep a map within its `max_entries`.
+fn write_evictions<K: Serialize>(
+    evicted: &[K],
+    id: &[u8],
+    key_codec: &KeyCodec,
+    offsets: &Option<Offsets>,
+    file: &Arc<Mutex<File>>,
+    retry: RetryPolicy,
+) -> Result<(), StructureError> {
+    let entries = evicted.iter().map(|key| {
+        let key = key_codec.encode(key)?;
+        forget_offset(offsets, &key);
+        Ok(DBEntry::RemoveHashMapEntry(id.to_vec(), key))
+    });
+    serialize_chunks_to_file(entries, DEFAULT_BATCH_CHUNK_SIZE, file, retry)?;
+    Ok(())
+}
+
+/// Builds the log entry for an insert, moving the value to the sidecar file if it is large.
+fn map_entry(
+    large_values: Option<&LargeValues>,
+    id: Vec<u8>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+) -> Result<DBEntry, StructureError> {
+    match large_values {
+        Some(large_values) => large_values.entry(id, key, value),
+        None => Ok(DBEntry::HashMapEntry(id, key, value)),
+    }
+}
+
+/// Builds the checksum record that follows `entry`, if the map records checksums and the
+/// value is stored in the log.
+fn checksum_entry(checksums: bool, entry: &DBEntry) -> Option<DBEntry> {
+    match entry {
+        DBEntry::HashMapEntry(id, key, value) if checksums => Some(DBEntry::EntryChecksum(
+            id.clone(),
+            key.clone(),
+            crc32(value),
+        )),
+        _ => None,
+    }
+}
+
+/// Classifies an entry of the log by the effect it has on a key of the hashmap with the
+/// (serialized) `id`.
+pub(super) fn map_record(id: &[u8], entry: &DBEntry) -> Option<Record> {
+    match entry {
+        DBEntry::HashMapEntry(entry_id, key, _)
+        | DBEntry::ExternalHashMapEntry(entry_id, key, _)
+            if entry_id == id =>
+        {
+            Some(Record::Write(key.clone()))
+        }
+        DBEntry::EntryTimestamp(entry_id, key, _)
+        | DBEntry::EntryChecksum(entry_id, key, _)
+        | DBEntry::EntryExpiry(entry_id, key, _)
+            if entry_id == id =>
+        {
+            Some(Record::Stamp(key.clone()))
+        }
+        DBEntry::RemoveHashMapEntry(entry_id, key) if entry_id == id => {
+            Some(Record::Remove(key.clone()))
+        }
+        _ => None,
+    }
+}
+
+/// Classifies an entry of the log like [`map_record`], for a hashmap whose records may also
+/// be stored under its `legacy_id`.
+fn owned_map_record(id: &[u8], legacy_id: &[u8], entry: &DBEntry) -> Option<Record> {
+    map_record(id, entry).or_else(|| map_record(legacy_id, entry))
+}
+
+/// Compacts the records of the hashmap with the (serialized) `id`, and those under its
+/// `legacy_id`, as `HashMap::compact` does.
+fn compact_map(
+    file: &Arc<Mutex<File>>,
+    id: &[u8],
+    legacy_id: &[u8],
+    path: Option<&LogPath>,
+    offsets: Option<&Offsets>,
+    durability: Durability,
+    recovery: RecoveryMode,
+) -> Result<(), StructureError> {
+    let mut file = lock_file(file)?;
+    let entries = read_log(&mut file, recovery)?;
+    let entries = compact_entries(entries, |entry| owned_map_record(id, legacy_id, entry));
+    replace_log(&mut file, path, &entries)?;
+    // The records that were kept have moved.
+    if let Some(offsets) = offsets {
+        offsets.clear();
+    }
+    if durability == Durability::Sync {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Returns the offset of the latest record of each key of the hashmap with the (serialized)
+/// `id` or `legacy_id` whose value is in the log, reading `file` from the start.
+fn logged_offsets<K>(
+    file: &mut File,
+    id: &[u8],
+    legacy_id: &[u8],
+    key_codec: &KeyCodec,
+    recovery: RecoveryMode,
+) -> Result<StdHashMap<K, u64>, StructureError>
+where
+    K: Hash + Eq + for<'de> Deserialize<'de>,
+{
+    let mut offsets = StdHashMap::new();
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+    scan_entries(BufReader::new(&mut *file), len, recovery, |entry, span| {
+        match entry {
+            DBEntry::HashMapEntry(entry_id, key, _) if entry_id == id || entry_id == legacy_id => {
+                offsets.insert(key_codec.decode(&key)?, span.start);
+            }
+            DBEntry::ExternalHashMapEntry(entry_id, key, _)
+            | DBEntry::RemoveHashMapEntry(entry_id, key)
+                if entry_id == id || entry_id == legacy_id =>
+            {
+                offsets.remove(&key_codec.decode::<K>(&key)?);
+            }
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(offsets)
+}
+
+/// What a map with `max_in_memory` needs to find its records again after its log is compacted.
+struct Reindex<K> {
+    spill: Arc<Spill<K>>,
+    id: Vec<u8>,
+    legacy_id: Vec<u8>,
+    key_codec: KeyCodec,
+    recovery: RecoveryMode,
+}
+
+impl<K> Reindex<K>
+where
+    K: Hash + Eq + Clone + for<'de> Deserialize<'de>,
+{
+    /// Moves the offsets of the map's records to where they are in the file now.
+    ///
+    /// The file is locked until the offsets are updated, so no write of the map can record
+    /// an offset in between.
+    fn run(&self, file: &Arc<Mutex<File>>) -> Result<(), StructureError> {
+        let mut file = lock_file(file)?;
+        let offsets = logged_offsets::<K>(
+            &mut file,
+            &self.id,
+            &self.legacy_id,
+            &self.key_codec,
+            self.recovery,
+        )?;
+        let mut state = self.spill.lock();
+        for (key, offset) in &offsets {
+            state.moved(key, *offset);
+        }
+        Ok(())
+    }
+}
+
+/// What a write of a map with `auto_compact_ratio` needs to check the file once it's done.
+struct CompactionCheck<K> {
+    auto_compact: AutoCompact,
+    file: Arc<Mutex<File>>,
+    id: Vec<u8>,
+    legacy_id: Vec<u8>,
+    path: Option<LogPath>,
+    offsets: Option<Offsets>,
+    reindex: Option<Reindex<K>>,
+    durability: Durability,
+    recovery: RecoveryMode,
+    writer: Option<Arc<Writer>>,
+}
+
+impl<K> CompactionCheck<K>
+where
+    K: Hash + Eq + Clone + for<'de> Deserialize<'de> + Send + 'static,
+{
+    /// Compacts the map's log if it has outgrown its live records by the ratio.
+    async fn run(self) -> Result<(), StructureError> {
+        let file_bytes = lock_file(&self.file)?.metadata()?.len();
+        let (file, id, legacy_id) = (self.file.clone(), self.id.clone(), self.legacy_id.clone());
+        let estimate =
+            move || estimate_compaction(&file, |entry| owned_map_record(&id, &legacy_id, entry));
+        let (file, id, legacy_id) = (self.file, self.id, self.legacy_id);
+        let (path, offsets) = (self.path, self.offsets);
+        let (reindex, durability, recovery) = (self.reindex, self.durability, self.recovery);
+        let compact = move || {
+            compact_map(
+                &file,
+                &id,
+                &legacy_id,
+                path.as_ref(),
+                offsets.as_ref(),
+                durability,
+                recovery,
+            )?;
+            if let Some(reindex) = &reindex {
+                reindex.run(&file)?;
+            }
+            Ok(lock_file(&file)?.metadata()?.len())
+        };
+        let check =
+            self.auto_compact
+                .after_write(file_bytes, self.writer.as_deref(), estimate, compact)?;
+        check.await
+    }
 }
 
 /// A file-backed, thread-safe hashmap structure.
@@ -30,11 +730,50 @@ pub struct HashMapConfig {
 /// `HashMap` provides a persistent, concurrent key-value store that is backed by a file.
 /// It supports operations like `insert`, `get`, and `remove`, with changes being
 /// written to disk.
+///
+/// Writes are persisted by spawned tasks, and dropping a map does not wait for them: a write
+/// whose handle wasn't awaited may still be in flight when the map is dropped. Await the
+/// handles, or close the map's database with `Database::close_async`, to be sure they
+/// finished.
 #[derive(Debug)]
 pub struct HashMap<K: Hash + Eq, V> {
-    inner: DashMap<K, V>,
+    inner: Arc<DashMap<K, V>>,
     file: Arc<Mutex<File>>,
     id: Vec<u8>,
+    legacy_id: Vec<u8>,
+    shard_amount: usize,
+    initial_capacity: usize,
+    type_fingerprint: bool,
+    batch_chunk_size: usize,
+    unknown_entries: UnknownEntryPolicy,
+    recovery: RecoveryMode,
+    durability: Durability,
+    record_timestamps: bool,
+    timestamps: Arc<DashMap<K, u64>>,
+    expiries: Expiries<K>,
+    offsets: Option<Offsets>,
+    errors: ErrorReporter,
+    key_locks: KeyLocks<K>,
+    loading: DashMap<K, Arc<OnceCell<V>>>,
+    external: Arc<DashMap<K, Stored>>,
+    spill: Option<Arc<Spill<K>>>,
+    large_values: Option<LargeValues>,
+    retry: RetryPolicy,
+    group_commit: Option<Arc<GroupCommit>>,
+    max_value_bytes: Option<usize>,
+    key_codec: KeyCodec,
+    load_concurrency: usize,
+    eviction: Option<Eviction<K>>,
+    unwritten: Option<Unwritten<K>>,
+    conflict_resolver: Option<Resolver<K, V>>,
+    checksums: bool,
+    format: SerializationFormat,
+    auto_compact: Option<AutoCompact>,
+    pending: Option<PendingWrites>,
+    writer: Option<Arc<Writer>>,
+    write_queue: Option<WriteQueue>,
+    path: Option<LogPath>,
+    skipped: Mutex<Vec<SkippedRecord>>,
 }
 
 impl<K: Hash + Eq, V> HashMap<K, V>
@@ -43,73 +782,492 @@ where
     V: Serialize + for<'de> Deserialize<'de> + Clone + Send + 'static,
 {
     /// Creates a new HashMap with a capacity of 0.
+    ///
+    /// The id is stored bincode-serialized, the canonical encoding every structure uses.
+    /// Records that older versions of [`with_config`](#method.with_config) stored under the
+    /// unencoded id are still loaded.
     pub fn new(file: Arc<Mutex<File>>, id: Vec<u8>) -> Result<Self, StructureError> {
+        Self::new_with(
+            file,
+            id,
+            RecoveryMode::default(),
+            SerializationFormat::default(),
+        )
+    }
+
+    /// Creates a new HashMap like [`new`](#method.new), loading the file in `recovery` mode
+    /// and serializing keys and values in `format`.
+    pub(crate) fn new_with(
+        file: Arc<Mutex<File>>,
+        id: Vec<u8>,
+        recovery: RecoveryMode,
+        format: SerializationFormat,
+    ) -> Result<Self, StructureError> {
+        let legacy_id = id;
+        let id = encode_id(&legacy_id)?;
         let instance = Self {
-            inner: DashMap::new(),
+            key_codec: KeyCodec::new(false, None, &id, &file, RetryPolicy::default())
+                .with_format(format),
+            inner: Arc::new(DashMap::new()),
             file,
-            id: bincode::serialize(&id)?,
+            id,
+            legacy_id,
+            shard_amount: default_shard_amount(),
+            initial_capacity: 0,
+            type_fingerprint: false,
+            batch_chunk_size: DEFAULT_BATCH_CHUNK_SIZE,
+            unknown_entries: UnknownEntryPolicy::default(),
+            recovery,
+            durability: Durability::default(),
+            record_timestamps: false,
+            timestamps: Arc::new(DashMap::new()),
+            expiries: Expiries::default(),
+            offsets: None,
+            errors: ErrorReporter::default(),
+            key_locks: KeyLocks::default(),
+            loading: DashMap::new(),
+            external: Arc::new(DashMap::new()),
+            spill: None,
+            large_values: None,
+            retry: RetryPolicy::default(),
+            group_commit: None,
+            max_value_bytes: None,
+            load_concurrency: 1,
+            eviction: None,
+            unwritten: None,
+            conflict_resolver: None,
+            checksums: false,
+            format,
+            auto_compact: None,
+            pending: None,
+            writer: None,
+            write_queue: None,
+            path: None,
+            skipped: Mutex::default(),
         };
-        instance.load_from_file()?;
+        instance.replay_file()?;
         Ok(instance)
     }
 
+    /// Routes the map's synced writes through a database's group commit.
+    pub(crate) fn with_group_commit(mut self, group_commit: Option<Arc<GroupCommit>>) -> Self {
+        self.group_commit = group_commit;
+        self
+    }
+
+    /// Registers the map's spawned writes with a database, so it can wait for them on close.
+    pub(crate) fn with_pending_writes(mut self, pending: PendingWrites) -> Self {
+        self.pending = Some(pending);
+        self
+    }
+
+    /// Queues the map's appends on a database's writer, so they reach the file in the order
+    /// they were applied in memory.
+    pub(crate) fn with_writer(mut self, writer: Arc<Writer>) -> Self {
+        self.writer = Some(writer);
+        self
+    }
+
+    /// Records the path of the map's file, so compaction can replace the file atomically.
+    pub(crate) fn with_path(mut self, path: LogPath) -> Self {
+        self.path = Some(path);
+        self
+    }
+
+    /// Records the in-memory changes of a write, to undo them if its append fails.
+    fn undo(&self, changes: Vec<Change<K, V>>) -> Undo<K, V> {
+        Undo {
+            inner: self.inner.clone(),
+            external: self.external.clone(),
+            writer: self.writer.clone(),
+            changes,
+        }
+    }
+
+    /// Reserves the next place in the write order of the map's writer, if it has one.
+    ///
+    /// With `write_queue_depth` set, this also takes a place in the map's write queue, which
+    /// the write's append waits for if the queue is full. The place is taken while holding the
+    /// slot, so the map's appends get their places in their write order.
+    fn reserve(&self) -> Result<Option<Slot<'_>>, StructureError> {
+        let Some(writer) = &self.writer else {
+            return Ok(None);
+        };
+        let slot = writer.reserve()?;
+        let permit = self.write_queue.as_ref().map(WriteQueue::acquire);
+        Ok(Some(slot.with_permit(permit)))
+    }
+
+    /// Returns the number of the map's appends queued on its writer but not started yet, which
+    /// never exceeds `write_queue_depth`. Always 0 for a map without a write queue depth.
+    pub fn queued_writes(&self) -> usize {
+        self.write_queue.as_ref().map_or(0, WriteQueue::len)
+    }
+
+    /// Returns `StructureError::ReadOnly` if the map's database was opened read-only.
+    fn check_writable(&self) -> Result<(), StructureError> {
+        self.writer
+            .as_ref()
+            .map_or(Ok(()), |writer| writer.check_writable())
+    }
+
+    /// Opens a HashMap like [`new`](#method.new), but first checks that the file is a
+    /// rustmap-db file.
+    ///
+    /// Returns `StructureError::NotARustmapFile` straight away for unrelated files, instead of
+    /// failing partway through the load with a bincode error.
+    pub fn try_open(file: Arc<Mutex<File>>, id: Vec<u8>) -> Result<Self, StructureError> {
+        validate_file(&file)?;
+        Self::new(file, id)
+    }
+
     /// Creates a new HashMap with a given capacity.
+    ///
+    /// The id is encoded like in [`new`](#method.new), so both open the same map.
     pub fn with_config(
         file: Arc<Mutex<File>>,
         id: Vec<u8>,
         config: HashMapConfig,
     ) -> Result<Self, StructureError> {
+        Self::open(file, id, config, None, None)
+    }
+
+    /// Creates a HashMap like [`with_config`](#method.with_config), resolving keys with more
+    /// than one record in the file with `resolver`.
+    ///
+    /// Normally the last record of a key wins. With a resolver, every later record of a key is
+    /// passed to it together with the value loaded so far, and the value it returns is kept.
+    /// This matters when several processes append to the same file. A winner that differs from
+    /// the last record is appended to the file once the load completes, so compaction keeps
+    /// it. The resolver is used again by every reload through `load_from_file`. Records of
+    /// values stored in the sidecar file aren't passed to it.
+    pub fn with_conflict_resolver<R>(
+        file: Arc<Mutex<File>>,
+        id: Vec<u8>,
+        config: HashMapConfig,
+        resolver: R,
+    ) -> Result<Self, StructureError>
+    where
+        R: ConflictResolver<K, V> + 'static,
+    {
+        Self::open(file, id, config, Some(Resolver::new(resolver)), None)
+    }
+
+    fn open(
+        file: Arc<Mutex<File>>,
+        id: Vec<u8>,
+        config: HashMapConfig,
+        conflict_resolver: Option<Resolver<K, V>>,
+        on_record: Option<OnRecord<'_, K, V>>,
+    ) -> Result<Self, StructureError> {
+        let legacy_id = id;
+        let id = encode_id(&legacy_id)?;
+        let retry = RetryPolicy {
+            retries: config.write_retries,
+            backoff: config.retry_backoff,
+        };
+        let auto_compact = match config.auto_compact_ratio {
+            Some(ratio) => Some(AutoCompact::new(ratio, lock_file(&file)?.metadata()?.len())),
+            None => None,
+        };
         let instance = Self {
-            inner: DashMap::with_capacity_and_shard_amount(config.capacity, config.shard_amount),
+            key_codec: KeyCodec::new(
+                config.canonical_keys,
+                config.hash_keys_above,
+                &id,
+                &file,
+                retry,
+            )
+            .with_format(config.format),
+            inner: Arc::new(DashMap::with_capacity_and_shard_amount(
+                config.capacity,
+                config.shard_amount,
+            )),
             file,
+            shard_amount: config.shard_amount,
+            initial_capacity: config.capacity,
+            type_fingerprint: config.type_fingerprint,
+            batch_chunk_size: config.batch_chunk_size,
+            unknown_entries: config.unknown_entries,
+            recovery: config.recovery,
+            durability: config.durability,
+            record_timestamps: config.timestamps,
+            timestamps: Arc::new(DashMap::new()),
+            expiries: Expiries::new(config.expiry_interval),
+            offsets: config.overwrite_in_place.then(Offsets::default),
+            errors: ErrorReporter::default(),
+            key_locks: KeyLocks::default(),
+            loading: DashMap::new(),
+            external: Arc::new(DashMap::new()),
+            spill: config
+                .max_in_memory
+                .map(|max_in_memory| Arc::new(Spill::new(max_in_memory))),
+            // The sidecar file keeps the name it was given before ids were encoded.
+            large_values: config
+                .large_value_dir
+                .map(|dir| LargeValues::new(config.large_value_threshold, dir, &legacy_id)),
+            retry,
+            group_commit: None,
+            max_value_bytes: config.max_value_bytes,
+            load_concurrency: config.load_concurrency,
+            eviction: config
+                .max_entries
+                .map(|max_entries| Eviction::new(max_entries, config.eviction)),
+            unwritten: config.skip_unwritten_tombstones.then(Unwritten::default),
+            conflict_resolver,
+            checksums: config.checksums,
+            format: config.format,
+            auto_compact,
+            pending: None,
+            writer: match config.write_queue_depth {
+                Some(_) => Some(Arc::new(Writer::start()?)),
+                None => None,
+            },
+            write_queue: config.write_queue_depth.map(WriteQueue::new),
+            path: None,
+            skipped: Mutex::default(),
             id,
+            legacy_id,
         };
-        instance.load_from_file()?;
+        let fingerprinted = instance.replay_file_with(on_record)?;
+        if config.type_fingerprint && !fingerprinted {
+            let fingerprint = type_fingerprint::<K, V>();
+            serialize_to_file(
+                &DBEntry::TypeFingerprint(instance.id.clone(), fingerprint),
+                &instance.file,
+                instance.retry,
+            )?;
+        }
         Ok(instance)
     }
 
-    /// Loads the hash set contents from the file.
+    /// Creates a HashMap like [`with_config`](#method.with_config), calling `on_record` with
+    /// each of the map's records as the load applies it.
     ///
-    /// Internal function used during initialization to load the set's state from the file.
-    fn load_from_file(&self) -> Result<(), StructureError> {
+    /// This lets auxiliary structures, such as secondary indexes, be built during startup
+    /// without iterating the map again. A write of a key is passed with the value it leaves
+    /// loaded, after any conflict resolver, and the removal of a loaded key with `None`, so a
+    /// key written several times is passed once per write. Entries that expired while the map
+    /// was closed are passed as removals once every record is applied. Applying the calls in
+    /// order leaves a structure matching the loaded map. Values stored in the sidecar file are
+    /// read back to be passed to it.
+    pub fn with_on_load<F>(
+        file: Arc<Mutex<File>>,
+        id: Vec<u8>,
+        config: HashMapConfig,
+        mut on_record: F,
+    ) -> Result<Self, StructureError>
+    where
+        F: FnMut(&K, Option<&V>),
+    {
+        Self::open(file, id, config, None, Some(&mut on_record))
+    }
+
+    /// Replays the hashmap's entries from the file on top of its in-memory state.
+    ///
+    /// Internal function used during initialization to load the map's state from the file.
+    ///
+    /// If the file records a type fingerprint for this structure it is checked against the
+    /// current types, and `true` is returned.
+    fn replay_file(&self) -> Result<bool, StructureError> {
+        self.replay_file_with(None)
+    }
+
+    /// Replays the hashmap's entries from the file like [`replay_file`](Self::replay_file),
+    /// passing each record it applies to `on_record` as described for
+    /// [`with_on_load`](Self::with_on_load).
+    fn replay_file_with(
+        &self,
+        mut on_record: Option<OnRecord<'_, K, V>>,
+    ) -> Result<bool, StructureError> {
         let mut file = lock_file(&self.file)?;
-        file.seek(SeekFrom::Start(0))?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        let mut cursor = std::io::Cursor::new(&buffer);
-
-        while cursor.position() < buffer.len() as u64 {
-            match bincode::deserialize_from::<_, DBEntry>(&mut cursor) {
-                Ok(entry) => match entry {
-                    DBEntry::HashMapEntry(id, key, value) => {
-                        if id == self.id {
-                            let key = bincode::deserialize::<K>(&key)?;
-                            let value = bincode::deserialize::<V>(&value)?;
-                            self.inner.insert(key, value);
-                        }
+        let mut fingerprinted = false;
+        // The keys whose resolved value differs from their last record.
+        let mut resolved = std::collections::HashSet::new();
+        let replay = |entry: DBEntry, span: Range<u64>| {
+            let offset = span.start;
+            match entry {
+                DBEntry::HashMapEntry(id, key, serialized) if self.owns(&id) => {
+                    if let Some(offsets) = &self.offsets {
+                        offsets.insert(key.clone(), offset);
                     }
-                    DBEntry::RemoveHashMapEntry(id, key) => {
-                        if id == self.id {
-                            let key = bincode::deserialize::<K>(&key)?;
-                            self.inner.remove(&key);
+                    let key = self.key_codec.decode::<K>(&key)?;
+                    if let Some(limit) = self.max_value_bytes {
+                        if serialized.len() > limit {
+                            return Err(StructureError::ValueTooLarge {
+                                size: serialized.len(),
+                                limit,
+                            });
                         }
                     }
-                    _ => {}
-                },
-                Err(e) => match e.as_ref() {
-                    bincode::ErrorKind::Io(e) => {
-                        if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                            break;
+                    let mut value = self.format.decode::<V>(&serialized)?;
+                    self.external.remove(&key);
+                    self.timestamps.remove(&key);
+                    self.expiries.remove(&key);
+                    if let Some(resolver) = &self.conflict_resolver {
+                        if let Some((_, old)) = self.inner.remove(&key) {
+                            value = resolver.resolve(&key, old, value);
+                            if self.format.encode(&value)? != serialized {
+                                resolved.insert(key.clone());
+                            } else {
+                                resolved.remove(&key);
+                            }
                         }
                     }
-                    _ => {
-                        return Err(StructureError::BinCodeError(e));
+                    if let Some(spill) = &self.spill {
+                        spill.lock().replayed(&key, offset);
+                    }
+                    if let Some(on_record) = on_record.as_mut() {
+                        on_record(&key, Some(&value));
                     }
-                },
+                    self.inner.insert(key, value);
+                    // A resolver needs the values loaded so far, so they are only evicted
+                    // once it has seen every record.
+                    if self.conflict_resolver.is_none() {
+                        self.spill_excess(None);
+                    }
+                }
+                DBEntry::ExternalHashMapEntry(id, key, location) if self.owns(&id) => {
+                    if self.large_values.is_none() {
+                        return Err(StructureError::LargeValueDirRequired);
+                    }
+                    forget_offset(&self.offsets, &key);
+                    let key = self.key_codec.decode::<K>(&key)?;
+                    self.inner.remove(&key);
+                    self.timestamps.remove(&key);
+                    self.expiries.remove(&key);
+                    resolved.remove(&key);
+                    self.forget_spilled(&key);
+                    if let Some(on_record) = on_record.as_mut() {
+                        let value = read_external(
+                            self.large_values.as_ref(),
+                            &location,
+                            self.key_codec.format(),
+                        )?;
+                        on_record(&key, Some(&value));
+                    }
+                    self.external.insert(key, Stored::Sidecar(location));
+                }
+                DBEntry::EntryTimestamp(id, key, timestamp) if self.owns(&id) => {
+                    forget_offset(&self.offsets, &key);
+                    let key = self.key_codec.decode::<K>(&key)?;
+                    if self.inner.contains_key(&key) || self.external.contains_key(&key) {
+                        self.timestamps.insert(key, timestamp);
+                    }
+                }
+                DBEntry::EntryExpiry(id, key, expiry) if self.owns(&id) => {
+                    forget_offset(&self.offsets, &key);
+                    let key = self.key_codec.decode::<K>(&key)?;
+                    if self.inner.contains_key(&key) || self.external.contains_key(&key) {
+                        self.expiries.set(&key, Some(expiry));
+                    }
+                }
+                DBEntry::RemoveHashMapEntry(id, key) if self.owns(&id) => {
+                    forget_offset(&self.offsets, &key);
+                    let key = self.key_codec.decode::<K>(&key)?;
+                    let removed = self.inner.remove(&key).is_some();
+                    let removed = self.external.remove(&key).is_some() || removed;
+                    self.timestamps.remove(&key);
+                    self.expiries.remove(&key);
+                    resolved.remove(&key);
+                    self.forget_spilled(&key);
+                    if let (true, Some(on_record)) = (removed, on_record.as_mut()) {
+                        on_record(&key, None);
+                    }
+                }
+                DBEntry::KeyAlias(id, alias, key) if self.owns(&id) => {
+                    self.key_codec.define(alias, key)?;
+                }
+                DBEntry::EntryChecksum(id, key, _) if self.owns(&id) => {
+                    // Overwriting the preceding write in place would invalidate the checksum.
+                    forget_offset(&self.offsets, &key);
+                }
+                DBEntry::TypeFingerprint(id, fingerprint) if self.owns(&id) => {
+                    check_fingerprint(type_fingerprint::<K, V>(), fingerprint)?;
+                    fingerprinted = true;
+                }
+                DBEntry::Extension(tag, _) => check_unknown_entry(self.unknown_entries, tag)?,
+                _ => {}
+            }
+            Ok(())
+        };
+        // Concurrent loading reads the whole file into memory first; otherwise entries are
+        // decoded one at a time as the file is read.
+        let len = file.metadata()?.len();
+        let skipped = if self.load_concurrency > 1 {
+            let buffer = read_concurrently(&*file, len, self.load_concurrency, LOAD_REGION_BYTES)?;
+            scan_entries(std::io::Cursor::new(&buffer), len, self.recovery, replay)?
+        } else {
+            file.seek(SeekFrom::Start(0))?;
+            scan_entries(BufReader::new(&mut *file), len, self.recovery, replay)?
+        };
+        *self
+            .skipped
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = skipped;
+
+        // Entries that expired while the map was closed are left out. Their records are
+        // dropped by the next compaction.
+        for key in self.expiries.take_expired(unix_millis(SystemTime::now())) {
+            let removed = self.inner.remove(&key).is_some();
+            let removed = self.external.remove(&key).is_some() || removed;
+            self.timestamps.remove(&key);
+            resolved.remove(&key);
+            self.forget_spilled(&key);
+            if let (true, Some(on_record)) = (removed, on_record.as_mut()) {
+                on_record(&key, None);
             }
         }
 
-        Ok(())
+        if !resolved.is_empty() {
+            let mut winners = Vec::new();
+            // The position of each winner logged in full within `winners`.
+            let mut logged = Vec::new();
+            for key in &resolved {
+                let value = match self.inner.get(key) {
+                    Some(value) => self.format.encode(value.value())?,
+                    None => continue,
+                };
+                // Resolved keys were decoded from the file, so encoding them defines no alias.
+                let entry_key = self.key_codec.encode(key)?;
+                forget_offset(&self.offsets, &entry_key);
+                let entry = map_entry(
+                    self.large_values.as_ref(),
+                    self.id.clone(),
+                    entry_key.clone(),
+                    value,
+                )?;
+                match &entry {
+                    DBEntry::HashMapEntry(..) => logged.push((key, winners.len() as u64)),
+                    _ => self.forget_spilled(key),
+                }
+                bincode::serialize_into(&mut winners, &entry)?;
+                if let Some(checksum) = checksum_entry(self.checksums, &entry) {
+                    bincode::serialize_into(&mut winners, &checksum)?;
+                }
+                if let Some(expiry) = self.expiries.get(key) {
+                    let expiry = DBEntry::EntryExpiry(self.id.clone(), entry_key, expiry);
+                    bincode::serialize_into(&mut winners, &expiry)?;
+                }
+            }
+            let end = file.seek(SeekFrom::End(0))?;
+            write_all_retrying(&mut *file, &winners, self.retry)?;
+            file.flush()?;
+            if let Some(spill) = &self.spill {
+                let mut state = spill.lock();
+                for (key, position) in logged {
+                    state.replayed(key, end + position);
+                }
+            }
+        }
+        self.spill_excess(None);
+
+        if let Some(eviction) = &self.eviction {
+            let keys = self.inner.iter().map(|entry| entry.key().clone());
+            let external = self.external.iter().map(|entry| entry.key().clone());
+            eviction.reset(keys.chain(external))?;
+        }
+        Ok(fingerprinted)
     }
 
     /// Inserts a key-value pair into the HashMap
@@ -121,116 +1279,1481 @@ where
     ///
     /// As a compromise you can try awaiting the JoinHandle later in your code if you don't need the result immediately.
     ///
+    /// If the write can't be appended to the file, the key's previous value is restored in
+    /// memory before the JoinHandle resolves with the error, unless a later write has changed
+    /// the key since. The same holds for every key of the batch and removal operations.
+    ///
     /// [`insert_batch`]: #method.insert_batch
     ///
     /// Returns a JoinHandle with a Result containing the old value (None if new) if the operation was successful.
     #[inline]
-    pub fn insert(&self, key: K, value: V) -> JoinHandle<Result<Option<V>, StructureError>> {
-        let old_value = self.inner.insert(key.clone(), value.clone());
-        let file = self.file.clone();
-        let id = self.id.clone();
+    pub fn insert(&self, key: K, value: V) -> JoinHandle<Result<Option<V>, StructureError>>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        let timestamp = self.record_timestamps.then(SystemTime::now);
+        self.write_insert(key, value, self.durability, timestamp, None)
+    }
+
+    /// Inserts a key-value pair like [`insert`](#method.insert), blocking the calling thread
+    /// until the write completes instead of spawning it.
+    ///
+    /// The write is performed on the calling thread, so this works without a tokio runtime.
+    /// It must not be called from within one, where it panics; use `insert` there.
+    ///
+    /// Returns the old value (None if new) if the operation was successful.
+    pub fn insert_sync(&self, key: K, value: V) -> Result<Option<V>, StructureError>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        blocking::run(|| self.insert(key, value))
+    }
+
+    /// Returns the value of `key`, first inserting the value returned by `f` if the key is
+    /// absent.
+    ///
+    /// The check and the insert happen under the key's lock (see
+    /// [`lock_key`](#method.lock_key)), so concurrent callers of this method on the same key
+    /// compute and write a value only once. `f` is only called, and an entry only appended to
+    /// the file, when the key was absent; otherwise the existing value is returned and nothing
+    /// is written. Like other key locks this is cooperative: a plain `insert` racing with this
+    /// call can still overwrite the key.
+    ///
+    /// JoinHandle will return a Result containing the value of the key if the operation was
+    /// successful.
+    pub fn get_or_insert_with<F>(&self, key: K, f: F) -> JoinHandle<Result<V, StructureError>>
+    where
+        F: FnOnce() -> V,
+        K: Sync,
+        V: Sync,
+    {
+        let guard = self.key_locks.lock(&key);
+        match self.try_get(&key) {
+            Ok(Some(value)) => {
+                let value = value.value().clone();
+                return tokio::spawn(async move { Ok(value) });
+            }
+            Ok(None) => {}
+            Err(e) => return self.spawn_write(async move { Err(e) }),
+        }
+        let value = f();
+        let write = self.insert(key, value.clone());
+        drop(guard);
         tokio::spawn(async move {
-            let key = bincode::serialize(&key)?;
-            let value = bincode::serialize(&value)?;
-            serialize_to_file(&DBEntry::HashMapEntry(id.clone(), key, value), &file)?;
-            Ok(old_value)
+            write.await??;
+            Ok(value)
         })
     }
 
-    /// Inserts a batch of key-value pairs into the HashMap.
+    /// Replaces the value of `key` with `new` if its current value equals `expected`, where
+    /// `None` expects the key to be absent.
     ///
-    /// Returns a JoinHandle that can be awaited to wait for the operation to complete.
+    /// The comparison and the insert are atomic with respect to every other write of a map
+    /// opened through a `Database`, as the write order of the database is held between them.
+    /// A standalone map only holds the key's lock (see [`lock_key`](#method.lock_key)), so
+    /// there the swap is atomic with respect to other swaps and key lock holders. Nothing is
+    /// written to the file when the current value doesn't match.
     ///
-    /// JoinHandle will return a Result containing a Vec of the old values (None if new) if the operation was successful.
-    pub fn insert_batch(
+    /// JoinHandle will return a Result containing whether the swap happened if the operation
+    /// was successful.
+    pub fn compare_and_swap(
         &self,
-        entries: Vec<(K, V)>,
-    ) -> JoinHandle<Result<Vec<Option<V>>, StructureError>> {
-        let mut old_values = Vec::with_capacity(entries.len());
-        for (key, value) in &entries {
-            old_values.push(self.inner.insert(key.clone(), value.clone()));
+        key: &K,
+        expected: Option<&V>,
+        new: V,
+    ) -> JoinHandle<Result<bool, StructureError>>
+    where
+        K: Sync,
+        V: PartialEq + Sync,
+    {
+        if let Err(e) = self.check_value_size(&new) {
+            return self.spawn_write(async move { Err(e) });
         }
+        let guard = self.key_locks.lock(key);
+        let slot = match self.reserve() {
+            Ok(slot) => slot,
+            Err(e) => return self.spawn_write(async move { Err(e) }),
+        };
+        let matches = match self.try_get(key) {
+            Ok(current) => current.as_ref().map(|current| current.value()) == expected,
+            Err(e) => return self.spawn_write(async move { Err(e) }),
+        };
+        if !matches {
+            return tokio::spawn(async { Ok(false) });
+        }
+        let timestamp = self.record_timestamps.then(SystemTime::now);
+        let write = self.write_insert_in(slot, key.clone(), new, self.durability, timestamp, None);
+        drop(guard);
+        tokio::spawn(async move {
+            write.await??;
+            Ok(true)
+        })
+    }
+
+    /// Inserts a key-value pair like [`insert`](#method.insert), recording `timestamp` as the
+    /// time of the write for [`remove_older_than`](#method.remove_older_than).
+    ///
+    /// The timestamp is recorded even if the map wasn't configured with `timestamps`, which
+    /// makes this useful for importing data with known ages and for tests with a controlled
+    /// clock.
+    pub fn insert_with_timestamp(
+        &self,
+        key: K,
+        value: V,
+        timestamp: SystemTime,
+    ) -> JoinHandle<Result<Option<V>, StructureError>>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        self.write_insert(key, value, self.durability, Some(timestamp), None)
+    }
+
+    /// Inserts a key-value pair like [`insert`](#method.insert) that expires after `ttl`.
+    ///
+    /// The expiry time is written to the file with the entry, so it still applies after the
+    /// map is reopened; entries that expired while it was closed aren't loaded. Once expired,
+    /// the entry is treated as absent by [`get`](#method.get) and
+    /// [`contains_key`](#method.contains_key), and a background task removes it within
+    /// `expiry_interval`, appending its removal. Writing the key again without a TTL clears
+    /// its expiry time.
+    pub fn insert_with_ttl(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> JoinHandle<Result<Option<V>, StructureError>>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        let now = SystemTime::now();
+        let timestamp = self.record_timestamps.then_some(now);
+        self.write_insert(key, value, self.durability, timestamp, Some(now + ttl))
+    }
+
+    /// Inserts a key-value pair like [`insert`](#method.insert), but always syncs the write to
+    /// disk before the JoinHandle completes, regardless of the map's `durability`.
+    ///
+    /// Use this for the occasional write that must survive a crash as soon as it completes.
+    pub fn insert_synced(&self, key: K, value: V) -> JoinHandle<Result<Option<V>, StructureError>>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        let timestamp = self.record_timestamps.then(SystemTime::now);
+        self.write_insert(key, value, Durability::Sync, timestamp, None)
+    }
+
+    /// Applies an insert in memory and spawns the task that persists it.
+    fn write_insert(
+        &self,
+        key: K,
+        value: V,
+        durability: Durability,
+        timestamp: Option<SystemTime>,
+        expiry: Option<SystemTime>,
+    ) -> JoinHandle<Result<Option<V>, StructureError>>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        if let Err(e) = self.check_value_size(&value) {
+            return self.spawn_write(async move { Err(e) });
+        }
+        let write = match self.reserve() {
+            Ok(slot) => self.write_insert_in(slot, key, value, durability, timestamp, expiry),
+            Err(e) => self.spawn_write(async move { Err(e) }),
+        };
+        if self.expiries.needs_sweeper() {
+            self.start_expiry();
+        }
+        write
+    }
 
+    /// Inserts a key-value pair in memory and persists it in the background, in the place of
+    /// the write order reserved by `slot`.
+    fn write_insert_in(
+        &self,
+        slot: Option<Slot<'_>>,
+        key: K,
+        value: V,
+        durability: Durability,
+        timestamp: Option<SystemTime>,
+        expiry: Option<SystemTime>,
+    ) -> JoinHandle<Result<Option<V>, StructureError>>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        let entry = (key, value);
+        let inserted = self.insert_in_memory(std::slice::from_ref(&entry));
+        let (old_value, evicted, mut spill_write) = match inserted {
+            Ok((mut old_values, evicted, mut spill_writes)) => (
+                old_values.pop().flatten(),
+                evicted,
+                spill_writes.pop().flatten(),
+            ),
+            Err(e) => return self.spawn_write(async move { Err(e) }),
+        };
+        let (key, value) = entry;
+        let undo = self.undo(vec![(key.clone(), Some(value.clone()), old_value.clone())]);
+        let pending = self
+            .unwritten
+            .as_ref()
+            .and_then(|unwritten| unwritten.track(&key, old_value.is_none()));
+        let timestamp = timestamp.map(unix_millis);
+        self.set_timestamp(&key, timestamp);
+        let expiry = expiry.map(unix_millis);
+        self.expiries.set(&key, expiry);
         let file = self.file.clone();
         let id = self.id.clone();
-        tokio::spawn(async move {
-            let entries = entries
+        let legacy_id = self.legacy_id.clone();
+        let large_values = self.large_values.clone();
+        let offsets = self.offsets.clone();
+        let retry = self.retry;
+        let group_commit = self.group_commit.clone();
+        let key_codec = self.key_codec.clone();
+        let checksums = self.checksums;
+        let appended = ordered(slot, move || {
+            let old_value = old_value
+                .map(|old| {
+                    old.resolve(
+                        &key,
+                        large_values.as_ref(),
+                        &file,
+                        &id,
+                        &legacy_id,
+                        &key_codec,
+                    )
+                })
+                .transpose()?;
+            if let Some(pending) = pending {
+                if !pending.claim(&key) {
+                    // The key was removed, or written by a later write, before this one ran.
+                    return Ok(old_value);
+                }
+            }
+            let key = key_codec.encode(&key)?;
+            let value = key_codec.format().encode(&value)?;
+            let stamp = timestamp
+                .map(|timestamp| DBEntry::EntryTimestamp(id.clone(), key.clone(), timestamp));
+            let expiry = expiry.map(|expiry| DBEntry::EntryExpiry(id.clone(), key.clone(), expiry));
+            let entry = map_entry(large_values.as_ref(), id.clone(), key.clone(), value)?;
+            let extras = checksum_entry(checksums, &entry)
                 .into_iter()
-                .map(|(key, value)| {
-                    let key = bincode::serialize(&key)?;
-                    let value = bincode::serialize(&value)?;
-                    Ok(DBEntry::HashMapEntry(id.clone(), key, value))
+                .chain(stamp)
+                .chain(expiry)
+                .collect::<Vec<_>>();
+            let logged = matches!(entry, DBEntry::HashMapEntry(..));
+            let written_at = match (&offsets, &entry) {
+                (Some(offsets), DBEntry::HashMapEntry(_, _, value)) if extras.is_empty() => {
+                    let offset = offsets.get(&key).map(|offset| *offset);
+                    let overwritten = match offset {
+                        Some(offset) => overwrite_entry(&entry, value.len(), offset, &file, retry)?,
+                        None => false,
+                    };
+                    match offset {
+                        Some(offset) if overwritten => offset,
+                        _ => {
+                            let offset = append_entry(&entry, &file, retry)?;
+                            offsets.insert(key, offset);
+                            offset
+                        }
+                    }
+                }
+                _ => {
+                    forget_offset(&offsets, &key);
+                    let entries = std::iter::once(entry).chain(extras).collect::<Vec<_>>();
+                    append_entries(&entries, &file, retry)?
+                }
+            };
+            if let Some(spill_write) = spill_write.as_mut().filter(|_| logged) {
+                spill_write.logged_at(written_at);
+            }
+            write_evictions(&evicted, &id, &key_codec, &offsets, &file, retry)?;
+            Ok(old_value)
+        });
+        let file = self.file.clone();
+        self.spawn_write(async move {
+            let old_value = appended.await.inspect_err(|_| undo.apply())?;
+            sync_write(&file, durability, group_commit.as_ref()).await?;
+            Ok(old_value)
+        })
+    }
+
+    /// Inserts a batch of key-value pairs into the HashMap.
+    ///
+    /// The entries are written to disk in chunks of at most `batch_chunk_size` entries
+    /// (see [`HashMapConfig`]), so very large batches don't need one huge intermediate buffer.
+    ///
+    /// Returns a JoinHandle that can be awaited to wait for the operation to complete.
+    ///
+    /// JoinHandle will return a Result containing a Vec of the old values (None if new) if the operation was successful.
+    pub fn insert_batch(
+        &self,
+        entries: Vec<(K, V)>,
+    ) -> JoinHandle<Result<Vec<Option<V>>, StructureError>>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        self.spawn_write(self.write_batch(entries))
+    }
+
+    /// Inserts a batch of key-value pairs like [`insert_batch`](#method.insert_batch),
+    /// blocking the calling thread until the write completes. See
+    /// [`insert_sync`](#method.insert_sync).
+    ///
+    /// Returns a Vec of the old values (None if new) if the operation was successful.
+    pub fn insert_batch_sync(&self, entries: Vec<(K, V)>) -> Result<Vec<Option<V>>, StructureError>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        blocking::run(|| self.insert_batch(entries))
+    }
+
+    /// Inserts every key-value pair produced by `iter`, written like
+    /// [`insert_batch`](#method.insert_batch).
+    ///
+    /// Returns a JoinHandle that can be awaited to wait for the operation to complete.
+    pub fn extend<I>(&self, iter: I) -> JoinHandle<Result<(), StructureError>>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Sync,
+        V: Sync,
+    {
+        let write = self.write_batch(iter.into_iter().collect());
+        self.spawn_write(async move {
+            write.await?;
+            Ok(())
+        })
+    }
+
+    /// Inserts a batch of key-value pairs like [`insert_batch`](#method.insert_batch), and
+    /// tallies how many keys were new and how many were updated.
+    ///
+    /// JoinHandle will return a Result containing the `BatchSummary` if the operation was successful.
+    pub fn insert_batch_summary(
+        &self,
+        entries: Vec<(K, V)>,
+    ) -> JoinHandle<Result<BatchSummary<V>, StructureError>>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        let write = self.write_batch(entries);
+        self.spawn_write(async move {
+            let old_values = write.await?;
+            let updated = old_values.iter().filter(|old| old.is_some()).count();
+            Ok(BatchSummary {
+                inserted: old_values.len() - updated,
+                updated,
+                old_values,
+            })
+        })
+    }
+
+    /// Inserts `entries` like [`insert_batch`](#method.insert_batch), but only if the map is
+    /// currently empty, for seeding defaults.
+    ///
+    /// The emptiness check and the insert happen while the file is locked, so when several
+    /// callers race to seed the same map exactly one of them does.
+    ///
+    /// JoinHandle will return a Result containing whether the entries were inserted if the
+    /// operation was successful.
+    pub fn init_once(&self, entries: Vec<(K, V)>) -> JoinHandle<Result<bool, StructureError>>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        let file = match lock_file(&self.file) {
+            Ok(file) => file,
+            Err(e) => return self.spawn_write(async move { Err(e) }),
+        };
+        if !self.is_empty() {
+            return self.spawn_write(async { Ok(false) });
+        }
+        let write = self.write_batch(entries);
+        drop(file);
+        self.spawn_write(async move {
+            write.await?;
+            Ok(true)
+        })
+    }
+
+    /// Applies a batch of inserts in memory and returns the future that persists them.
+    ///
+    /// If any value is too large the batch is rejected as a whole, without changing memory,
+    /// and the future resolves to the error.
+    fn write_batch(
+        &self,
+        entries: Vec<(K, V)>,
+    ) -> impl std::future::Future<Output = Result<Vec<Option<V>>, StructureError>> + Send + 'static
+    where
+        K: Sync,
+        V: Sync,
+    {
+        let rejected = entries
+            .iter()
+            .try_for_each(|(_, value)| self.check_value_size(value))
+            .err();
+        let (slot, rejected) = match rejected {
+            Some(e) => (None, Some(e)),
+            None => match self.reserve() {
+                Ok(slot) => (slot, None),
+                Err(e) => (None, Some(e)),
+            },
+        };
+        let timestamp = self
+            .record_timestamps
+            .then(|| unix_millis(SystemTime::now()));
+        let (rejected, old_values, evicted, mut spill_writes) = match rejected {
+            Some(e) => (Some(e), Vec::new(), Vec::new(), Vec::new()),
+            None => match self.insert_in_memory(&entries) {
+                Ok((old_values, evicted, spill_writes)) => {
+                    (None, old_values, evicted, spill_writes)
+                }
+                Err(e) => (Some(e), Vec::new(), Vec::new(), Vec::new()),
+            },
+        };
+        let undo = self.undo(
+            entries
+                .iter()
+                .zip(&old_values)
+                .map(|((key, value), old)| (key.clone(), Some(value.clone()), old.clone()))
+                .collect(),
+        );
+        if rejected.is_none() {
+            for (key, _) in &entries {
+                self.set_timestamp(key, timestamp);
+                self.expiries.remove(key);
+                if let Some(unwritten) = &self.unwritten {
+                    unwritten.forget(key);
+                }
+            }
+        }
+
+        let file = self.file.clone();
+        let id = self.id.clone();
+        let legacy_id = self.legacy_id.clone();
+        let chunk_size = self.batch_chunk_size;
+        let durability = self.durability;
+        let large_values = self.large_values.clone();
+        let offsets = self.offsets.clone();
+        let retry = self.retry;
+        let group_commit = self.group_commit.clone();
+        let key_codec = self.key_codec.clone();
+        let checksums = self.checksums;
+        let appended = ordered(slot, move || {
+            if let Some(e) = rejected {
+                return Err(e);
+            }
+            let old_values = entries
+                .iter()
+                .zip(old_values)
+                .map(|((key, _), old)| {
+                    old.map(|old| {
+                        old.resolve(
+                            key,
+                            large_values.as_ref(),
+                            &file,
+                            &id,
+                            &legacy_id,
+                            &key_codec,
+                        )
+                    })
+                    .transpose()
                 })
-                .collect::<Result<Vec<DBEntry>, StructureError>>()?;
-            serialize_to_file(&entries, &file)?;
-            return Ok(old_values);
+                .collect::<Result<Vec<_>, _>>()?;
+            let entries = entries.into_iter().flat_map(|(key, value)| {
+                let serialized = key_codec
+                    .encode(&key)
+                    .and_then(|key| Ok((key, key_codec.format().encode(&value)?)));
+                let (key, value) = match serialized {
+                    Ok(serialized) => serialized,
+                    Err(e) => return vec![Err(e)],
+                };
+                forget_offset(&offsets, &key);
+                let stamp = timestamp.map(|timestamp| {
+                    Ok(DBEntry::EntryTimestamp(id.clone(), key.clone(), timestamp))
+                });
+                let entry = match map_entry(large_values.as_ref(), id.clone(), key, value) {
+                    Ok(entry) => entry,
+                    Err(e) => return vec![Err(e)],
+                };
+                let checksum = checksum_entry(checksums, &entry).map(Ok);
+                std::iter::once(Ok(entry))
+                    .chain(checksum)
+                    .chain(stamp)
+                    .collect::<Vec<_>>()
+            });
+            // Every pair is written as one record of its value, followed by its stamps.
+            let mut spill_writes = spill_writes.iter_mut();
+            serialize_chunks_at(entries, chunk_size, &file, retry, |entry, offset| {
+                let spill_write = match entry {
+                    DBEntry::HashMapEntry(..) => spill_writes.next(),
+                    DBEntry::ExternalHashMapEntry(..) => {
+                        spill_writes.next();
+                        None
+                    }
+                    _ => None,
+                };
+                if let Some(Some(spill_write)) = spill_write {
+                    spill_write.logged_at(offset);
+                }
+            })?;
+            write_evictions(&evicted, &id, &key_codec, &offsets, &file, retry)?;
+            Ok(old_values)
+        });
+        let file = self.file.clone();
+        async move {
+            let old_values = appended.await.inspect_err(|_| undo.apply())?;
+            sync_write(&file, durability, group_commit.as_ref()).await?;
+            Ok(old_values)
+        }
+    }
+
+    /// Inserts `entries` into memory, enforcing the `max_entries` setting.
+    ///
+    /// Returns the previous value of each entry, and the keys evicted to make room for them.
+    /// Under `EvictionPolicy::RejectNew` the entries are rejected as a whole, without changing
+    /// memory, if their new keys don't fit.
+    fn insert_in_memory(&self, entries: &[(K, V)]) -> Result<Inserted<K, V>, StructureError> {
+        let mut spill_writes = Vec::with_capacity(entries.len());
+        let mut insert = |(key, value): &(K, V)| {
+            spill_writes.push(self.spill.as_ref().map(|spill| spill.write(key)));
+            let old_value = self.inner.insert(key.clone(), value.clone());
+            self.previous(key, old_value)
+        };
+        let Some(eviction) = &self.eviction else {
+            let old_values = entries.iter().map(insert).collect();
+            self.spill_excess(None);
+            return Ok((old_values, Vec::new(), spill_writes));
+        };
+
+        let mut order = eviction.lock()?;
+        let limit = eviction.max_entries();
+        if eviction.policy() == EvictionPolicy::RejectNew {
+            let new_keys = entries
+                .iter()
+                .map(|(key, _)| key)
+                .filter(|key| !self.inner.contains_key(key) && !self.external.contains_key(key))
+                .collect::<std::collections::HashSet<_>>();
+            if self.len() + new_keys.len() > limit {
+                return Err(StructureError::MapFull { limit });
+            }
+        }
+        let mut old_values = Vec::with_capacity(entries.len());
+        for entry in entries {
+            old_values.push(insert(entry));
+            order.inserted(&entry.0, eviction.policy());
+        }
+        let mut evicted = Vec::new();
+        while self.len() > limit {
+            let Some(key) = order.pop() else { break };
+            let in_memory = self.inner.remove(&key).is_some();
+            if in_memory | self.external.remove(&key).is_some() {
+                self.timestamps.remove(&key);
+                self.expiries.remove(&key);
+                self.forget_spilled(&key);
+                evicted.push(key);
+            }
+        }
+        drop(order);
+        self.spill_excess(None);
+        Ok((old_values, evicted, spill_writes))
+    }
+
+    /// Checks the serialized size of `value` against the `max_value_bytes` setting.
+    fn check_value_size(&self, value: &V) -> Result<(), StructureError> {
+        if let Some(limit) = self.max_value_bytes {
+            let size = self.format.encoded_len(value)?;
+            if size > limit {
+                return Err(StructureError::ValueTooLarge { size, limit });
+            }
+        }
+        Ok(())
+    }
+
+    /// Registers a handler that is called with the error of every failed background write.
+    ///
+    /// Errors are still returned through the write's JoinHandle as well, but this catches
+    /// failures of writes whose JoinHandle was dropped. The handler runs on the write task and
+    /// receives a copy of the error; a panic in it is caught. Registering a handler replaces
+    /// the previous one.
+    pub fn set_error_handler(&self, handler: impl Fn(StructureError) + Send + Sync + 'static) {
+        self.errors.set(handler);
+    }
+
+    /// Spawns a task persisting a write, reporting its error to the error handler if it fails.
+    fn spawn_write<T, F>(&self, write: F) -> JoinHandle<Result<T, StructureError>>
+    where
+        T: Send + 'static,
+        F: std::future::Future<Output = Result<T, StructureError>> + Send + 'static,
+    {
+        let errors = self.errors.clone();
+        let registered = self.pending.as_ref().map(PendingWrites::begin);
+        let compaction = self
+            .auto_compact
+            .clone()
+            .map(|auto_compact| CompactionCheck {
+                auto_compact,
+                file: self.file.clone(),
+                id: self.id.clone(),
+                legacy_id: self.legacy_id.clone(),
+                path: self.path.clone(),
+                offsets: self.offsets.clone(),
+                reindex: self.reindex(),
+                durability: self.durability,
+                recovery: self.recovery,
+                writer: self.writer.clone(),
+            });
+        tokio::spawn(async move {
+            let result = write.await;
+            let succeeded = result.is_ok();
+            if let Some(compaction) = compaction.filter(|_| succeeded) {
+                // The write itself succeeded, so a failed compaction is only reported.
+                if let Err(e) = compaction.run().await {
+                    errors.report(&e);
+                }
+            }
+            drop(registered);
+            if let Err(e) = &result {
+                errors.report(e);
+            }
+            result
         })
     }
 
+    /// Starts the background task that removes the entries inserted with a TTL once they
+    /// expire, appending their removals like [`remove_batch`](#method.remove_batch) does.
+    ///
+    /// The task stops when no entry has an expiry time left, or once the map is dropped, and
+    /// is started again by the next insert. Failed removals are reported to the error handler.
+    fn start_expiry(&self)
+    where
+        K: Sync,
+        V: Sync,
+    {
+        let inner = Arc::downgrade(&self.inner);
+        let external = self.external.clone();
+        let spill = self.spill.clone();
+        let timestamps = self.timestamps.clone();
+        let expiries = self.expiries.clone();
+        let file = self.file.clone();
+        let id = self.id.clone();
+        let chunk_size = self.batch_chunk_size.max(1);
+        let durability = self.durability;
+        let offsets = self.offsets.clone();
+        let retry = self.retry;
+        let group_commit = self.group_commit.clone();
+        let key_codec = self.key_codec.clone();
+        let writer = self.writer.clone();
+        let errors = self.errors.clone();
+        self.expiries.start(move || {
+            let inner = inner.upgrade()?;
+            let (external, spill, timestamps, expiries) = (
+                external.clone(),
+                spill.clone(),
+                timestamps.clone(),
+                expiries.clone(),
+            );
+            let (file, id, offsets, key_codec, writer, errors) = (
+                file.clone(),
+                id.clone(),
+                offsets.clone(),
+                key_codec.clone(),
+                writer.clone(),
+                errors.clone(),
+            );
+            let group_commit = group_commit.clone();
+            Some(async move {
+                let removal = async {
+                    let appended = {
+                        let slot = writer.as_ref().map(|writer| writer.reserve()).transpose()?;
+                        let now = unix_millis(SystemTime::now());
+                        let expired = expiries
+                            .take_expired(now)
+                            .into_iter()
+                            .filter_map(|key| {
+                                timestamps.remove(&key);
+                                if let Some(spill) = &spill {
+                                    spill.forget(&key);
+                                }
+                                let removed = inner.remove(&key).map(|(key, _)| key);
+                                removed.or_else(|| external.remove(&key).map(|(key, _)| key))
+                            })
+                            .collect::<Vec<_>>();
+                        if expired.is_empty() {
+                            return Ok(());
+                        }
+                        let file = file.clone();
+                        ordered(slot, move || {
+                            let entries = expired.iter().map(|key| {
+                                let key = key_codec.encode(key)?;
+                                forget_offset(&offsets, &key);
+                                Ok(DBEntry::RemoveHashMapEntry(id.clone(), key))
+                            });
+                            serialize_chunks_to_file(entries, chunk_size, &file, retry)
+                        })
+                    };
+                    appended.await?;
+                    sync_write(&file, durability, group_commit.as_ref()).await
+                };
+                if let Err(e) = removal.await {
+                    errors.report(&e);
+                }
+            })
+        });
+    }
+
     /// Gets a reference to the value corresponding to the given key.
     ///
-    /// Returns None if the key does not exist.
+    /// Returns None if the key does not exist, or if its value is stored in the sidecar file
+    /// and could not be read (see [`try_get`](#method.try_get)).
+    ///
+    /// The returned reference holds a read lock on the key's shard until it is dropped. Writing
+    /// to a key of the same shard while holding it, for example inserting into the same key,
+    /// deadlocks. Prefer [`get_cloned`](#method.get_cloned) unless avoiding the clone matters.
     #[inline(always)]
     pub fn get(&self, key: &K) -> Option<ValueRefPair<'_, K, V>> {
-        self.inner.get(key).map(|inner| ValueRefPair::new(inner))
+        self.try_get(key).ok().flatten()
+    }
+
+    /// Gets a copy of the value corresponding to the given key.
+    ///
+    /// The value is cloned and the shard's read lock released before this returns, so unlike
+    /// [`get`](#method.get) the result can be held across writes to the map. Returns None in
+    /// the same cases as `get`.
+    #[inline]
+    pub fn get_cloned(&self, key: &K) -> Option<V> {
+        self.get(key).map(|value| value.value().clone())
+    }
+
+    /// Returns true if the HashMap contains a value for the given key.
+    ///
+    /// Unlike [`get`](#method.get), no reference to the value is kept, and a value stored in
+    /// the sidecar file isn't read.
+    #[inline]
+    pub fn contains_key(&self, key: &K) -> bool {
+        (self.inner.contains_key(key) || self.external.contains_key(key)) && !self.is_expired(key)
+    }
+
+    /// Gets a reference to the value corresponding to the given key, surfacing load errors.
+    ///
+    /// Unlike [`get`](#method.get), this distinguishes a failure to read the value from a miss:
+    /// `Ok(None)` means the key does not exist, while `Err` means the value could not be loaded.
+    /// Values stored in the sidecar file are read on first access and kept in memory after
+    /// that; a map that holds all of its values in memory never fails here.
+    #[inline]
+    pub fn try_get(&self, key: &K) -> Result<Option<ValueRefPair<'_, K, V>>, StructureError> {
+        if self.is_expired(key) {
+            return Ok(None);
+        }
+        if let Some(eviction) = &self.eviction {
+            eviction.touch(key);
+        }
+        if let Some(spill) = &self.spill {
+            spill.touch(key);
+        }
+        loop {
+            if let Some(inner) = self.inner.get(key) {
+                return Ok(Some(ValueRefPair::new(inner)));
+            }
+            if !self.load_external(key)? {
+                return Ok(None);
+            }
+            // Another read may evict the value again before it is returned, in which case
+            // it is loaded once more.
+            self.spill_excess(Some(key));
+        }
+    }
+
+    /// Reads the value of `key` from the file rather than from memory, checking it against the
+    /// checksum recorded with it.
+    ///
+    /// This is an integrity-checked read for critical lookups: the latest record of the key is
+    /// found by scanning the log, and its value is only returned if its bytes still match the
+    /// CRC32 written after it. Returns `StructureError::ChecksumMismatch` if they don't, and
+    /// `StructureError::ChecksumMissing` if the record has no checksum, because the map wasn't
+    /// configured with `checksums` when it was written or the value is in the sidecar file.
+    /// `Ok(None)` means the key has no live record in the file.
+    pub fn get_verified(&self, key: &K) -> Result<Option<V>, StructureError> {
+        let key = self.key_codec.encode(key)?;
+        let mut latest: Option<(Vec<u8>, Option<u32>)> = None;
+        let mut external = false;
+        scan_file(&self.file, |entry, _| {
+            match entry {
+                DBEntry::HashMapEntry(id, entry_key, value)
+                    if self.owns(&id) && entry_key == key =>
+                {
+                    latest = Some((value, None));
+                    external = false;
+                }
+                DBEntry::EntryChecksum(id, entry_key, checksum)
+                    if self.owns(&id) && entry_key == key =>
+                {
+                    if let Some((_, recorded)) = &mut latest {
+                        *recorded = Some(checksum);
+                    }
+                }
+                DBEntry::ExternalHashMapEntry(id, entry_key, _)
+                    if self.owns(&id) && entry_key == key =>
+                {
+                    latest = None;
+                    external = true;
+                }
+                DBEntry::RemoveHashMapEntry(id, entry_key)
+                    if self.owns(&id) && entry_key == key =>
+                {
+                    latest = None;
+                    external = false;
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+        if external {
+            return Err(StructureError::ChecksumMissing);
+        }
+        let Some((value, recorded)) = latest else {
+            return Ok(None);
+        };
+        let expected = recorded.ok_or(StructureError::ChecksumMissing)?;
+        let found = crc32(&value);
+        if found != expected {
+            return Err(StructureError::ChecksumMismatch { expected, found });
+        }
+        Ok(Some(self.format.decode(&value)?))
+    }
+
+    /// Returns owned copies of the values of `keys`, in the same order as `keys`.
+    ///
+    /// No shard lock is held once this returns, so the results can be kept across `.await`
+    /// points. Keys that don't exist, or whose sidecar value could not be read, yield None.
+    pub fn get_many_cloned(&self, keys: &[K]) -> Vec<Option<V>> {
+        keys.iter()
+            .map(|key| self.get(key).map(|value| value.value().clone()))
+            .collect()
+    }
+
+    /// Returns a copy of the value of `key`, calling `loader` to fetch it on a miss.
+    ///
+    /// The loaded value is inserted into the map and persisted before it is returned.
+    /// Concurrent misses for the same key are coalesced: only one caller's loader runs and the
+    /// others wait for its result. If that loader fails, its error is returned to its own
+    /// caller and the next waiting caller runs its loader instead.
+    pub async fn get_or_load<F, Fut>(&self, key: K, loader: F) -> Result<V, StructureError>
+    where
+        F: FnOnce(&K) -> Fut,
+        Fut: std::future::Future<Output = Result<V, StructureError>>,
+        K: Sync,
+        V: Sync,
+    {
+        if let Some(value) = self.try_get(&key)? {
+            return Ok(value.value().clone());
+        }
+        let cell = self.loading.entry(key.clone()).or_default().clone();
+        let value = cell
+            .get_or_try_init(|| async {
+                // A previous load may have finished between the miss and joining the cell.
+                if let Some(value) = self.try_get(&key)? {
+                    return Ok(value.value().clone());
+                }
+                let value = loader(&key).await?;
+                self.insert(key.clone(), value.clone()).await??;
+                Ok(value)
+            })
+            .await
+            .cloned();
+        self.loading
+            .remove_if(&key, |_, loading| Arc::ptr_eq(loading, &cell));
+        value
+    }
+
+    /// Moves the value of `key` into memory from the sidecar file, or from its record in the
+    /// log if it was evicted by `max_in_memory`. Returns false if it wasn't stored there.
+    fn load_external(&self, key: &K) -> Result<bool, StructureError> {
+        let Some(stored) = self.external.get(key).map(|stored| stored.clone()) else {
+            return Ok(false);
+        };
+        let (value, offsets) = match &stored {
+            Stored::Sidecar(location) => (
+                read_external(self.large_values.as_ref(), location, self.format)?,
+                None,
+            ),
+            Stored::Log(offset) => {
+                // The record may have moved since the value was evicted.
+                let expected = self
+                    .spill
+                    .as_ref()
+                    .and_then(|spill| spill.lock().offset(key))
+                    .unwrap_or(*offset);
+                let entry_key = self.key_codec.encode(key)?;
+                let (value, found) = read_logged(
+                    &self.file,
+                    &self.id,
+                    &self.legacy_id,
+                    &entry_key,
+                    expected,
+                    self.format,
+                )?;
+                (value, Some((expected, found)))
+            }
+        };
+        if let Some((key, _)) = self
+            .external
+            .remove_if(key, |_, current| *current == stored)
+        {
+            if let (Some(spill), Some((expected, found))) = (&self.spill, offsets) {
+                spill.lock().loaded(&key, expected, found);
+            }
+            self.inner.entry(key).or_insert(value);
+        }
+        Ok(true)
+    }
+
+    /// Moves every value still in the sidecar file or the log into memory, skipping any that
+    /// can't be read.
+    fn load_all_external(&self) {
+        let external = self
+            .external
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        for key in &external {
+            let _ = self.load_external(key);
+        }
+    }
+
+    /// Evicts the least recently used values from memory while the map holds more than its
+    /// `max_in_memory`, leaving them to be read back from the log. `keep` isn't evicted.
+    fn spill_excess(&self, keep: Option<&K>) {
+        if let Some(spill) = &self.spill {
+            spill.evict(&self.inner, keep, |key, offset| {
+                self.external.insert(key, Stored::Log(offset));
+            });
+        }
+    }
+
+    /// Forgets a key that was removed from the map in the `max_in_memory` index.
+    fn forget_spilled(&self, key: &K) {
+        if let Some(spill) = &self.spill {
+            spill.forget(key);
+        }
+    }
+
+    /// Returns what the map needs to find its records again after a compaction, if it has a
+    /// `max_in_memory`.
+    fn reindex(&self) -> Option<Reindex<K>> {
+        self.spill.clone().map(|spill| Reindex {
+            spill,
+            id: self.id.clone(),
+            legacy_id: self.legacy_id.clone(),
+            key_codec: self.key_codec.clone(),
+            recovery: self.recovery,
+        })
+    }
+
+    /// Returns the number of values the HashMap holds in memory, leaving out the ones in the
+    /// sidecar file that weren't read yet and the ones evicted to the log by `max_in_memory`.
+    pub fn in_memory_len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns true if `key` was inserted with a TTL that has run out.
+    fn is_expired(&self, key: &K) -> bool {
+        self.expiries
+            .get(key)
+            .is_some_and(|expiry| expiry <= unix_millis(SystemTime::now()))
+    }
+
+    /// Records or clears the in-memory timestamp of `key` after a write.
+    fn set_timestamp(&self, key: &K, timestamp: Option<u64>) {
+        match timestamp {
+            Some(timestamp) => {
+                self.timestamps.insert(key.clone(), timestamp);
+            }
+            None => {
+                self.timestamps.remove(key);
+            }
+        }
+    }
+
+    /// Pairs the value an operation removed from memory with where `key` is stored outside
+    /// memory, if its value hadn't been loaded yet.
+    fn previous(&self, key: &K, in_memory: Option<V>) -> Option<Previous<V>> {
+        let external = self.external.remove(key);
+        match in_memory {
+            Some(value) => Some(Previous::Value(value)),
+            None => external.map(|(_, stored)| Previous::External(stored)),
+        }
+    }
+
+    /// Returns an iterator over references to the key-value pairs of the HashMap.
+    ///
+    /// Values still in the sidecar file, or evicted to the log by `max_in_memory`, are loaded
+    /// first; any that can't be read are left out. Evicted values stay in memory until the
+    /// next write or read after the iterator is dropped. The order of the pairs is unspecified.
+    /// Like dashmap's own iterators, the iterator and every reference it yields hold a read lock
+    /// on a shard of the map: writing to the map while holding them, including from the same
+    /// thread, may deadlock.
+    pub fn iter(&self) -> impl Iterator<Item = ValueRefPair<'_, K, V>> {
+        self.load_all_external();
+        self.inner.iter().map(ValueRefPair::from_multi)
+    }
+
+    /// Returns an iterator over owned copies of the keys of the HashMap.
+    ///
+    /// The keys are cloned out in a single pass before the iterator is returned, so unlike
+    /// [`iter`](#method.iter) it holds no locks and the map can be written to while iterating.
+    /// The order of the keys is unspecified.
+    pub fn keys(&self) -> impl Iterator<Item = K> {
+        let mut keys = Vec::with_capacity(self.len());
+        keys.extend(self.inner.iter().map(|entry| entry.key().clone()));
+        keys.extend(self.external.iter().map(|entry| entry.key().clone()));
+        keys.into_iter()
+    }
+
+    /// Returns an iterator over owned copies of the values of the HashMap.
+    ///
+    /// Like [`keys`](#method.keys), the values are cloned out before the iterator is returned,
+    /// so it holds no locks. Values still in the sidecar file are loaded first; any that can't
+    /// be read are left out. The order of the values is unspecified.
+    pub fn values(&self) -> impl Iterator<Item = V> {
+        self.load_all_external();
+        let values = self
+            .inner
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect::<Vec<_>>();
+        self.spill_excess(None);
+        values.into_iter()
+    }
+
+    /// Collects owned copies of every key-value pair in the HashMap.
+    ///
+    /// This is a single pass over the map's shards, taking each shard's read lock once rather
+    /// than once per key as repeated `get` calls would. The order of the returned pairs is
+    /// unspecified. Values still in the sidecar file are loaded first; any that can't be read
+    /// are left out.
+    pub fn collect_pairs(&self) -> Vec<(K, V)> {
+        self.load_all_external();
+        let pairs = self
+            .inner
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        self.spill_excess(None);
+        pairs
+    }
+
+    /// Returns a copy of the HashMap's entries ordered by key, for comparing in assertions.
+    ///
+    /// Unlike [`collect_pairs`](#method.collect_pairs), whose order follows the in-memory
+    /// layout, the order is deterministic, so two maps with the same contents give equal views.
+    pub fn debug_entries(&self) -> std::collections::BTreeMap<K, V>
+    where
+        K: Ord,
+    {
+        self.collect_pairs().into_iter().collect()
+    }
+
+    /// Reads the file and returns the current pairs of the HashMap in the order they were last
+    /// written.
+    ///
+    /// Unlike [`collect_pairs`](#method.collect_pairs), which follows the in-memory layout,
+    /// this reflects the log: a key that is written again moves to the end, and removed keys
+    /// are left out. Writes that are still in flight are not included.
+    pub fn iter_log_order(&self) -> Result<Vec<(K, V)>, StructureError> {
+        let mut latest = std::collections::HashMap::new();
+        let mut sequence = 0u64;
+        scan_file(&self.file, |entry, _| {
+            match &entry {
+                DBEntry::HashMapEntry(id, key, _) | DBEntry::ExternalHashMapEntry(id, key, _)
+                    if self.owns(id) =>
+                {
+                    latest.insert(key.clone(), (sequence, entry));
+                    sequence += 1;
+                }
+                DBEntry::RemoveHashMapEntry(id, key) if self.owns(id) => {
+                    latest.remove(key);
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+
+        let mut writes = latest.into_values().collect::<Vec<_>>();
+        writes.sort_unstable_by_key(|(sequence, _)| *sequence);
+        writes
+            .into_iter()
+            .map(|(_, entry)| match entry {
+                DBEntry::HashMapEntry(_, key, value) => {
+                    Ok((self.key_codec.decode(&key)?, self.format.decode(&value)?))
+                }
+                DBEntry::ExternalHashMapEntry(_, key, location) => Ok((
+                    self.key_codec.decode(&key)?,
+                    read_external(self.large_values.as_ref(), &location, self.format)?,
+                )),
+                _ => unreachable!("only writes are kept"),
+            })
+            .collect()
+    }
+
+    /// Locks a single key, blocking until no other caller holds it.
+    ///
+    /// This lets a caller perform a multi-step read-modify-persist on one key without other
+    /// `lock_key` holders interleaving on it. Locking is cooperative: `insert`, `remove` and the
+    /// other methods don't take key locks, so the guard holder can still call them. Keys other
+    /// than `key` are never blocked. The key is unlocked when the returned guard is dropped.
+    pub fn lock_key(&self, key: &K) -> KeyGuard<'_, K> {
+        self.key_locks.lock(key)
     }
 
     /// Removes a key from the HashMap, returning the value at the key if the key was previously in the HashMap.
     ///
     /// Returns None if the key did not exist.
-    pub fn remove(&self, key: &K) -> Option<JoinHandle<Result<Option<V>, StructureError>>> {
-        if let Some((key, value)) = self.inner.remove(key) {
-            let file = self.file.clone();
-            let id = self.id.clone();
-            Some(tokio::spawn(async move {
-                let key = bincode::serialize(&key)?;
-                serialize_to_file(&DBEntry::RemoveHashMapEntry(id.clone(), key), &file)?;
-                Ok(Some(value))
-            }))
-        } else {
-            None
+    pub fn remove(&self, key: &K) -> Option<JoinHandle<Result<Option<V>, StructureError>>>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        let slot = match self.reserve() {
+            Ok(slot) => slot,
+            Err(e) => return Some(self.spawn_write(async move { Err(e) })),
+        };
+        let (key, value) = match self.inner.remove(key) {
+            Some((key, value)) => (key, Previous::Value(value)),
+            None => {
+                let (key, stored) = self.external.remove(key)?;
+                (key, Previous::External(stored))
+            }
+        };
+        self.timestamps.remove(&key);
+        self.expiries.remove(&key);
+        self.forget_spilled(&key);
+        if let Some(eviction) = &self.eviction {
+            eviction.forget(&key);
         }
+        let undo = self.undo(vec![(key.clone(), None, Some(value.clone()))]);
+        let unwritten = self
+            .unwritten
+            .as_ref()
+            .is_some_and(|unwritten| unwritten.forget(&key));
+        let file = self.file.clone();
+        let id = self.id.clone();
+        let legacy_id = self.legacy_id.clone();
+        let durability = self.durability;
+        let large_values = self.large_values.clone();
+        let offsets = self.offsets.clone();
+        let retry = self.retry;
+        let group_commit = self.group_commit.clone();
+        let key_codec = self.key_codec.clone();
+        let appended = ordered(slot, move || {
+            let value = value.resolve(
+                &key,
+                large_values.as_ref(),
+                &file,
+                &id,
+                &legacy_id,
+                &key_codec,
+            )?;
+            if unwritten {
+                // The insert was cancelled before it reached the file, so there is nothing to
+                // remove from it.
+                return Ok(Some(value));
+            }
+            let key = key_codec.encode(&key)?;
+            forget_offset(&offsets, &key);
+            serialize_to_file(&DBEntry::RemoveHashMapEntry(id.clone(), key), &file, retry)?;
+            Ok(Some(value))
+        });
+        let file = self.file.clone();
+        Some(self.spawn_write(async move {
+            let value = appended.await.inspect_err(|_| undo.apply())?;
+            sync_write(&file, durability, group_commit.as_ref()).await?;
+            Ok(value)
+        }))
+    }
+
+    /// Removes a key like [`remove`](#method.remove), blocking the calling thread until the
+    /// write completes. See [`insert_sync`](#method.insert_sync).
+    ///
+    /// Returns the removed value, or None if the key did not exist.
+    pub fn remove_sync(&self, key: &K) -> Result<Option<V>, StructureError>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        blocking::run(|| async {
+            match self.remove(key) {
+                Some(removal) => removal.await,
+                None => Ok(Ok(None)),
+            }
+        })
     }
 
     /// Removes a batch of keys from the HashMap.
     ///
+    /// Like [`insert_batch`](#method.insert_batch), the removals are written in chunks of at most
+    /// `batch_chunk_size` entries.
+    ///
     /// Returns a JoinHandle that can be awaited to wait for the operation to complete.
     ///
     /// JoinHandle will return a Result containing a Vec of the removed key-value pairs if the operation was successful.
-    pub fn remove_batch(&self, keys: Vec<K>) -> JoinHandle<Result<Vec<(K, V)>, StructureError>> {
+    pub fn remove_batch(&self, keys: Vec<K>) -> JoinHandle<Result<Vec<(K, V)>, StructureError>>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        let slot = match self.reserve() {
+            Ok(slot) => slot,
+            Err(e) => return self.spawn_write(async move { Err(e) }),
+        };
         let mut removed_values = Vec::with_capacity(keys.len());
         for key in &keys {
+            self.timestamps.remove(key);
+            self.expiries.remove(key);
+            if let Some(eviction) = &self.eviction {
+                eviction.forget(key);
+            }
+            let unwritten = self
+                .unwritten
+                .as_ref()
+                .is_some_and(|unwritten| unwritten.forget(key));
             if let Some((key, value)) = self.inner.remove(key) {
-                removed_values.push((key, value));
+                removed_values.push((key, Previous::Value(value), unwritten));
+            } else if let Some((key, stored)) = self.external.remove(key) {
+                removed_values.push((key, Previous::External(stored), unwritten));
             }
+            self.forget_spilled(key);
         }
+        let undo = self.undo(
+            removed_values
+                .iter()
+                .map(|(key, value, _)| (key.clone(), None, Some(value.clone())))
+                .collect(),
+        );
 
         let file = self.file.clone();
         let id = self.id.clone();
-        tokio::spawn(async move {
-            let entries = removed_values
-                .clone()
+        let legacy_id = self.legacy_id.clone();
+        let chunk_size = self.batch_chunk_size;
+        let durability = self.durability;
+        let large_values = self.large_values.clone();
+        let offsets = self.offsets.clone();
+        let retry = self.retry;
+        let group_commit = self.group_commit.clone();
+        let key_codec = self.key_codec.clone();
+        let appended = ordered(slot, move || {
+            let mut written = Vec::with_capacity(removed_values.len());
+            let removed_values = removed_values
                 .into_iter()
-                .map(|(key, _)| {
-                    let key = bincode::serialize(&key)?;
-                    Ok(DBEntry::RemoveHashMapEntry(id.clone(), key))
+                .map(|(key, value, unwritten)| {
+                    if !unwritten {
+                        written.push(key.clone());
+                    }
+                    let value = value.resolve(
+                        &key,
+                        large_values.as_ref(),
+                        &file,
+                        &id,
+                        &legacy_id,
+                        &key_codec,
+                    )?;
+                    Ok((key, value))
                 })
-                .collect::<Result<Vec<DBEntry>, StructureError>>()?;
-            serialize_to_file(&entries, &file)?;
+                .collect::<Result<Vec<_>, StructureError>>()?;
+            let entries = written.iter().map(|key| {
+                let key = key_codec.encode(key)?;
+                forget_offset(&offsets, &key);
+                Ok(DBEntry::RemoveHashMapEntry(id.clone(), key))
+            });
+            serialize_chunks_to_file(entries, chunk_size, &file, retry)?;
+            Ok(removed_values)
+        });
+        let file = self.file.clone();
+        self.spawn_write(async move {
+            let removed_values = appended.await.inspect_err(|_| undo.apply())?;
+            sync_write(&file, durability, group_commit.as_ref()).await?;
             Ok(removed_values)
         })
     }
 
+    /// Removes a batch of keys like [`remove_batch`](#method.remove_batch), blocking the
+    /// calling thread until the write completes. See [`insert_sync`](#method.insert_sync).
+    ///
+    /// Returns a Vec of the removed key-value pairs if the operation was successful.
+    pub fn remove_batch_sync(&self, keys: Vec<K>) -> Result<Vec<(K, V)>, StructureError>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        blocking::run(|| self.remove_batch(keys))
+    }
+
+    /// Removes every entry whose recorded timestamp is before `cutoff`.
+    ///
+    /// Only entries written with a timestamp (see the `timestamps` setting and
+    /// [`insert_with_timestamp`](#method.insert_with_timestamp)) are considered; entries without
+    /// one are kept. The removals are written like [`remove_batch`](#method.remove_batch).
+    ///
+    /// Returns a JoinHandle resolving to the number of entries that were removed.
+    pub fn remove_older_than(&self, cutoff: SystemTime) -> JoinHandle<Result<usize, StructureError>>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        let cutoff = unix_millis(cutoff);
+        let expired = self
+            .timestamps
+            .iter()
+            .filter(|entry| *entry.value() < cutoff)
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        let removal = self.remove_batch(expired);
+        tokio::spawn(async move { Ok(removal.await??.len()) })
+    }
+
+    /// Removes every entry of the HashMap and returns the removed pairs.
+    ///
+    /// Unlike [`clear`](#method.clear), which rewrites the file, the removals are appended in
+    /// one batch like [`remove_batch`](#method.remove_batch), and the caller gets the removed
+    /// data back. Values still in the sidecar file are read back when the removals are
+    /// written. Entries inserted concurrently with the drain may be kept.
+    ///
+    /// JoinHandle will return a Result containing the removed key-value pairs, in unspecified
+    /// order, if the operation was successful.
+    pub fn drain(&self) -> JoinHandle<Result<Vec<(K, V)>, StructureError>>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        self.remove_batch(self.keys().collect())
+    }
+
+    /// Keeps only the entries for which `f` returns true, removing the others.
+    ///
+    /// Values still in the sidecar file are loaded first so `f` sees every entry; entries
+    /// whose value can't be read are kept. The removals are written in one batch like
+    /// [`remove_batch`](#method.remove_batch). Entries are tested before they are removed, so
+    /// an entry written concurrently may be removed with the value it had when it was tested.
+    ///
+    /// Returns a JoinHandle that can be awaited to wait for the removals to be written.
+    pub fn retain<F>(&self, f: F) -> JoinHandle<Result<(), StructureError>>
+    where
+        F: Fn(&K, &V) -> bool,
+        K: Sync,
+        V: Sync,
+    {
+        self.load_all_external();
+        let rejected = self
+            .inner
+            .iter()
+            .filter(|entry| !f(entry.key(), entry.value()))
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        let removal = self.remove_batch(rejected);
+        self.spill_excess(None);
+        tokio::spawn(async move {
+            removal.await??;
+            Ok(())
+        })
+    }
+
+    /// Removes every key produced by `stream` from the HashMap.
+    ///
+    /// The stream is consumed in the background in chunks of at most `batch_chunk_size` keys;
+    /// the keys of each chunk that are present are removed from memory and their removals
+    /// appended to the file in one write. Keys that aren't in the map are skipped.
+    ///
+    /// Returns a JoinHandle resolving to the number of keys that were removed.
+    pub fn remove_stream<S>(&self, stream: S) -> JoinHandle<Result<usize, StructureError>>
+    where
+        S: Stream<Item = K> + Send + 'static,
+        K: Sync,
+        V: Sync,
+    {
+        let inner = self.inner.clone();
+        let external = self.external.clone();
+        let spill = self.spill.clone();
+        let timestamps = self.timestamps.clone();
+        let expiries = self.expiries.clone();
+        let file = self.file.clone();
+        let id = self.id.clone();
+        let chunk_size = self.batch_chunk_size.max(1);
+        let durability = self.durability;
+        let offsets = self.offsets.clone();
+        let retry = self.retry;
+        let group_commit = self.group_commit.clone();
+        let key_codec = self.key_codec.clone();
+        let writer = self.writer.clone();
+        self.spawn_write(async move {
+            let mut chunks = Box::pin(stream.chunks(chunk_size));
+            let mut removed = 0;
+            while let Some(keys) = chunks.next().await {
+                let appended = {
+                    let slot = writer.as_ref().map(|writer| writer.reserve()).transpose()?;
+                    let removed_keys = keys
+                        .into_iter()
+                        .filter_map(|key| {
+                            timestamps.remove(&key);
+                            expiries.remove(&key);
+                            if let Some(spill) = &spill {
+                                spill.forget(&key);
+                            }
+                            let removed = inner.remove(&key).map(|(key, _)| key);
+                            removed.or_else(|| external.remove(&key).map(|(key, _)| key))
+                        })
+                        .collect::<Vec<_>>();
+                    removed += removed_keys.len();
+                    let (id, file, key_codec, offsets) =
+                        (id.clone(), file.clone(), key_codec.clone(), offsets.clone());
+                    ordered(slot, move || {
+                        let entries = removed_keys.iter().map(|key| {
+                            let key = key_codec.encode(key)?;
+                            forget_offset(&offsets, &key);
+                            Ok(DBEntry::RemoveHashMapEntry(id.clone(), key))
+                        });
+                        serialize_chunks_to_file(entries, chunk_size, &file, retry)?;
+                        Ok(())
+                    })
+                };
+                appended.await?;
+                sync_write(&file, durability, group_commit.as_ref()).await?;
+            }
+            Ok(removed)
+        })
+    }
+
     /// Returns the number of key-value pairs in the HashMap.
     #[inline]
     pub fn len(&self) -> usize {
-        self.inner.len()
+        self.inner.len() + self.external.len()
     }
 
     /// Returns true if the HashMap contains no key-value pairs.
     #[inline]
     pub fn is_empty(&self) -> bool {
-        self.inner.is_empty()
+        self.inner.is_empty() && self.external.is_empty()
+    }
+
+    /// Returns the records that couldn't be read the last time the map was loaded from its
+    /// file.
+    ///
+    /// Records are only skipped in [`RecoveryMode::Lenient`]; otherwise an unreadable record
+    /// fails the load, and this is empty. Any of the map's writes in the skipped records are
+    /// missing from it.
+    pub fn skipped_records(&self) -> Vec<SkippedRecord> {
+        self.skipped
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
     }
 
     /// Clears the HashMap, removing all key-value pairs.
@@ -238,43 +2761,393 @@ where
     /// Returns a Result containing () if the operation was successful.
     ///
     /// This function is thread-safe since it locks the file and uses a temporary file for writing.
+    /// The sidecar value file, if any, is deleted.
     pub fn clear(&self) -> Result<(), StructureError> {
+        self.check_writable()?;
         self.inner.clear();
+        self.external.clear();
+        self.timestamps.clear();
+        self.expiries.clear();
+        if let Some(spill) = &self.spill {
+            spill.reset();
+        }
+        if let Some(eviction) = &self.eviction {
+            eviction.reset(None)?;
+        }
+        if let Some(unwritten) = &self.unwritten {
+            unwritten.clear();
+        }
+        if let Some(offsets) = &self.offsets {
+            offsets.clear();
+        }
         let mut file = lock_file(&self.file)?;
-        file.seek(SeekFrom::Start(0))?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        let mut cursor = std::io::Cursor::new(buffer);
-        let mut entries_to_keep = Vec::new();
-        while let Ok(entry) = bincode::deserialize_from::<_, DBEntry>(&mut cursor) {
-            match entry.clone() {
-                DBEntry::HashMapEntry(id, _, _) => {
-                    if id != self.id {
-                        entries_to_keep.push(entry);
-                    }
-                }
-                DBEntry::RemoveHashMapEntry(id, _) => {
-                    if id != self.id {
-                        entries_to_keep.push(entry);
-                    }
-                }
-                _ => entries_to_keep.push(entry),
+        self.rewrite_file(&mut file, Vec::new())?;
+        if let Some(large_values) = &self.large_values {
+            large_values.remove_file()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the whole contents of the HashMap with `entries`.
+    ///
+    /// The file is rewritten under its lock with only these entries for this map, keeping the
+    /// records of other structures. When the map was opened through a `Database`, the new log
+    /// is built in a temporary file and atomically renamed over the database file, so the log
+    /// on disk never passes through an empty or partial state. The in-memory map is then
+    /// updated before the lock is released: new values are inserted first and keys absent
+    /// from `entries` removed afterwards, so readers see each key's old or new value but never
+    /// an empty map in between.
+    pub fn replace_all(&self, entries: Vec<(K, V)>) -> Result<(), StructureError> {
+        self.check_writable()?;
+        let mut records = Vec::with_capacity(entries.len());
+        for (key, value) in &entries {
+            self.check_value_size(value)?;
+            let key = self.key_codec.encode(key)?;
+            let value = self.format.encode(value)?;
+            let entry = map_entry(self.large_values.as_ref(), self.id.clone(), key, value)?;
+            let checksum = checksum_entry(self.checksums, &entry);
+            records.extend(std::iter::once(entry).chain(checksum));
+        }
+
+        let mut file = lock_file(&self.file)?;
+        self.rewrite_file(&mut file, records)?;
+        if self.durability == Durability::Sync {
+            file.sync_all()?;
+        }
+
+        if let Some(offsets) = &self.offsets {
+            offsets.clear();
+        }
+        if let Some(spill) = &self.spill {
+            let logged = logged_offsets::<K>(
+                &mut file,
+                &self.id,
+                &self.legacy_id,
+                &self.key_codec,
+                self.recovery,
+            )?;
+            spill.reset();
+            let mut state = spill.lock();
+            for (key, offset) in &logged {
+                state.replayed(key, *offset);
             }
         }
-        let serialized_entries = entries_to_keep
-            .into_iter()
-            .map(|entry| bincode::serialize(&entry))
-            .collect::<Result<Vec<_>, _>>()?
+        self.timestamps.clear();
+        self.expiries.clear();
+        let mut keys = std::collections::HashSet::with_capacity(entries.len());
+        for (key, value) in entries {
+            self.external.remove(&key);
+            self.inner.insert(key.clone(), value);
+            keys.insert(key);
+        }
+        self.inner.retain(|key, _| keys.contains(key));
+        self.external.clear();
+        if let Some(eviction) = &self.eviction {
+            eviction.reset(keys)?;
+        }
+        if let Some(unwritten) = &self.unwritten {
+            unwritten.clear();
+        }
+        self.spill_excess(None);
+        Ok(())
+    }
+
+    /// Rewrites the file without any of this map's records, followed by `entries`.
+    ///
+    /// The caller holds the file's lock for the whole rewrite. Loads and scans hold the same
+    /// lock from start to finish, so they always see the log either entirely before or
+    /// entirely after the rewrite. When the map was opened through a `Database`, the new log
+    /// is written to a temporary file and renamed over the database file with `replace_log`,
+    /// so a crash part way through leaves the old log on disk; otherwise the file is rewritten
+    /// in place.
+    fn rewrite_file(&self, file: &mut File, entries: Vec<DBEntry>) -> Result<(), StructureError> {
+        let existing = read_log(file, self.recovery)?;
+        let entries = existing
             .into_iter()
-            .flatten()
-            .collect::<Vec<u8>>();
-        file.set_len(0)?;
-        file.seek(SeekFrom::Start(0))?;
-        file.write_all(serialized_entries.as_slice())?;
-        file.flush()?;
+            .filter(|entry| self.record(entry).is_none())
+            .chain(entries)
+            .collect::<Vec<_>>();
+        replace_log(file, self.path.as_ref(), &entries)
+    }
+
+    /// Returns true if entries stored under `id` belong to this map, under either its id or
+    /// the unencoded id older versions of `with_config` stored.
+    fn owns(&self, id: &[u8]) -> bool {
+        id == self.id || id == self.legacy_id
+    }
+
+    /// Classifies an entry of the log by the effect it has on one of this map's keys.
+    fn record(&self, entry: &DBEntry) -> Option<Record> {
+        owned_map_record(&self.id, &self.legacy_id, entry)
+    }
+
+    /// Compacts the log, dropping the overwritten records and tombstones of this HashMap.
+    ///
+    /// Only the latest record of each live key is kept, along with every record belonging to
+    /// other structures. When the map was opened through a `Database`, the compacted log is
+    /// written to a temporary file next to the database file, synced, and renamed over it, so a
+    /// crash during compaction leaves either the old log or the new one. Maps opened on a bare
+    /// file handle have no path to rename over, so their file is rewritten in place.
+    ///
+    /// Values in the sidecar file are kept where they are, so the sidecar file itself isn't
+    /// compacted.
+    pub fn compact(&self) -> Result<(), StructureError> {
+        self.check_writable()?;
+        compact_map(
+            &self.file,
+            &self.id,
+            &self.legacy_id,
+            self.path.as_ref(),
+            self.offsets.as_ref(),
+            self.durability,
+            self.recovery,
+        )?;
+        match self.reindex() {
+            Some(reindex) => reindex.run(&self.file),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns how many of this HashMap's records in the log are live and how many are dead.
+    ///
+    /// The file is scanned once. Every record of the map counts towards the totals, and a
+    /// record is dead once a later record of the same key supersedes it, as described on
+    /// [`StructureStats`]. Records of other structures aren't counted.
+    pub fn stats(&self) -> Result<StructureStats, StructureError> {
+        structure_stats(&self.file, |entry| self.record(entry))
+    }
+
+    /// Returns true if this HashMap's records take up more than `ratio` times the space of
+    /// its live records, so compacting it is worthwhile. See
+    /// [`StructureStats::needs_compaction`].
+    pub fn needs_compaction(&self, ratio: f64) -> Result<bool, StructureError> {
+        Ok(self.stats()?.needs_compaction(ratio))
+    }
+
+    /// Estimates how much space compacting this HashMap would reclaim, without rewriting the file.
+    ///
+    /// The file is scanned once: the latest record of each live key is counted as kept, along
+    /// with every record belonging to other structures, while overwritten records and
+    /// tombstones of this HashMap count as reclaimable.
+    pub fn compaction_estimate(&self) -> Result<CompactionEstimate, StructureError> {
+        estimate_compaction(&self.file, |entry| self.record(entry))
+    }
+
+    /// Creates a detached copy of the HashMap for tests, holding its current contents.
+    ///
+    /// The copy is backed by an anonymous temporary file that is never linked into the file
+    /// system and is deleted when the copy is dropped, so it can be mutated freely without
+    /// affecting the original or its file. Values stored in the original's sidecar file are
+    /// loaded into the copy, which keeps every value in its own log.
+    pub fn in_memory_copy(&self) -> Result<HashMap<K, V>, StructureError> {
+        let file = Arc::new(Mutex::new(tempfile::tempfile()?));
+        let copy = self.empty_sibling(file, None);
+        let pairs = self.collect_pairs();
+        let entries = pairs.iter().flat_map(|(key, value)| {
+            let serialized = copy
+                .key_codec
+                .encode(key)
+                .and_then(|key| Ok((key, copy.format.encode(value)?)));
+            let (key, value) = match serialized {
+                Ok(serialized) => serialized,
+                Err(e) => return vec![Err(e)],
+            };
+            let entry = DBEntry::HashMapEntry(copy.id.clone(), key, value);
+            let checksum = checksum_entry(copy.checksums, &entry).map(Ok);
+            std::iter::once(Ok(entry)).chain(checksum).collect()
+        });
+        serialize_chunks_to_file(entries, self.batch_chunk_size, &copy.file, self.retry)?;
+        copy.replay_file()?;
+        Ok(copy)
+    }
+
+    /// Writes a fully compacted copy of the HashMap to a new file at `dest`, leaving the
+    /// original and its file untouched.
+    ///
+    /// The new file holds only this map's live state: one record per key, the time of its last
+    /// write if one is recorded, and the type fingerprint if the map records one. Opening it
+    /// with the same id and settings gives the map's current contents. Values stored in the
+    /// sidecar file are written into the new log. Any existing file at `dest` is replaced, and
+    /// the new file is synced before this returns.
+    pub fn compact_to(&self, dest: &Path) -> Result<(), StructureError> {
+        let file = Arc::new(Mutex::new(File::create(dest)?));
+        let key_codec = self.key_codec.sibling(&self.id, &file);
+        let fingerprint = self.type_fingerprint.then(|| {
+            Ok(DBEntry::TypeFingerprint(
+                self.id.clone(),
+                type_fingerprint::<K, V>(),
+            ))
+        });
+        let pairs = self.collect_pairs();
+        let entries = pairs.iter().flat_map(|(key, value)| {
+            let timestamp = self.timestamps.get(key).map(|timestamp| *timestamp);
+            let expiry = self.expiries.get(key);
+            let serialized = key_codec
+                .encode(key)
+                .and_then(|key| Ok((key, self.format.encode(value)?)));
+            let (key, value) = match serialized {
+                Ok(serialized) => serialized,
+                Err(e) => return vec![Err(e)],
+            };
+            let stamp = timestamp.map(|timestamp| {
+                Ok(DBEntry::EntryTimestamp(
+                    self.id.clone(),
+                    key.clone(),
+                    timestamp,
+                ))
+            });
+            let expiry =
+                expiry.map(|expiry| Ok(DBEntry::EntryExpiry(self.id.clone(), key.clone(), expiry)));
+            let entry = DBEntry::HashMapEntry(self.id.clone(), key, value);
+            let checksum = checksum_entry(self.checksums, &entry).map(Ok);
+            std::iter::once(Ok(entry))
+                .chain(checksum)
+                .chain(stamp)
+                .chain(expiry)
+                .collect()
+        });
+        serialize_chunks_to_file(
+            fingerprint.into_iter().chain(entries),
+            self.batch_chunk_size,
+            &file,
+            self.retry,
+        )?;
+        lock_file(&file)?.sync_all()?;
         Ok(())
     }
 
+    /// Creates an empty HashMap with the same id and settings as this one, backed by `file`.
+    fn empty_sibling(&self, file: Arc<Mutex<File>>, large_values: Option<LargeValues>) -> Self {
+        Self {
+            key_codec: self.key_codec.sibling(&self.id, &file),
+            inner: Arc::new(DashMap::with_shard_amount(self.shard_amount)),
+            file,
+            id: self.id.clone(),
+            legacy_id: self.legacy_id.clone(),
+            shard_amount: self.shard_amount,
+            initial_capacity: self.initial_capacity,
+            type_fingerprint: self.type_fingerprint,
+            batch_chunk_size: self.batch_chunk_size,
+            unknown_entries: self.unknown_entries,
+            recovery: self.recovery,
+            durability: self.durability,
+            record_timestamps: self.record_timestamps,
+            timestamps: Arc::new(DashMap::new()),
+            expiries: self.expiries.sibling(),
+            offsets: self.offsets.as_ref().map(|_| Offsets::default()),
+            errors: self.errors.clone(),
+            key_locks: KeyLocks::default(),
+            loading: DashMap::new(),
+            external: Arc::new(DashMap::new()),
+            spill: self.spill.as_ref().map(|spill| Arc::new(spill.sibling())),
+            large_values,
+            retry: self.retry,
+            group_commit: None,
+            max_value_bytes: self.max_value_bytes,
+            load_concurrency: self.load_concurrency,
+            eviction: self.eviction.as_ref().map(Eviction::sibling),
+            unwritten: self.unwritten.as_ref().map(|_| Unwritten::default()),
+            conflict_resolver: self.conflict_resolver.clone(),
+            checksums: self.checksums,
+            format: self.format,
+            auto_compact: None,
+            pending: None,
+            writer: None,
+            write_queue: None,
+            path: None,
+            skipped: Mutex::default(),
+        }
+    }
+
+    /// Checks that replaying the file reproduces the in-memory state of the HashMap.
+    ///
+    /// The file is loaded into a temporary map with the same settings and compared key by key.
+    /// Writes that are still in flight, or whose JoinHandle was dropped before they ran, are
+    /// reported as divergent keys in `StructureError::NotPersisted`. Intended for tests that
+    /// verify durability.
+    pub fn assert_persisted(&self) -> Result<(), StructureError>
+    where
+        K: std::fmt::Debug,
+        V: PartialEq,
+    {
+        let persisted = self.empty_sibling(self.file.clone(), self.large_values.clone());
+        persisted.replay_file()?;
+
+        let mut found = persisted
+            .collect_pairs()
+            .into_iter()
+            .collect::<std::collections::HashMap<_, _>>();
+        let mut divergent = Vec::new();
+        for (key, value) in self.collect_pairs() {
+            if found.remove(&key).as_ref() != Some(&value) {
+                divergent.push(format!("{:?}", key));
+            }
+        }
+        divergent.extend(found.keys().map(|key| format!("{:?}", key)));
+        if divergent.is_empty() {
+            Ok(())
+        } else {
+            Err(StructureError::NotPersisted(divergent))
+        }
+    }
+
+    /// Returns the settings the HashMap is running with, including any defaults that were
+    /// applied at construction.
+    pub fn config(&self) -> EffectiveConfig {
+        EffectiveConfig {
+            shard_amount: self.shard_amount,
+            capacity: self.initial_capacity,
+            batch_chunk_size: self.batch_chunk_size,
+            type_fingerprint: self.type_fingerprint,
+            unknown_entries: self.unknown_entries,
+            recovery: self.recovery,
+            durability: self.durability,
+            timestamps: self.record_timestamps,
+            overwrite_in_place: self.offsets.is_some(),
+            large_value_threshold: self
+                .large_values
+                .as_ref()
+                .and_then(|large_values| large_values.threshold()),
+            large_value_dir: self
+                .large_values
+                .as_ref()
+                .map(|large_values| large_values.dir().clone()),
+            write_retries: self.retry.retries,
+            retry_backoff: self.retry.backoff,
+            max_value_bytes: self.max_value_bytes,
+            canonical_keys: self.key_codec.canonical(),
+            hash_keys_above: self.key_codec.hash_above(),
+            load_concurrency: self.load_concurrency,
+            max_entries: self.eviction.as_ref().map(Eviction::max_entries),
+            eviction: self
+                .eviction
+                .as_ref()
+                .map_or_else(EvictionPolicy::default, Eviction::policy),
+            skip_unwritten_tombstones: self.unwritten.is_some(),
+            checksums: self.checksums,
+            format: self.format,
+        }
+    }
+
+    /// Returns how many keys fall into each shard of the in-memory map, indexed by shard.
+    ///
+    /// Keys are assigned to shards by their hash, so a hasher or `Hash` implementation that
+    /// maps many keys to similar hashes shows up as a few crowded shards. Keys whose values are
+    /// still in the sidecar file are counted in the shard they will be loaded into. The counts
+    /// are taken one shard at a time, so concurrent writes may be partially reflected.
+    pub fn hash_distribution(&self) -> Vec<usize> {
+        let mut distribution = self
+            .inner
+            .shards()
+            .iter()
+            .map(|shard| shard.read().len())
+            .collect::<Vec<_>>();
+        for entry in self.external.iter() {
+            distribution[self.inner.determine_map(entry.key())] += 1;
+        }
+        distribution
+    }
+
     /// Returns the capacity of the HashMap.
     ///
     /// The capacity is the number of key-value pairs that the HashMap can hold without reallocating memory.
@@ -285,3 +3158,180 @@ where
         self.inner.capacity()
     }
 }
+
+impl<K: Hash + Eq, V> PersistentStructure for HashMap<K, V>
+where
+    K: Serialize + for<'de> Deserialize<'de> + Clone + Send + 'static,
+    V: Serialize + for<'de> Deserialize<'de> + Clone + Send + 'static,
+{
+    fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn load_from_file(&self) -> Result<(), StructureError> {
+        self.inner.clear();
+        self.external.clear();
+        self.timestamps.clear();
+        self.expiries.clear();
+        if let Some(spill) = &self.spill {
+            spill.reset();
+        }
+        if let Some(offsets) = &self.offsets {
+            offsets.clear();
+        }
+        self.replay_file()?;
+        Ok(())
+    }
+
+    fn append_entry(&self, entry: &DBEntry) -> Result<(), StructureError> {
+        self.check_writable()?;
+        if self.record(entry).is_none() {
+            return Err(StructureError::ForeignEntry);
+        }
+        serialize_to_file(entry, &self.file, self.retry)?;
+        sync_file(&self.file, self.durability)
+    }
+
+    fn clear(&self) -> Result<(), StructureError> {
+        HashMap::clear(self)
+    }
+
+    fn compact(&self) -> Result<(), StructureError> {
+        HashMap::compact(self)
+    }
+
+    fn stats(&self) -> Result<CompactionEstimate, StructureError> {
+        self.compaction_estimate()
+    }
+}
+
+impl<K: Hash + Eq> HashMap<K, u64>
+where
+    K: Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+{
+    /// Adds `n` to the count of `key`, treating a missing key as a count of 0.
+    ///
+    /// This is for maps used as counters or multisets. The addition is applied atomically in
+    /// memory, saturating at `u64::MAX`. The background write appends the key's count as it
+    /// is when the file lock is taken, so concurrent additions to the same key always leave the
+    /// latest count last in the log, whatever order their writes run in.
+    ///
+    /// Returns a JoinHandle resolving to the count after this addition.
+    pub fn add_count(&self, key: K, n: u64) -> JoinHandle<Result<u64, StructureError>> {
+        self.update_count(key, |count| count.saturating_add(n))
+    }
+
+    /// Adds `delta` to the counter at `key`, treating a missing key as 0, and persists the
+    /// result.
+    ///
+    /// The value is updated atomically under the key's shard lock, so concurrent increments
+    /// never lose an update the way a `get` followed by an `insert` can. The result saturates
+    /// at 0 and `u64::MAX`. Each call appends a single `HashMapEntry` with the new value,
+    /// written like [`add_count`](#method.add_count), so the latest value is always last in
+    /// the log.
+    ///
+    /// Returns a JoinHandle resolving to the value after this increment.
+    pub fn increment(&self, key: &K, delta: i64) -> JoinHandle<Result<u64, StructureError>> {
+        self.update_count(key.clone(), |count| count.saturating_add_signed(delta))
+    }
+
+    /// Replaces the count of `key` with `update` applied to it, treating a missing key as a
+    /// count of 0, and appends the key's count in the background.
+    fn update_count<F>(&self, key: K, update: F) -> JoinHandle<Result<u64, StructureError>>
+    where
+        F: FnOnce(u64) -> u64,
+    {
+        // Registered first, so the count can't be evicted between its load and its update.
+        let mut spill_write = self.spill.as_ref().map(|spill| spill.write(&key));
+        if let Err(e) = self.load_external(&key) {
+            return self.spawn_write(async move { Err(e) });
+        }
+        let slot = match self.reserve() {
+            Ok(slot) => slot,
+            Err(e) => return self.spawn_write(async move { Err(e) }),
+        };
+        let count = {
+            let mut count = self.inner.entry(key.clone()).or_insert(0);
+            *count = update(*count);
+            *count
+        };
+        if let Some(unwritten) = &self.unwritten {
+            unwritten.forget(&key);
+        }
+        let timestamp = self
+            .record_timestamps
+            .then(|| unix_millis(SystemTime::now()));
+        self.set_timestamp(&key, timestamp);
+        self.expiries.remove(&key);
+        self.spill_excess(None);
+        let inner = self.inner.clone();
+        let file = self.file.clone();
+        let id = self.id.clone();
+        let durability = self.durability;
+        let large_values = self.large_values.clone();
+        let offsets = self.offsets.clone();
+        let retry = self.retry;
+        let group_commit = self.group_commit.clone();
+        let key_codec = self.key_codec.clone();
+        let checksums = self.checksums;
+        let appended = ordered(slot, move || {
+            let serialized_key = key_codec.encode(&key)?;
+            forget_offset(&offsets, &serialized_key);
+            {
+                let mut file = lock_file(&file)?;
+                // A concurrent removal already logged its own record for the key.
+                let Some(current) = inner.get(&key).map(|current| *current) else {
+                    return Ok(count);
+                };
+                let value = key_codec.format().encode(&current)?;
+                let mut buffer = Vec::new();
+                let entry = map_entry(
+                    large_values.as_ref(),
+                    id.clone(),
+                    serialized_key.clone(),
+                    value,
+                )?;
+                bincode::serialize_into(&mut buffer, &entry)?;
+                if let Some(checksum) = checksum_entry(checksums, &entry) {
+                    bincode::serialize_into(&mut buffer, &checksum)?;
+                }
+                if let Some(timestamp) = timestamp {
+                    let stamp = DBEntry::EntryTimestamp(id, serialized_key, timestamp);
+                    bincode::serialize_into(&mut buffer, &stamp)?;
+                }
+                let offset = file.seek(SeekFrom::End(0))?;
+                write_all_retrying(&mut *file, &buffer, retry)?;
+                file.flush()?;
+                if let Some(spill_write) = spill_write.as_mut() {
+                    if matches!(entry, DBEntry::HashMapEntry(..)) {
+                        spill_write.logged_at(offset);
+                    }
+                }
+            }
+            Ok(count)
+        });
+        let file = self.file.clone();
+        self.spawn_write(async move {
+            let count = appended.await?;
+            sync_write(&file, durability, group_commit.as_ref()).await?;
+            Ok(count)
+        })
+    }
+
+    /// Returns the sum of the counts of every key, saturating at `u64::MAX`.
+    ///
+    /// Counts still in the sidecar file are loaded first; any that can't be read are left out.
+    pub fn total_count(&self) -> u64 {
+        self.load_all_external();
+        let total = self
+            .inner
+            .iter()
+            .fold(0u64, |total, entry| total.saturating_add(*entry.value()));
+        self.spill_excess(None);
+        total
+    }
+}
@@ -4,22 +4,71 @@ use serde::{Deserialize, Serialize};
 use std::{
     fs::File,
     hash::Hash,
-    io::{Read, Seek, SeekFrom, Write},
     sync::{Arc, Mutex},
 };
 use tokio::task::JoinHandle;
 
-use crate::{db::db_entry::DBEntry, StructureError};
+use crate::{
+    db::db_entry::{DBEntry, RecoveryMode, SkippedRecord, UnknownEntryPolicy},
+    StructureError,
+};
 
-use super::{lock_file, serialize_to_file, value_ref::ValueRef};
+use super::{
+    blocking,
+    canonical::encode_key,
+    check_fingerprint, check_unknown_entry,
+    codec::{Codec, SerializationFormat},
+    encode_id, lock_file,
+    pending::PendingWrites,
+    persistent::{compact_entries, estimate_compaction, PersistentStructure, Record},
+    read_log, replace_log, scan_file_with, serialize_chunks_to_file, serialize_to_file,
+    stats::CompactionEstimate,
+    type_fingerprint,
+    value_ref::ValueRef,
+    writer::{ordered, Slot, Writer},
+    LogPath, RetryPolicy, DEFAULT_BATCH_CHUNK_SIZE,
+};
 
 /// Configuration for creating a `HashSet`.
 ///
 /// Defines the parameters for creating a `HashSet`, including the initial capacity.
 #[derive(Debug, Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
 pub struct HashSetConfig {
     #[builder(default = "0")]
     pub capacity: usize,
+    /// The maximum number of elements serialized and written at once by the batch operations.
+    #[builder(default = "DEFAULT_BATCH_CHUNK_SIZE")]
+    pub batch_chunk_size: usize,
+    /// Whether to record a fingerprint of the structure's types in the file, so reopening it
+    /// with different types fails with `StructureError::TypeMismatch`.
+    #[builder(default = "false")]
+    pub type_fingerprint: bool,
+    /// How extension entries this version doesn't recognise are treated while loading.
+    #[builder(default)]
+    pub unknown_entries: UnknownEntryPolicy,
+    /// How records that can't be read in the middle of the file are treated while loading.
+    #[builder(default)]
+    pub recovery: RecoveryMode,
+    /// Whether elements are written in a canonical encoding, in which the entries of any maps
+    /// inside them are sorted, so equal elements always serialize to the same bytes.
+    #[builder(default = "false")]
+    pub canonical_keys: bool,
+    /// The format elements are serialized in. Has to be the same whenever the set is opened,
+    /// and can't be combined with `canonical_keys` unless it's bincode.
+    #[builder(default)]
+    pub format: SerializationFormat,
+}
+
+impl HashSetConfigBuilder {
+    fn validate(&self) -> Result<(), String> {
+        if self.canonical_keys == Some(true)
+            && !matches!(self.format, None | Some(SerializationFormat::Bincode))
+        {
+            return Err("canonical_keys requires the bincode format".to_string());
+        }
+        Ok(())
+    }
 }
 
 /// A file-backed, thread-safe hash set structure.
@@ -32,6 +81,16 @@ pub struct HashSet<K: Hash + Eq> {
     inner: DashSet<K>,
     file: Arc<Mutex<File>>,
     id: Vec<u8>,
+    legacy_id: Vec<u8>,
+    batch_chunk_size: usize,
+    unknown_entries: UnknownEntryPolicy,
+    recovery: RecoveryMode,
+    canonical_keys: bool,
+    format: SerializationFormat,
+    pending: Option<PendingWrites>,
+    writer: Option<Arc<Writer>>,
+    path: Option<LogPath>,
+    skipped: Mutex<Vec<SkippedRecord>>,
 }
 
 impl<K: Hash + Eq> HashSet<K>
@@ -41,19 +100,49 @@ where
     /// Creates a new `HashSet` with the default capacity.
     ///
     /// Initializes an empty `HashSet` with default settings and loads existing data from the file if available.
+    ///
+    /// The id is stored bincode-serialized, the same canonical encoding `HashMap::new` uses.
+    /// Elements that older versions recorded under the unencoded id are still loaded.
     pub fn new(file: Arc<Mutex<File>>, id: Vec<u8>) -> Result<Self, StructureError> {
+        Self::new_with(
+            file,
+            id,
+            RecoveryMode::default(),
+            SerializationFormat::default(),
+        )
+    }
+
+    /// Creates a new `HashSet` like [`new`](#method.new), loading the file in `recovery` mode
+    /// and serializing elements in `format`.
+    pub(crate) fn new_with(
+        file: Arc<Mutex<File>>,
+        id: Vec<u8>,
+        recovery: RecoveryMode,
+        format: SerializationFormat,
+    ) -> Result<Self, StructureError> {
         let instance = Self {
             inner: DashSet::new(),
             file,
-            id,
+            id: encode_id(&id)?,
+            legacy_id: id,
+            batch_chunk_size: DEFAULT_BATCH_CHUNK_SIZE,
+            unknown_entries: UnknownEntryPolicy::default(),
+            recovery,
+            canonical_keys: false,
+            format,
+            pending: None,
+            writer: None,
+            path: None,
+            skipped: Mutex::default(),
         };
-        instance.load_from_file()?;
+        instance.replay_file()?;
         Ok(instance)
     }
 
     /// Creates a new `HashSet` with specified configuration.
     ///
     /// Allows for custom configuration of the `HashSet`, including setting the initial capacity.
+    /// The id is encoded like in [`new`](#method.new).
     pub fn with_config(
         file: Arc<Mutex<File>>,
         id: Vec<u8>,
@@ -62,53 +151,117 @@ where
         let instance = Self {
             inner: DashSet::with_capacity(config.capacity),
             file,
-            id,
+            id: encode_id(&id)?,
+            legacy_id: id,
+            batch_chunk_size: config.batch_chunk_size,
+            unknown_entries: config.unknown_entries,
+            recovery: config.recovery,
+            canonical_keys: config.canonical_keys,
+            format: config.format,
+            pending: None,
+            writer: None,
+            path: None,
+            skipped: Mutex::default(),
         };
-        instance.load_from_file()?;
+        let fingerprinted = instance.replay_file()?;
+        if config.type_fingerprint && !fingerprinted {
+            let fingerprint = type_fingerprint::<K, ()>();
+            serialize_to_file(
+                &DBEntry::TypeFingerprint(instance.id.clone(), fingerprint),
+                &instance.file,
+                RetryPolicy::default(),
+            )?;
+        }
         Ok(instance)
     }
 
-    /// Loads the hash set contents from the file.
+    /// Registers the set's spawned writes with a database, so it can wait for them on close.
+    pub(crate) fn with_pending_writes(mut self, pending: PendingWrites) -> Self {
+        self.pending = Some(pending);
+        self
+    }
+
+    /// Queues the set's appends on a database's writer, so they reach the file in the order
+    /// they were applied in memory.
+    pub(crate) fn with_writer(mut self, writer: Arc<Writer>) -> Self {
+        self.writer = Some(writer);
+        self
+    }
+
+    /// Records the path of the set's file, so compaction can replace the file atomically.
+    pub(crate) fn with_path(mut self, path: LogPath) -> Self {
+        self.path = Some(path);
+        self
+    }
+
+    /// Reserves the next place in the write order of the set's database, if it has one.
+    fn reserve(&self) -> Result<Option<Slot<'_>>, StructureError> {
+        self.writer
+            .as_ref()
+            .map(|writer| writer.reserve())
+            .transpose()
+    }
+
+    /// Returns `StructureError::ReadOnly` if the set's database was opened read-only.
+    fn check_writable(&self) -> Result<(), StructureError> {
+        self.writer
+            .as_ref()
+            .map_or(Ok(()), |writer| writer.check_writable())
+    }
+
+    /// Spawns a task persisting a write with `append`, in the order of `slot` if there is one.
+    /// The task is registered with the database's pending writes.
+    fn spawn_append<T, A>(
+        &self,
+        slot: Option<Slot<'_>>,
+        append: A,
+    ) -> JoinHandle<Result<T, StructureError>>
+    where
+        T: Send + 'static,
+        A: FnOnce() -> Result<T, StructureError> + Send + 'static,
+    {
+        let registered = self.pending.as_ref().map(PendingWrites::begin);
+        let write = ordered(slot, append);
+        tokio::spawn(async move {
+            let result = write.await;
+            drop(registered);
+            result
+        })
+    }
+
+    /// Replays the hash set's entries from the file on top of its in-memory state.
     ///
     /// Internal function used during initialization to load the set's state from the file.
-    fn load_from_file(&self) -> Result<(), StructureError> {
-        let mut file = lock_file(&self.file)?;
-        file.seek(SeekFrom::Start(0))?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        let mut cursor = std::io::Cursor::new(&buffer);
-
-        while cursor.position() < buffer.len() as u64 {
-            match bincode::deserialize_from::<_, DBEntry>(&mut cursor) {
-                Ok(entry) => match entry {
-                    DBEntry::HashSetEntry(id, key) => {
-                        if id == self.id {
-                            let key = bincode::deserialize::<K>(&key)?;
-                            self.inner.insert(key);
-                        }
-                    }
-                    DBEntry::RemoveHashSetEntry(id, key) => {
-                        if id == self.id {
-                            let key = bincode::deserialize::<K>(&key)?;
-                            self.inner.remove(&key);
-                        }
-                    }
-                    _ => {}
-                },
-                Err(e) => match e.as_ref() {
-                    bincode::ErrorKind::Io(e) => {
-                        if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                            break;
-                        }
-                    }
-                    _ => {
-                        return Err(StructureError::BinCodeError(e));
-                    }
-                },
+    ///
+    /// If the file records a type fingerprint for this structure it is checked against the
+    /// current types, and `true` is returned.
+    fn replay_file(&self) -> Result<bool, StructureError> {
+        let mut fingerprinted = false;
+        let skipped = scan_file_with(&self.file, self.recovery, |entry, _| {
+            match entry {
+                DBEntry::HashSetEntry(id, key) if self.owns(&id) => {
+                    let key = self.format.decode::<K>(&key)?;
+                    self.inner.insert(key);
+                }
+                DBEntry::RemoveHashSetEntry(id, key) if self.owns(&id) => {
+                    let key = self.format.decode::<K>(&key)?;
+                    self.inner.remove(&key);
+                }
+                DBEntry::TypeFingerprint(id, fingerprint) if self.owns(&id) => {
+                    check_fingerprint(type_fingerprint::<K, ()>(), fingerprint)?;
+                    fingerprinted = true;
+                }
+                DBEntry::Extension(tag, _) => check_unknown_entry(self.unknown_entries, tag)?,
+                _ => {}
             }
-        }
+            Ok(())
+        })?;
+        *self
+            .skipped
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = skipped;
 
-        Ok(())
+        Ok(fingerprinted)
     }
 
     /// Inserts a batch of elements into the `HashSet`.
@@ -116,20 +269,46 @@ where
     /// More efficient than individual `insert` calls for adding multiple elements. Returns a `JoinHandle` to await the operation's completion.
     #[inline]
     pub fn insert(&self, key: K) -> JoinHandle<Result<bool, StructureError>> {
+        let slot = match self.reserve() {
+            Ok(slot) => slot,
+            Err(e) => return self.spawn_append(None, move || Err(e)),
+        };
         let old_value = self.inner.insert(key.clone());
         let file = self.file.clone();
         let id = self.id.clone();
-        tokio::spawn(async move {
-            let key = bincode::serialize(&key)?;
-            serialize_to_file(&DBEntry::HashSetEntry(id.clone(), key), &file)?;
+        let canonical_keys = self.canonical_keys;
+        let format = self.format;
+        self.spawn_append(slot, move || {
+            let key = encode_key(&key, canonical_keys, format)?;
+            serialize_to_file(
+                &DBEntry::HashSetEntry(id.clone(), key),
+                &file,
+                RetryPolicy::default(),
+            )?;
             Ok(old_value)
         })
     }
 
+    /// Inserts an element like [`insert`](#method.insert), blocking the calling thread until
+    /// the write completes instead of spawning it.
+    ///
+    /// The write is performed on the calling thread, so this works without a tokio runtime.
+    /// It must not be called from within one, where it panics; use `insert` there.
+    ///
+    /// Returns whether the element was newly inserted if the operation was successful.
+    pub fn insert_sync(&self, key: K) -> Result<bool, StructureError> {
+        blocking::run(|| self.insert(key))
+    }
+
     /// Inserts a batch of elements into the `HashSet`.
     ///
     /// More efficient than individual `insert` calls for adding multiple elements. Returns a `JoinHandle` to await the operation's completion.
+    /// The elements are written in chunks of at most `batch_chunk_size` elements (see [`HashSetConfig`]).
     pub fn insert_batch(&self, entries: Vec<K>) -> JoinHandle<Result<Vec<bool>, StructureError>> {
+        let slot = match self.reserve() {
+            Ok(slot) => slot,
+            Err(e) => return self.spawn_append(None, move || Err(e)),
+        };
         let mut old_values = Vec::with_capacity(entries.len());
         for key in &entries {
             old_values.push(self.inner.insert(key.clone()));
@@ -137,16 +316,31 @@ where
 
         let file = self.file.clone();
         let id = self.id.clone();
+        let canonical_keys = self.canonical_keys;
+        let format = self.format;
+        let chunk_size = self.batch_chunk_size;
+        self.spawn_append(slot, move || {
+            let entries = entries.into_iter().map(|key| {
+                let key = encode_key(&key, canonical_keys, format)?;
+                Ok(DBEntry::HashSetEntry(id.clone(), key))
+            });
+            serialize_chunks_to_file(entries, chunk_size, &file, RetryPolicy::default())?;
+            Ok(old_values)
+        })
+    }
+
+    /// Inserts every element produced by `iter`, written like
+    /// [`insert_batch`](#method.insert_batch).
+    ///
+    /// Returns a `JoinHandle` to await the operation's completion.
+    pub fn extend<I>(&self, iter: I) -> JoinHandle<Result<(), StructureError>>
+    where
+        I: IntoIterator<Item = K>,
+    {
+        let insert = self.insert_batch(iter.into_iter().collect());
         tokio::spawn(async move {
-            let entries = entries
-                .into_iter()
-                .map(|key| {
-                    let key = bincode::serialize(&key)?;
-                    Ok(DBEntry::HashSetEntry(id.clone(), key))
-                })
-                .collect::<Result<Vec<DBEntry>, StructureError>>()?;
-            serialize_to_file(&entries, &file)?;
-            return Ok(old_values);
+            insert.await??;
+            Ok(())
         })
     }
 
@@ -158,16 +352,34 @@ where
         self.inner.get(key).map(|inner| ValueRef::new(inner))
     }
 
+    /// Returns true if the element is present in the `HashSet`.
+    ///
+    /// Unlike [`get`](#method.get), no reference to the element is kept.
+    #[inline]
+    pub fn contains(&self, key: &K) -> bool {
+        self.inner.contains(key)
+    }
+
     /// Removes an element from the `HashSet`, returning it if it was present.
     ///
     /// Returns a `JoinHandle` that can be awaited to determine the result of the operation.
     pub fn remove(&self, key: &K) -> Option<JoinHandle<Result<Option<K>, StructureError>>> {
+        let slot = match self.reserve() {
+            Ok(slot) => slot,
+            Err(e) => return Some(self.spawn_append(None, move || Err(e))),
+        };
         if let Some(key) = self.inner.remove(key) {
             let file = self.file.clone();
             let id = self.id.clone();
-            Some(tokio::spawn(async move {
-                let k = bincode::serialize(&key)?;
-                serialize_to_file(&DBEntry::RemoveHashSetEntry(id.clone(), k), &file)?;
+            let canonical_keys = self.canonical_keys;
+            let format = self.format;
+            Some(self.spawn_append(slot, move || {
+                let k = encode_key(&key, canonical_keys, format)?;
+                serialize_to_file(
+                    &DBEntry::RemoveHashSetEntry(id.clone(), k),
+                    &file,
+                    RetryPolicy::default(),
+                )?;
                 Ok(Some(key))
             }))
         } else {
@@ -175,10 +387,34 @@ where
         }
     }
 
+    /// Inserts a batch of elements like [`insert_batch`](#method.insert_batch), blocking the
+    /// calling thread until the write completes. See [`insert_sync`](#method.insert_sync).
+    pub fn insert_batch_sync(&self, entries: Vec<K>) -> Result<Vec<bool>, StructureError> {
+        blocking::run(|| self.insert_batch(entries))
+    }
+
+    /// Removes an element like [`remove`](#method.remove), blocking the calling thread until
+    /// the write completes. See [`insert_sync`](#method.insert_sync).
+    ///
+    /// Returns the removed element, or None if it wasn't present.
+    pub fn remove_sync(&self, key: &K) -> Result<Option<K>, StructureError> {
+        blocking::run(|| async {
+            match self.remove(key) {
+                Some(removal) => removal.await,
+                None => Ok(Ok(None)),
+            }
+        })
+    }
+
     /// Removes a batch of elements from the `HashSet`.
     ///
     /// More efficient than individual `remove` calls for removing multiple elements. Returns a `JoinHandle` to await the operation's completion.
+    /// The removals are written in chunks of at most `batch_chunk_size` elements.
     pub fn remove_batch(&self, keys: Vec<K>) -> JoinHandle<Result<Vec<K>, StructureError>> {
+        let slot = match self.reserve() {
+            Ok(slot) => slot,
+            Err(e) => return self.spawn_append(None, move || Err(e)),
+        };
         let mut removed_values = Vec::with_capacity(keys.len());
         for key in &keys {
             if let Some(key) = self.inner.remove(key) {
@@ -188,20 +424,44 @@ where
 
         let file = self.file.clone();
         let id = self.id.clone();
-        tokio::spawn(async move {
-            let entries = removed_values
-                .clone()
-                .into_iter()
-                .map(|key| {
-                    let key = bincode::serialize(&key)?;
-                    Ok(DBEntry::RemoveHashMapEntry(id.clone(), key))
-                })
-                .collect::<Result<Vec<DBEntry>, StructureError>>()?;
-            serialize_to_file(&entries, &file)?;
+        let canonical_keys = self.canonical_keys;
+        let format = self.format;
+        let chunk_size = self.batch_chunk_size;
+        self.spawn_append(slot, move || {
+            let entries = removed_values.iter().map(|key| {
+                let key = encode_key(key, canonical_keys, format)?;
+                Ok(DBEntry::RemoveHashSetEntry(id.clone(), key))
+            });
+            serialize_chunks_to_file(entries, chunk_size, &file, RetryPolicy::default())?;
             Ok(removed_values)
         })
     }
 
+    /// Removes a batch of elements like [`remove_batch`](#method.remove_batch), blocking the
+    /// calling thread until the write completes. See [`insert_sync`](#method.insert_sync).
+    pub fn remove_batch_sync(&self, keys: Vec<K>) -> Result<Vec<K>, StructureError> {
+        blocking::run(|| self.remove_batch(keys))
+    }
+
+    /// Removes the elements for which `f` returns true, returning them.
+    ///
+    /// The matching elements are collected first and then removed like
+    /// [`remove_batch`](#method.remove_batch), so the removals are written in chunks of at most
+    /// `batch_chunk_size` elements. An element removed concurrently after being matched isn't
+    /// returned.
+    pub fn drain_filter<F>(&self, mut f: F) -> JoinHandle<Result<Vec<K>, StructureError>>
+    where
+        F: FnMut(&K) -> bool,
+    {
+        let matching = self
+            .inner
+            .iter()
+            .filter(|key| f(key.key()))
+            .map(|key| key.key().clone())
+            .collect();
+        self.remove_batch(matching)
+    }
+
     /// Returns the number of elements in the `HashSet`.
     #[inline]
     pub fn len(&self) -> usize {
@@ -214,44 +474,66 @@ where
         self.inner.is_empty()
     }
 
+    /// Returns the records that couldn't be read the last time the set was loaded from its
+    /// file.
+    ///
+    /// Records are only skipped in [`RecoveryMode::Lenient`]; otherwise an unreadable record
+    /// fails the load, and this is empty. Any of the set's writes in the skipped records are
+    /// missing from it.
+    pub fn skipped_records(&self) -> Vec<SkippedRecord> {
+        self.skipped
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
     /// Clears all elements from the `HashSet`.
     ///
-    /// This operation is thread-safe and ensures changes are persisted to disk.
+    /// This operation is thread-safe and ensures changes are persisted to disk. The log is
+    /// rewritten without the set's records, keeping those of other structures; when the set
+    /// was opened through a `Database`, it is replaced through a temporary file as `compact`
+    /// does, so a crash leaves either the old log or the new one.
     pub fn clear(&self) -> Result<(), StructureError> {
+        self.check_writable()?;
         self.inner.clear();
         let mut file = lock_file(&self.file)?;
-        file.seek(SeekFrom::Start(0))?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        let mut cursor = std::io::Cursor::new(buffer);
-        let mut entries_to_keep = Vec::new();
-        while let Ok(entry) = bincode::deserialize_from::<_, DBEntry>(&mut cursor) {
-            match entry.clone() {
-                DBEntry::HashSetEntry(id, _) => {
-                    if id != self.id {
-                        entries_to_keep.push(entry);
-                    }
-                }
-                DBEntry::RemoveHashSetEntry(id, _) => {
-                    if id != self.id {
-                        entries_to_keep.push(entry);
-                    }
-                }
-                _ => entries_to_keep.push(entry),
+        let entries = read_log(&mut file, self.recovery)?;
+        let entries = entries
+            .into_iter()
+            .filter(|entry| self.record(entry).is_none())
+            .collect::<Vec<_>>();
+        replace_log(&mut file, self.path.as_ref(), &entries)
+    }
+
+    /// Compacts the log, dropping the overwritten records and tombstones of this `HashSet`.
+    ///
+    /// Only the latest record of each member is kept, along with every record belonging to
+    /// other structures. When the set was opened through a `Database`, the compacted log is
+    /// written to a temporary file next to the database file and renamed over it, as
+    /// `HashMap::compact` does; otherwise the file is rewritten in place.
+    pub fn compact(&self) -> Result<(), StructureError> {
+        self.check_writable()?;
+        let mut file = lock_file(&self.file)?;
+        let entries = read_log(&mut file, self.recovery)?;
+        let entries = compact_entries(entries, |entry| self.record(entry));
+        replace_log(&mut file, self.path.as_ref(), &entries)
+    }
+
+    /// Returns true if entries stored under `id` belong to this set, under either its id or
+    /// the unencoded id older versions stored.
+    fn owns(&self, id: &[u8]) -> bool {
+        id == self.id || id == self.legacy_id
+    }
+
+    /// Classifies an entry of the log by the effect it has on one of this set's elements.
+    fn record(&self, entry: &DBEntry) -> Option<Record> {
+        match entry {
+            DBEntry::HashSetEntry(id, key) if self.owns(id) => Some(Record::Write(key.clone())),
+            DBEntry::RemoveHashSetEntry(id, key) if self.owns(id) => {
+                Some(Record::Remove(key.clone()))
             }
+            _ => None,
         }
-        let serialized_entries = entries_to_keep
-            .into_iter()
-            .map(|entry| bincode::serialize(&entry))
-            .collect::<Result<Vec<_>, _>>()?
-            .into_iter()
-            .flatten()
-            .collect::<Vec<u8>>();
-        file.set_len(0)?;
-        file.seek(SeekFrom::Start(0))?;
-        file.write_all(serialized_entries.as_slice())?;
-        file.flush()?;
-        Ok(())
     }
 
     /// Returns the capacity of the `HashSet`.
@@ -262,3 +544,42 @@ where
         self.inner.capacity()
     }
 }
+
+impl<K: Hash + Eq> PersistentStructure for HashSet<K>
+where
+    K: Serialize + for<'de> Deserialize<'de> + Clone + Send + 'static + std::fmt::Debug,
+{
+    fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    fn len(&self) -> usize {
+        HashSet::len(self)
+    }
+
+    fn load_from_file(&self) -> Result<(), StructureError> {
+        self.inner.clear();
+        self.replay_file()?;
+        Ok(())
+    }
+
+    fn append_entry(&self, entry: &DBEntry) -> Result<(), StructureError> {
+        self.check_writable()?;
+        if self.record(entry).is_none() {
+            return Err(StructureError::ForeignEntry);
+        }
+        serialize_to_file(entry, &self.file, RetryPolicy::default())
+    }
+
+    fn clear(&self) -> Result<(), StructureError> {
+        HashSet::clear(self)
+    }
+
+    fn compact(&self) -> Result<(), StructureError> {
+        HashSet::compact(self)
+    }
+
+    fn stats(&self) -> Result<CompactionEstimate, StructureError> {
+        estimate_compaction(&self.file, |entry| self.record(entry))
+    }
+}
@@ -0,0 +1,258 @@
+//! Key encoding module for rustmap-db.
+//!
+//! This module provides `KeyCodec`, which turns a map's keys into the bytes stored in its
+//! records and back. Keys are serialized in the map's format, canonically if the map asks for
+//! it, and keys above a size threshold can be replaced by a short alias derived from their
+//! hash, so a large key is only written in full once however often it is overwritten or
+//! removed.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap as StdHashMap},
+    fs::File,
+    hash::{Hash as _, Hasher as _},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{db::db_entry::DBEntry, StructureError};
+
+use super::{
+    canonical::encode_key,
+    codec::{Codec, SerializationFormat},
+    serialize_to_file, RetryPolicy,
+};
+
+/// Prefixes a key written in full when aliasing is enabled.
+const FULL_KEY: u8 = 0;
+
+/// Prefixes a key alias.
+const ALIASED_KEY: u8 = 1;
+
+/// Encodes and decodes the key bytes of one map's records.
+#[derive(Debug, Clone)]
+pub(crate) struct KeyCodec {
+    canonical: bool,
+    format: SerializationFormat,
+    aliases: Option<Arc<KeyAliases>>,
+}
+
+impl KeyCodec {
+    /// Creates a codec writing keys canonically if `canonical` is set, and aliasing keys
+    /// longer than `hash_above` bytes. Alias definitions are appended to `file` under `id`.
+    pub(crate) fn new(
+        canonical: bool,
+        hash_above: Option<usize>,
+        id: &[u8],
+        file: &Arc<Mutex<File>>,
+        retry: RetryPolicy,
+    ) -> Self {
+        Self {
+            canonical,
+            format: SerializationFormat::default(),
+            aliases: hash_above.map(|threshold| {
+                Arc::new(KeyAliases {
+                    threshold,
+                    id: id.to_vec(),
+                    file: file.clone(),
+                    retry,
+                    table: Mutex::default(),
+                })
+            }),
+        }
+    }
+
+    /// Creates a codec with the same settings as this one, for a map backed by `file`.
+    pub(crate) fn sibling(&self, id: &[u8], file: &Arc<Mutex<File>>) -> Self {
+        let aliases = self.aliases.as_ref();
+        Self::new(
+            self.canonical,
+            aliases.map(|aliases| aliases.threshold),
+            id,
+            file,
+            aliases.map_or_else(RetryPolicy::default, |aliases| aliases.retry),
+        )
+        .with_format(self.format)
+    }
+
+    /// Serializes keys in `format` rather than bincode.
+    pub(crate) fn with_format(mut self, format: SerializationFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Returns the format keys, and the values of the map, are serialized in.
+    pub(crate) fn format(&self) -> SerializationFormat {
+        self.format
+    }
+
+    /// Returns whether keys are serialized canonically.
+    pub(crate) fn canonical(&self) -> bool {
+        self.canonical
+    }
+
+    /// Returns the serialized size above which keys are aliased, if they are.
+    pub(crate) fn hash_above(&self) -> Option<usize> {
+        self.aliases.as_ref().map(|aliases| aliases.threshold)
+    }
+
+    /// Returns the bytes `key` is stored as in the map's records.
+    ///
+    /// The first time a key above the threshold is encoded, its alias is defined by appending
+    /// a `KeyAlias` entry to the file, so the definition precedes every record using it.
+    pub(crate) fn encode<K: Serialize>(&self, key: &K) -> Result<Vec<u8>, StructureError> {
+        let key = encode_key(key, self.canonical, self.format)?;
+        match &self.aliases {
+            Some(aliases) => aliases.encode(key),
+            None => Ok(key),
+        }
+    }
+
+    /// Deserializes a key from the bytes of one of the map's records.
+    pub(crate) fn decode<K>(&self, bytes: &[u8]) -> Result<K, StructureError>
+    where
+        K: for<'de> Deserialize<'de>,
+    {
+        match &self.aliases {
+            Some(aliases) => match bytes.split_first() {
+                Some((&FULL_KEY, key)) => self.format.decode(key),
+                Some((&ALIASED_KEY, _)) => {
+                    let table = aliases
+                        .table
+                        .lock()
+                        .map_err(|_| StructureError::MutexLockError)?;
+                    let key = table
+                        .keys
+                        .get(bytes)
+                        .ok_or(StructureError::UnknownKeyAlias)?;
+                    self.format.decode(key)
+                }
+                _ => Err(StructureError::UnknownKeyAlias),
+            },
+            None => self.format.decode(bytes),
+        }
+    }
+
+    /// Records an alias defined by a `KeyAlias` entry read from the file.
+    pub(crate) fn define(&self, alias: Vec<u8>, key: Vec<u8>) -> Result<(), StructureError> {
+        if let Some(aliases) = &self.aliases {
+            let mut table = aliases
+                .table
+                .lock()
+                .map_err(|_| StructureError::MutexLockError)?;
+            table.aliases.insert(key.clone(), alias.clone());
+            table.keys.insert(alias, key);
+        }
+        Ok(())
+    }
+}
+
+/// The aliases of a map's large keys.
+#[derive(Debug)]
+struct KeyAliases {
+    threshold: usize,
+    id: Vec<u8>,
+    file: Arc<Mutex<File>>,
+    retry: RetryPolicy,
+    table: Mutex<AliasTable>,
+}
+
+/// The defined aliases, indexed both ways.
+#[derive(Debug, Default)]
+struct AliasTable {
+    aliases: StdHashMap<Vec<u8>, Vec<u8>>,
+    keys: StdHashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl KeyAliases {
+    fn encode(&self, key: Vec<u8>) -> Result<Vec<u8>, StructureError> {
+        if key.len() <= self.threshold {
+            let mut full = Vec::with_capacity(key.len() + 1);
+            full.push(FULL_KEY);
+            full.extend_from_slice(&key);
+            return Ok(full);
+        }
+
+        let mut table = self
+            .table
+            .lock()
+            .map_err(|_| StructureError::MutexLockError)?;
+        if let Some(alias) = table.aliases.get(&key) {
+            return Ok(alias.clone());
+        }
+
+        // The alias is the key's hash followed by a counter, which is bumped past the aliases
+        // of other keys with the same hash.
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let alias = (0u32..)
+            .map(|n| {
+                let mut alias = vec![ALIASED_KEY];
+                alias.extend_from_slice(&hash.to_be_bytes());
+                alias.extend_from_slice(&n.to_be_bytes());
+                alias
+            })
+            .find(|alias| !table.keys.contains_key(alias))
+            .expect("a free alias exists");
+
+        // The definition is written while the table is locked, so no record can use the alias
+        // before it is in the file.
+        serialize_to_file(
+            &DBEntry::KeyAlias(self.id.clone(), alias.clone(), key.clone()),
+            &self.file,
+            self.retry,
+        )?;
+        table.aliases.insert(key.clone(), alias.clone());
+        table.keys.insert(alias.clone(), key);
+        Ok(alias)
+    }
+}
+
+#[cfg(test)]
+mod key_codec_tests {
+    use super::*;
+
+    fn aliasing_codec() -> KeyCodec {
+        let file = Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+        KeyCodec::new(false, Some(8), &[1], &file, RetryPolicy::default())
+    }
+
+    #[test]
+    fn test_small_keys_are_written_in_full() {
+        let codec = aliasing_codec();
+        let bytes = codec.encode(&7u32).unwrap();
+        assert_eq!(bytes, [FULL_KEY, 7, 0, 0, 0]);
+        assert_eq!(codec.decode::<u32>(&bytes).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_colliding_hashes_get_distinct_aliases() {
+        let codec = aliasing_codec();
+        let key = "a key longer than the threshold".to_string();
+        let alias = codec.encode(&key).unwrap();
+        assert_eq!(alias.len(), 13);
+        assert_eq!(codec.encode(&key).unwrap(), alias);
+
+        // Another key claiming the first alias of a key's hash pushes the key to the next one.
+        let other = aliasing_codec();
+        other
+            .define(alias.clone(), bincode::serialize("another key").unwrap())
+            .unwrap();
+        let bumped = other.encode(&key).unwrap();
+        assert_eq!(bumped[..9], alias[..9]);
+        assert_eq!(bumped[9..], 1u32.to_be_bytes());
+        assert_eq!(other.decode::<String>(&bumped).unwrap(), key);
+        assert_eq!(other.decode::<String>(&alias).unwrap(), "another key");
+    }
+
+    #[test]
+    fn test_unknown_alias_fails_to_decode() {
+        let mut alias = vec![ALIASED_KEY];
+        alias.extend_from_slice(&[0; 12]);
+        assert!(matches!(
+            aliasing_codec().decode::<String>(&alias),
+            Err(StructureError::UnknownKeyAlias)
+        ));
+    }
+}
@@ -0,0 +1,78 @@
+//! Per-key locking for rustmap-db structures.
+//!
+//! This module provides `KeyLocks`, a table of keys that are currently locked, and
+//! `KeyGuard`, which holds one of those locks until it is dropped.
+
+use std::{
+    collections::HashSet,
+    hash::Hash,
+    sync::{Condvar, Mutex},
+};
+
+/// A table of locked keys.
+///
+/// Only the set of locked keys is shared, so locking one key never blocks callers
+/// locking a different key.
+#[derive(Debug)]
+pub(crate) struct KeyLocks<K> {
+    locked: Mutex<HashSet<K>>,
+    released: Condvar,
+}
+
+impl<K> Default for KeyLocks<K> {
+    fn default() -> Self {
+        Self {
+            locked: Mutex::new(HashSet::new()),
+            released: Condvar::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> KeyLocks<K> {
+    /// Blocks until `key` is free, then locks it for the lifetime of the returned guard.
+    pub(crate) fn lock(&self, key: &K) -> KeyGuard<'_, K> {
+        let mut locked = self
+            .locked
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        while locked.contains(key) {
+            locked = self
+                .released
+                .wait(locked)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        locked.insert(key.clone());
+        KeyGuard {
+            locks: self,
+            key: key.clone(),
+        }
+    }
+}
+
+/// Exclusive access to a single key of a structure.
+///
+/// Returned by `HashMap::lock_key`. The key is unlocked when the guard is dropped.
+#[derive(Debug)]
+pub struct KeyGuard<'a, K: Hash + Eq> {
+    locks: &'a KeyLocks<K>,
+    key: K,
+}
+
+impl<'a, K: Hash + Eq> KeyGuard<'a, K> {
+    /// Returns a reference to the locked key.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+}
+
+impl<'a, K: Hash + Eq> Drop for KeyGuard<'a, K> {
+    fn drop(&mut self) {
+        let mut locked = self
+            .locks
+            .locked
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        locked.remove(&self.key);
+        self.locks.released.notify_all();
+    }
+}
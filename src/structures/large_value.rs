@@ -0,0 +1,116 @@
+//! Sidecar storage for large values.
+//!
+//! This module provides `LargeValues`, which moves serialized values above a size threshold
+//! out of the log and into an append-only sidecar file, leaving only a `ValueLocation` in the
+//! log entry.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read as _, Seek as _, SeekFrom, Write as _},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    db::db_entry::{DBEntry, ValueLocation},
+    StructureError,
+};
+
+/// The sidecar value file of one structure.
+#[derive(Debug, Clone)]
+pub(crate) struct LargeValues {
+    threshold: Option<usize>,
+    dir: PathBuf,
+    file_name: String,
+    lock: Arc<Mutex<()>>,
+}
+
+impl LargeValues {
+    /// Creates the sidecar storage for the structure with the given id.
+    ///
+    /// Values are only moved out of the log when `threshold` is set, but a directory is
+    /// always needed to read values that were stored in it before.
+    pub(crate) fn new(threshold: Option<usize>, dir: PathBuf, id: &[u8]) -> Self {
+        let file_name = id
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        Self {
+            threshold,
+            dir,
+            file_name: format!("{}.values", file_name),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Returns the size above which values are moved to the sidecar file, if any.
+    pub(crate) fn threshold(&self) -> Option<usize> {
+        self.threshold
+    }
+
+    /// Returns the directory holding the sidecar file.
+    pub(crate) fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    /// Builds the log entry for an inserted key-value pair.
+    ///
+    /// If the serialized value is larger than the threshold it is appended to the sidecar
+    /// file and the entry only records its location.
+    pub(crate) fn entry(
+        &self,
+        id: Vec<u8>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<DBEntry, StructureError> {
+        match self.threshold {
+            Some(threshold) if value.len() > threshold => {
+                let location = self.write(&value)?;
+                Ok(DBEntry::ExternalHashMapEntry(id, key, location))
+            }
+            _ => Ok(DBEntry::HashMapEntry(id, key, value)),
+        }
+    }
+
+    /// Reads the serialized value stored at `location`.
+    pub(crate) fn read(&self, location: &ValueLocation) -> Result<Vec<u8>, StructureError> {
+        let mut file = File::open(self.dir.join(&location.file))?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        let mut value = vec![0; location.len as usize];
+        file.read_exact(&mut value)?;
+        Ok(value)
+    }
+
+    /// Deletes the sidecar file, if it exists.
+    pub(crate) fn remove_file(&self) -> Result<(), StructureError> {
+        let _lock = self
+            .lock
+            .lock()
+            .map_err(|_| StructureError::MutexLockError)?;
+        match fs::remove_file(self.dir.join(&self.file_name)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Appends a serialized value to the sidecar file and returns its location.
+    fn write(&self, value: &[u8]) -> Result<ValueLocation, StructureError> {
+        let _lock = self
+            .lock
+            .lock()
+            .map_err(|_| StructureError::MutexLockError)?;
+        fs::create_dir_all(&self.dir)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(&self.file_name))?;
+        let offset = file.metadata()?.len();
+        file.write_all(value)?;
+        file.flush()?;
+        Ok(ValueLocation {
+            file: self.file_name.clone(),
+            offset,
+            len: value.len() as u64,
+        })
+    }
+}
@@ -3,34 +3,926 @@
 //! This module provides the key-value storage structures with persistence capabilities.
 
 use std::{
+    collections::HashMap as StdHashMap,
     fs::File,
-    io::{Seek as _, SeekFrom, Write as _},
+    hash::Hash,
+    io::{self, BufReader, Read as _, Seek as _, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use serde::Serialize;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
 
-use crate::StructureError;
+use crate::{
+    db::db_entry::{
+        frame_checksums, frame_len, is_frame_checksum_mismatch, DBEntry, RecoveryMode,
+        SkippedRecord, UnknownEntryPolicy, FRAME_CHECKSUM,
+    },
+    StructureError,
+};
 
+pub mod async_map;
+mod auto_compact;
+mod blocking;
+mod canonical;
+pub(crate) mod checksum;
+pub mod codec;
+pub mod conflict;
+mod error_handler;
+pub mod eviction;
+mod expiry;
+pub(crate) mod group_commit;
 pub mod hashmap;
 pub mod hashset;
+mod key_codec;
+pub mod key_lock;
+mod large_value;
+pub mod multimap;
+pub mod ordered_map;
+pub(crate) mod pending;
+pub mod persistent;
+pub mod snapshot_map;
+mod spill;
+pub mod stats;
 pub mod structure_error;
 pub mod value_ref;
+pub(crate) mod writer;
+
+/// The default number of entries written per chunk by the batch operations.
+pub const DEFAULT_BATCH_CHUNK_SIZE: usize = 4096;
+
+/// The size of the regions read in parallel by a load with a `load_concurrency` above 1.
+const LOAD_REGION_BYTES: usize = 1 << 20;
+
+/// How durable a structure's writes are once their JoinHandle completes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Writes are flushed to the operating system, which may still lose them on a crash or
+    /// power loss.
+    #[default]
+    Flush,
+    /// Writes are also synced to disk with `sync_all` before they complete.
+    Sync,
+}
+
+/// How writes to the file are retried after a transient I/O error.
+///
+/// The default policy doesn't retry at all.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RetryPolicy {
+    /// The number of times a write is retried before its error is returned.
+    pub(crate) retries: u32,
+    /// The delay before the first retry, doubled for every retry after it.
+    pub(crate) backoff: Duration,
+}
+
+/// Returns true for the I/O errors that may succeed if the write is simply tried again.
+#[inline]
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Writes the whole of `buf` to `writer`, retrying transient errors as `retry` allows.
+///
+/// Like `write_all`, an `Interrupted` error is always retried straight away. Progress is kept
+/// across retries, so bytes written before a failure are never written twice. The backoff
+/// sleeps the current thread, and the caller keeps holding the file lock while it does.
+pub(crate) fn write_all_retrying<W: Write>(
+    writer: &mut W,
+    mut buf: &[u8],
+    retry: RetryPolicy,
+) -> io::Result<()> {
+    let mut failures = 0;
+    while !buf.is_empty() {
+        match writer.write(buf) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => buf = &buf[written..],
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) if is_transient(&e) && failures < retry.retries => {
+                std::thread::sleep(retry.backoff.saturating_mul(1 << failures.min(16)));
+                failures += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Computes a stable fingerprint of the key and value types of a structure.
+///
+/// The fingerprint is a 64-bit FNV-1a hash of the type names, which (unlike `DefaultHasher`)
+/// stays the same across Rust releases, so it can be stored in the file and checked on reopen.
+/// Sets pass `()` as the value type.
+fn type_fingerprint<K, V>() -> u64 {
+    let names = [std::any::type_name::<K>(), std::any::type_name::<V>()];
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in names.join("\0").bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Checks a fingerprint read from the file against the expected one.
+#[inline]
+fn check_fingerprint(expected: u64, found: u64) -> Result<(), StructureError> {
+    if expected == found {
+        Ok(())
+    } else {
+        Err(StructureError::TypeMismatch { expected, found })
+    }
+}
+
+/// Checks that the file holds a sequence of valid database entries.
+///
+/// Anything after an `EndOfLog` entry is ignored. The entries are read with [`scan_file`], so
+/// no entry may claim to be longer than the rest of the file, and a corrupted or crafted length
+/// can't make the check allocate more than that. A truncated final entry is accepted, as
+/// `load_from_file` tolerates it, and so is an entry whose frame checksum doesn't match, which
+/// `load_from_file` reports itself. Any other failure to parse an entry means the file was not
+/// written by rustmap-db and `StructureError::NotARustmapFile` is returned.
+fn validate_file(file: &Arc<Mutex<File>>) -> Result<(), StructureError> {
+    match scan_file(file, |_, _| Ok(())) {
+        Ok(()) | Err(StructureError::ChecksumMismatch { .. }) => Ok(()),
+        Err(StructureError::BinCodeError(_)) => Err(StructureError::NotARustmapFile),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads every entry in the file in order, passing each one to `f` along with the range of bytes
+/// it occupies in the file.
+///
+/// Entries are decoded one at a time through a buffered reader, so the file is never held in
+/// memory as a whole. The file lock is held for the whole scan. A truncated final entry, or a
+/// final entry whose frame checksum doesn't match, ends the scan, as in `load_from_file`, and so
+/// does an `EndOfLog` entry once it is passed to `f`. A frame checksum mismatch anywhere else
+/// fails the scan with `StructureError::ChecksumMismatch`.
+pub(crate) fn scan_file<F>(file: &Arc<Mutex<File>>, f: F) -> Result<(), StructureError>
+where
+    F: FnMut(DBEntry, Range<u64>) -> Result<(), StructureError>,
+{
+    scan_file_with(file, RecoveryMode::Strict, f)?;
+    Ok(())
+}
+
+/// Reads every entry in the file like [`scan_file`], handling unreadable entries as `recovery`
+/// says, and returns the records that were skipped.
+pub(crate) fn scan_file_with<F>(
+    file: &Arc<Mutex<File>>,
+    recovery: RecoveryMode,
+    f: F,
+) -> Result<Vec<SkippedRecord>, StructureError>
+where
+    F: FnMut(DBEntry, Range<u64>) -> Result<(), StructureError>,
+{
+    let mut file = lock_file(file)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+    scan_entries(BufReader::new(&mut *file), len, recovery, f)
+}
+
+/// Reads the entries of a log of `len` bytes from `reader`, from its current position, like
+/// [`scan_file_with`].
+///
+/// In lenient mode an entry that can't be read is skipped, along with everything up to the
+/// next complete frame whose checksum matches. Entries that end the log part way through are
+/// skipped the same way if such a frame follows them, since a corrupted length makes an entry
+/// in the middle of the log look truncated. The skipped ranges are returned, leaving out a
+/// truncated or torn final entry, which is what an interrupted append leaves behind. In strict
+/// mode nothing is skipped.
+///
+/// An entry may not be longer than the rest of the log, so a corrupted or crafted length can't
+/// make the reader allocate more than the size of the file. An entry claiming more is treated
+/// like a truncated one.
+pub(crate) fn scan_entries<R, F>(
+    mut reader: R,
+    len: u64,
+    recovery: RecoveryMode,
+    mut f: F,
+) -> Result<Vec<SkippedRecord>, StructureError>
+where
+    R: io::Read + io::Seek,
+    F: FnMut(DBEntry, Range<u64>) -> Result<(), StructureError>,
+{
+    let mut position = reader.stream_position()?;
+    let mut skipped = Vec::new();
+
+    while position < len {
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(len - position);
+        let e = match options.deserialize_from::<_, DBEntry>(&mut reader) {
+            Ok(entry) => {
+                let end = reader.stream_position()?;
+                let end_of_log = entry == DBEntry::EndOfLog;
+                f(entry, position..end)?;
+                position = end;
+                if end_of_log {
+                    break;
+                }
+                continue;
+            }
+            Err(e) => e,
+        };
+        let error = match e.as_ref() {
+            bincode::ErrorKind::Io(io) if io.kind() == std::io::ErrorKind::UnexpectedEof => None,
+            bincode::ErrorKind::SizeLimit => None,
+            error if is_frame_checksum_mismatch(error) => {
+                let end = reader.stream_position()?;
+                if end >= len {
+                    None
+                } else {
+                    let mut frame = vec![0; (end - position) as usize];
+                    reader.seek(SeekFrom::Start(position))?;
+                    reader.read_exact(&mut frame)?;
+                    Some(match frame_checksums(&frame) {
+                        Some((expected, found)) => {
+                            StructureError::ChecksumMismatch { expected, found }
+                        }
+                        None => StructureError::BinCodeError(e),
+                    })
+                }
+            }
+            _ => Some(StructureError::BinCodeError(e)),
+        };
+        if recovery == RecoveryMode::Strict {
+            return error.map_or(Ok(skipped), Err);
+        }
+        match next_frame(&mut reader, position + 1, len)? {
+            Some(next) => {
+                skipped.push(SkippedRecord {
+                    offset: position,
+                    len: next - position,
+                    error,
+                });
+                position = next;
+            }
+            None => {
+                if error.is_some() {
+                    skipped.push(SkippedRecord {
+                        offset: position,
+                        len: len - position,
+                        error,
+                    });
+                }
+                break;
+            }
+        }
+    }
+
+    Ok(skipped)
+}
+
+/// Returns the offset of the first complete frame at or after `from` whose checksum matches,
+/// searching byte by byte, and leaves `reader` positioned at it.
+fn next_frame<R>(reader: &mut R, from: u64, len: u64) -> io::Result<Option<u64>>
+where
+    R: io::Read + io::Seek,
+{
+    let mut header = [0; FRAME_CHECKSUM.start];
+    for offset in from..len.saturating_sub(header.len() as u64 - 1) {
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(&mut header)?;
+        let Some(frame_len) = frame_len(&header).filter(|frame_len| *frame_len <= len - offset)
+        else {
+            continue;
+        };
+        let mut frame = vec![0; frame_len as usize];
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(&mut frame)?;
+        if matches!(frame_checksums(&frame), Some((recorded, found)) if recorded == found) {
+            reader.seek(SeekFrom::Start(offset))?;
+            return Ok(Some(offset));
+        }
+    }
+    Ok(None)
+}
+
+/// Replays the hashmap entries of the structure with the (serialized) `id` into a standard map.
+///
+/// Used by the map variants that don't keep their contents in a DashMap. They don't support
+/// sidecar values, so those fail with `StructureError::LargeValueDirRequired`.
+fn load_map_entries<K, V>(
+    file: &Arc<Mutex<File>>,
+    id: &[u8],
+) -> Result<StdHashMap<K, V>, StructureError>
+where
+    K: for<'de> Deserialize<'de> + Hash + Eq,
+    V: for<'de> Deserialize<'de>,
+{
+    let mut map = StdHashMap::new();
+    replay_map_entries(file, id, |key, value| match value {
+        Some(value) => {
+            map.insert(key, value);
+        }
+        None => {
+            map.remove(&key);
+        }
+    })?;
+    Ok(map)
+}
 
+/// Replays the hashmap entries of the structure with the (serialized) `id`, in log order,
+/// passing each write to `apply` as its key with `Some` value and each removal as its key with
+/// `None`.
+///
+/// Sidecar values fail with `StructureError::LargeValueDirRequired`, as for `load_map_entries`.
+fn replay_map_entries<K, V, F>(
+    file: &Arc<Mutex<File>>,
+    id: &[u8],
+    mut apply: F,
+) -> Result<(), StructureError>
+where
+    K: for<'de> Deserialize<'de>,
+    V: for<'de> Deserialize<'de>,
+    F: FnMut(K, Option<V>),
+{
+    scan_file(file, |entry, _| {
+        match entry {
+            DBEntry::HashMapEntry(entry_id, key, value) if entry_id == id => {
+                apply(
+                    bincode::deserialize(&key)?,
+                    Some(bincode::deserialize(&value)?),
+                );
+            }
+            DBEntry::ExternalHashMapEntry(entry_id, _, _) if entry_id == id => {
+                return Err(StructureError::LargeValueDirRequired);
+            }
+            DBEntry::RemoveHashMapEntry(entry_id, key) if entry_id == id => {
+                apply(bincode::deserialize(&key)?, None);
+            }
+            DBEntry::TypeFingerprint(entry_id, fingerprint) if entry_id == id => {
+                check_fingerprint(type_fingerprint::<K, V>(), fingerprint)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    })
+}
+
+/// Returns the offset the log ends at, reading the file in `recovery` mode.
+///
+/// That is the offset of the file's `EndOfLog` entry if it has one, and otherwise the end of
+/// its last complete entry, so anything after it is padding or a partially written entry.
+/// The records skipped on the way are returned along with it.
+pub(crate) fn log_end(
+    file: &Arc<Mutex<File>>,
+    recovery: RecoveryMode,
+) -> Result<(u64, Vec<SkippedRecord>), StructureError> {
+    let mut end = 0;
+    let skipped = scan_file_with(file, recovery, |entry, span| {
+        end = match entry {
+            DBEntry::EndOfLog => span.start,
+            _ => span.end,
+        };
+        Ok(())
+    })?;
+    Ok((end, skipped))
+}
+
+/// Reads every complete entry of the log, up to its end marker if it has one, to rewrite it.
+///
+/// The entries are read like [`scan_entries`] does in `recovery` mode, so no entry may be
+/// longer than the rest of the file. A truncated or torn final entry is left out, as it was
+/// never completely written. In strict mode any other entry that can't be read fails with its
+/// error, so a rewrite never drops the records after it; in lenient mode it is skipped along
+/// with the bytes up to the next intact record, and only those bytes are lost by a rewrite.
+pub(crate) fn read_log(
+    file: &mut File,
+    recovery: RecoveryMode,
+) -> Result<Vec<DBEntry>, StructureError> {
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+    let mut entries = Vec::new();
+    scan_entries(BufReader::new(&mut *file), len, recovery, |entry, _| {
+        if entry != DBEntry::EndOfLog {
+            entries.push(entry);
+        }
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+/// How a database holds the advisory lock on its file, which keeps other databases from
+/// opening the file at the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FileLock {
+    /// No other database may open the file.
+    Exclusive,
+    /// Other databases may open the file with a shared lock, but not with an exclusive one.
+    Shared,
+}
+
+impl FileLock {
+    /// Takes the lock on `file`, failing with `io::ErrorKind::WouldBlock` if a conflicting
+    /// lock is held, rather than waiting for it.
+    pub(crate) fn acquire(self, file: &File, path: &Path) -> io::Result<()> {
+        let locked = match self {
+            FileLock::Exclusive => file.try_lock(),
+            FileLock::Shared => file.try_lock_shared(),
+        };
+        locked.map_err(|e| match e {
+            std::fs::TryLockError::WouldBlock => io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} is locked by another database", path.display()),
+            ),
+            std::fs::TryLockError::Error(e) => e,
+        })
+    }
+}
+
+/// The path of a database file and the lock its database holds on it.
+#[derive(Debug, Clone)]
+pub(crate) struct LogPath {
+    pub(crate) path: PathBuf,
+    pub(crate) lock: FileLock,
+}
+
+/// Replaces the contents of the log `file` with `entries`.
+///
+/// With the file's `path`, the entries are written to a temporary file in the same directory,
+/// synced and renamed over the path, and `file` is swapped for the new file, so every
+/// structure sharing the handle moves to it and a crash leaves either the old log or the new
+/// one. The database's lock is taken on the new file before it replaces the old one.
+///
+/// Structures opened on a bare file handle have no path to rename over, so their file is
+/// truncated and rewritten in place, and a crash part way through can lose its contents.
+/// Either way, anything `read_log` didn't return, such as a partially written final entry or
+/// the end of log marker and its padding, is dropped, so later appends follow the last entry
+/// directly.
+pub(crate) fn replace_log(
+    file: &mut File,
+    path: Option<&LogPath>,
+    entries: &[DBEntry],
+) -> Result<(), StructureError> {
+    let Some(LogPath { path, lock }) = path else {
+        let mut rewritten = Vec::new();
+        for entry in entries {
+            bincode::serialize_into(&mut rewritten, entry)?;
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&rewritten)?;
+        file.flush()?;
+        return Ok(());
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut replacement = tempfile::NamedTempFile::new_in(dir)?;
+    {
+        let mut writer = io::BufWriter::new(replacement.as_file_mut());
+        for entry in entries {
+            bincode::serialize_into(&mut writer, entry)?;
+        }
+        writer.flush()?;
+    }
+    replacement.as_file().sync_all()?;
+    lock.acquire(replacement.as_file(), path)?;
+    let replacement = replacement.persist(path).map_err(|e| e.error)?;
+    sync_dir(path)?;
+    *file = replacement;
+    Ok(())
+}
+
+/// Syncs the directory containing `path`, making the creation, renaming or removal of the
+/// file durable.
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Applies the unknown entry policy to an extension entry that a structure doesn't recognise.
 #[inline]
-fn lock_file(file: &Arc<Mutex<File>>) -> Result<std::sync::MutexGuard<'_, File>, StructureError> {
-    file.lock().map_err(|_| StructureError::MutexLockError)
+fn check_unknown_entry(policy: UnknownEntryPolicy, tag: u8) -> Result<(), StructureError> {
+    match policy {
+        UnknownEntryPolicy::Skip => Ok(()),
+        UnknownEntryPolicy::Reject => Err(StructureError::UnknownEntry(tag)),
+    }
+}
+
+/// Encodes the id a structure is created with into the id stored in its log entries.
+///
+/// This is the canonical encoding shared by every structure: the id is bincode-serialized as
+/// a byte sequence, so it is prefixed with its length.
+pub(crate) fn encode_id(id: &[u8]) -> Result<Vec<u8>, StructureError> {
+    Ok(bincode::serialize(id)?)
 }
 
+/// Locks the shared file.
+///
+/// If a thread panicked while holding the lock, the lock is recovered instead of failing every
+/// later write: a partial entry the panicking write may have left at the end of the file is
+/// cut off, and the lock is then usable again. If the tail can't be checked, the error is
+/// returned and the next lock tries again.
+#[inline]
+pub(crate) fn lock_file(
+    file: &Arc<Mutex<File>>,
+) -> Result<std::sync::MutexGuard<'_, File>, StructureError> {
+    match file.lock() {
+        Ok(guard) => Ok(guard),
+        Err(poisoned) => {
+            let mut guard = poisoned.into_inner();
+            trim_partial_entry(&mut guard)?;
+            file.clear_poison();
+            Ok(guard)
+        }
+    }
+}
+
+/// Cuts the file off after its last complete entry, removing the partial entry of an
+/// interrupted append.
+fn trim_partial_entry(file: &mut File) -> Result<(), StructureError> {
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+    let mut end = 0;
+    scan_entries(
+        BufReader::new(&mut *file),
+        len,
+        RecoveryMode::Strict,
+        |_, span| {
+            end = span.end;
+            Ok(())
+        },
+    )?;
+    if end < len {
+        file.set_len(end)?;
+    }
+    Ok(())
+}
+
+/// A source that can read at a given offset without moving a shared cursor, so regions of it
+/// can be read from several threads at once.
+pub(crate) trait ReadAt: Sync {
+    /// Fills `buf` with the bytes starting at `offset`.
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+}
+
+impl ReadAt for File {
+    #[cfg(unix)]
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_read(self, buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the first `len` bytes of `source` as regions of `region` bytes, with up to
+/// `concurrency` regions in flight at once.
+///
+/// The regions are read into their place in the returned buffer, so it holds the bytes in file
+/// order whatever order the reads complete in. This overlaps the latency of the reads on
+/// high-latency storage, such as network filesystems.
+pub(crate) fn read_concurrently<R: ReadAt>(
+    source: &R,
+    len: u64,
+    concurrency: usize,
+    region: usize,
+) -> io::Result<Vec<u8>> {
+    let concurrency = concurrency.max(1);
+    let region = region.max(1);
+    let mut buffer = vec![0; len as usize];
+    let mut workers = (0..concurrency).map(|_| Vec::new()).collect::<Vec<_>>();
+    for (index, chunk) in buffer.chunks_mut(region).enumerate() {
+        workers[index % concurrency].push(((index * region) as u64, chunk));
+    }
+    std::thread::scope(|scope| {
+        let handles = workers
+            .into_iter()
+            .filter(|chunks| !chunks.is_empty())
+            .map(|chunks| {
+                scope.spawn(move || {
+                    chunks
+                        .into_iter()
+                        .try_for_each(|(offset, chunk)| source.read_exact_at(chunk, offset))
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("region reader panicked"))
+    })?;
+    Ok(buffer)
+}
+
+/// Syncs the file to disk if `durability` requires it.
+#[inline]
+fn sync_file(file: &Arc<Mutex<File>>, durability: Durability) -> Result<(), StructureError> {
+    if durability == Durability::Sync {
+        lock_file(file)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Serializes `data` and appends it to the end of the file.
+///
+/// The data is serialized before the file lock is taken, so the lock is only held for the
+/// seek, write and flush and a slow serialization doesn't block other writers. Transient write
+/// errors are retried according to `retry`.
 #[inline]
 fn serialize_to_file<T: Serialize>(
     data: &T,
     file: &Arc<Mutex<File>>,
+    retry: RetryPolicy,
 ) -> Result<(), StructureError> {
-    let mut file = file.lock().map_err(|_| StructureError::MutexLockError)?;
-    file.seek(SeekFrom::End(0))?;
     let serialized_data = bincode::serialize(data)?;
-    file.write_all(&serialized_data)?;
+    let mut file = lock_file(file)?;
+    file.seek(SeekFrom::End(0))?;
+    write_all_retrying(&mut *file, &serialized_data, retry)?;
     file.flush()?;
     Ok(())
 }
+
+/// Serializes `entry` and appends it to the end of the file, returning the offset it was
+/// written at.
+fn append_entry(
+    entry: &DBEntry,
+    file: &Arc<Mutex<File>>,
+    retry: RetryPolicy,
+) -> Result<u64, StructureError> {
+    append_entries(std::slice::from_ref(entry), file, retry)
+}
+
+/// Serializes `entries` and appends them to the end of the file in one write, returning the
+/// offset the first one was written at.
+fn append_entries(
+    entries: &[DBEntry],
+    file: &Arc<Mutex<File>>,
+    retry: RetryPolicy,
+) -> Result<u64, StructureError> {
+    let mut serialized_entries = Vec::new();
+    for entry in entries {
+        bincode::serialize_into(&mut serialized_entries, entry)?;
+    }
+    let mut file = lock_file(file)?;
+    let offset = file.seek(SeekFrom::End(0))?;
+    write_all_retrying(&mut *file, &serialized_entries, retry)?;
+    file.flush()?;
+    Ok(offset)
+}
+
+/// Overwrites the record at `offset` with `entry`, if the record there differs from it only in
+/// its trailing `value_len` bytes.
+///
+/// That is the case exactly when the existing record belongs to the same structure and key and
+/// holds a value of the same serialized length. The record is read back under the file lock
+/// before writing, so an offset made stale by a rewrite of the file is detected rather than
+/// corrupting it. Returns whether the record was overwritten.
+fn overwrite_entry(
+    entry: &DBEntry,
+    value_len: usize,
+    offset: u64,
+    file: &Arc<Mutex<File>>,
+    retry: RetryPolicy,
+) -> Result<bool, StructureError> {
+    let serialized_entry = bincode::serialize(entry)?;
+    let prefix_len = serialized_entry.len() - value_len;
+    let mut file = lock_file(file)?;
+    let mut existing = vec![0; prefix_len];
+    file.seek(SeekFrom::Start(offset))?;
+    if file.read_exact(&mut existing).is_err() {
+        return Ok(false);
+    }
+    // The frame checksum covers the value, so it's the one part of the prefix that may differ.
+    existing[FRAME_CHECKSUM].copy_from_slice(&serialized_entry[FRAME_CHECKSUM]);
+    if existing != serialized_entry[..prefix_len] {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    write_all_retrying(&mut *file, &serialized_entry, retry)?;
+    file.flush()?;
+    Ok(true)
+}
+
+/// Serializes `entries` and appends them to the file in chunks of at most `chunk_size` entries.
+///
+/// Each chunk is serialized into its own buffer and written under a separate lock acquisition,
+/// which caps the size of the intermediate buffer for very large batches. Entries are written
+/// back to back so they can be replayed one at a time by `load_from_file`.
+///
+/// Returns the number of writes performed.
+fn serialize_chunks_to_file<I>(
+    entries: I,
+    chunk_size: usize,
+    file: &Arc<Mutex<File>>,
+    retry: RetryPolicy,
+) -> Result<usize, StructureError>
+where
+    I: IntoIterator<Item = Result<DBEntry, StructureError>>,
+{
+    serialize_chunks_at(entries, chunk_size, file, retry, |_, _| {})
+}
+
+/// Appends `entries` like [`serialize_chunks_to_file`], passing each entry and the offset it
+/// was written at to `written`, in order, once its chunk is written.
+fn serialize_chunks_at<I, F>(
+    entries: I,
+    chunk_size: usize,
+    file: &Arc<Mutex<File>>,
+    retry: RetryPolicy,
+    mut written: F,
+) -> Result<usize, StructureError>
+where
+    I: IntoIterator<Item = Result<DBEntry, StructureError>>,
+    F: FnMut(&DBEntry, u64),
+{
+    let chunk_size = chunk_size.max(1);
+    let mut entries = entries.into_iter().peekable();
+    let mut writes = 0;
+    while entries.peek().is_some() {
+        let mut buffer = Vec::new();
+        let mut chunk = Vec::new();
+        for entry in entries.by_ref().take(chunk_size) {
+            let entry = entry?;
+            chunk.push((buffer.len() as u64, entry));
+            bincode::serialize_into(&mut buffer, &chunk[chunk.len() - 1].1)?;
+        }
+        let mut file = lock_file(file)?;
+        let offset = file.seek(SeekFrom::End(0))?;
+        write_all_retrying(&mut *file, &buffer, retry)?;
+        file.flush()?;
+        drop(file);
+        for (start, entry) in &chunk {
+            written(entry, offset + start);
+        }
+        writes += 1;
+    }
+    Ok(writes)
+}
+
+#[cfg(test)]
+mod structures_tests {
+    use super::*;
+    use crate::HashMap;
+
+    #[test]
+    fn test_serialize_chunks_to_file_writes_in_chunks() {
+        let file = Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+        let id = bincode::serialize(&vec![1u8]).unwrap();
+        let entries = (0..100u32).map(|i| {
+            Ok(DBEntry::HashMapEntry(
+                id.clone(),
+                bincode::serialize(&i)?,
+                bincode::serialize(&(i * 2))?,
+            ))
+        });
+
+        let writes = serialize_chunks_to_file(entries, 7, &file, RetryPolicy::default()).unwrap();
+        assert_eq!(writes, 15);
+
+        let map = HashMap::<u32, u32>::new(file, vec![1]).unwrap();
+        assert_eq!(map.len(), 100);
+        for i in 0..100u32 {
+            assert_eq!(map.get(&i).unwrap().value(), &(i * 2));
+        }
+    }
+
+    /// A reader over an in-memory buffer that takes `delay` per read, like a file on a
+    /// high-latency network filesystem, and records how many reads overlapped.
+    struct SlowReader {
+        data: Vec<u8>,
+        delay: Duration,
+        in_flight: std::sync::atomic::AtomicUsize,
+        max_in_flight: std::sync::atomic::AtomicUsize,
+    }
+
+    impl ReadAt for SlowReader {
+        fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            use std::sync::atomic::Ordering;
+
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            std::thread::sleep(self.delay);
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_read_concurrently_overlaps_slow_reads() {
+        use std::{sync::atomic::Ordering, time::Instant};
+
+        let reader = SlowReader {
+            data: (0..10_000u32).flat_map(|i| i.to_le_bytes()).collect(),
+            delay: Duration::from_millis(20),
+            in_flight: Default::default(),
+            max_in_flight: Default::default(),
+        };
+        let len = reader.data.len() as u64;
+
+        let start = Instant::now();
+        let sequential = read_concurrently(&reader, len, 1, 5_000).unwrap();
+        let sequential_time = start.elapsed();
+        assert_eq!(sequential, reader.data);
+        assert_eq!(reader.max_in_flight.swap(0, Ordering::SeqCst), 1);
+
+        let start = Instant::now();
+        let concurrent = read_concurrently(&reader, len, 4, 5_000).unwrap();
+        let concurrent_time = start.elapsed();
+        assert_eq!(concurrent, reader.data);
+        assert_eq!(reader.max_in_flight.load(Ordering::SeqCst), 4);
+        // 8 regions take 8 delays one at a time, but only 2 with 4 in flight.
+        assert!(concurrent_time * 2 < sequential_time);
+    }
+
+    /// A writer that fails with `kind` a given number of times before accepting writes.
+    struct FlakyWriter {
+        failures: u32,
+        kind: io::ErrorKind,
+        attempts: u32,
+        written: Vec<u8>,
+    }
+
+    impl FlakyWriter {
+        fn new(failures: u32, kind: io::ErrorKind) -> Self {
+            Self {
+                failures,
+                kind,
+                attempts: 0,
+                written: Vec::new(),
+            }
+        }
+    }
+
+    impl Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.attempts += 1;
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(self.kind.into());
+            }
+            // Accept at most a few bytes at a time to exercise partial writes.
+            let len = buf.len().min(3);
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_all_retrying_recovers_from_transient_errors() {
+        let retry = RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_millis(1),
+        };
+        let mut writer = FlakyWriter::new(2, io::ErrorKind::WouldBlock);
+        write_all_retrying(&mut writer, b"hello world", retry).unwrap();
+        assert_eq!(writer.written, b"hello world");
+        assert_eq!(writer.attempts, 6);
+
+        let mut writer = FlakyWriter::new(3, io::ErrorKind::WouldBlock);
+        let error = write_all_retrying(&mut writer, b"hello", retry).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(writer.attempts, 3);
+        assert!(writer.written.is_empty());
+    }
+
+    #[test]
+    fn test_write_all_retrying_fails_fast_on_other_errors() {
+        let retry = RetryPolicy {
+            retries: 5,
+            backoff: Duration::from_millis(1),
+        };
+        let mut writer = FlakyWriter::new(1, io::ErrorKind::PermissionDenied);
+        let error = write_all_retrying(&mut writer, b"hello", retry).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(writer.attempts, 1);
+
+        // Interrupted writes are retried without using up the budget, like `write_all`.
+        let mut writer = FlakyWriter::new(3, io::ErrorKind::Interrupted);
+        write_all_retrying(&mut writer, b"hello", RetryPolicy::default()).unwrap();
+        assert_eq!(writer.written, b"hello");
+    }
+}
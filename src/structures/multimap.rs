@@ -0,0 +1,221 @@
+//! Multimap module for rustmap-db.
+//!
+//! This module provides `MultiMap`, a file-backed map from each key to a list of values. Every
+//! insert appends one value to its key's list, and is logged as a single record rather than by
+//! rewriting the whole list.
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    db::db_entry::{DBEntry, UnknownEntryPolicy},
+    StructureError,
+};
+
+use super::{
+    check_unknown_entry, encode_id,
+    pending::PendingWrites,
+    scan_file, serialize_to_file,
+    writer::{ordered, Slot, Writer},
+    RetryPolicy,
+};
+
+/// A file-backed, thread-safe map from keys to lists of values.
+///
+/// The values of a key are kept in the order they were inserted, and the same value may
+/// appear more than once. A key is present as long as it has at least one value.
+#[derive(Debug)]
+pub struct MultiMap<K: Hash + Eq, V> {
+    inner: DashMap<K, Vec<V>>,
+    file: Arc<Mutex<File>>,
+    id: Vec<u8>,
+    pending: Option<PendingWrites>,
+    writer: Option<Arc<Writer>>,
+}
+
+impl<K: Hash + Eq, V> MultiMap<K, V>
+where
+    K: Serialize + for<'de> Deserialize<'de> + Clone + Send + 'static,
+    V: Serialize + for<'de> Deserialize<'de> + Clone + PartialEq + Send + 'static,
+{
+    /// Creates a new `MultiMap`, loading its contents from the file.
+    ///
+    /// The id is stored bincode-serialized, like the ids of the other structures.
+    pub fn new(file: Arc<Mutex<File>>, id: Vec<u8>) -> Result<Self, StructureError> {
+        let instance = Self {
+            inner: DashMap::new(),
+            file,
+            id: encode_id(&id)?,
+            pending: None,
+            writer: None,
+        };
+        instance.replay_file()?;
+        Ok(instance)
+    }
+
+    /// Registers the multimap's spawned writes with a database, so it can wait for them on
+    /// close.
+    pub(crate) fn with_pending_writes(mut self, pending: PendingWrites) -> Self {
+        self.pending = Some(pending);
+        self
+    }
+
+    /// Queues the multimap's appends on a database's writer, so they reach the file in the order
+    /// they were applied in memory.
+    pub(crate) fn with_writer(mut self, writer: Arc<Writer>) -> Self {
+        self.writer = Some(writer);
+        self
+    }
+
+    /// Reserves the next place in the write order of the multimap's database, if it has one.
+    fn reserve(&self) -> Result<Option<Slot<'_>>, StructureError> {
+        self.writer
+            .as_ref()
+            .map(|writer| writer.reserve())
+            .transpose()
+    }
+
+    /// Spawns a task persisting a write with `append`, in the order of `slot` if there is one.
+    /// The task is registered with the database's pending writes.
+    fn spawn_append<T, A>(
+        &self,
+        slot: Option<Slot<'_>>,
+        append: A,
+    ) -> JoinHandle<Result<T, StructureError>>
+    where
+        T: Send + 'static,
+        A: FnOnce() -> Result<T, StructureError> + Send + 'static,
+    {
+        let registered = self.pending.as_ref().map(PendingWrites::begin);
+        let write = ordered(slot, append);
+        tokio::spawn(async move {
+            let result = write.await;
+            drop(registered);
+            result
+        })
+    }
+
+    /// Replays the multimap's entries from the file on top of its in-memory state.
+    fn replay_file(&self) -> Result<(), StructureError> {
+        scan_file(&self.file, |entry, _| {
+            match entry {
+                DBEntry::MultiMapEntry(id, key, value) if id == self.id => {
+                    let key = bincode::deserialize::<K>(&key)?;
+                    let value = bincode::deserialize::<V>(&value)?;
+                    self.inner.entry(key).or_default().push(value);
+                }
+                DBEntry::RemoveMultiMapValue(id, key, value) if id == self.id => {
+                    let key = bincode::deserialize::<K>(&key)?;
+                    let value = bincode::deserialize::<V>(&value)?;
+                    self.take_value(&key, &value);
+                }
+                DBEntry::Extension(tag, _) => {
+                    check_unknown_entry(UnknownEntryPolicy::default(), tag)?
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    /// Appends `value` to the values of `key`.
+    ///
+    /// Returns a JoinHandle with a Result containing the number of values the key has after
+    /// the insert if the operation was successful.
+    pub fn insert(&self, key: K, value: V) -> JoinHandle<Result<usize, StructureError>> {
+        let slot = match self.reserve() {
+            Ok(slot) => slot,
+            Err(e) => return self.spawn_append(None, move || Err(e)),
+        };
+        let count = {
+            let mut values = self.inner.entry(key.clone()).or_default();
+            values.push(value.clone());
+            values.len()
+        };
+        let file = self.file.clone();
+        let id = self.id.clone();
+        self.spawn_append(slot, move || {
+            let key = bincode::serialize(&key)?;
+            let value = bincode::serialize(&value)?;
+            serialize_to_file(
+                &DBEntry::MultiMapEntry(id, key, value),
+                &file,
+                RetryPolicy::default(),
+            )?;
+            Ok(count)
+        })
+    }
+
+    /// Returns copies of the values of `key`, in the order they were inserted.
+    ///
+    /// Returns None if the key has no values.
+    pub fn get(&self, key: &K) -> Option<Vec<V>> {
+        self.inner.get(key).map(|values| values.value().clone())
+    }
+
+    /// Removes the first occurrence of `value` from the values of `key`. The key is removed
+    /// along with its last value.
+    ///
+    /// Returns None if the key doesn't have the value; otherwise a JoinHandle with a Result
+    /// containing the removed value if the operation was successful.
+    pub fn remove_value(
+        &self,
+        key: &K,
+        value: &V,
+    ) -> Option<JoinHandle<Result<V, StructureError>>> {
+        let slot = match self.reserve() {
+            Ok(slot) => slot,
+            Err(e) => return Some(self.spawn_append(None, move || Err(e))),
+        };
+        let removed = self.take_value(key, value)?;
+        let file = self.file.clone();
+        let id = self.id.clone();
+        let key = bincode::serialize(key);
+        Some(self.spawn_append(slot, move || {
+            let value = bincode::serialize(&removed)?;
+            serialize_to_file(
+                &DBEntry::RemoveMultiMapValue(id, key?, value),
+                &file,
+                RetryPolicy::default(),
+            )?;
+            Ok(removed)
+        }))
+    }
+
+    /// Removes the first occurrence of `value` from the values of `key` in memory, dropping
+    /// the key if it has no values left.
+    fn take_value(&self, key: &K, value: &V) -> Option<V> {
+        let mut values = self.inner.get_mut(key)?;
+        let position = values.iter().position(|existing| existing == value)?;
+        let removed = values.remove(position);
+        let empty = values.is_empty();
+        drop(values);
+        if empty {
+            self.inner.remove_if(key, |_, values| values.is_empty());
+        }
+        Some(removed)
+    }
+
+    /// Returns true if `key` has at least one value.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.inner.contains_key(key)
+    }
+
+    /// Returns the number of keys in the `MultiMap`.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns true if the `MultiMap` contains no keys.
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
@@ -0,0 +1,263 @@
+//! Ordered map module for rustmap-db.
+//!
+//! This module provides `OrderedMap`, a file-backed map that keeps its keys sorted. Its
+//! contents are a `BTreeMap` behind a read-write lock, so besides the lookups of `HashMap` it
+//! supports range scans and iteration in key order.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    ops::RangeBounds,
+    sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    db::db_entry::{DBEntry, RecoveryMode},
+    StructureError,
+};
+
+use super::{
+    encode_id,
+    hashmap::map_record,
+    lock_file,
+    pending::PendingWrites,
+    read_log, replace_log, replay_map_entries, serialize_to_file,
+    writer::{ordered, Slot, Writer},
+    LogPath, RetryPolicy,
+};
+
+/// A file-backed, thread-safe map that keeps its keys in order.
+///
+/// Reads take a shared lock on the whole map and writes an exclusive one, so it trades the
+/// sharded throughput of [`HashMap`](crate::HashMap) for ordered access. It shares the file
+/// format of `HashMap`, so a map written by one can be opened by the other with the same id.
+/// Values are returned as copies, since they can't outlive the lock.
+#[derive(Debug)]
+pub struct OrderedMap<K, V> {
+    inner: RwLock<BTreeMap<K, V>>,
+    file: Arc<Mutex<File>>,
+    id: Vec<u8>,
+    pending: Option<PendingWrites>,
+    writer: Option<Arc<Writer>>,
+    path: Option<LogPath>,
+}
+
+impl<K, V> OrderedMap<K, V>
+where
+    K: Serialize + for<'de> Deserialize<'de> + Ord + Clone + Send + Sync + 'static,
+    V: Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+{
+    /// Creates a new OrderedMap, loading its contents from the file.
+    ///
+    /// The id is stored bincode-serialized, like the ids of the other structures.
+    pub fn new(file: Arc<Mutex<File>>, id: Vec<u8>) -> Result<Self, StructureError> {
+        let id = encode_id(&id)?;
+        let mut map = BTreeMap::new();
+        replay_map_entries(&file, &id, |key, value| match value {
+            Some(value) => {
+                map.insert(key, value);
+            }
+            None => {
+                map.remove(&key);
+            }
+        })?;
+        Ok(Self {
+            inner: RwLock::new(map),
+            file,
+            id,
+            pending: None,
+            writer: None,
+            path: None,
+        })
+    }
+
+    /// Registers the map's spawned writes with a database, so it can wait for them on close.
+    pub(crate) fn with_pending_writes(mut self, pending: PendingWrites) -> Self {
+        self.pending = Some(pending);
+        self
+    }
+
+    /// Queues the map's appends on a database's writer, so they reach the file in the order
+    /// they were applied in memory.
+    pub(crate) fn with_writer(mut self, writer: Arc<Writer>) -> Self {
+        self.writer = Some(writer);
+        self
+    }
+
+    /// Records the path of the map's file, so `clear` can replace the file atomically.
+    pub(crate) fn with_path(mut self, path: LogPath) -> Self {
+        self.path = Some(path);
+        self
+    }
+
+    /// Reserves the next place in the write order of the map's database, if it has one.
+    fn reserve(&self) -> Result<Option<Slot<'_>>, StructureError> {
+        self.writer
+            .as_ref()
+            .map(|writer| writer.reserve())
+            .transpose()
+    }
+
+    /// Spawns a task persisting a write with `append`, in the order of `slot` if there is one.
+    /// The task is registered with the database's pending writes.
+    fn spawn_append<T, A>(
+        &self,
+        slot: Option<Slot<'_>>,
+        append: A,
+    ) -> JoinHandle<Result<T, StructureError>>
+    where
+        T: Send + 'static,
+        A: FnOnce() -> Result<T, StructureError> + Send + 'static,
+    {
+        let registered = self.pending.as_ref().map(PendingWrites::begin);
+        let write = ordered(slot, append);
+        tokio::spawn(async move {
+            let result = write.await;
+            drop(registered);
+            result
+        })
+    }
+
+    /// Takes the shared lock on the map.
+    ///
+    /// A panic can't leave the `BTreeMap` half-updated, so a poisoned lock is recovered.
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<K, V>> {
+        self.inner
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Takes the exclusive lock on the map, recovering it if it is poisoned like `read`.
+    fn write(&self) -> RwLockWriteGuard<'_, BTreeMap<K, V>> {
+        self.inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Inserts a key-value pair into the OrderedMap.
+    ///
+    /// Returns a JoinHandle with a Result containing the old value (None if new) if the operation was successful.
+    pub fn insert(&self, key: K, value: V) -> JoinHandle<Result<Option<V>, StructureError>> {
+        let slot = match self.reserve() {
+            Ok(slot) => slot,
+            Err(e) => return self.spawn_append(None, move || Err(e)),
+        };
+        let old_value = self.write().insert(key.clone(), value.clone());
+        let file = self.file.clone();
+        let id = self.id.clone();
+        self.spawn_append(slot, move || {
+            let key = bincode::serialize(&key)?;
+            let value = bincode::serialize(&value)?;
+            serialize_to_file(
+                &DBEntry::HashMapEntry(id, key, value),
+                &file,
+                RetryPolicy::default(),
+            )?;
+            Ok(old_value)
+        })
+    }
+
+    /// Returns a copy of the value corresponding to the given key.
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.read().get(key).cloned()
+    }
+
+    /// Returns true if the map contains the given key.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.read().contains_key(key)
+    }
+
+    /// Removes a key from the OrderedMap.
+    ///
+    /// Returns None if the key did not exist; otherwise a JoinHandle with a Result containing
+    /// the removed value if the operation was successful.
+    pub fn remove(&self, key: &K) -> Option<JoinHandle<Result<Option<V>, StructureError>>> {
+        let slot = match self.reserve() {
+            Ok(slot) => slot,
+            Err(e) => return Some(self.spawn_append(None, move || Err(e))),
+        };
+        let old_value = self.write().remove(key)?;
+        let file = self.file.clone();
+        let id = self.id.clone();
+        let key = bincode::serialize(key);
+        Some(self.spawn_append(slot, move || {
+            serialize_to_file(
+                &DBEntry::RemoveHashMapEntry(id, key?),
+                &file,
+                RetryPolicy::default(),
+            )?;
+            Ok(Some(old_value))
+        }))
+    }
+
+    /// Clears the OrderedMap, removing all key-value pairs.
+    ///
+    /// The file is rewritten under its lock without the map's records, keeping the records of
+    /// other structures. When the map was opened through a `Database`, the new log is written
+    /// to a temporary file and renamed over the database file, so a crash leaves either the
+    /// old log or the new one; otherwise the file is rewritten in place.
+    pub fn clear(&self) -> Result<(), StructureError> {
+        if let Some(writer) = &self.writer {
+            writer.check_writable()?;
+        }
+        let mut map = self.write();
+        let mut file = lock_file(&self.file)?;
+        let entries = read_log(&mut file, RecoveryMode::Strict)?
+            .into_iter()
+            .filter(|entry| map_record(&self.id, entry).is_none())
+            .collect::<Vec<_>>();
+        replace_log(&mut file, self.path.as_ref(), &entries)?;
+        map.clear();
+        Ok(())
+    }
+
+    /// Returns the number of key-value pairs in the OrderedMap.
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    /// Returns true if the OrderedMap contains no key-value pairs.
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    /// Returns copies of the key-value pairs with keys in `range`, in ascending key order.
+    ///
+    /// The pairs are cloned out before the iterator is returned, so it holds no lock and the
+    /// map can be written to while iterating.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> impl DoubleEndedIterator<Item = (K, V)> {
+        self.read()
+            .range(range)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Returns copies of every key-value pair, in ascending key order.
+    ///
+    /// Like [`range`](#method.range), the pairs are cloned out before the iterator is returned.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (K, V)> {
+        self.range(..)
+    }
+
+    /// Returns copies of the keys, in ascending order.
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = K> {
+        self.read().keys().cloned().collect::<Vec<_>>().into_iter()
+    }
+
+    /// Returns a copy of the pair with the smallest key, or None if the map is empty.
+    pub fn first(&self) -> Option<(K, V)> {
+        self.read()
+            .first_key_value()
+            .map(|(key, value)| (key.clone(), value.clone()))
+    }
+
+    /// Returns a copy of the pair with the largest key, or None if the map is empty.
+    pub fn last(&self) -> Option<(K, V)> {
+        self.read()
+            .last_key_value()
+            .map(|(key, value)| (key.clone(), value.clone()))
+    }
+}
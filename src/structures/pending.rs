@@ -0,0 +1,75 @@
+//! Pending write tracking for rustmap-db.
+//!
+//! This module provides `PendingWrites`, which counts the writes the structures of a database
+//! have spawned but not finished yet, so the database can wait for all of them before closing.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use tokio::sync::Notify;
+
+#[derive(Debug, Default)]
+struct Inner {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+/// The number of writes still in flight across the structures sharing this tracker.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PendingWrites(Arc<Inner>);
+
+impl PendingWrites {
+    /// Registers a write, which stays pending until the returned guard is dropped.
+    ///
+    /// Must be called before the write's task is spawned, so a concurrent `idle` can't miss it.
+    pub(crate) fn begin(&self) -> PendingWrite {
+        self.0.count.fetch_add(1, Ordering::SeqCst);
+        PendingWrite(self.0.clone())
+    }
+
+    /// Waits until no writes are pending.
+    pub(crate) async fn idle(&self) {
+        loop {
+            let notified = self.0.idle.notified();
+            if self.0.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// A registered write, finished when dropped.
+#[derive(Debug)]
+pub(crate) struct PendingWrite(Arc<Inner>);
+
+impl Drop for PendingWrite {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod pending_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_idle_waits_for_guards() {
+        let pending = PendingWrites::default();
+        pending.idle().await;
+
+        let guard = pending.begin();
+        let waiter = tokio::spawn({
+            let pending = pending.clone();
+            async move { pending.idle().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+        drop(guard);
+        waiter.await.unwrap();
+    }
+}
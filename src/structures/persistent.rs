@@ -0,0 +1,189 @@
+//! Persistent structure module for rustmap-db.
+//!
+//! This module provides the `PersistentStructure` trait, which captures the file operations
+//! shared by every file-backed structure, along with the helpers the structures use to
+//! implement them.
+
+use std::{
+    collections::{HashMap as StdHashMap, HashSet as StdHashSet},
+    fs::File,
+    sync::{Arc, Mutex},
+};
+
+use crate::{db::db_entry::DBEntry, StructureError};
+
+use super::{
+    lock_file, scan_file,
+    stats::{CompactionEstimate, StructureStats},
+};
+
+/// The operations shared by the file-backed structures, independent of their element types.
+///
+/// The trait is object safe, so code that only maintains structures (reloading, compacting or
+/// inspecting them) can work with any of them through a `&dyn PersistentStructure`.
+pub trait PersistentStructure {
+    /// Returns the id the structure's entries are stored under in the file.
+    fn id(&self) -> &[u8];
+
+    /// Returns the number of elements in the structure.
+    fn len(&self) -> usize;
+
+    /// Returns true if the structure contains no elements.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Discards the in-memory state and replays the structure's entries from the file.
+    ///
+    /// Writes that are still in flight when the file is read are not reflected.
+    fn load_from_file(&self) -> Result<(), StructureError>;
+
+    /// Appends a raw entry to the structure's log without applying it in memory.
+    ///
+    /// The entry takes effect the next time the structure is loaded. Returns
+    /// `StructureError::ForeignEntry` if the entry doesn't write, remove or stamp an element
+    /// of this structure.
+    fn append_entry(&self, entry: &DBEntry) -> Result<(), StructureError>;
+
+    /// Removes every element from the structure and its records from the file.
+    fn clear(&self) -> Result<(), StructureError>;
+
+    /// Rewrites the file keeping only the latest record of each live element of the structure.
+    ///
+    /// Overwritten records and tombstones of the structure are dropped; the records of other
+    /// structures are kept as they are. The file is rewritten under its lock.
+    fn compact(&self) -> Result<(), StructureError>;
+
+    /// Estimates how much space [`compact`](#tymethod.compact) would reclaim, without
+    /// rewriting the file.
+    fn stats(&self) -> Result<CompactionEstimate, StructureError>;
+}
+
+/// The role of a record in the log of the structure being compacted.
+pub(crate) enum Record {
+    /// Writes the element with the given serialized key.
+    Write(Vec<u8>),
+    /// Stamps the preceding write of the key, such as with its time or checksum.
+    Stamp(Vec<u8>),
+    /// Removes the key.
+    Remove(Vec<u8>),
+}
+
+/// Reduces the records `record` recognises to the latest write of each live key, along with
+/// the stamps that follow it.
+///
+/// Entries `record` doesn't recognise, such as those of other structures, are kept. The order
+/// of the remaining entries is preserved.
+pub(crate) fn compact_entries<F>(entries: Vec<DBEntry>, record: F) -> Vec<DBEntry>
+where
+    F: Fn(&DBEntry) -> Option<Record>,
+{
+    let mut live = StdHashMap::new();
+    for (index, entry) in entries.iter().enumerate() {
+        match record(entry) {
+            Some(Record::Write(key)) => {
+                live.insert(key, (index, Vec::new()));
+            }
+            Some(Record::Stamp(key)) => {
+                if let Some((_, stamps)) = live.get_mut(&key) {
+                    stamps.push(index);
+                }
+            }
+            Some(Record::Remove(key)) => {
+                live.remove(&key);
+            }
+            None => {}
+        }
+    }
+    let keep = live
+        .into_values()
+        .flat_map(|(write, stamps)| std::iter::once(write).chain(stamps))
+        .collect::<StdHashSet<_>>();
+    entries
+        .into_iter()
+        .enumerate()
+        .filter(|(index, entry)| keep.contains(index) || record(entry).is_none())
+        .map(|(_, entry)| entry)
+        .collect()
+}
+
+/// Counts the live and dead records `record` recognises, as described on `StructureStats`.
+pub(crate) fn structure_stats<F>(
+    file: &Arc<Mutex<File>>,
+    record: F,
+) -> Result<StructureStats, StructureError>
+where
+    F: Fn(&DBEntry) -> Option<Record>,
+{
+    let mut stats = StructureStats::default();
+    // The number of records and bytes of the latest write of each live key and its stamps.
+    let mut live: StdHashMap<Vec<u8>, (usize, u64)> = StdHashMap::new();
+    scan_file(file, |entry, span| {
+        let len = span.end - span.start;
+        let Some(record) = record(&entry) else {
+            return Ok(());
+        };
+        stats.total_records += 1;
+        stats.disk_bytes += len;
+        match record {
+            Record::Write(key) => {
+                live.insert(key, (1, len));
+            }
+            Record::Stamp(key) => {
+                if let Some((records, bytes)) = live.get_mut(&key) {
+                    *records += 1;
+                    *bytes += len;
+                }
+            }
+            Record::Remove(key) => {
+                live.remove(&key);
+            }
+        }
+        Ok(())
+    })?;
+    stats.live_entries = live.len();
+    let (live_records, live_bytes) = live
+        .values()
+        .fold((0, 0), |(records, bytes), (r, b)| (records + r, bytes + b));
+    stats.dead_records = stats.total_records - live_records;
+    stats.dead_bytes = stats.disk_bytes - live_bytes;
+    Ok(stats)
+}
+
+/// Estimates the effect of `compact_entries` on the file without rewriting it.
+pub(crate) fn estimate_compaction<F>(
+    file: &Arc<Mutex<File>>,
+    record: F,
+) -> Result<CompactionEstimate, StructureError>
+where
+    F: Fn(&DBEntry) -> Option<Record>,
+{
+    let mut kept_bytes = 0;
+    let mut live: StdHashMap<Vec<u8>, u64> = StdHashMap::new();
+    scan_file(file, |entry, span| {
+        let len = span.end - span.start;
+        match record(&entry) {
+            Some(Record::Write(key)) => {
+                live.insert(key, len);
+            }
+            Some(Record::Stamp(key)) => {
+                if let Some(live_len) = live.get_mut(&key) {
+                    *live_len += len;
+                }
+            }
+            Some(Record::Remove(key)) => {
+                live.remove(&key);
+            }
+            None => kept_bytes += len,
+        }
+        Ok(())
+    })?;
+    let current_bytes = lock_file(file)?.metadata()?.len();
+    let estimated_bytes = kept_bytes + live.values().sum::<u64>();
+    Ok(CompactionEstimate {
+        current_bytes,
+        estimated_bytes,
+        reclaimable_bytes: current_bytes.saturating_sub(estimated_bytes),
+        live_entries: live.len(),
+    })
+}
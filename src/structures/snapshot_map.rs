@@ -0,0 +1,160 @@
+//! Snapshot hashmap module for rustmap-db.
+//!
+//! This module provides `SnapshotHashMap`, a file-backed map for read-heavy workloads. Its
+//! contents are an immutable map behind an atomically swapped pointer: readers load the current
+//! version without taking any lock, and writers publish a modified copy. Memory of replaced
+//! versions is reclaimed through epoch-based garbage collection once no reader can see them.
+
+use crossbeam_epoch::{self as epoch, Atomic, Owned};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap as StdHashMap,
+    fs::File,
+    hash::Hash,
+    sync::{atomic::Ordering, Arc, Mutex},
+};
+use tokio::task::JoinHandle;
+
+use crate::{db::db_entry::DBEntry, StructureError};
+
+use super::{encode_id, load_map_entries, serialize_to_file, RetryPolicy};
+
+/// A file-backed hashmap with lock-free reads.
+///
+/// Every write copies the whole map, so writes cost O(n) while reads never contend with
+/// each other or with writers. Writers are serialized among themselves, and persist their
+/// changes by appending to the log in a background task like `HashMap`, whose file format it
+/// shares.
+#[derive(Debug)]
+pub struct SnapshotHashMap<K, V> {
+    current: Atomic<Arc<StdHashMap<K, V>>>,
+    writer: Mutex<()>,
+    file: Arc<Mutex<File>>,
+    id: Vec<u8>,
+}
+
+impl<K, V> SnapshotHashMap<K, V>
+where
+    K: Serialize + for<'de> Deserialize<'de> + Hash + Eq + Clone + Send + Sync + 'static,
+    V: Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+{
+    /// Creates a new SnapshotHashMap, loading its contents from the file.
+    pub fn new(file: Arc<Mutex<File>>, id: Vec<u8>) -> Result<Self, StructureError> {
+        let id = encode_id(&id)?;
+        let map = load_map_entries(&file, &id)?;
+        Ok(Self {
+            current: Atomic::new(Arc::new(map)),
+            writer: Mutex::new(()),
+            file,
+            id,
+        })
+    }
+
+    /// Returns the current version of the map.
+    ///
+    /// The snapshot is immutable: later writes publish new versions and never change it.
+    pub fn snapshot(&self) -> Arc<StdHashMap<K, V>> {
+        let guard = epoch::pin();
+        let current = self.current.load(Ordering::Acquire, &guard);
+        // SAFETY: `current` is never null, and a version is only destroyed through
+        // `defer_destroy` after it was replaced, so it stays valid while `guard` is pinned.
+        unsafe { current.deref() }.clone()
+    }
+
+    /// Returns a copy of the value corresponding to the given key, without taking any lock.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let guard = epoch::pin();
+        let current = self.current.load(Ordering::Acquire, &guard);
+        // SAFETY: see `snapshot`.
+        unsafe { current.deref() }.get(key).cloned()
+    }
+
+    /// Returns true if the map contains the given key.
+    pub fn contains_key(&self, key: &K) -> bool {
+        let guard = epoch::pin();
+        let current = self.current.load(Ordering::Acquire, &guard);
+        // SAFETY: see `snapshot`.
+        unsafe { current.deref() }.contains_key(key)
+    }
+
+    /// Returns the number of key-value pairs in the map.
+    pub fn len(&self) -> usize {
+        self.snapshot().len()
+    }
+
+    /// Returns true if the map contains no key-value pairs.
+    pub fn is_empty(&self) -> bool {
+        self.snapshot().is_empty()
+    }
+
+    /// Inserts a key-value pair, publishing a new version of the map.
+    ///
+    /// Returns a JoinHandle with a Result containing the old value (None if new) if the operation was successful.
+    pub fn insert(&self, key: K, value: V) -> JoinHandle<Result<Option<V>, StructureError>> {
+        let old_value = self.publish(|map| map.insert(key.clone(), value.clone()));
+        let file = self.file.clone();
+        let id = self.id.clone();
+        tokio::task::spawn_blocking(move || {
+            let old_value = old_value?;
+            let key = bincode::serialize(&key)?;
+            let value = bincode::serialize(&value)?;
+            serialize_to_file(
+                &DBEntry::HashMapEntry(id, key, value),
+                &file,
+                RetryPolicy::default(),
+            )?;
+            Ok(old_value)
+        })
+    }
+
+    /// Removes a key, publishing a new version of the map if it was present.
+    ///
+    /// Returns None if the key did not exist.
+    pub fn remove(&self, key: &K) -> Option<JoinHandle<Result<Option<V>, StructureError>>> {
+        if !self.contains_key(key) {
+            return None;
+        }
+        let old_value = self.publish(|map| map.remove(key));
+        let file = self.file.clone();
+        let id = self.id.clone();
+        let key = bincode::serialize(key);
+        Some(tokio::task::spawn_blocking(move || {
+            let old_value = old_value?;
+            serialize_to_file(
+                &DBEntry::RemoveHashMapEntry(id, key?),
+                &file,
+                RetryPolicy::default(),
+            )?;
+            Ok(old_value)
+        }))
+    }
+
+    /// Applies `f` to a copy of the current version and publishes the copy.
+    fn publish<T>(&self, f: impl FnOnce(&mut StdHashMap<K, V>) -> T) -> Result<T, StructureError> {
+        let _writer = self
+            .writer
+            .lock()
+            .map_err(|_| StructureError::MutexLockError)?;
+        let guard = epoch::pin();
+        let current = self.current.load(Ordering::Acquire, &guard);
+        // SAFETY: see `snapshot`.
+        let mut map = StdHashMap::clone(unsafe { current.deref() });
+        let result = f(&mut map);
+        let old = self
+            .current
+            .swap(Owned::new(Arc::new(map)), Ordering::AcqRel, &guard);
+        // SAFETY: `old` was just unlinked, so no reader that pins after this can load it.
+        unsafe { guard.defer_destroy(old) };
+        Ok(result)
+    }
+}
+
+impl<K, V> Drop for SnapshotHashMap<K, V> {
+    fn drop(&mut self) {
+        // SAFETY: `&mut self` guarantees that no reader holds a reference into the map.
+        unsafe {
+            let current = self.current.load(Ordering::Relaxed, epoch::unprotected());
+            drop(current.into_owned());
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Memory limits for rustmap-db hashmaps.
+//!
+//! A hashmap configured with `max_in_memory` keeps at most that many values in memory. This
+//! module provides `Spill`, which evicts the least recently used values once there are more,
+//! and indexes the offset of the latest record of each key, so an evicted value can be read
+//! back from the log without scanning it.
+
+use std::{
+    collections::HashMap as StdHashMap,
+    hash::Hash,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use dashmap::DashMap;
+
+use super::eviction::EvictionOrder;
+
+/// The values a hashmap holds in memory, and where their latest records are in the log.
+#[derive(Debug)]
+pub(crate) struct Spill<K> {
+    max_in_memory: usize,
+    state: Mutex<SpillState<K>>,
+}
+
+impl<K: Hash + Eq + Clone> Spill<K> {
+    pub(crate) fn new(max_in_memory: usize) -> Self {
+        Self {
+            max_in_memory,
+            state: Mutex::new(SpillState::default()),
+        }
+    }
+
+    /// Creates an empty index with the same limit as this one.
+    pub(crate) fn sibling(&self) -> Self {
+        Self::new(self.max_in_memory)
+    }
+
+    /// Locks the index, which is left consistent by every critical section.
+    ///
+    /// Loads hold the lock while they move a value into memory, so a write to the key can't
+    /// slip between the read and the move.
+    pub(crate) fn lock(&self) -> MutexGuard<'_, SpillState<K>> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Records a write of `key` that is about to change its value in memory.
+    ///
+    /// Until the returned `SpillWrite` is dropped, once the write reached the file, the key's
+    /// value isn't in the log, so it is kept in memory.
+    pub(crate) fn write(self: &Arc<Self>, key: &K) -> SpillWrite<K> {
+        let mut state = self.lock();
+        let token = state.next_token;
+        state.next_token += 1;
+        state.offsets.remove(key);
+        state.pending.insert(key.clone(), token);
+        state.order.push(key.clone());
+        SpillWrite {
+            spill: self.clone(),
+            key: key.clone(),
+            token,
+            offset: None,
+        }
+    }
+
+    /// Records that the write holding `token` wrote `key` at `offset`, or elsewhere than in a
+    /// record of the log if `offset` is None. Does nothing if a later write of the key, or its
+    /// removal, superseded it.
+    fn written(&self, key: &K, token: u64, offset: Option<u64>) {
+        let mut state = self.lock();
+        if state.pending.get(key) != Some(&token) {
+            return;
+        }
+        state.pending.remove(key);
+        if let Some(offset) = offset {
+            state.offsets.insert(key.clone(), offset);
+        }
+    }
+
+    /// Records a read of `key`, which makes it the most recently used value.
+    ///
+    /// Must not be called while holding a reference into the map, like `Eviction::touch`.
+    pub(crate) fn touch(&self, key: &K) {
+        let mut state = self.lock();
+        if state.order.contains(key) {
+            state.order.push(key.clone());
+        }
+    }
+
+    /// Forgets a key that was removed from the map.
+    pub(crate) fn forget(&self, key: &K) {
+        let mut state = self.lock();
+        state.order.remove(key);
+        state.offsets.remove(key);
+        state.pending.remove(key);
+    }
+
+    /// Forgets every key.
+    pub(crate) fn reset(&self) {
+        *self.lock() = SpillState::default();
+    }
+
+    /// Evicts the least recently used values of `inner` until it holds at most
+    /// `max_in_memory`, as far as it can.
+    ///
+    /// Only values whose latest write is in the log are evicted. Each evicted key is passed to
+    /// `evicted` with the offset of its record, while its shard is still locked, so the key is
+    /// never absent from both. The shards are only locked if they are free, so this never
+    /// waits for a reference into the map; a value whose shard is busy stays in memory until
+    /// the next eviction. `keep`, such as a value that was just read back, isn't evicted.
+    pub(crate) fn evict<V>(
+        &self,
+        inner: &DashMap<K, V>,
+        keep: Option<&K>,
+        mut evicted: impl FnMut(K, u64),
+    ) {
+        let excess = inner.len().saturating_sub(self.max_in_memory);
+        if excess == 0 {
+            return;
+        }
+        let mut state = self.lock();
+        let candidates = state
+            .order
+            .iter()
+            .filter(|key| Some(*key) != keep)
+            .filter_map(|key| Some((key.clone(), *state.offsets.get(key)?)))
+            .take(excess)
+            .collect::<Vec<_>>();
+        for (key, offset) in candidates {
+            let Some(mut shard) = inner.shards()[inner.determine_map(&key)].try_write() else {
+                continue;
+            };
+            if shard.contains_key(&key) {
+                evicted(key.clone(), offset);
+                shard.remove(&key);
+            }
+            // Keys removed from the map since they were indexed are dropped as well.
+            state.order.remove(&key);
+        }
+    }
+}
+
+/// A write of a key whose value was changed in memory, which reports where it logged the value
+/// when it is dropped.
+pub(crate) struct SpillWrite<K: Hash + Eq + Clone> {
+    spill: Arc<Spill<K>>,
+    key: K,
+    token: u64,
+    offset: Option<u64>,
+}
+
+impl<K: Hash + Eq + Clone> SpillWrite<K> {
+    /// Records that the write appended the key's value in full at `offset`. A write that
+    /// doesn't record one, because it failed or stored the value in the sidecar file, keeps
+    /// the value in memory.
+    pub(crate) fn logged_at(&mut self, offset: u64) {
+        self.offset = Some(offset);
+    }
+}
+
+impl<K: Hash + Eq + Clone> Drop for SpillWrite<K> {
+    fn drop(&mut self) {
+        self.spill.written(&self.key, self.token, self.offset);
+    }
+}
+
+/// The index of a hashmap's values.
+#[derive(Debug)]
+pub(crate) struct SpillState<K> {
+    /// The keys whose values are in memory, least recently used first.
+    order: EvictionOrder<K>,
+    /// The offset of the latest record of each key, once it is in the log, whether its value
+    /// is in memory or was evicted.
+    offsets: StdHashMap<K, u64>,
+    /// The token of the latest write of each key that hasn't reached the file yet.
+    pending: StdHashMap<K, u64>,
+    next_token: u64,
+}
+
+impl<K> Default for SpillState<K> {
+    fn default() -> Self {
+        Self {
+            order: EvictionOrder::default(),
+            offsets: StdHashMap::new(),
+            pending: StdHashMap::new(),
+            next_token: 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> SpillState<K> {
+    /// Returns the offset of the latest record of `key`, if it is known.
+    pub(crate) fn offset(&self, key: &K) -> Option<u64> {
+        self.offsets.get(key).copied()
+    }
+
+    /// Records that the value of `key` was moved into memory from its record, which was
+    /// looked up at `expected` and found at `offset`.
+    ///
+    /// The offset is only changed if it is still `expected`, since a write of the key that
+    /// raced with the load records its own.
+    pub(crate) fn loaded(&mut self, key: &K, expected: u64, offset: u64) {
+        self.order.push(key.clone());
+        if self.pending.contains_key(key) {
+            return;
+        }
+        match self.offsets.get_mut(key) {
+            Some(current) if *current == expected => *current = offset,
+            Some(_) => {}
+            None => {
+                self.offsets.insert(key.clone(), offset);
+            }
+        }
+    }
+
+    /// Records that replaying the log found the latest write of `key` at `offset`.
+    pub(crate) fn replayed(&mut self, key: &K, offset: u64) {
+        self.pending.remove(key);
+        self.offsets.insert(key.clone(), offset);
+        self.order.push(key.clone());
+    }
+
+    /// Moves the record of `key` to `offset` after the log was rewritten, if its latest write
+    /// reached the file before the rewrite.
+    pub(crate) fn moved(&mut self, key: &K, offset: u64) {
+        if let Some(current) = self.offsets.get_mut(key) {
+            *current = offset;
+        }
+    }
+}
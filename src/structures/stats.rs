@@ -0,0 +1,116 @@
+//! Statistics types for rustmap-db structures.
+//!
+//! This module defines the reports returned by the structures' introspection methods,
+//! such as the estimate of how much space a compaction would reclaim.
+
+use std::{path::PathBuf, time::Duration};
+
+use crate::{Durability, EvictionPolicy, RecoveryMode, SerializationFormat, UnknownEntryPolicy};
+
+/// An estimate of the effect of compacting a structure, produced without rewriting the file.
+///
+/// Returned by `HashMap::compaction_estimate`. Compaction keeps the latest record of every
+/// live key of the structure, drops overwritten records and tombstones, and leaves the
+/// records of other structures untouched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    /// The current size of the file in bytes.
+    pub current_bytes: u64,
+    /// The estimated size of the file in bytes after compaction.
+    pub estimated_bytes: u64,
+    /// The number of bytes compaction would reclaim.
+    pub reclaimable_bytes: u64,
+    /// The number of live entries the structure would keep.
+    pub live_entries: usize,
+}
+
+/// How a structure's records are spread over the log, live or dead.
+///
+/// Returned by `HashMap::stats`. The numbers are derived from a scan of the log: a write of a
+/// key is dead once a later record writes or removes the key, a tombstone is always dead, and
+/// a stamp, such as a timestamp or checksum, shares the fate of the write it follows. These are
+/// the records compaction drops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StructureStats {
+    /// The number of keys the log leaves live.
+    pub live_entries: usize,
+    /// The number of records of the structure in the log.
+    pub total_records: usize,
+    /// The number of records that are dead.
+    pub dead_records: usize,
+    /// The number of bytes the structure's records take up in the file.
+    pub disk_bytes: u64,
+    /// The number of bytes the dead records take up in the file.
+    pub dead_bytes: u64,
+}
+
+impl StructureStats {
+    /// Returns true if the structure's records take up more than `ratio` times the space of
+    /// its live records, so compacting it is worthwhile.
+    pub fn needs_compaction(&self, ratio: f64) -> bool {
+        let live_bytes = self.disk_bytes - self.dead_bytes;
+        self.disk_bytes > 0 && self.disk_bytes as f64 > live_bytes as f64 * ratio
+    }
+}
+
+/// The outcome of `HashMap::insert_batch_summary`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchSummary<V> {
+    /// The number of keys that weren't in the map before the batch.
+    pub inserted: usize,
+    /// The number of keys whose value the batch replaced.
+    pub updated: usize,
+    /// The old value of each entry in the batch, in order (None if new).
+    pub old_values: Vec<Option<V>>,
+}
+
+/// The settings a `HashMap` is running with, after defaults have been applied.
+///
+/// Returned by `HashMap::config`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EffectiveConfig {
+    /// The number of shards of the in-memory map.
+    pub shard_amount: usize,
+    /// The initial capacity the in-memory map was created with.
+    pub capacity: usize,
+    /// The maximum number of entries written at once by the batch operations.
+    pub batch_chunk_size: usize,
+    /// Whether a type fingerprint is recorded in the file.
+    pub type_fingerprint: bool,
+    /// How unknown extension entries are treated while loading.
+    pub unknown_entries: UnknownEntryPolicy,
+    /// How unreadable records in the middle of the file are treated while loading.
+    pub recovery: RecoveryMode,
+    /// Whether writes are synced to disk before they complete.
+    pub durability: Durability,
+    /// Whether the time of every insert is recorded in the file.
+    pub timestamps: bool,
+    /// Whether inserts overwrite same-length records in place.
+    pub overwrite_in_place: bool,
+    /// The size above which values are stored in the sidecar file, if any.
+    pub large_value_threshold: Option<usize>,
+    /// The directory of the sidecar value file, if any.
+    pub large_value_dir: Option<PathBuf>,
+    /// How many times a write is retried after a transient I/O error.
+    pub write_retries: u32,
+    /// The delay before the first retry of a failed write.
+    pub retry_backoff: Duration,
+    /// The largest serialized value the inserts accept, if limited.
+    pub max_value_bytes: Option<usize>,
+    /// Whether keys are written in the canonical encoding.
+    pub canonical_keys: bool,
+    /// The format keys and values are serialized in.
+    pub format: SerializationFormat,
+    /// The serialized size above which keys are written as aliases, if any.
+    pub hash_keys_above: Option<usize>,
+    /// How many regions of the file are read at once while loading.
+    pub load_concurrency: usize,
+    /// The most entries the map holds, if limited.
+    pub max_entries: Option<usize>,
+    /// What an insert past `max_entries` does.
+    pub eviction: EvictionPolicy,
+    /// Whether removals of keys whose insert wasn't written yet skip their tombstone.
+    pub skip_unwritten_tombstones: bool,
+    /// Whether values written to the log are followed by a checksum.
+    pub checksums: bool,
+}
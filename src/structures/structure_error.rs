@@ -23,9 +23,159 @@ pub enum StructureError {
     #[error("Bincode Error {0}")]
     BinCodeError(#[from] bincode::Error),
 
+    /// An error that occurs during JSON serialization or deserialization of the keys and
+    /// values of a structure using `SerializationFormat::Json`.
+    #[error("JSON Error {0}")]
+    JsonError(#[from] serde_json::Error),
+
     /// An error that occurs when a mutex lock could not be acquired. This typically
     /// indicates that another thread panicked while holding the lock or that the
     /// lock is somehow poisoned.
     #[error("Mutex Lock Error")]
     MutexLockError,
+
+    /// An error that occurs when a structure is opened with different key/value types than
+    /// it was created with. `expected` is the fingerprint of the types used to open the
+    /// structure and `found` is the fingerprint recorded in the file.
+    #[error("Type Mismatch: expected fingerprint {expected:#018x}, found {found:#018x}")]
+    TypeMismatch { expected: u64, found: u64 },
+
+    /// An error that occurs when a file that was expected to be a rustmap-db database
+    /// doesn't contain a valid sequence of database entries.
+    #[error("Not a rustmap-db file")]
+    NotARustmapFile,
+
+    /// An error that occurs when a structure configured to reject unknown entries finds an
+    /// extension entry it doesn't recognise, typically one written by a newer version.
+    #[error("Unknown entry with tag {0}")]
+    UnknownEntry(u8),
+
+    /// An error that occurs when a hashmap has values stored in a sidecar file, but was
+    /// opened without a `large_value_dir` to read them from.
+    #[error("Large value directory required")]
+    LargeValueDirRequired,
+
+    /// An error that occurs when a background write task panicked or was cancelled before
+    /// it could report its result.
+    #[error("Join Error: {0}")]
+    JoinError(#[from] tokio::task::JoinError),
+
+    /// An error that occurs when the in-memory state of a structure doesn't match what
+    /// replaying its file produces. Holds the debug representation of each divergent key.
+    #[error("Not persisted: {} divergent keys ({})", .0.len(), .0.join(", "))]
+    NotPersisted(Vec<String>),
+
+    /// An error that occurs when a structure is renamed to an id that is already used by
+    /// another structure in the database.
+    #[error("Structure already exists: {0}")]
+    StructureExists(String),
+
+    /// An error that occurs when a raw entry appended to a structure's log doesn't write,
+    /// remove or stamp an element of that structure.
+    #[error("Entry belongs to another structure")]
+    ForeignEntry,
+
+    /// An error that occurs when a value serializes to more bytes than the structure's
+    /// `max_value_bytes` setting allows.
+    #[error("Value too large: {size} bytes exceeds the limit of {limit} bytes")]
+    ValueTooLarge { size: usize, limit: usize },
+
+    /// An error that occurs when a record refers to a key alias that no earlier entry defines,
+    /// typically because a map is opened with a different `hash_keys_above` setting than it was
+    /// written with.
+    #[error("Unknown key alias")]
+    UnknownKeyAlias,
+
+    /// An error that occurs when an insert would add a key to a map already holding its
+    /// `max_entries`, and the map's eviction policy is `RejectNew`.
+    #[error("Map is full: it already holds its limit of {limit} entries")]
+    MapFull { limit: usize },
+
+    /// An error that occurs when a value read from the file doesn't match the checksum
+    /// recorded with it, so its bytes were corrupted on disk.
+    #[error("Checksum mismatch: expected {expected:#010x}, found {found:#010x}")]
+    ChecksumMismatch { expected: u32, found: u32 },
+
+    /// An error that occurs when a checksum-verified read finds a record without a checksum.
+    #[error("No checksum is recorded for the value")]
+    ChecksumMissing,
+
+    /// An error that occurs when a write queued on a database's writer never completed,
+    /// because its append panicked or the writer stopped.
+    #[error("Writer stopped before completing the write")]
+    WriterStopped,
+
+    /// An error that occurs when writing to a structure of a database opened read-only.
+    #[error("Database is read-only")]
+    ReadOnly,
+
+    /// An error that occurs when a value evicted from memory by a map's `max_in_memory` can't
+    /// be read back, because the log no longer holds its record, typically after another
+    /// handle rewrote the log without it.
+    #[error("The record of an evicted value is missing from the log")]
+    RecordMissing,
+
+    /// An error that occurs when a database is opened on a file that doesn't start with a
+    /// rustmap-db file header or entry, so it was not written by rustmap-db.
+    #[error("Bad magic number: not a rustmap-db database file")]
+    BadMagic,
+
+    /// An error that occurs when a database file records a version of the file format newer
+    /// than this version of rustmap-db supports.
+    #[error("Unsupported file format version {0}")]
+    UnsupportedVersion(u32),
+}
+
+impl StructureError {
+    /// Creates a copy of the error, for reporting it in more than one place.
+    ///
+    /// Errors wrapping an `io::Error`, bincode error or `JoinError` aren't `Clone`, so they are
+    /// copied with the same kind and message but without their source.
+    pub(crate) fn duplicate(&self) -> StructureError {
+        match self {
+            StructureError::IoError(e) => {
+                StructureError::IoError(std::io::Error::new(e.kind(), e.to_string()))
+            }
+            StructureError::BinCodeError(e) => {
+                StructureError::BinCodeError(Box::new(bincode::ErrorKind::Custom(e.to_string())))
+            }
+            StructureError::JsonError(e) => {
+                StructureError::JsonError(serde::de::Error::custom(e.to_string()))
+            }
+            StructureError::MutexLockError => StructureError::MutexLockError,
+            StructureError::TypeMismatch { expected, found } => StructureError::TypeMismatch {
+                expected: *expected,
+                found: *found,
+            },
+            StructureError::NotARustmapFile => StructureError::NotARustmapFile,
+            StructureError::UnknownEntry(tag) => StructureError::UnknownEntry(*tag),
+            StructureError::LargeValueDirRequired => StructureError::LargeValueDirRequired,
+            StructureError::JoinError(e) => {
+                StructureError::IoError(std::io::Error::other(e.to_string()))
+            }
+            StructureError::NotPersisted(keys) => StructureError::NotPersisted(keys.clone()),
+            StructureError::StructureExists(id) => StructureError::StructureExists(id.clone()),
+            StructureError::ForeignEntry => StructureError::ForeignEntry,
+            StructureError::ValueTooLarge { size, limit } => StructureError::ValueTooLarge {
+                size: *size,
+                limit: *limit,
+            },
+            StructureError::UnknownKeyAlias => StructureError::UnknownKeyAlias,
+            StructureError::MapFull { limit } => StructureError::MapFull { limit: *limit },
+            StructureError::ChecksumMismatch { expected, found } => {
+                StructureError::ChecksumMismatch {
+                    expected: *expected,
+                    found: *found,
+                }
+            }
+            StructureError::ChecksumMissing => StructureError::ChecksumMissing,
+            StructureError::WriterStopped => StructureError::WriterStopped,
+            StructureError::ReadOnly => StructureError::ReadOnly,
+            StructureError::RecordMissing => StructureError::RecordMissing,
+            StructureError::BadMagic => StructureError::BadMagic,
+            StructureError::UnsupportedVersion(version) => {
+                StructureError::UnsupportedVersion(*version)
+            }
+        }
+    }
 }
@@ -10,8 +10,17 @@ use std::hash::Hash;
 /// `ValueRef` is a wrapper around a reference to a value in the map, providing a way
 /// to read values without taking ownership of them. This is useful when you want to inspect
 /// values stored in the map without affecting their state or ownership.
+///
+/// It either refers to a single entry looked up by key, or to an entry reached while iterating
+/// over the map. In both cases it holds a read lock on the entry's shard until it is dropped.
 pub struct ValueRefPair<'a, K, V> {
-    inner: dashmap::mapref::one::Ref<'a, K, V>,
+    inner: PairRef<'a, K, V>,
+}
+
+/// The dashmap reference a `ValueRefPair` wraps.
+enum PairRef<'a, K, V> {
+    One(dashmap::mapref::one::Ref<'a, K, V>),
+    Multi(dashmap::mapref::multiple::RefMulti<'a, K, V>),
 }
 
 impl<'a, K, V> ValueRefPair<'a, K, V>
@@ -24,28 +33,40 @@ where
     ///
     /// * `inner` - A reference to the key-value pair.
     pub fn new(inner: dashmap::mapref::one::Ref<'a, K, V>) -> Self {
-        Self { inner }
+        Self {
+            inner: PairRef::One(inner),
+        }
+    }
+
+    /// Creates a new `ValueRef` from a key-value pair reached while iterating over the map.
+    pub(crate) fn from_multi(inner: dashmap::mapref::multiple::RefMulti<'a, K, V>) -> Self {
+        Self {
+            inner: PairRef::Multi(inner),
+        }
     }
 
     /// Returns a reference to the value.
     ///
     /// This method allows you to read the value associated with the key without cloning it.
     pub fn value(&self) -> &V {
-        self.inner.value()
+        self.pair().1
     }
 
     /// Returns a reference to the key.
     ///
     /// This method allows you to read the key associated with the value.
     pub fn key(&self) -> &K {
-        self.inner.key()
+        self.pair().0
     }
 
     /// Returns a reference to the key-value pair.
     ///
     /// This method allows you to access both the key and the value without taking ownership.
     pub fn pair(&self) -> (&K, &V) {
-        self.inner.pair()
+        match &self.inner {
+            PairRef::One(inner) => inner.pair(),
+            PairRef::Multi(inner) => inner.pair(),
+        }
     }
 
     /// Consumes the `ValueRef`, returning the owned key-value pair.
@@ -57,7 +78,8 @@ where
         K: Clone,
         V: Clone,
     {
-        (self.inner.key().clone(), self.inner.value().clone())
+        let (key, value) = self.pair();
+        (key.clone(), value.clone())
     }
 }
 
@@ -0,0 +1,399 @@
+//! Ordered writes for rustmap-db.
+//!
+//! This module provides `Writer`, the single background writer of a database. The structures
+//! opened through a database queue their appends to it instead of appending from their own
+//! spawned tasks, so the log records each structure's writes in the order they were applied in
+//! memory.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+};
+
+use tokio::sync::{
+    oneshot::{self, error::TryRecvError},
+    OwnedSemaphorePermit, Semaphore,
+};
+
+use crate::StructureError;
+
+/// An append waiting for its turn on the writer.
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The queue of a database's appends, run one at a time on a dedicated thread.
+///
+/// Every append carries a sequence number, taken when its write reserves its place, and the
+/// writer thread runs the appends in that order whatever order they arrive in.
+#[derive(Debug)]
+pub(crate) struct Writer {
+    queue: mpsc::Sender<(u64, Job)>,
+    next: Arc<AtomicU64>,
+    order: Mutex<()>,
+    read_only: bool,
+}
+
+impl Writer {
+    /// Starts the writer thread, which runs until every handle to the writer is dropped and
+    /// the queue is drained.
+    pub(crate) fn start() -> std::io::Result<Self> {
+        let (queue, jobs) = mpsc::channel::<(u64, Job)>();
+        thread::Builder::new()
+            .name("rustmap-db-writer".to_string())
+            .spawn(move || {
+                let mut arrived = BTreeMap::new();
+                let mut next = 0;
+                for (sequence, job) in jobs {
+                    arrived.insert(sequence, job);
+                    while let Some(job) = arrived.remove(&next) {
+                        // A panicking append drops its result sender, which fails only that write.
+                        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                        next += 1;
+                    }
+                }
+            })?;
+        Ok(Self {
+            queue,
+            next: Arc::new(AtomicU64::new(0)),
+            order: Mutex::new(()),
+            read_only: false,
+        })
+    }
+
+    /// Returns a writer for a single structure, appending through the same queue.
+    ///
+    /// Its reservations only wait for the other writes of that structure, so changing one
+    /// structure in memory never waits for a write to another.
+    pub(crate) fn for_structure(&self) -> Self {
+        Self {
+            queue: self.queue.clone(),
+            next: self.next.clone(),
+            order: Mutex::new(()),
+            read_only: self.read_only,
+        }
+    }
+
+    /// Makes the writer refuse every write, for a database opened read-only.
+    pub(crate) fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Returns `StructureError::ReadOnly` if the database was opened read-only.
+    pub(crate) fn check_writable(&self) -> Result<(), StructureError> {
+        match self.read_only {
+            true => Err(StructureError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    /// Reserves the next place in the write order.
+    ///
+    /// Writers hold the slot while they apply their change in memory and submit its append
+    /// through it, so a structure's appends run in the same order as its in-memory changes.
+    /// Only writes through this writer wait for the slot. The append takes its sequence number
+    /// when it's submitted, so a write to another structure that waits on this one's in-memory
+    /// change neither blocks it nor is blocked by it.
+    /// Fails with `StructureError::ReadOnly` if the database was opened read-only.
+    ///
+    /// A writer that panics while holding its slot leaves the queue as it was, so a poisoned
+    /// lock is recovered rather than failing every later write.
+    pub(crate) fn reserve(&self) -> Result<Slot<'_>, StructureError> {
+        self.check_writable()?;
+        Ok(Slot {
+            writer: self,
+            _order: self.order.lock().unwrap_or_else(PoisonError::into_inner),
+            permit: None,
+        })
+    }
+
+    /// Takes the next sequence number of the database's write order.
+    fn place(&self) -> Place {
+        Place {
+            sequence: self.next.fetch_add(1, Ordering::Relaxed),
+            queue: self.queue.clone(),
+            filled: false,
+        }
+    }
+}
+
+/// A reserved place in a structure's write order.
+pub(crate) struct Slot<'a> {
+    writer: &'a Writer,
+    _order: MutexGuard<'a, ()>,
+    permit: Option<QueuePermit>,
+}
+
+/// A sequence number of the write order, given an empty append if it's dropped without one so
+/// the appends after it still run.
+struct Place {
+    sequence: u64,
+    queue: mpsc::Sender<(u64, Job)>,
+    filled: bool,
+}
+
+impl Place {
+    fn fill(mut self, job: Job) -> Result<(), StructureError> {
+        self.filled = true;
+        self.queue
+            .send((self.sequence, job))
+            .map_err(|_| StructureError::WriterStopped)
+    }
+}
+
+impl Drop for Place {
+    fn drop(&mut self) {
+        if !self.filled {
+            let _ = self.queue.send((self.sequence, Box::new(|| ())));
+        }
+    }
+}
+
+impl Slot<'_> {
+    /// Counts the append submitted through this slot against a queue bound until it starts.
+    pub(crate) fn with_permit(mut self, permit: Option<QueuePermit>) -> Self {
+        self.permit = permit;
+        self
+    }
+
+    /// Queues `append` in the slot's place, returning a future of its result.
+    ///
+    /// If the slot's permit is still waiting for room in its queue, the append is handed to the
+    /// writer once the future has waited for it, and the appends after it wait meanwhile.
+    fn submit<T, A>(
+        self,
+        append: A,
+    ) -> impl Future<Output = Result<T, StructureError>> + Send + 'static
+    where
+        T: Send + 'static,
+        A: FnOnce() -> Result<T, StructureError> + Send + 'static,
+    {
+        let (result, receiver) = oneshot::channel();
+        let job = move |permit: Option<OwnedSemaphorePermit>| -> Job {
+            Box::new(move || {
+                drop(permit);
+                let _ = result.send(append());
+            })
+        };
+        let place = self.writer.place();
+        let queued = match self.permit {
+            Some(QueuePermit::Waiting(turn)) => Err((place, turn, job)),
+            Some(QueuePermit::Taken(permit)) => Ok(place.fill(job(Some(permit)))),
+            None => Ok(place.fill(job(None))),
+        };
+        async move {
+            match queued {
+                Ok(filled) => filled?,
+                Err((place, turn, job)) => {
+                    let permit = turn.wait().await?;
+                    place.fill(job(Some(permit)))?;
+                }
+            }
+            receiver.await.map_err(|_| StructureError::WriterStopped)?
+        }
+    }
+}
+
+/// A bound on the number of a structure's appends queued on its writer but not started yet.
+///
+/// Writers take a permit while they hold their slot. When the queue is full the permit waits
+/// for one of the queued appends to start, in the future of the write rather than on the
+/// calling thread, and the permits are handed out in the order they were taken, which is the
+/// structure's write order. The writer runs on its own thread, so the queued appends start
+/// whether or not the runtime of the waiting tasks makes progress.
+#[derive(Debug, Clone)]
+pub(crate) struct WriteQueue(Arc<Bound>);
+
+#[derive(Debug)]
+struct Bound {
+    depth: usize,
+    permits: Arc<Semaphore>,
+    last_turn: Mutex<Option<oneshot::Receiver<()>>>,
+}
+
+impl WriteQueue {
+    /// Creates a bound of `depth` queued appends.
+    pub(crate) fn new(depth: usize) -> Self {
+        Self(Arc::new(Bound {
+            depth,
+            permits: Arc::new(Semaphore::new(depth)),
+            last_turn: Mutex::new(None),
+        }))
+    }
+
+    /// Takes a place in the queue without waiting, or a turn to wait for one after every place
+    /// taken before it if the queue is full.
+    pub(crate) fn acquire(&self) -> QueuePermit {
+        let mut last_turn = self
+            .0
+            .last_turn
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let earlier_waiting = last_turn
+            .as_mut()
+            .is_some_and(|turn| matches!(turn.try_recv(), Err(TryRecvError::Empty)));
+        if !earlier_waiting {
+            if let Ok(permit) = self.0.permits.clone().try_acquire_owned() {
+                *last_turn = None;
+                return QueuePermit::Taken(permit);
+            }
+        }
+        let (taken, turn) = oneshot::channel();
+        QueuePermit::Waiting(Turn {
+            previous: last_turn.replace(turn),
+            taken,
+            permits: self.0.permits.clone(),
+        })
+    }
+
+    /// Returns the number of appends queued but not started yet.
+    pub(crate) fn len(&self) -> usize {
+        self.0.depth - self.0.permits.available_permits()
+    }
+}
+
+/// A place in a `WriteQueue`, given back when the append it was taken for starts.
+#[derive(Debug)]
+pub(crate) enum QueuePermit {
+    Taken(OwnedSemaphorePermit),
+    Waiting(Turn),
+}
+
+/// A wait for a place in a full `WriteQueue`, after the waits that came before it.
+#[derive(Debug)]
+pub(crate) struct Turn {
+    previous: Option<oneshot::Receiver<()>>,
+    taken: oneshot::Sender<()>,
+    permits: Arc<Semaphore>,
+}
+
+impl Turn {
+    async fn wait(self) -> Result<OwnedSemaphorePermit, StructureError> {
+        if let Some(previous) = self.previous {
+            // A dropped turn gives up its place, so an error also means it's our turn.
+            let _ = previous.await;
+        }
+        let permit = self
+            .permits
+            .acquire_owned()
+            .await
+            .map_err(|_| StructureError::WriterStopped)?;
+        let _ = self.taken.send(());
+        Ok(permit)
+    }
+}
+
+/// Appends a write's entries with `append`, in the order of `slot` if there is one.
+///
+/// With a slot the append is queued on the database's writer immediately, unless it has to
+/// wait for room in a write queue, and the returned future waits for it to run. Without one the
+/// append runs on tokio's blocking thread pool when the future is polled, so its file I/O
+/// doesn't stall the other tasks of the runtime.
+pub(crate) fn ordered<T, A>(
+    slot: Option<Slot<'_>>,
+    append: A,
+) -> impl Future<Output = Result<T, StructureError>> + Send + 'static
+where
+    T: Send + 'static,
+    A: FnOnce() -> Result<T, StructureError> + Send + 'static,
+{
+    enum Append<Q, A> {
+        Queued(Q),
+        Direct(A),
+    }
+    let append = match slot {
+        Some(slot) => Append::Queued(slot.submit(append)),
+        None => Append::Direct(append),
+    };
+    async move {
+        match append {
+            Append::Queued(queued) => queued.await,
+            Append::Direct(append) => tokio::task::spawn_blocking(append).await?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod writer_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_appends_run_in_submission_order() {
+        let writer = Writer::start().unwrap();
+        let log = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let appends = (0..100)
+            .map(|i| {
+                let log = log.clone();
+                ordered(Some(writer.reserve().unwrap()), move || {
+                    log.lock().unwrap().push(i);
+                    Ok(i)
+                })
+            })
+            .collect::<Vec<_>>();
+        for (i, append) in appends.into_iter().enumerate().rev() {
+            assert_eq!(append.await.unwrap(), i);
+        }
+        assert_eq!(*log.lock().unwrap(), (0..100).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_panicking_append_fails_only_its_write() {
+        let writer = Writer::start().unwrap();
+        let failed = ordered(Some(writer.reserve().unwrap()), || -> Result<(), _> {
+            panic!("append failed")
+        });
+        assert!(matches!(failed.await, Err(StructureError::WriterStopped)));
+        let next = ordered(Some(writer.reserve().unwrap()), || Ok(1));
+        assert_eq!(next.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_write_queue_waits_while_full() {
+        let writer = Writer::start().unwrap();
+        let queue = WriteQueue::new(2);
+        let (release, blocked) = mpsc::channel::<()>();
+        let first = ordered(
+            Some(writer.reserve().unwrap().with_permit(Some(queue.acquire()))),
+            move || Ok(blocked.recv().ok()),
+        );
+        while queue.len() > 0 {
+            tokio::task::yield_now().await;
+        }
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let appends = (0..5)
+            .map(|i| {
+                let log = log.clone();
+                ordered(
+                    Some(writer.reserve().unwrap().with_permit(Some(queue.acquire()))),
+                    move || {
+                        log.lock().unwrap().push(i);
+                        Ok(i)
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(queue.len(), 2);
+
+        // The waiting appends are polled last to first, and still take their places in order.
+        let appends = appends
+            .into_iter()
+            .rev()
+            .map(tokio::spawn)
+            .collect::<Vec<_>>();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(appends.iter().all(|append| !append.is_finished()));
+        assert_eq!(queue.len(), 2);
+        release.send(()).unwrap();
+        assert_eq!(first.await.unwrap(), Some(()));
+        for (i, append) in appends.into_iter().rev().enumerate() {
+            assert_eq!(append.await.unwrap().unwrap(), i);
+        }
+        assert_eq!(*log.lock().unwrap(), (0..5).collect::<Vec<_>>());
+        assert_eq!(queue.len(), 0);
+    }
+}
@@ -0,0 +1,88 @@
+//! Test suite for the `AsyncHashMap` in rustmap-db.
+//!
+//! These tests exercise concurrent async readers and writers, including readers that hold the
+//! map's read guard across `.await` points.
+
+use std::{
+    fs::File,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rustmap_db::{AsyncHashMap, DBMaker, HashMap};
+
+fn temp_file() -> Arc<Mutex<File>> {
+    Arc::new(Mutex::new(tempfile::tempfile().unwrap()))
+}
+
+/// Tests basic operations and that the contents survive a reopen.
+#[tokio::test]
+async fn test_insert_get_remove_persist() {
+    let file = temp_file();
+    let map = AsyncHashMap::<String, u32>::new(file.clone(), vec![1]).unwrap();
+    assert_eq!(map.insert("a".to_string(), 1).await.unwrap(), None);
+    assert_eq!(map.insert("a".to_string(), 2).await.unwrap(), Some(1));
+    map.insert("b".to_string(), 3).await.unwrap();
+    assert_eq!(map.remove(&"b".to_string()).await.unwrap(), Some(3));
+    assert_eq!(map.remove(&"b".to_string()).await.unwrap(), None);
+    drop(map);
+
+    let map = AsyncHashMap::<String, u32>::new(file.clone(), vec![1]).unwrap();
+    assert_eq!(map.len().await, 1);
+    assert_eq!(map.get(&"a".to_string()).await, Some(2));
+    assert!(!map.contains_key(&"b".to_string()).await);
+
+    let map = HashMap::<String, u32>::new(file, vec![1]).unwrap();
+    assert_eq!(map.get(&"a".to_string()).unwrap().value(), &2);
+}
+
+/// Tests that readers holding the guard across `.await` don't stall concurrent tasks.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_concurrent_readers_and_writers() {
+    let map = Arc::new(AsyncHashMap::<u32, u32>::new(temp_file(), vec![2]).unwrap());
+    let mut handles = Vec::new();
+
+    for i in 0..20u32 {
+        let map = map.clone();
+        handles.push(tokio::spawn(async move {
+            map.insert(i, i * 10).await.unwrap();
+        }));
+    }
+    for _ in 0..20 {
+        let map = map.clone();
+        handles.push(tokio::spawn(async move {
+            let guard = map.read().await;
+            let before = guard.len();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            assert_eq!(guard.len(), before);
+        }));
+    }
+
+    for handle in handles {
+        tokio::time::timeout(Duration::from_secs(10), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    assert_eq!(map.len().await, 20);
+    for i in 0..20u32 {
+        assert_eq!(map.get(&i).await, Some(i * 10));
+    }
+}
+
+/// Tests creating an `AsyncHashMap` through the database.
+#[tokio::test]
+async fn test_database_async_hash_map() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = DBMaker::file_db(dir.path().join("async.db"))
+        .make()
+        .unwrap();
+    let map = db
+        .async_hash_map::<String, String>("map".to_string())
+        .unwrap();
+    map.insert("k".to_string(), "v".to_string()).await.unwrap();
+    drop(map);
+
+    let map = db.hash_map::<String, String>("map".to_string()).unwrap();
+    assert_eq!(map.get(&"k".to_string()).unwrap().value(), "v");
+}
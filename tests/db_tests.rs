@@ -1,6 +1,16 @@
-use std::{fs::File, io::Read as _, path::PathBuf};
+use std::{
+    fs::File,
+    io::{Read as _, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use rustmap_db::DBMaker;
+use rustmap_db::{
+    db::db_entry::{DBEntry, FORMAT_VERSION},
+    DBMaker, Database, Durability, HashMapConfigBuilder, SerializationFormat, StructureError,
+    StructureInfo, StructureKind,
+};
 
 #[tokio::test]
 async fn test_hashmap_and_hashset_insert_serialization() {
@@ -21,6 +31,7 @@ async fn test_hashmap_and_hashset_insert_serialization() {
     file.read_to_end(&mut buffer).unwrap();
     drop(hashmap);
     drop(hashset);
+    drop(db);
     let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
     let hashmap = db
         .hash_map::<String, String>("test_hashmap".to_string())
@@ -33,3 +44,1430 @@ async fn test_hashmap_and_hashset_insert_serialization() {
     assert!(hashset.get(&"key".to_string()).is_some());
     std::fs::remove_file(filename).unwrap();
 }
+
+#[tokio::test]
+async fn test_point_get_reflects_latest_record() {
+    let filename = "test_point_get.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<String, u32>("points".to_string()).unwrap();
+    let kept = bincode::serialize(&"kept".to_string()).unwrap();
+    let removed = bincode::serialize(&"removed".to_string()).unwrap();
+
+    hashmap
+        .insert("kept".to_string(), 1)
+        .await
+        .unwrap()
+        .unwrap();
+    hashmap
+        .insert("kept".to_string(), 2)
+        .await
+        .unwrap()
+        .unwrap();
+    hashmap
+        .insert("removed".to_string(), 3)
+        .await
+        .unwrap()
+        .unwrap();
+    hashmap
+        .remove(&"removed".to_string())
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
+    drop(hashmap);
+
+    let value = db.point_get("points", &kept).unwrap().unwrap();
+    assert_eq!(bincode::deserialize::<u32>(&value).unwrap(), 2);
+    assert!(db.point_get("points", &removed).unwrap().is_none());
+    assert!(db.point_get("other", &kept).unwrap().is_none());
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_sync_makes_writes_durable() {
+    let filename = "test_sync.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<String, u32>("synced".to_string()).unwrap();
+    hashmap.insert("key".to_string(), 1).await.unwrap().unwrap();
+    db.sync().unwrap();
+    drop(hashmap);
+    drop(db);
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<String, u32>("synced".to_string()).unwrap();
+    assert_eq!(hashmap.get(&"key".to_string()).unwrap().value(), &1);
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_tombstones_lists_removed_keys() {
+    let filename = "test_tombstones.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<String, u32>("audited".to_string()).unwrap();
+    let hashset = db.hash_set::<String>("audited_set".to_string()).unwrap();
+    for key in ["a", "b", "c"] {
+        hashmap.insert(key.to_string(), 1).await.unwrap().unwrap();
+    }
+    hashset.insert("s".to_string()).await.unwrap().unwrap();
+    hashmap
+        .remove(&"a".to_string())
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
+    hashmap
+        .remove(&"c".to_string())
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
+    hashmap.insert("a".to_string(), 2).await.unwrap().unwrap();
+    hashset
+        .remove(&"s".to_string())
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
+
+    let serialize = |key: &str| bincode::serialize(&key.to_string()).unwrap();
+    assert_eq!(
+        db.tombstones("audited").unwrap(),
+        vec![serialize("a"), serialize("c")]
+    );
+    assert_eq!(db.tombstones("audited_set").unwrap(), vec![serialize("s")]);
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_fork_is_isolated() {
+    let filename = "test_fork_original.db";
+    let fork_filename = "test_fork_copy.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<String, u32>("forked".to_string()).unwrap();
+    hashmap
+        .insert("shared".to_string(), 1)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let fork = db.fork(Path::new(fork_filename)).unwrap();
+    let forked_map = fork.hash_map::<String, u32>("forked".to_string()).unwrap();
+    assert_eq!(forked_map.get(&"shared".to_string()).unwrap().value(), &1);
+    forked_map
+        .insert("fork_only".to_string(), 2)
+        .await
+        .unwrap()
+        .unwrap();
+    forked_map
+        .remove(&"shared".to_string())
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
+    drop(hashmap);
+
+    let hashmap = db.hash_map::<String, u32>("forked".to_string()).unwrap();
+    assert_eq!(hashmap.get(&"shared".to_string()).unwrap().value(), &1);
+    assert!(hashmap.get(&"fork_only".to_string()).is_none());
+    std::fs::remove_file(filename).unwrap();
+    std::fs::remove_file(fork_filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_prefetch() {
+    let filename = "test_prefetch.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db
+        .hash_map::<u32, String>("prefetched".to_string())
+        .unwrap();
+    let entries = (0..1000).map(|i| (i, "x".repeat(100))).collect();
+    hashmap.insert_batch(entries).await.unwrap().unwrap();
+
+    db.prefetch().unwrap();
+    let hashmap = db
+        .hash_map::<u32, String>("prefetched".to_string())
+        .unwrap();
+    assert_eq!(hashmap.len(), 1000);
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_repair_mismatched_set_tombstones() {
+    let filename = "test_repair.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashset = db.hash_set::<String>("repaired_set".to_string()).unwrap();
+    for key in ["a", "b", "c"] {
+        hashset.insert(key.to_string()).await.unwrap().unwrap();
+    }
+    drop(hashset);
+
+    // Tombstones as written by the old `HashSet::remove_batch`.
+    let name = "repaired_set";
+    let mut set_id = name.len().to_be_bytes().to_vec();
+    set_id.extend_from_slice(name.as_bytes());
+    {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(filename)
+            .unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        for key in ["a", "b"] {
+            let key = bincode::serialize(&key.to_string()).unwrap();
+            let entry = DBEntry::RemoveHashMapEntry(set_id.clone(), key);
+            file.write_all(&bincode::serialize(&entry).unwrap())
+                .unwrap();
+        }
+    }
+    let hashset = db.hash_set::<String>("repaired_set".to_string()).unwrap();
+    assert_eq!(hashset.len(), 3);
+
+    let report = db.repair("repaired_set").unwrap();
+    assert_eq!(report.set_tombstones_fixed, 2);
+    assert_eq!(report.map_tombstones_fixed, 0);
+    assert_eq!(report.scanned_entries, 6);
+
+    let hashset = db.hash_set::<String>("repaired_set".to_string()).unwrap();
+    assert_eq!(hashset.len(), 1);
+    assert!(hashset.get(&"c".to_string()).is_some());
+    assert!(!db.repair("repaired_set").unwrap().repaired());
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_open_trims_padding_after_end_of_log() {
+    let filename = "test_end_of_log.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, u32>("padded".to_string()).unwrap();
+    hashmap.insert(1, 10).await.unwrap().unwrap();
+    drop(hashmap);
+    drop(db);
+    let logical_len = std::fs::metadata(filename).unwrap().len();
+    {
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(filename)
+            .unwrap();
+        file.write_all(&bincode::serialize(&DBEntry::EndOfLog).unwrap())
+            .unwrap();
+        file.write_all(&[0; 1024]).unwrap();
+    }
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    assert_eq!(std::fs::metadata(filename).unwrap().len(), logical_len);
+    let hashmap = db.hash_map::<u32, u32>("padded".to_string()).unwrap();
+    hashmap.insert(2, 20).await.unwrap().unwrap();
+    drop(hashmap);
+
+    let hashmap = db.hash_map::<u32, u32>("padded".to_string()).unwrap();
+    assert_eq!(hashmap.get(&1).unwrap().value(), &10);
+    assert_eq!(hashmap.get(&2).unwrap().value(), &20);
+    std::fs::remove_file(filename).unwrap();
+}
+
+/// Tests that opening a database cuts off a partially written final entry, unless it's opened
+/// read-only.
+#[tokio::test]
+async fn test_open_trims_partial_tail() {
+    let filename = "test_partial_tail.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, u32>("map".to_string()).unwrap();
+    hashmap.insert(1, 10).await.unwrap().unwrap();
+    drop(hashmap);
+    drop(db);
+    let logical_len = std::fs::metadata(filename).unwrap().len();
+    {
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(filename)
+            .unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+    }
+
+    let db = DBMaker::file_db(PathBuf::from(filename))
+        .read_only()
+        .make()
+        .unwrap();
+    assert_eq!(std::fs::metadata(filename).unwrap().len(), logical_len + 3);
+    drop(db);
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    assert_eq!(std::fs::metadata(filename).unwrap().len(), logical_len);
+    let hashmap = db.hash_map::<u32, u32>("map".to_string()).unwrap();
+    hashmap.insert(2, 20).await.unwrap().unwrap();
+    drop(hashmap);
+
+    let hashmap = db.hash_map::<u32, u32>("map".to_string()).unwrap();
+    assert_eq!(hashmap.get(&1).unwrap().value(), &10);
+    assert_eq!(hashmap.get(&2).unwrap().value(), &20);
+    drop(hashmap);
+    drop(db);
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_rename_structure() {
+    let filename = "test_rename.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<String, u32>("before".to_string()).unwrap();
+    for (key, value) in [("a", 1), ("b", 2), ("c", 3)] {
+        hashmap
+            .insert(key.to_string(), value)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    hashmap
+        .remove(&"b".to_string())
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
+    drop(hashmap);
+    let other = db.hash_map::<String, u32>("other".to_string()).unwrap();
+    other.insert("x".to_string(), 9).await.unwrap().unwrap();
+    drop(other);
+
+    assert!(matches!(
+        db.rename_structure("before", "other"),
+        Err(StructureError::StructureExists(id)) if id == "other"
+    ));
+    db.rename_structure("before", "after").unwrap();
+
+    let hashmap = db.hash_map::<String, u32>("after".to_string()).unwrap();
+    assert_eq!(hashmap.len(), 2);
+    assert_eq!(hashmap.get(&"a".to_string()).unwrap().value(), &1);
+    assert_eq!(hashmap.get(&"c".to_string()).unwrap().value(), &3);
+    assert!(db
+        .hash_map::<String, u32>("before".to_string())
+        .unwrap()
+        .is_empty());
+    let other = db.hash_map::<String, u32>("other".to_string()).unwrap();
+    assert_eq!(other.get(&"x".to_string()).unwrap().value(), &9);
+
+    // The renamed map keeps working after a reopen of the database.
+    hashmap.insert("d".to_string(), 4).await.unwrap().unwrap();
+    drop((hashmap, other, db));
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<String, u32>("after".to_string()).unwrap();
+    assert_eq!(hashmap.len(), 3);
+    std::fs::remove_file(filename).unwrap();
+}
+
+/// Tests that renaming an id renames the hashset and multimap stored under it along with the
+/// hashmap, and that they load under the new id after a reopen.
+#[tokio::test]
+async fn test_rename_structure_of_every_kind() {
+    let filename = "test_rename_every_kind.db";
+    let _ = std::fs::remove_file(filename);
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, String>("users_v1".to_string()).unwrap();
+    let hashset = db.hash_set::<u32>("users_v1".to_string()).unwrap();
+    let multimap = db.multi_map::<u32, String>("users_v1".to_string()).unwrap();
+    for i in 0..3 {
+        hashmap.insert(i, i.to_string()).await.unwrap().unwrap();
+        hashset.insert(i).await.unwrap().unwrap();
+        multimap.insert(i, i.to_string()).await.unwrap().unwrap();
+    }
+    drop((hashmap, hashset, multimap));
+
+    db.rename_structure("users_v1", "users_v2").unwrap();
+    drop(db);
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let structures = db.list_structures().unwrap();
+    assert_eq!(structures.len(), 3);
+    assert!(structures
+        .iter()
+        .all(|structure| structure.id == "users_v2" && structure.len == 3));
+    let hashset = db.hash_set::<u32>("users_v2".to_string()).unwrap();
+    let multimap = db.multi_map::<u32, String>("users_v2".to_string()).unwrap();
+    assert!((0..3).all(|i| hashset.contains(&i)));
+    assert_eq!(multimap.get(&2), Some(vec!["2".to_string()]));
+    assert!(db
+        .hash_set::<u32>("users_v1".to_string())
+        .unwrap()
+        .is_empty());
+    assert!(db
+        .multi_map::<u32, String>("users_v1".to_string())
+        .unwrap()
+        .is_empty());
+    drop((hashset, multimap, db));
+
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_delete_structure() {
+    let filename = "test_delete_structure.db";
+    let _ = std::fs::remove_file(filename);
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let doomed = db.hash_map::<String, u32>("doomed".to_string()).unwrap();
+    let kept = db.hash_map::<String, u32>("kept".to_string()).unwrap();
+    let doomed_set = db.hash_set::<String>("doomed".to_string()).unwrap();
+    for (key, value) in [("a", 1), ("b", 2), ("c", 3)] {
+        doomed
+            .insert(key.to_string(), value)
+            .await
+            .unwrap()
+            .unwrap();
+        kept.insert(key.to_string(), value * 10)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    doomed_set.insert("a".to_string()).await.unwrap().unwrap();
+    drop((doomed, kept, doomed_set));
+
+    db.delete_structure("doomed".to_string()).unwrap();
+    db.delete_structure("missing".to_string()).unwrap();
+    drop(db);
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let kept = db.hash_map::<String, u32>("kept".to_string()).unwrap();
+    assert_eq!(kept.len(), 3);
+    for (key, value) in [("a", 10), ("b", 20), ("c", 30)] {
+        assert_eq!(kept.get(&key.to_string()).unwrap().value(), &value);
+    }
+    assert!(db
+        .hash_set::<String>("doomed".to_string())
+        .unwrap()
+        .is_empty());
+    // The type fingerprint is deleted too, so the id can be reused with other types.
+    assert!(db
+        .hash_map::<u32, String>("doomed".to_string())
+        .unwrap()
+        .is_empty());
+    drop((kept, db));
+
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_group_commit_shares_syncs() {
+    let filename = "test_group_commit.db";
+    let db = DBMaker::file_db(PathBuf::from(filename))
+        .group_commit(Duration::from_millis(20))
+        .make()
+        .unwrap();
+    let config = || {
+        HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .durability(Durability::Sync)
+            .build()
+            .unwrap()
+    };
+    let first = db
+        .hash_map_with_config::<u32, u32>("first".to_string(), config())
+        .unwrap();
+    let second = db
+        .hash_map_with_config::<u32, u32>("second".to_string(), config())
+        .unwrap();
+
+    let mut handles = Vec::new();
+    for i in 0..100 {
+        handles.push(first.insert(i, i));
+        handles.push(second.insert(i, i * 2));
+    }
+    for handle in handles {
+        handle.await.unwrap().unwrap();
+    }
+    let syncs = db.group_syncs();
+    assert!(syncs > 0);
+    assert!(syncs < 20, "{} syncs for 200 inserts", syncs);
+    drop((first, second, db));
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    assert_eq!(db.group_syncs(), 0);
+    let first = db
+        .hash_map_with_config::<u32, u32>("first".to_string(), config())
+        .unwrap();
+    let second = db
+        .hash_map_with_config::<u32, u32>("second".to_string(), config())
+        .unwrap();
+    for i in 0..100 {
+        assert_eq!(first.get(&i).unwrap().value(), &i);
+        assert_eq!(second.get(&i).unwrap().value(), &(i * 2));
+    }
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_fold_log_counts_entries_per_id() {
+    let filename = "test_fold_log.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, u32>("folded_map".to_string()).unwrap();
+    let hashset = db.hash_set::<u32>("folded_set".to_string()).unwrap();
+    for i in 0..5 {
+        hashmap.insert(i, i).await.unwrap().unwrap();
+    }
+    hashmap.remove(&0).unwrap().await.unwrap().unwrap();
+    for i in 0..3 {
+        hashset.insert(i).await.unwrap().unwrap();
+    }
+
+    let counts = db
+        .fold_log(std::collections::HashMap::new(), |mut counts, entry| {
+            if let Some(id) = entry.id() {
+                *counts.entry(id.to_vec()).or_insert(0) += 1;
+            }
+            counts
+        })
+        .unwrap();
+    assert_eq!(counts.len(), 2);
+    let map_id = bincode::serialize(&raw_id("folded_map")).unwrap();
+    assert_eq!(counts[&map_id], 6);
+    let set_id = bincode::serialize(&raw_id("folded_set")).unwrap();
+    assert_eq!(counts[&set_id], 3);
+
+    let total = db.fold_log(0, |total, _| total + 1).unwrap();
+    // The entries of both structures and the file header.
+    assert_eq!(total, 10);
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_exists_and_file_size() {
+    let filename = "test_file_size.db";
+    assert!(!Database::exists(filename));
+
+    let entry = DBEntry::HashSetEntry(raw_id("sized_set"), bincode::serialize(&1u32).unwrap());
+    let bytes = bincode::serialize(&entry).unwrap();
+    std::fs::write(filename, &bytes).unwrap();
+    assert!(Database::exists(filename));
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    assert_eq!(db.file_size().unwrap(), bytes.len() as u64);
+    let hashset = db.hash_set::<u32>("sized_set".to_string()).unwrap();
+    assert!(hashset.get(&1).is_some());
+    hashset.insert(2).await.unwrap().unwrap();
+    // The new element is stored under the encoded id, which is prefixed with its length.
+    assert_eq!(db.file_size().unwrap(), 2 * bytes.len() as u64 + 8);
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_close_async_awaits_pending_writes() {
+    let filename = "test_close_async.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db
+        .hash_map::<u32, String>("pending_map".to_string())
+        .unwrap();
+    let hashset = db.hash_set::<u32>("pending_set".to_string()).unwrap();
+    for i in 0..100 {
+        drop(hashmap.insert(i, i.to_string()));
+        drop(hashset.insert(i));
+    }
+    drop(hashmap);
+    drop(hashset);
+    db.close_async().await.unwrap();
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db
+        .hash_map::<u32, String>("pending_map".to_string())
+        .unwrap();
+    let hashset = db.hash_set::<u32>("pending_set".to_string()).unwrap();
+    assert_eq!(hashmap.len(), 100);
+    assert_eq!(hashset.len(), 100);
+    for i in 0..100 {
+        assert_eq!(hashmap.get(&i).unwrap().value(), &i.to_string());
+        assert!(hashset.get(&i).is_some());
+    }
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_writes_reach_log_in_memory_order() {
+    let filename = "test_ordered_writes.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = Arc::new(db.hash_map::<u32, u32>("ordered".to_string()).unwrap());
+    let hashset = Arc::new(db.hash_set::<u32>("ordered_set".to_string()).unwrap());
+    let writers = (0..8)
+        .map(|task| {
+            let hashmap = hashmap.clone();
+            let hashset = hashset.clone();
+            tokio::spawn(async move {
+                for i in 0..200 {
+                    drop(hashmap.insert(i % 4, task * 1000 + i));
+                    drop(hashmap.insert_batch(vec![(4, task * 1000 + i)]));
+                    if i % 2 == 0 {
+                        drop(hashset.insert(i % 4));
+                    } else {
+                        drop(hashset.remove(&(i % 4)));
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.await.unwrap();
+    }
+    let expected = hashmap.collect_pairs();
+    let expected_set = (0..4)
+        .filter(|key| hashset.get(key).is_some())
+        .collect::<Vec<_>>();
+    drop(hashmap);
+    drop(hashset);
+    db.close_async().await.unwrap();
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, u32>("ordered".to_string()).unwrap();
+    let hashset = db.hash_set::<u32>("ordered_set".to_string()).unwrap();
+    for (key, value) in expected {
+        assert_eq!(hashmap.get(&key).unwrap().value(), &value);
+    }
+    let reloaded_set = (0..4)
+        .filter(|key| hashset.get(key).is_some())
+        .collect::<Vec<_>>();
+    assert_eq!(reloaded_set, expected_set);
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_reference_held_while_writing_another_structure() {
+    let filename = "test_held_reference.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let first = Arc::new(db.hash_map::<u32, u32>("first".to_string()).unwrap());
+    let second = Arc::new(db.hash_map::<u32, u32>("second".to_string()).unwrap());
+    first.insert(1, 1).await.unwrap().unwrap();
+
+    let held = first.get(&1).unwrap();
+    let runtime = tokio::runtime::Handle::current();
+    let blocked = std::thread::spawn({
+        let (first, runtime) = (first.clone(), runtime.clone());
+        move || {
+            let _runtime = runtime.enter();
+            first.insert(1, 2)
+        }
+    });
+    std::thread::sleep(Duration::from_millis(50));
+    let (written, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn({
+        let second = second.clone();
+        move || {
+            let _runtime = runtime.enter();
+            written.send(second.insert(1, 1)).unwrap();
+        }
+    });
+    let write = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), write)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(held.value(), &1);
+
+    drop(held);
+    blocked.join().unwrap().await.unwrap().unwrap();
+    assert_eq!(first.get_cloned(&1), Some(2));
+    assert_eq!(second.get_cloned(&1), Some(1));
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_structures_share_id_encoding() {
+    let filename = "test_id_encoding.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, u32>("shared".to_string()).unwrap();
+    let hashset = db.hash_set::<u32>("shared".to_string()).unwrap();
+    let configured = db
+        .hash_map_with_config::<u32, u32>(
+            "shared".to_string(),
+            HashMapConfigBuilder::default()
+                .shard_amount(8)
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    hashmap.insert(1, 1).await.unwrap().unwrap();
+    hashset.insert(1).await.unwrap().unwrap();
+    configured.insert(2, 2).await.unwrap().unwrap();
+
+    let ids = db
+        .fold_log(Vec::new(), |mut ids, entry| {
+            match entry {
+                DBEntry::HashMapEntry(id, _, _) | DBEntry::HashSetEntry(id, _) => {
+                    ids.push(id.clone())
+                }
+                _ => {}
+            }
+            ids
+        })
+        .unwrap();
+    let expected = bincode::serialize(&raw_id("shared")).unwrap();
+    assert_eq!(ids, vec![expected.clone(), expected.clone(), expected]);
+
+    // Every constructor encodes the id it is given the same way.
+    let file = Arc::new(std::sync::Mutex::new(tempfile::tempfile().unwrap()));
+    let hashmap = rustmap_db::HashMap::<u32, u32>::new(file.clone(), vec![7]).unwrap();
+    let hashset = rustmap_db::HashSet::<u32>::new(file.clone(), vec![7]).unwrap();
+    let configured = rustmap_db::HashMap::<u32, u32>::with_config(
+        file.clone(),
+        vec![7],
+        HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    hashmap.insert(1, 1).await.unwrap().unwrap();
+    hashset.insert(1).await.unwrap().unwrap();
+    configured.insert(2, 2).await.unwrap().unwrap();
+    let mut bytes = Vec::new();
+    {
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut bytes).unwrap();
+    }
+    let mut cursor = std::io::Cursor::new(&bytes);
+    let map_entry = bincode::deserialize_from::<_, DBEntry>(&mut cursor).unwrap();
+    let set_entry = bincode::deserialize_from::<_, DBEntry>(&mut cursor).unwrap();
+    let configured_entry = bincode::deserialize_from::<_, DBEntry>(&mut cursor).unwrap();
+    assert_eq!(map_entry.id(), set_entry.id());
+    assert_eq!(map_entry.id(), configured_entry.id());
+    std::fs::remove_file(filename).unwrap();
+}
+
+/// Tests that a hashmap written through `hash_map` is the one `hash_map_with_config` opens
+/// with the same name after a reopen, and the other way round, and that the database's own
+/// lookups find both.
+#[tokio::test]
+async fn test_hash_map_constructors_share_records() {
+    let filename = "test_hash_map_constructors.db";
+    let config = || {
+        HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .build()
+            .unwrap()
+    };
+    {
+        let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+        let plain = db.hash_map::<u32, String>("plain".to_string()).unwrap();
+        let configured = db
+            .hash_map_with_config::<u32, String>("configured".to_string(), config())
+            .unwrap();
+        plain.insert(1, "one".to_string()).await.unwrap().unwrap();
+        plain.insert(2, "two".to_string()).await.unwrap().unwrap();
+        configured
+            .insert(3, "three".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        configured.remove(&3).unwrap().await.unwrap().unwrap();
+        configured
+            .insert(4, "four".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        db.close_async().await.unwrap();
+    }
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let plain = db
+        .hash_map_with_config::<u32, String>("plain".to_string(), config())
+        .unwrap();
+    assert_eq!(plain.len(), 2);
+    assert_eq!(plain.get_cloned(&1).unwrap(), "one");
+    let configured = db
+        .hash_map::<u32, String>("configured".to_string())
+        .unwrap();
+    assert_eq!(configured.len(), 1);
+    assert_eq!(configured.get_cloned(&4).unwrap(), "four");
+
+    let key = bincode::serialize(&4u32).unwrap();
+    let value = db.point_get("configured", &key).unwrap().unwrap();
+    assert_eq!(bincode::deserialize::<String>(&value).unwrap(), "four");
+    assert_eq!(
+        db.tombstones("configured").unwrap(),
+        vec![bincode::serialize(&3u32).unwrap()]
+    );
+    drop(plain);
+    drop(configured);
+    drop(db);
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_compact_keeps_one_record_per_key() {
+    let filename = "test_compact_replace.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, u32>("compacted".to_string()).unwrap();
+    let hashset = db.hash_set::<u32>("bystander".to_string()).unwrap();
+    for i in 0..50 {
+        hashmap.insert(1, i).await.unwrap().unwrap();
+    }
+    hashset.insert(7).await.unwrap().unwrap();
+    let before = db.file_size().unwrap();
+    hashmap.compact().unwrap();
+    assert!(db.file_size().unwrap() < before);
+
+    // Writes after compaction go to the replacement file, through every handle.
+    hashmap.insert(2, 2).await.unwrap().unwrap();
+    hashset.insert(8).await.unwrap().unwrap();
+    drop(hashmap);
+    drop(hashset);
+    db.close_async().await.unwrap();
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let key = bincode::serialize(&1u32).unwrap();
+    let records = db
+        .fold_log(0, |records, entry| match entry {
+            DBEntry::HashMapEntry(_, entry_key, _) if *entry_key == key => records + 1,
+            _ => records,
+        })
+        .unwrap();
+    assert_eq!(records, 1);
+    let hashmap = db.hash_map::<u32, u32>("compacted".to_string()).unwrap();
+    let hashset = db.hash_set::<u32>("bystander".to_string()).unwrap();
+    assert_eq!(hashmap.get(&1).unwrap().value(), &49);
+    assert_eq!(hashmap.get(&2).unwrap().value(), &2);
+    assert!(hashset.get(&7).is_some());
+    assert!(hashset.get(&8).is_some());
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_hashset_compact_keeps_one_record_per_member() {
+    let filename = "test_hashset_compact_replace.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashset = db.hash_set::<u32>("compacted".to_string()).unwrap();
+    let hashmap = db.hash_map::<u32, u32>("bystander".to_string()).unwrap();
+    for _ in 0..10 {
+        hashset.insert(1).await.unwrap().unwrap();
+        hashset.remove(&1).unwrap().await.unwrap().unwrap();
+    }
+    hashset.insert(1).await.unwrap().unwrap();
+    hashmap.insert(7, 7).await.unwrap().unwrap();
+    hashset.compact().unwrap();
+    hashmap.insert(8, 8).await.unwrap().unwrap();
+    drop(hashset);
+    drop(hashmap);
+    db.close_async().await.unwrap();
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let member = bincode::serialize(&1u32).unwrap();
+    let (entries, tombstones) = db
+        .fold_log((0, 0), |(entries, tombstones), entry| match entry {
+            DBEntry::HashSetEntry(_, key) if *key == member => (entries + 1, tombstones),
+            DBEntry::RemoveHashSetEntry(_, key) if *key == member => (entries, tombstones + 1),
+            _ => (entries, tombstones),
+        })
+        .unwrap();
+    assert_eq!((entries, tombstones), (1, 0));
+    let hashset = db.hash_set::<u32>("compacted".to_string()).unwrap();
+    let hashmap = db.hash_map::<u32, u32>("bystander".to_string()).unwrap();
+    assert!(hashset.get(&1).is_some());
+    assert_eq!(hashmap.get(&7).unwrap().value(), &7);
+    assert_eq!(hashmap.get(&8).unwrap().value(), &8);
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_drop_flushes_database() {
+    let filename = "test_drop_flush.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, String>("dropped".to_string()).unwrap();
+    hashmap.insert(1, "one".to_string()).await.unwrap().unwrap();
+    drop(db);
+    drop(hashmap);
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, String>("dropped".to_string()).unwrap();
+    assert_eq!(hashmap.get_cloned(&1).unwrap(), "one");
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_read_only_rejects_writes() {
+    let filename = "test_read_only.db";
+    let missing = DBMaker::file_db(PathBuf::from(filename)).read_only().make();
+    assert_eq!(missing.err().unwrap().kind(), std::io::ErrorKind::NotFound);
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, String>("map".to_string()).unwrap();
+    let hashset = db.hash_set::<u32>("set".to_string()).unwrap();
+    hashmap.insert(1, "one".to_string()).await.unwrap().unwrap();
+    hashset.insert(1).await.unwrap().unwrap();
+    drop(hashmap);
+    drop(hashset);
+    db.close_async().await.unwrap();
+    let size = std::fs::metadata(filename).unwrap().len();
+
+    let db = DBMaker::file_db(PathBuf::from(filename))
+        .read_only()
+        .make()
+        .unwrap();
+    let hashmap = db.hash_map::<u32, String>("map".to_string()).unwrap();
+    let hashset = db.hash_set::<u32>("set".to_string()).unwrap();
+    assert_eq!(hashmap.get(&1).unwrap().value(), "one");
+    assert!(hashmap.contains_key(&1));
+    assert_eq!(hashmap.len(), 1);
+    assert_eq!(hashmap.iter().count(), 1);
+    assert!(hashset.contains(&1));
+
+    assert!(matches!(
+        hashmap.insert(2, "two".to_string()).await.unwrap(),
+        Err(StructureError::ReadOnly)
+    ));
+    assert!(matches!(
+        hashmap.remove(&1).unwrap().await.unwrap(),
+        Err(StructureError::ReadOnly)
+    ));
+    assert!(matches!(hashmap.clear(), Err(StructureError::ReadOnly)));
+    assert!(matches!(
+        hashset.insert(2).await.unwrap(),
+        Err(StructureError::ReadOnly)
+    ));
+    assert!(matches!(hashset.compact(), Err(StructureError::ReadOnly)));
+    assert!(matches!(db.repair("map"), Err(StructureError::ReadOnly)));
+
+    // Rejected writes leave memory and the file unchanged.
+    assert_eq!(hashmap.get(&1).unwrap().value(), "one");
+    assert!(!hashmap.contains_key(&2));
+    assert!(!hashset.contains(&2));
+    assert_eq!(std::fs::metadata(filename).unwrap().len(), size);
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[tokio::test]
+async fn test_open_locks_the_file() {
+    let filename = "test_file_lock.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let second = DBMaker::file_db(PathBuf::from(filename)).make();
+    assert_eq!(second.err().unwrap().kind(), std::io::ErrorKind::WouldBlock);
+    let shared = DBMaker::file_db(PathBuf::from(filename))
+        .allow_shared()
+        .make();
+    assert_eq!(shared.err().unwrap().kind(), std::io::ErrorKind::WouldBlock);
+
+    // The lock is released with the last handle to the file.
+    let hashmap = db.hash_map::<u32, u32>("locked".to_string()).unwrap();
+    drop(db);
+    assert!(DBMaker::file_db(PathBuf::from(filename)).make().is_err());
+    drop(hashmap);
+
+    // Any number of shared readers can hold the file, but not alongside a writer.
+    let readers = (0..2)
+        .map(|_| {
+            DBMaker::file_db(PathBuf::from(filename))
+                .read_only()
+                .allow_shared()
+                .make()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert!(DBMaker::file_db(PathBuf::from(filename)).make().is_err());
+    drop(readers);
+    DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    std::fs::remove_file(filename).unwrap();
+}
+
+/// Tests that a lenient database skips a corrupted record in the middle of the file, reports
+/// it and recovers the records after it, while a strict one refuses to load it.
+#[tokio::test]
+async fn test_lenient_skips_corrupted_middle_record() {
+    let filename = "test_lenient.db";
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, String>("map".to_string()).unwrap();
+    let hashset = db.hash_set::<u32>("set".to_string()).unwrap();
+    hashmap
+        .insert(1, "first".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    hashmap
+        .insert(2, "second".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    hashset.insert(3).await.unwrap().unwrap();
+    hashmap
+        .insert(4, "fourth".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    drop(hashmap);
+    drop(hashset);
+    db.close_async().await.unwrap();
+
+    let mut contents = std::fs::read(filename).unwrap();
+    let position = contents
+        .windows(6)
+        .position(|window| window == b"second")
+        .unwrap();
+    contents[position] = b'S';
+    std::fs::write(filename, &contents).unwrap();
+
+    let error = DBMaker::file_db(PathBuf::from(filename))
+        .make()
+        .err()
+        .unwrap();
+    assert!(matches!(
+        error.get_ref().unwrap().downcast_ref::<StructureError>(),
+        Some(StructureError::ChecksumMismatch { .. })
+    ));
+
+    let db = DBMaker::file_db(PathBuf::from(filename))
+        .lenient()
+        .make()
+        .unwrap();
+    let hashmap = db.hash_map::<u32, String>("map".to_string()).unwrap();
+    let hashset = db.hash_set::<u32>("set".to_string()).unwrap();
+    assert_eq!(hashmap.get(&1).unwrap().value(), "first");
+    assert!(hashmap.get(&2).is_none());
+    assert_eq!(hashmap.get(&4).unwrap().value(), "fourth");
+    assert!(hashset.contains(&3));
+    let skipped = db.skipped_records();
+    assert_eq!(skipped.len(), 1);
+    assert!(skipped[0].offset < position as u64);
+    assert!(skipped[0].offset + skipped[0].len > position as u64);
+    assert!(matches!(
+        skipped[0].error,
+        Some(StructureError::ChecksumMismatch { .. })
+    ));
+    assert_eq!(hashmap.skipped_records().len(), 1);
+    assert_eq!(hashset.skipped_records()[0].offset, skipped[0].offset);
+    drop(hashmap);
+    drop(hashset);
+    drop(db);
+
+    std::fs::remove_file(filename).unwrap();
+}
+
+/// Tests that rewriting the log never drops the records after one that can't be read: a
+/// strict database refuses the rewrite, and a lenient one only loses the unreadable record.
+#[tokio::test]
+async fn test_rewrite_keeps_records_after_unreadable_one() {
+    let filename = "test_rewrite_unreadable.db";
+    let _ = std::fs::remove_file(filename);
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, String>("map".to_string()).unwrap();
+    let other = db.hash_map::<u32, String>("other".to_string()).unwrap();
+    for (key, value) in [(1, "first"), (2, "second"), (3, "third")] {
+        hashmap
+            .insert(key, value.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        other.insert(key, value.to_string()).await.unwrap().unwrap();
+    }
+    drop((hashmap, other));
+    db.sync().unwrap();
+
+    let mut contents = std::fs::read(filename).unwrap();
+    let position = contents
+        .windows(6)
+        .position(|window| window == b"second")
+        .unwrap();
+    contents[position] = b'S';
+    std::fs::write(filename, &contents).unwrap();
+
+    assert!(matches!(
+        db.rename_structure("map", "renamed"),
+        Err(StructureError::ChecksumMismatch { .. })
+    ));
+    assert_eq!(std::fs::read(filename).unwrap(), contents);
+    drop(db);
+
+    let db = DBMaker::file_db(PathBuf::from(filename))
+        .lenient()
+        .make()
+        .unwrap();
+    db.rename_structure("map", "renamed").unwrap();
+    drop(db);
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let renamed = db.hash_map::<u32, String>("renamed".to_string()).unwrap();
+    let other = db.hash_map::<u32, String>("other".to_string()).unwrap();
+    assert_eq!(renamed.get(&1).unwrap().value(), "first");
+    assert!(renamed.get(&2).is_none());
+    assert_eq!(renamed.get(&3).unwrap().value(), "third");
+    assert_eq!(other.len(), 3);
+    drop((renamed, other, db));
+
+    std::fs::remove_file(filename).unwrap();
+}
+
+/// Tests that the format a database was created with is recorded in the file, so it is used
+/// again when the database is reopened without specifying it.
+#[tokio::test]
+async fn test_format_is_recorded_in_file() {
+    let filename = "test_format_recorded.db";
+    let _ = std::fs::remove_file(filename);
+    let db = DBMaker::file_db(PathBuf::from(filename))
+        .format(SerializationFormat::Json)
+        .make()
+        .unwrap();
+    let hashmap = db.hash_map::<u32, String>("map".to_string()).unwrap();
+    let hashset = db.hash_set::<String>("set".to_string()).unwrap();
+    hashmap
+        .insert(1, "readable value".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    hashset
+        .insert("readable member".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    drop(hashmap);
+    drop(hashset);
+    db.close_async().await.unwrap();
+
+    let contents = std::fs::read(filename).unwrap();
+    for text in [&b"\"readable value\""[..], &b"\"readable member\""[..]] {
+        assert!(contents.windows(text.len()).any(|window| window == text));
+    }
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, String>("map".to_string()).unwrap();
+    let hashset = db.hash_set::<String>("set".to_string()).unwrap();
+    assert_eq!(hashmap.config().format, SerializationFormat::Json);
+    assert_eq!(hashmap.get(&1).unwrap().value(), "readable value");
+    assert!(hashset.contains(&"readable member".to_string()));
+    hashmap.compact().unwrap();
+    drop(hashmap);
+    drop(hashset);
+    drop(db);
+
+    let error = DBMaker::file_db(PathBuf::from(filename))
+        .format(SerializationFormat::Bincode)
+        .make()
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+    let db = DBMaker::file_db(PathBuf::from(filename))
+        .format(SerializationFormat::Json)
+        .make()
+        .unwrap();
+    let hashmap = db.hash_map::<u32, String>("map".to_string()).unwrap();
+    assert_eq!(hashmap.get(&1).unwrap().value(), "readable value");
+    drop(hashmap);
+    drop(db);
+
+    std::fs::remove_file(filename).unwrap();
+}
+
+/// Tests that a new database file starts with a header recording the file format version, and
+/// that files without a valid header are rejected without being modified.
+#[tokio::test]
+async fn test_file_header_is_validated() {
+    let filename = "test_file_header.db";
+    let _ = std::fs::remove_file(filename);
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, u32>("map".to_string()).unwrap();
+    hashmap.insert(1, 1).await.unwrap().unwrap();
+    drop(hashmap);
+    drop(db);
+
+    let header = bincode::serialize(&DBEntry::FileHeader(FORMAT_VERSION, 0)).unwrap();
+    assert!(std::fs::read(filename).unwrap().starts_with(&header));
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, u32>("map".to_string()).unwrap();
+    assert_eq!(*hashmap.get(&1).unwrap().value(), 1);
+    drop(hashmap);
+    drop(db);
+
+    let contents = b"this is not a rustmap-db database file";
+    std::fs::write(filename, contents).unwrap();
+    let error = DBMaker::file_db(PathBuf::from(filename))
+        .make()
+        .err()
+        .unwrap();
+    assert!(matches!(
+        error.get_ref().unwrap().downcast_ref::<StructureError>(),
+        Some(StructureError::BadMagic)
+    ));
+    assert_eq!(std::fs::read(filename).unwrap(), contents);
+
+    let header = bincode::serialize(&DBEntry::FileHeader(FORMAT_VERSION + 1, 0)).unwrap();
+    std::fs::write(filename, &header).unwrap();
+    let error = DBMaker::file_db(PathBuf::from(filename))
+        .make()
+        .err()
+        .unwrap();
+    assert!(matches!(
+        error.get_ref().unwrap().downcast_ref::<StructureError>(),
+        Some(StructureError::UnsupportedVersion(version)) if *version == FORMAT_VERSION + 1
+    ));
+    assert_eq!(std::fs::read(filename).unwrap(), header);
+
+    std::fs::remove_file(filename).unwrap();
+}
+
+/// Tests that a file of random bytes is rejected without being modified, even when it starts
+/// with an entry, instead of being taken for a log written before the file header existed, and
+/// that such a log still opens.
+#[tokio::test]
+async fn test_random_bytes_file_is_rejected() {
+    let filename = "test_random_bytes.db";
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let random = (0..4096)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect::<Vec<_>>();
+    let first_entry = bincode::serialize(&DBEntry::LogFormat(0)).unwrap();
+    for contents in [random.clone(), [first_entry, random].concat()] {
+        std::fs::write(filename, &contents).unwrap();
+        let error = DBMaker::file_db(PathBuf::from(filename))
+            .make()
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(matches!(
+            error.get_ref().unwrap().downcast_ref::<StructureError>(),
+            Some(StructureError::BadMagic)
+        ));
+        assert_eq!(std::fs::read(filename).unwrap(), contents);
+    }
+
+    std::fs::remove_file(filename).unwrap();
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, u32>("map".to_string()).unwrap();
+    hashmap.insert(1, 1).await.unwrap().unwrap();
+    drop(hashmap);
+    drop(db);
+    let header = bincode::serialize(&DBEntry::FileHeader(FORMAT_VERSION, 0)).unwrap();
+    let log = std::fs::read(filename).unwrap();
+    std::fs::write(filename, &log[header.len()..]).unwrap();
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, u32>("map".to_string()).unwrap();
+    assert_eq!(hashmap.get_cloned(&1), Some(1));
+    drop(hashmap);
+    drop(db);
+    std::fs::remove_file(filename).unwrap();
+}
+
+/// Tests that automatic compaction runs on the database's writer, keeping the file bounded
+/// while writes from several tasks keep overwriting and removing keys.
+#[tokio::test]
+async fn test_auto_compact_through_writer() {
+    let filename = "test_auto_compact.db";
+    let _ = std::fs::remove_file(filename);
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .auto_compact_ratio(2.0)
+        .build()
+        .unwrap();
+    let hashmap = Arc::new(
+        db.hash_map_with_config::<u32, Vec<u8>>("map".to_string(), config)
+            .unwrap(),
+    );
+    let mut largest = 0;
+    for round in 0..100u32 {
+        let writes = (0..4u32)
+            .map(|task| {
+                let hashmap = hashmap.clone();
+                tokio::spawn(async move {
+                    let key = task * 10 + round % 10;
+                    hashmap.insert(key, vec![task as u8; 64]).await??;
+                    if let Some(removal) = hashmap.remove(&(key + 1)) {
+                        removal.await??;
+                    }
+                    Ok::<_, StructureError>(())
+                })
+            })
+            .collect::<Vec<_>>();
+        for write in writes {
+            write.await.unwrap().unwrap();
+        }
+        largest = largest.max(db.file_size().unwrap());
+    }
+    let live = hashmap.compaction_estimate().unwrap().estimated_bytes;
+    assert!(largest < live * 8, "{} >= 8 * {}", largest, live);
+    let expected = hashmap
+        .iter()
+        .map(|entry| (*entry.key(), entry.value().clone()))
+        .collect::<std::collections::HashMap<_, _>>();
+    drop(hashmap);
+    db.close_async().await.unwrap();
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .build()
+        .unwrap();
+    let hashmap = db
+        .hash_map_with_config::<u32, Vec<u8>>("map".to_string(), config)
+        .unwrap();
+    let loaded = hashmap
+        .iter()
+        .map(|entry| (*entry.key(), entry.value().clone()))
+        .collect::<std::collections::HashMap<_, _>>();
+    assert_eq!(loaded, expected);
+    drop(hashmap);
+    drop(db);
+
+    std::fs::remove_file(filename).unwrap();
+}
+
+/// Tests that the sizes reported per structure cover every record of the structure and add up
+/// to the size of the file.
+#[tokio::test]
+async fn test_size_by_id() {
+    let filename = "test_size_by_id.db";
+    let _ = std::fs::remove_file(filename);
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let small = db.hash_map::<u32, u32>("small".to_string()).unwrap();
+    let large = db.hash_map::<u32, String>("large".to_string()).unwrap();
+    small.insert(1, 1).await.unwrap().unwrap();
+    for i in 0..10 {
+        large.insert(i, "x".repeat(100)).await.unwrap().unwrap();
+    }
+    large.remove(&0).unwrap().await.unwrap().unwrap();
+
+    let sizes = db.size_by_id().unwrap();
+    assert_eq!(sizes.len(), 2);
+    let small_size = sizes[&bincode::serialize(&raw_id("small")).unwrap()];
+    let large_size = sizes[&bincode::serialize(&raw_id("large")).unwrap()];
+    assert!(small_size > 0);
+    assert!(large_size > small_size * 10);
+    let header = bincode::serialize(&DBEntry::FileHeader(FORMAT_VERSION, 0)).unwrap();
+    assert_eq!(
+        small_size + large_size + header.len() as u64,
+        db.file_size().unwrap()
+    );
+    drop(small);
+    drop(large);
+    drop(db);
+
+    std::fs::remove_file(filename).unwrap();
+}
+
+/// Tests that the structures of a file are listed with their kind and number of live entries.
+#[tokio::test]
+async fn test_list_structures() {
+    let filename = "test_list_structures.db";
+    let _ = std::fs::remove_file(filename);
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, String>("users".to_string()).unwrap();
+    let hashset = db.hash_set::<String>("tags".to_string()).unwrap();
+    let multimap = db.multi_map::<u32, String>("labels".to_string()).unwrap();
+    for i in 0..5 {
+        hashmap.insert(i, i.to_string()).await.unwrap().unwrap();
+    }
+    hashmap
+        .insert(0, "zero".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    hashmap.remove(&1).unwrap().await.unwrap().unwrap();
+    for tag in ["a", "b", "c"] {
+        hashset.insert(tag.to_string()).await.unwrap().unwrap();
+    }
+    multimap
+        .insert(1, "red".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    multimap
+        .insert(1, "blue".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    multimap
+        .insert(2, "red".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    multimap
+        .remove_value(&2, &"red".to_string())
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
+    drop(hashmap);
+    drop(hashset);
+    drop(multimap);
+    drop(db);
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let structures = db.list_structures().unwrap();
+    assert_eq!(
+        structures,
+        vec![
+            StructureInfo {
+                id: "labels".to_string(),
+                kind: StructureKind::MultiMap,
+                len: 1,
+            },
+            StructureInfo {
+                id: "tags".to_string(),
+                kind: StructureKind::HashSet,
+                len: 3,
+            },
+            StructureInfo {
+                id: "users".to_string(),
+                kind: StructureKind::HashMap,
+                len: 4,
+            },
+        ]
+    );
+    drop(db);
+
+    std::fs::remove_file(filename).unwrap();
+}
+
+/// Tests that the synchronous writes of hashmaps and hashsets work without a tokio runtime.
+#[test]
+fn test_sync_writes_without_runtime() {
+    let filename = "test_sync_writes.db";
+    let _ = std::fs::remove_file(filename);
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, String>("map".to_string()).unwrap();
+    let hashset = db.hash_set::<u32>("set".to_string()).unwrap();
+    assert_eq!(hashmap.insert_sync(1, "one".to_string()).unwrap(), None);
+    assert_eq!(
+        hashmap.insert_sync(1, "uno".to_string()).unwrap(),
+        Some("one".to_string())
+    );
+    let old = hashmap
+        .insert_batch_sync(vec![(2, "two".to_string()), (3, "three".to_string())])
+        .unwrap();
+    assert_eq!(old, vec![None, None]);
+    assert_eq!(hashmap.remove_sync(&2).unwrap(), Some("two".to_string()));
+    assert_eq!(hashmap.remove_sync(&2).unwrap(), None);
+    assert_eq!(
+        hashmap.remove_batch_sync(vec![3]).unwrap(),
+        vec![(3, "three".to_string())]
+    );
+
+    assert!(hashset.insert_sync(1).unwrap());
+    assert!(!hashset.insert_sync(1).unwrap());
+    assert_eq!(
+        hashset.insert_batch_sync(vec![2, 3]).unwrap(),
+        vec![true, true]
+    );
+    assert_eq!(hashset.remove_sync(&2).unwrap(), Some(2));
+    assert_eq!(hashset.remove_sync(&2).unwrap(), None);
+    assert_eq!(hashset.remove_batch_sync(vec![3]).unwrap(), vec![3]);
+    drop(hashmap);
+    drop(hashset);
+    db.sync().unwrap();
+    drop(db);
+
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    let hashmap = db.hash_map::<u32, String>("map".to_string()).unwrap();
+    let hashset = db.hash_set::<u32>("set".to_string()).unwrap();
+    assert_eq!(hashmap.len(), 1);
+    assert_eq!(hashmap.get(&1).unwrap().value(), "uno");
+    assert_eq!(hashset.len(), 1);
+    assert!(hashset.contains(&1));
+    drop(hashmap);
+    drop(hashset);
+    drop(db);
+
+    std::fs::remove_file(filename).unwrap();
+}
+
+/// Returns the id bytes `Database` derives from a structure name.
+fn raw_id(name: &str) -> Vec<u8> {
+    let mut id = name.len().to_be_bytes().to_vec();
+    id.extend_from_slice(name.as_bytes());
+    id
+}
@@ -7,11 +7,20 @@
 use std::{
     fs::File,
     hash::Hash,
+    io::{Read, Seek, SeekFrom, Write},
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use rustmap_db::{DBMaker, HashMap, HashMapConfigBuilder};
+use rustmap_db::{
+    db::db_entry::DBEntry, structures::DEFAULT_BATCH_CHUNK_SIZE, DBMaker, Durability,
+    EvictionPolicy, HashMap, HashMapConfigBuilder, RecoveryMode, SerializationFormat,
+    StructureError, UnknownEntryPolicy,
+};
 use serde::{Deserialize, Serialize};
 
 // Below are the tests for the HashMap structure.
@@ -32,7 +41,7 @@ fn test_with_config() {
         .unwrap();
     let map = HashMap::<String, String>::with_config(file, vec![1], config).unwrap();
     assert_eq!(map.len(), 0);
-    assert_eq!(map.is_empty(), true);
+    assert!(map.is_empty());
     assert_eq!(map.capacity(), 112);
 }
 
@@ -77,9 +86,9 @@ async fn test_clear() {
         .await
         .unwrap()
         .unwrap();
-    assert_eq!(map.is_empty(), false);
+    assert!(!map.is_empty());
     map.clear().unwrap();
-    assert_eq!(map.is_empty(), true);
+    assert!(map.is_empty());
 }
 
 /// Tests the insertion of an existing key to verify that the value is updated.
@@ -187,8 +196,15 @@ async fn test_insert_remove_serialization() {
 /// Tests that clearing a `HashMap` persists correctly to disk.
 #[tokio::test]
 async fn test_clear_serialization() {
-    let map = create::<String, String>("test_clear.db", "test_clear");
-    let map2 = create::<String, String>("test_clear.db", "test_clear_2");
+    let db = DBMaker::file_db(PathBuf::from("test_clear.db"))
+        .make()
+        .unwrap();
+    let map = db
+        .hash_map::<String, String>("test_clear".to_string())
+        .unwrap();
+    let map2 = db
+        .hash_map::<String, String>("test_clear_2".to_string())
+        .unwrap();
     let key = "key".to_string();
     let value = "value".to_string();
     map.insert(key.clone(), value.clone())
@@ -202,10 +218,16 @@ async fn test_clear_serialization() {
     assert_eq!(map.get(&key).unwrap().value(), &value);
     assert_eq!(map2.get(&key).unwrap().value(), &value);
     map.clear().unwrap();
-    drop(map);
-    drop(map2);
-    let map = create::<String, String>("test_clear.db", "test_clear");
-    let map2 = create::<String, String>("test_clear.db", "test_clear_2");
+    drop((map, map2, db));
+    let db = DBMaker::file_db(PathBuf::from("test_clear.db"))
+        .make()
+        .unwrap();
+    let map = db
+        .hash_map::<String, String>("test_clear".to_string())
+        .unwrap();
+    let map2 = db
+        .hash_map::<String, String>("test_clear_2".to_string())
+        .unwrap();
     assert!(map.get(&key).is_none());
     assert_eq!(map2.get(&key).unwrap().value(), &value);
     std::fs::remove_file("test_clear.db").unwrap();
@@ -251,12 +273,2351 @@ async fn test_concurrent_read_write() {
     }
 }
 
-/// Utility function to create a `HashMap` with a given id.
-fn create<K, V>(filename: &str, id: &str) -> HashMap<K, V>
-where
-    K: Hash + Eq + Serialize + for<'de> Deserialize<'de> + Clone + Send + 'static,
-    V: Serialize + for<'de> Deserialize<'de> + Clone + Send + 'static,
-{
-    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
-    db.hash_map(id.to_string()).unwrap()
+/// Tests that a batch larger than the chunk size is written in pieces and fully persisted.
+#[tokio::test]
+async fn test_insert_batch_chunked() {
+    let file = temp_file();
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .batch_chunk_size(16)
+        .build()
+        .unwrap();
+    let map = HashMap::<u64, String>::with_config(file.clone(), vec![12], config).unwrap();
+    let entries: Vec<_> = (0..1000u64).map(|i| (i, format!("value{}", i))).collect();
+    map.insert_batch(entries.clone()).await.unwrap().unwrap();
+    drop(map);
+
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .build()
+        .unwrap();
+    let map = HashMap::<u64, String>::with_config(file, vec![12], config).unwrap();
+    assert_eq!(map.len(), entries.len());
+    for (key, value) in entries {
+        assert_eq!(map.get(&key).unwrap().value(), &value);
+    }
+}
+
+/// Tests that `try_get` reports hits and misses as `Ok`.
+#[tokio::test]
+async fn test_try_get() {
+    let file = temp_file();
+    let map = HashMap::<String, String>::new(file, vec![13]).unwrap();
+    map.insert("key".to_string(), "value".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    let hit = map.try_get(&"key".to_string()).unwrap();
+    assert_eq!(hit.unwrap().value(), "value");
+    assert!(map.try_get(&"missing".to_string()).unwrap().is_none());
+}
+
+/// Tests that reopening a fingerprinted map with a different value type is rejected.
+#[tokio::test]
+async fn test_type_fingerprint_mismatch() {
+    let file = temp_file();
+    let config = || {
+        HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .type_fingerprint(true)
+            .build()
+            .unwrap()
+    };
+    let map = HashMap::<String, u32>::with_config(file.clone(), vec![14], config()).unwrap();
+    map.insert("key".to_string(), 1).await.unwrap().unwrap();
+    drop(map);
+
+    let map = HashMap::<String, u32>::with_config(file.clone(), vec![14], config()).unwrap();
+    assert_eq!(map.get(&"key".to_string()).unwrap().value(), &1);
+    drop(map);
+
+    let result = HashMap::<String, u64>::with_config(file, vec![14], config());
+    assert!(matches!(result, Err(StructureError::TypeMismatch { .. })));
+}
+
+/// Tests that `collect_pairs` returns every inserted pair.
+#[tokio::test]
+async fn test_collect_pairs() {
+    let file = temp_file();
+    let map = HashMap::<u32, String>::new(file, vec![15]).unwrap();
+    let entries: Vec<_> = (0..50u32).map(|i| (i, format!("value{}", i))).collect();
+    map.insert_batch(entries.clone()).await.unwrap().unwrap();
+
+    let mut pairs = map.collect_pairs();
+    pairs.sort();
+    assert_eq!(pairs, entries);
+}
+
+/// A value whose serialization is deliberately slow.
+#[derive(Clone, Deserialize)]
+struct SlowValue(u64);
+
+impl Serialize for SlowValue {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        std::thread::sleep(Duration::from_millis(300));
+        serializer.serialize_u64(self.0)
+    }
+}
+
+/// Tests that a slow serialization doesn't block other writers on the same file.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_slow_serialization_does_not_block_writers() {
+    let file = temp_file();
+    let slow_map = HashMap::<String, SlowValue>::new(file.clone(), vec![16]).unwrap();
+    let fast_map = HashMap::<String, String>::new(file, vec![17]).unwrap();
+
+    let slow = slow_map.insert("slow".to_string(), SlowValue(1));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let fast = fast_map.insert("fast".to_string(), "value".to_string());
+    tokio::time::timeout(Duration::from_millis(200), fast)
+        .await
+        .expect("fast write was blocked by a slow serialization")
+        .unwrap()
+        .unwrap();
+    assert!(!slow.is_finished());
+    slow.await.unwrap().unwrap();
+}
+
+/// Tests that `try_open` rejects a file that isn't a rustmap-db file.
+#[test]
+fn test_try_open_rejects_text_file() {
+    let mut text = tempfile::tempfile().unwrap();
+    text.write_all(b"this is just a plain text file\n").unwrap();
+    let result = HashMap::<String, String>::try_open(Arc::new(Mutex::new(text)), vec![18]);
+    assert!(matches!(result, Err(StructureError::NotARustmapFile)));
+}
+
+/// Tests that `try_open` survives an entry whose length prefix claims far more bytes than the
+/// file holds, treating it like a truncated final entry instead of trying to read it.
+#[tokio::test]
+async fn test_try_open_bounds_crafted_length() {
+    let file = temp_file();
+    let map = HashMap::<u32, u32>::new(file.clone(), vec![19]).unwrap();
+    map.insert(1, 1).await.unwrap().unwrap();
+    drop(map);
+    {
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        let mut crafted = 0u32.to_le_bytes().to_vec();
+        crafted.extend_from_slice(&(u64::MAX / 2).to_le_bytes());
+        file.write_all(&crafted).unwrap();
+    }
+
+    let map = HashMap::<u32, u32>::try_open(file, vec![19]).unwrap();
+    assert_eq!(map.len(), 1);
+    assert_eq!(map.get(&1).unwrap().value(), &1);
+}
+
+/// Tests that `try_open` accepts a file written by rustmap-db.
+#[tokio::test]
+async fn test_try_open_accepts_database_file() {
+    let file = temp_file();
+    let map = HashMap::<String, String>::new(file.clone(), vec![19]).unwrap();
+    map.insert("key".to_string(), "value".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    drop(map);
+    let map = HashMap::<String, String>::try_open(file, vec![19]).unwrap();
+    assert_eq!(map.get(&"key".to_string()).unwrap().value(), "value");
+}
+
+/// Tests that `lock_key` serializes holders of the same key without blocking other keys.
+#[test]
+fn test_lock_key() {
+    let map = Arc::new(HashMap::<String, u64>::new(temp_file(), vec![20]).unwrap());
+    let start = Instant::now();
+
+    let first = {
+        let map = map.clone();
+        std::thread::spawn(move || {
+            let _guard = map.lock_key(&"shared".to_string());
+            std::thread::sleep(Duration::from_millis(300));
+        })
+    };
+    std::thread::sleep(Duration::from_millis(50));
+
+    let contended = {
+        let map = map.clone();
+        std::thread::spawn(move || {
+            let _guard = map.lock_key(&"shared".to_string());
+            start.elapsed()
+        })
+    };
+    let unrelated = {
+        let map = map.clone();
+        std::thread::spawn(move || {
+            let guard = map.lock_key(&"other".to_string());
+            assert_eq!(guard.key(), "other");
+            start.elapsed()
+        })
+    };
+
+    assert!(unrelated.join().unwrap() < Duration::from_millis(250));
+    first.join().unwrap();
+    assert!(contended.join().unwrap() >= Duration::from_millis(300));
+}
+
+/// Tests removing keys fed from a stream, including keys that aren't present.
+#[tokio::test]
+async fn test_remove_stream() {
+    let file = temp_file();
+    let config = || {
+        HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .batch_chunk_size(3)
+            .build()
+            .unwrap()
+    };
+    let map = HashMap::<u32, u32>::with_config(file.clone(), vec![21], config()).unwrap();
+    map.insert_batch((0..10).map(|i| (i, i)).collect())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let keys = futures::stream::iter(vec![1, 3, 5, 7, 100, 200, 9]);
+    let removed = map.remove_stream(keys).await.unwrap().unwrap();
+    assert_eq!(removed, 5);
+    drop(map);
+
+    let map = HashMap::<u32, u32>::with_config(file, vec![21], config()).unwrap();
+    let mut keys = map
+        .collect_pairs()
+        .into_iter()
+        .map(|(k, _)| k)
+        .collect::<Vec<_>>();
+    keys.sort();
+    assert_eq!(keys, vec![0, 2, 4, 6, 8]);
+}
+
+/// Tests that a compaction estimate reports reclaimable space without touching the file.
+#[tokio::test]
+async fn test_compaction_estimate() {
+    let file = temp_file();
+    let map = HashMap::<String, u64>::new(file.clone(), vec![22]).unwrap();
+    for i in 0..10 {
+        map.insert("churned".to_string(), i).await.unwrap().unwrap();
+    }
+    map.insert("removed".to_string(), 0).await.unwrap().unwrap();
+    map.remove(&"removed".to_string())
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
+    map.insert("kept".to_string(), 1).await.unwrap().unwrap();
+
+    let before = read_all(&file);
+    let estimate = map.compaction_estimate().unwrap();
+    assert_eq!(read_all(&file), before);
+
+    assert_eq!(estimate.current_bytes, before.len() as u64);
+    assert_eq!(estimate.live_entries, 2);
+    assert!(estimate.reclaimable_bytes > 0);
+    assert_eq!(
+        estimate.current_bytes,
+        estimate.estimated_bytes + estimate.reclaimable_bytes
+    );
+}
+
+/// Tests that an extension entry from a newer version is skipped or rejected by policy.
+#[tokio::test]
+async fn test_unknown_extension_entry_policy() {
+    let file = temp_file();
+    let map = HashMap::<String, u32>::new(file.clone(), vec![23]).unwrap();
+    map.insert("before".to_string(), 1).await.unwrap().unwrap();
+    {
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(&[250]).unwrap();
+        file.write_all(&bincode::serialize(&vec![7u8; 12]).unwrap())
+            .unwrap();
+    }
+    map.insert("after".to_string(), 2).await.unwrap().unwrap();
+    drop(map);
+
+    let map = HashMap::<String, u32>::new(file.clone(), vec![23]).unwrap();
+    assert_eq!(map.get(&"before".to_string()).unwrap().value(), &1);
+    assert_eq!(map.get(&"after".to_string()).unwrap().value(), &2);
+
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .unknown_entries(UnknownEntryPolicy::Reject)
+        .build()
+        .unwrap();
+    let id = vec![23u8];
+    let result = HashMap::<String, u32>::with_config(file, id, config);
+    assert!(matches!(result, Err(StructureError::UnknownEntry(250))));
+}
+
+/// Tests that values above the large value threshold live in a sidecar file and read back exactly.
+#[tokio::test]
+async fn test_large_values_in_sidecar_file() {
+    let file = temp_file();
+    let dir = tempfile::tempdir().unwrap();
+    let config = || {
+        HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .large_value_threshold(64)
+            .large_value_dir(dir.path())
+            .build()
+            .unwrap()
+    };
+    let large = (0..10_000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    let map = HashMap::<String, Vec<u8>>::with_config(file.clone(), vec![24], config()).unwrap();
+    map.insert("small".to_string(), vec![1, 2, 3])
+        .await
+        .unwrap()
+        .unwrap();
+    map.insert("large".to_string(), large.clone())
+        .await
+        .unwrap()
+        .unwrap();
+    drop(map);
+
+    assert!(read_all(&file).len() < large.len());
+    let sidecars = std::fs::read_dir(dir.path()).unwrap().count();
+    assert_eq!(sidecars, 1);
+
+    let map = HashMap::<String, Vec<u8>>::with_config(file.clone(), vec![24], config()).unwrap();
+    assert_eq!(map.len(), 2);
+    assert_eq!(
+        map.try_get(&"large".to_string()).unwrap().unwrap().value(),
+        &large
+    );
+    assert_eq!(
+        map.get(&"small".to_string()).unwrap().value(),
+        &vec![1, 2, 3]
+    );
+
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .build()
+        .unwrap();
+    let result = HashMap::<String, Vec<u8>>::with_config(file, vec![24], config);
+    assert!(matches!(result, Err(StructureError::LargeValueDirRequired)));
+}
+
+/// Tests that the batch summary counts new and updated keys.
+#[tokio::test]
+async fn test_insert_batch_summary() {
+    let map = HashMap::<String, u32>::new(temp_file(), vec![25]).unwrap();
+    map.insert("a".to_string(), 1).await.unwrap().unwrap();
+    map.insert("b".to_string(), 2).await.unwrap().unwrap();
+
+    let summary = map
+        .insert_batch_summary(vec![
+            ("a".to_string(), 10),
+            ("c".to_string(), 30),
+            ("b".to_string(), 20),
+            ("d".to_string(), 40),
+            ("e".to_string(), 50),
+        ])
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(summary.inserted, 3);
+    assert_eq!(summary.updated, 2);
+    assert_eq!(summary.old_values, vec![Some(1), None, Some(2), None, None]);
+    assert_eq!(map.len(), 5);
+}
+
+/// Tests that concurrent misses on the same key run the loader exactly once.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_get_or_load_coalesces_misses() {
+    let file = temp_file();
+    let map = Arc::new(HashMap::<String, u32>::new(file.clone(), vec![26]).unwrap());
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let mut handles = Vec::new();
+    for _ in 0..16 {
+        let map = map.clone();
+        let calls = calls.clone();
+        handles.push(tokio::spawn(async move {
+            map.get_or_load("key".to_string(), |_| async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(42)
+            })
+            .await
+            .unwrap()
+        }));
+    }
+    for handle in handles {
+        assert_eq!(handle.await.unwrap(), 42);
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    let loaded = map
+        .get_or_load("key".to_string(), |_| async { Ok(0) })
+        .await
+        .unwrap();
+    assert_eq!(loaded, 42);
+
+    let reopened = HashMap::<String, u32>::new(file, vec![26]).unwrap();
+    assert_eq!(reopened.get(&"key".to_string()).unwrap().value(), &42);
+}
+
+/// Tests that awaited writes are persisted and dropped, unflushed writes can diverge.
+#[tokio::test]
+async fn test_assert_persisted() {
+    let map = HashMap::<String, u32>::new(temp_file(), vec![27]).unwrap();
+    map.insert("a".to_string(), 1).await.unwrap().unwrap();
+    map.insert_batch(vec![("b".to_string(), 2), ("c".to_string(), 3)])
+        .await
+        .unwrap()
+        .unwrap();
+    map.remove(&"b".to_string())
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
+    map.assert_persisted().unwrap();
+
+    // On the current-thread runtime the spawned write can't run until this task yields.
+    drop(map.insert("d".to_string(), 4));
+    match map.assert_persisted() {
+        Err(StructureError::NotPersisted(keys)) => assert_eq!(keys, vec!["\"d\"".to_string()]),
+        other => panic!("expected divergence, got {:?}", other),
+    }
+
+    // The append then runs on the blocking thread pool, so give it time to finish.
+    for _ in 0..1000 {
+        if map.assert_persisted().is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    map.assert_persisted().unwrap();
+}
+
+/// Tests that a synced insert survives reopening the file straight after it completes.
+#[tokio::test]
+async fn test_insert_synced() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("synced.db");
+    let db = DBMaker::file_db(path.clone())
+        .allow_shared()
+        .make()
+        .unwrap();
+    let map = db.hash_map::<String, u32>("map".to_string()).unwrap();
+    map.insert_synced("durable".to_string(), 7)
+        .await
+        .unwrap()
+        .unwrap();
+
+    // Simulate a crash by reopening the file without dropping or flushing the map.
+    let reopened = DBMaker::file_db(path).allow_shared().make().unwrap();
+    let recovered = reopened.hash_map::<String, u32>("map".to_string()).unwrap();
+    assert_eq!(recovered.get(&"durable".to_string()).unwrap().value(), &7);
+    drop(map);
+}
+
+/// Tests that the effective configuration reports the configured and default settings.
+#[test]
+fn test_effective_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(16)
+        .capacity(100)
+        .durability(Durability::Sync)
+        .large_value_threshold(1024)
+        .large_value_dir(dir.path())
+        .write_retries(3)
+        .retry_backoff(Duration::from_millis(5))
+        .build()
+        .unwrap();
+    let map = HashMap::<String, u32>::with_config(temp_file(), vec![28], config).unwrap();
+    let effective = map.config();
+    assert_eq!(effective.shard_amount, 16);
+    assert_eq!(effective.capacity, 100);
+    assert_eq!(effective.batch_chunk_size, DEFAULT_BATCH_CHUNK_SIZE);
+    assert!(!effective.type_fingerprint);
+    assert_eq!(effective.unknown_entries, UnknownEntryPolicy::Skip);
+    assert_eq!(effective.durability, Durability::Sync);
+    assert_eq!(effective.large_value_threshold, Some(1024));
+    assert_eq!(effective.large_value_dir.as_deref(), Some(dir.path()));
+    assert_eq!(effective.write_retries, 3);
+    assert_eq!(effective.retry_backoff, Duration::from_millis(5));
+
+    let map = HashMap::<String, u32>::new(temp_file(), vec![28]).unwrap();
+    let effective = map.config();
+    assert!(effective.shard_amount.is_power_of_two());
+    assert_eq!(effective.durability, Durability::Flush);
+    assert_eq!(effective.large_value_dir, None);
+    assert_eq!(effective.write_retries, 0);
+}
+
+/// Tests that only entries stamped before the cutoff are removed, including after a reopen.
+#[tokio::test]
+async fn test_remove_older_than() {
+    let file = temp_file();
+    let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let at = |secs: u64| epoch + Duration::from_secs(secs);
+    let map = HashMap::<String, u32>::new(file.clone(), vec![29]).unwrap();
+    for (key, value, secs) in [("old1", 1, 10), ("old2", 2, 20), ("new", 3, 40)] {
+        map.insert_with_timestamp(key.to_string(), value, at(secs))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    map.insert_with_timestamp("refreshed".to_string(), 4, at(5))
+        .await
+        .unwrap()
+        .unwrap();
+    map.insert_with_timestamp("refreshed".to_string(), 5, at(50))
+        .await
+        .unwrap()
+        .unwrap();
+    map.insert("unstamped".to_string(), 6)
+        .await
+        .unwrap()
+        .unwrap();
+    drop(map);
+
+    let map = HashMap::<String, u32>::new(file, vec![29]).unwrap();
+    assert_eq!(map.remove_older_than(at(30)).await.unwrap().unwrap(), 2);
+    assert!(map.get(&"old1".to_string()).is_none());
+    assert!(map.get(&"old2".to_string()).is_none());
+    assert_eq!(map.get(&"new".to_string()).unwrap().value(), &3);
+    assert_eq!(map.get(&"refreshed".to_string()).unwrap().value(), &5);
+    assert_eq!(map.get(&"unstamped".to_string()).unwrap().value(), &6);
+    map.assert_persisted().unwrap();
+}
+
+/// Tests that overwriting fixed-size values in place doesn't grow the file.
+#[tokio::test]
+async fn test_overwrite_in_place() {
+    let file = temp_file();
+    let config = || {
+        HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .overwrite_in_place(true)
+            .build()
+            .unwrap()
+    };
+    let map = HashMap::<String, u64>::with_config(file.clone(), vec![30], config()).unwrap();
+    map.insert("other".to_string(), 0).await.unwrap().unwrap();
+    map.insert("counter".to_string(), 0).await.unwrap().unwrap();
+    let size = read_all(&file).len();
+    for i in 1..=100 {
+        map.insert("counter".to_string(), i).await.unwrap().unwrap();
+    }
+    assert_eq!(read_all(&file).len(), size);
+    drop(map);
+
+    let map = HashMap::<String, u64>::with_config(file.clone(), vec![30], config()).unwrap();
+    assert_eq!(map.get(&"counter".to_string()).unwrap().value(), &100);
+    map.insert("counter".to_string(), 101)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_all(&file).len(), size);
+
+    // Values of a different length fall back to appending.
+    let names = HashMap::<String, String>::with_config(file.clone(), vec![31], config()).unwrap();
+    names
+        .insert("name".to_string(), "a".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    let size = read_all(&file).len();
+    names
+        .insert("name".to_string(), "bb".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(read_all(&file).len() > size);
+    drop(names);
+    let names = HashMap::<String, String>::with_config(file, vec![31], config()).unwrap();
+    assert_eq!(names.get(&"name".to_string()).unwrap().value(), "bb");
+}
+
+/// Tests that a failed background write reaches the error handler even if its handle is dropped.
+#[tokio::test]
+async fn test_error_handler() {
+    let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+    let read_only = Arc::new(Mutex::new(File::open(&path).unwrap()));
+    let map = HashMap::<String, u32>::new(read_only, vec![32]).unwrap();
+    let errors = Arc::new(AtomicUsize::new(0));
+    let handler_errors = errors.clone();
+    map.set_error_handler(move |error| {
+        assert!(matches!(error, StructureError::IoError(_)));
+        handler_errors.fetch_add(1, Ordering::SeqCst);
+    });
+
+    drop(map.insert("dropped".to_string(), 1));
+    let awaited = map.insert("awaited".to_string(), 2).await.unwrap();
+    assert!(matches!(awaited, Err(StructureError::IoError(_))));
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while errors.load(Ordering::SeqCst) < 2 && Instant::now() < deadline {
+        tokio::task::yield_now().await;
+    }
+    assert_eq!(errors.load(Ordering::SeqCst), 2);
+}
+
+/// Tests that mutating an in-memory copy leaves the original and its file untouched.
+#[tokio::test]
+async fn test_in_memory_copy() {
+    let file = temp_file();
+    let map = HashMap::<String, u32>::new(file.clone(), vec![33]).unwrap();
+    map.insert("a".to_string(), 1).await.unwrap().unwrap();
+    map.insert("b".to_string(), 2).await.unwrap().unwrap();
+    let before = read_all(&file);
+
+    let copy = map.in_memory_copy().unwrap();
+    assert_eq!(copy.len(), 2);
+    assert_eq!(copy.get(&"a".to_string()).unwrap().value(), &1);
+    copy.insert("a".to_string(), 10).await.unwrap().unwrap();
+    copy.insert("c".to_string(), 3).await.unwrap().unwrap();
+    copy.remove(&"b".to_string())
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
+    copy.assert_persisted().unwrap();
+
+    assert_eq!(read_all(&file), before);
+    assert_eq!(map.len(), 2);
+    assert_eq!(map.get(&"a".to_string()).unwrap().value(), &1);
+    assert!(map.get(&"c".to_string()).is_none());
+}
+
+/// Tests that replacing all entries never exposes an empty map and persists only the new entries.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_replace_all() {
+    let file = temp_file();
+    let map = Arc::new(HashMap::<u32, u32>::new(file.clone(), vec![34]).unwrap());
+    let other = HashMap::<u32, u32>::new(file.clone(), vec![35]).unwrap();
+    other.insert(1, 1).await.unwrap().unwrap();
+    let old = (0..100).map(|i| (i, i)).collect::<Vec<_>>();
+    map.insert_batch(old).await.unwrap().unwrap();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let observer = {
+        let map = map.clone();
+        let done = done.clone();
+        std::thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                assert!(!map.is_empty());
+                assert!(map.get(&50).is_some());
+            }
+        })
+    };
+    for round in 1..=20u32 {
+        let entries = (50..150).map(|i| (i, i * round)).collect();
+        map.replace_all(entries).unwrap();
+    }
+    done.store(true, Ordering::SeqCst);
+    observer.join().unwrap();
+
+    assert_eq!(map.len(), 100);
+    assert!(map.get(&0).is_none());
+    assert_eq!(map.get(&149).unwrap().value(), &(149 * 20));
+    map.assert_persisted().unwrap();
+    drop(map);
+
+    let map = HashMap::<u32, u32>::new(file.clone(), vec![34]).unwrap();
+    assert_eq!(map.len(), 100);
+    assert_eq!(map.get(&50).unwrap().value(), &1000);
+    let other = HashMap::<u32, u32>::new(file, vec![35]).unwrap();
+    assert_eq!(other.get(&1).unwrap().value(), &1);
+}
+
+/// Tests that replacing all entries of a map opened through a `Database` swaps in a new log
+/// file rather than rewriting the old one in place, so the old log stays whole on disk until
+/// the new one replaces it.
+#[tokio::test]
+async fn test_replace_all_swaps_log_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("replace_all.db");
+    let db = DBMaker::file_db(path.clone()).make().unwrap();
+    let map = db.hash_map::<u32, u32>("map".to_string()).unwrap();
+    let other = db.hash_map::<u32, u32>("other".to_string()).unwrap();
+    other.insert(1, 1).await.unwrap().unwrap();
+    map.insert_batch((0..100).map(|i| (i, i)).collect())
+        .await
+        .unwrap()
+        .unwrap();
+    db.sync().unwrap();
+
+    let old_contents = std::fs::read(&path).unwrap();
+    let mut old_log = File::open(&path).unwrap();
+    map.replace_all((50..60).map(|i| (i, i * 2)).collect())
+        .unwrap();
+
+    // The handle still reads the old log in full, so it was never truncated or overwritten.
+    let mut contents = Vec::new();
+    old_log.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, old_contents);
+    assert!(std::fs::metadata(&path).unwrap().len() < old_contents.len() as u64);
+    let names = std::fs::read_dir(dir.path()).unwrap().count();
+    assert_eq!(names, 1);
+
+    map.insert(1000, 1).await.unwrap().unwrap();
+    drop((map, other, db));
+    let db = DBMaker::file_db(path).make().unwrap();
+    let map = db.hash_map::<u32, u32>("map".to_string()).unwrap();
+    let other = db.hash_map::<u32, u32>("other".to_string()).unwrap();
+    assert_eq!(map.len(), 11);
+    assert_eq!(map.get(&55).unwrap().value(), &110);
+    assert_eq!(map.get(&1000).unwrap().value(), &1);
+    assert_eq!(other.get(&1).unwrap().value(), &1);
+}
+
+/// Tests that loading stops at the end of log marker and ignores the zero padding after it.
+#[tokio::test]
+async fn test_end_of_log_ignores_padding() {
+    let file = temp_file();
+    let map = HashMap::<u32, u32>::new(file.clone(), vec![36]).unwrap();
+    map.insert(1, 10).await.unwrap().unwrap();
+    map.insert(2, 20).await.unwrap().unwrap();
+    {
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(&bincode::serialize(&DBEntry::EndOfLog).unwrap())
+            .unwrap();
+        file.write_all(&[0; 4096]).unwrap();
+        // An entry after the marker must be ignored too.
+        let key = bincode::serialize(&3u32).unwrap();
+        let id = bincode::serialize(&vec![36u8]).unwrap();
+        let entry = DBEntry::HashMapEntry(id, key, bincode::serialize(&30u32).unwrap());
+        file.write_all(&bincode::serialize(&entry).unwrap())
+            .unwrap();
+    }
+
+    let map = HashMap::<u32, u32>::try_open(file.clone(), vec![36]).unwrap();
+    assert_eq!(map.len(), 2);
+    assert!(map.get(&3).is_none());
+    assert_eq!(map.compaction_estimate().unwrap().live_entries, 2);
+}
+
+/// Tests that records older versions of `with_config` stored under the unencoded id are still
+/// loaded, by both constructors, and compacted together with the records under the encoded id.
+#[tokio::test]
+async fn test_with_config_loads_unencoded_id_records() {
+    let file = temp_file();
+    {
+        let mut file = file.lock().unwrap();
+        for (key, value) in [(1u32, 10u32), (2, 20)] {
+            let entry = DBEntry::HashMapEntry(
+                vec![81],
+                bincode::serialize(&key).unwrap(),
+                bincode::serialize(&value).unwrap(),
+            );
+            file.write_all(&bincode::serialize(&entry).unwrap())
+                .unwrap();
+        }
+    }
+    let config = || {
+        HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .build()
+            .unwrap()
+    };
+
+    let map = HashMap::<u32, u32>::with_config(file.clone(), vec![81], config()).unwrap();
+    assert_eq!(map.get_cloned(&1), Some(10));
+    assert_eq!(map.insert(2, 21).await.unwrap().unwrap(), Some(20));
+    assert_eq!(map.remove(&1).unwrap().await.unwrap().unwrap(), Some(10));
+    map.compact().unwrap();
+    drop(map);
+
+    let map = HashMap::<u32, u32>::new(file.clone(), vec![81]).unwrap();
+    assert_eq!(map.len(), 1);
+    assert_eq!(map.get_cloned(&2), Some(21));
+    let stats = map.stats().unwrap();
+    assert_eq!((stats.live_entries, stats.total_records), (1, 1));
+}
+
+/// Tests that `get_many_cloned` returns owned values aligned with the input keys.
+#[tokio::test]
+async fn test_get_many_cloned() {
+    let map = HashMap::<String, String>::new(temp_file(), vec![37]).unwrap();
+    map.insert("a".to_string(), "1".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    map.insert("c".to_string(), "3".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let keys = ["a", "b", "c", "a"].map(String::from);
+    let values = map.get_many_cloned(&keys);
+    // Holding the owned values while writing to the same keys must not deadlock.
+    map.insert("a".to_string(), "4".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        values,
+        vec![
+            Some("1".to_string()),
+            None,
+            Some("3".to_string()),
+            Some("1".to_string())
+        ]
+    );
+    assert!(map.get_many_cloned(&[]).is_empty());
+}
+
+/// A key whose `Hash` implementation ignores its contents, so every key collides.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CollidingKey(u32);
+
+impl Hash for CollidingKey {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        0u8.hash(state);
+    }
+}
+
+/// Tests that the hash distribution exposes the shard skew caused by a bad hash.
+#[tokio::test]
+async fn test_hash_distribution() {
+    let config = || {
+        HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .build()
+            .unwrap()
+    };
+
+    let map = HashMap::<CollidingKey, u32>::with_config(temp_file(), vec![38], config()).unwrap();
+    let entries = (0..200).map(|i| (CollidingKey(i), i)).collect();
+    map.insert_batch(entries).await.unwrap().unwrap();
+    let distribution = map.hash_distribution();
+    assert_eq!(distribution.len(), 8);
+    assert_eq!(distribution.iter().max(), Some(&200));
+    assert_eq!(distribution.iter().filter(|&&count| count > 0).count(), 1);
+
+    let map = HashMap::<u32, u32>::with_config(temp_file(), vec![38], config()).unwrap();
+    let entries = (0..200).map(|i| (i, i)).collect();
+    map.insert_batch(entries).await.unwrap().unwrap();
+    let distribution = map.hash_distribution();
+    assert_eq!(distribution.iter().sum::<usize>(), 200);
+    assert!(distribution.iter().all(|&count| count > 0 && count < 100));
+}
+
+/// Tests that loads running concurrently with rewrites of the file see a consistent log.
+#[test]
+fn test_scan_during_rewrite_is_consistent() {
+    let file = temp_file();
+    let first = (0..500u32).map(|i| (i, i)).collect::<Vec<_>>();
+    let second = (0..300u32).map(|i| (i, i + 1000)).collect::<Vec<_>>();
+    let map = HashMap::<u32, u32>::new(file.clone(), vec![39]).unwrap();
+    map.replace_all(first.clone()).unwrap();
+
+    let done = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            for round in 0..50 {
+                let entries = if round % 2 == 0 { &second } else { &first };
+                map.replace_all(entries.clone()).unwrap();
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+        while !done.load(Ordering::SeqCst) {
+            let loaded = HashMap::<u32, u32>::new(file.clone(), vec![39]).unwrap();
+            let mut pairs = loaded.collect_pairs();
+            pairs.sort_unstable();
+            assert!(
+                pairs == first || pairs == second,
+                "torn read of {} pairs",
+                pairs.len()
+            );
+            let estimate = loaded.compaction_estimate().unwrap();
+            assert!(estimate.live_entries == 500 || estimate.live_entries == 300);
+        }
+    });
+}
+
+/// Tests that counts added to repeated keys accumulate and survive a reload.
+#[tokio::test]
+async fn test_add_count() {
+    let file = temp_file();
+    let map = HashMap::<String, u64>::new(file.clone(), vec![40]).unwrap();
+    assert_eq!(map.add_count("a".to_string(), 2).await.unwrap().unwrap(), 2);
+    assert_eq!(map.add_count("b".to_string(), 5).await.unwrap().unwrap(), 5);
+    assert_eq!(map.add_count("a".to_string(), 3).await.unwrap().unwrap(), 5);
+    let handles = (0..100)
+        .map(|_| map.add_count("c".to_string(), 1))
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.await.unwrap().unwrap();
+    }
+    assert_eq!(map.get(&"c".to_string()).unwrap().value(), &100);
+    assert_eq!(map.total_count(), 110);
+
+    let map = HashMap::<String, u64>::new(file, vec![40]).unwrap();
+    assert_eq!(map.get(&"a".to_string()).unwrap().value(), &5);
+    assert_eq!(map.get(&"b".to_string()).unwrap().value(), &5);
+    assert_eq!(map.get(&"c".to_string()).unwrap().value(), &100);
+    assert_eq!(map.total_count(), 110);
+}
+
+/// Tests that pairs are returned in the order of their last write.
+#[tokio::test]
+async fn test_iter_log_order() {
+    let file = temp_file();
+    let map = HashMap::<String, u32>::new(file.clone(), vec![41]).unwrap();
+    for (key, value) in [("a", 1), ("b", 2), ("c", 3), ("d", 4)] {
+        map.insert(key.to_string(), value).await.unwrap().unwrap();
+    }
+    map.insert("a".to_string(), 5).await.unwrap().unwrap();
+    map.remove(&"c".to_string())
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
+    map.insert("c".to_string(), 6).await.unwrap().unwrap();
+    map.remove(&"b".to_string())
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
+
+    let expected = [("d", 4), ("a", 5), ("c", 6)]
+        .map(|(key, value)| (key.to_string(), value))
+        .to_vec();
+    assert_eq!(map.iter_log_order().unwrap(), expected);
+    let map = HashMap::<String, u32>::new(file, vec![41]).unwrap();
+    assert_eq!(map.iter_log_order().unwrap(), expected);
+}
+
+/// Tests that values above the size limit are rejected without changing memory or the file.
+#[tokio::test]
+async fn test_max_value_bytes() {
+    let file = temp_file();
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .max_value_bytes(16)
+        .build()
+        .unwrap();
+    let map = HashMap::<u32, String>::with_config(file.clone(), vec![42], config).unwrap();
+    map.insert(1, "small".to_string()).await.unwrap().unwrap();
+    let len = read_all(&file).len();
+
+    let large = "x".repeat(100);
+    let result = map.insert(1, large.clone()).await.unwrap();
+    assert!(matches!(
+        result,
+        Err(StructureError::ValueTooLarge {
+            size: 108,
+            limit: 16
+        })
+    ));
+    let result = map
+        .insert_batch(vec![(2, "ok".to_string()), (3, large)])
+        .await
+        .unwrap();
+    assert!(matches!(result, Err(StructureError::ValueTooLarge { .. })));
+
+    assert_eq!(map.get(&1).unwrap().value(), "small");
+    assert!(map.get(&2).is_none());
+    assert_eq!(map.len(), 1);
+    assert_eq!(read_all(&file).len(), len);
+    assert_eq!(map.config().max_value_bytes, Some(16));
+}
+
+/// A key whose serialized form depends on the iteration order of its tag map.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct TaggedKey {
+    name: String,
+    tags: std::collections::HashMap<String, u32>,
+}
+
+impl TaggedKey {
+    /// Builds the key with a fresh tag map, so equal keys usually iterate their tags in
+    /// different orders.
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            tags: (0..8).map(|i| (format!("tag{}", i), i)).collect(),
+        }
+    }
+}
+
+impl Hash for TaggedKey {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        let mut tags = self.tags.iter().collect::<Vec<_>>();
+        tags.sort();
+        tags.hash(state);
+    }
+}
+
+/// Tests that with canonical keys, equal keys match up in the file even when their maps
+/// serialize in different orders, so removals survive compaction and lookups work after reload.
+#[tokio::test]
+async fn test_canonical_keys() {
+    let file = temp_file();
+    let config = || {
+        HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .canonical_keys(true)
+            .build()
+            .unwrap()
+    };
+    let map = HashMap::<TaggedKey, u32>::with_config(file.clone(), vec![43], config()).unwrap();
+    for i in 0..20 {
+        map.insert(TaggedKey::new(&format!("key{}", i)), i)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    for i in (0..20).step_by(2) {
+        map.remove(&TaggedKey::new(&format!("key{}", i)))
+            .unwrap()
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    map.insert(TaggedKey::new("key1"), 100)
+        .await
+        .unwrap()
+        .unwrap();
+    map.compact().unwrap();
+
+    let reloaded = HashMap::<TaggedKey, u32>::with_config(file, vec![43], config()).unwrap();
+    assert_eq!(reloaded.len(), 10);
+    for i in 0..20 {
+        let value = reloaded
+            .get(&TaggedKey::new(&format!("key{}", i)))
+            .map(|value| *value.value());
+        let expected = match i {
+            1 => Some(100),
+            i if i % 2 == 0 => None,
+            i => Some(i),
+        };
+        assert_eq!(value, expected, "key{}", i);
+    }
+    assert!(reloaded.config().canonical_keys);
+}
+
+/// Tests that large keys are written in full only once with `hash_keys_above`, so overwriting
+/// them grows the log far less, and that the map reloads and compacts correctly.
+#[tokio::test]
+async fn test_hash_keys_above() {
+    let config = |hash: bool| {
+        let mut builder = HashMapConfigBuilder::default();
+        builder.shard_amount(8);
+        if hash {
+            builder.hash_keys_above(64);
+        }
+        builder.build().unwrap()
+    };
+    fn key(i: u32) -> String {
+        format!("/data/{}/file{}", "nested/".repeat(100), i)
+    }
+    async fn write(map: &HashMap<String, u32>) {
+        for round in 0..50 {
+            for i in 0..3 {
+                map.insert(key(i), round).await.unwrap().unwrap();
+            }
+            map.insert("short".to_string(), round)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        map.remove(&key(2)).unwrap().await.unwrap().unwrap();
+    }
+
+    let plain_file = temp_file();
+    let plain = HashMap::with_config(plain_file.clone(), vec![44], config(false)).unwrap();
+    write(&plain).await;
+    let hashed_file = temp_file();
+    let hashed = HashMap::with_config(hashed_file.clone(), vec![44], config(true)).unwrap();
+    write(&hashed).await;
+    assert!(read_all(&hashed_file).len() * 5 < read_all(&plain_file).len());
+    assert_eq!(hashed.config().hash_keys_above, Some(64));
+
+    let assert_state = |map: &HashMap<String, u32>| {
+        assert_eq!(map.len(), 3);
+        assert_eq!(*map.get(&key(0)).unwrap().value(), 49);
+        assert_eq!(*map.get(&key(1)).unwrap().value(), 49);
+        assert!(map.get(&key(2)).is_none());
+        assert_eq!(*map.get(&"short".to_string()).unwrap().value(), 49);
+    };
+    let reloaded =
+        HashMap::<String, u32>::with_config(hashed_file.clone(), vec![44], config(true)).unwrap();
+    assert_state(&reloaded);
+
+    reloaded.compact().unwrap();
+    reloaded.insert(key(2), 7).await.unwrap().unwrap();
+    let reloaded =
+        HashMap::<String, u32>::with_config(hashed_file, vec![44], config(true)).unwrap();
+    assert_eq!(*reloaded.get(&key(2)).unwrap().value(), 7);
+    reloaded.remove(&key(2)).unwrap().await.unwrap().unwrap();
+    assert_state(&reloaded);
+}
+
+/// Tests that a map loaded with several regions of the file read at once matches one loaded
+/// sequentially, for a file spanning several regions.
+#[tokio::test]
+async fn test_load_concurrency() {
+    let file = temp_file();
+    let map = HashMap::<u32, String>::new(file.clone(), vec![45]).unwrap();
+    let entries = (0..30_000)
+        .map(|i| (i, format!("value {} {}", i, "x".repeat(i as usize % 100))))
+        .collect::<Vec<_>>();
+    map.insert_batch(entries.clone()).await.unwrap().unwrap();
+    map.remove_batch((0..1_000).collect())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(read_all(&file).len() > 3 << 20);
+
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .load_concurrency(4)
+        .build()
+        .unwrap();
+    let loaded = HashMap::<u32, String>::with_config(file, vec![45], config).unwrap();
+    assert_eq!(loaded.len(), 29_000);
+    for (key, value) in &entries[1_000..] {
+        assert_eq!(loaded.get(key).unwrap().value(), value);
+    }
+    assert_eq!(loaded.config().load_concurrency, 4);
+}
+
+/// Tests that `with_on_load` reports each record as it's applied, including overwrites and
+/// removals, so a secondary index maintained from the calls matches the loaded map.
+#[tokio::test]
+async fn test_with_on_load_builds_secondary_index() {
+    let file = temp_file();
+    let config = || {
+        HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .build()
+            .unwrap()
+    };
+    let map = HashMap::<u32, String>::with_config(file.clone(), vec![46], config()).unwrap();
+    for i in 0..20 {
+        map.insert(i, format!("group{}", i % 3))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    map.insert(0, "group9".to_string()).await.unwrap().unwrap();
+    map.remove(&1).unwrap().await.unwrap().unwrap();
+    map.insert(2, "group7".to_string()).await.unwrap().unwrap();
+    map.remove(&2).unwrap().await.unwrap().unwrap();
+
+    let mut groups = std::collections::HashMap::<u32, String>::new();
+    let mut by_group = std::collections::BTreeMap::<String, Vec<u32>>::new();
+    let mut key_two = Vec::new();
+    let loaded = HashMap::<u32, String>::with_on_load(file, vec![46], config(), |key, value| {
+        if *key == 2 {
+            key_two.push(value.cloned());
+        }
+        if let Some(old) = groups.remove(key) {
+            by_group.get_mut(&old).unwrap().retain(|other| other != key);
+        }
+        if let Some(value) = value {
+            groups.insert(*key, value.clone());
+            by_group.entry(value.clone()).or_default().push(*key);
+        }
+    })
+    .unwrap();
+
+    assert_eq!(
+        key_two,
+        vec![Some("group2".to_string()), Some("group7".to_string()), None]
+    );
+    assert_eq!(groups.len(), loaded.len());
+    let mut expected = std::collections::BTreeMap::<String, Vec<u32>>::new();
+    for (key, value) in loaded.iter_log_order().unwrap() {
+        expected.entry(value).or_default().push(key);
+    }
+    by_group.retain(|_, keys| !keys.is_empty());
+    for keys in by_group.values_mut().chain(expected.values_mut()) {
+        keys.sort();
+    }
+    assert_eq!(by_group, expected);
+    assert_eq!(by_group["group9"], vec![0]);
+    assert!(!by_group.values().flatten().any(|key| [1, 2].contains(key)));
+}
+
+/// Tests that `compact_to` writes only the live entries of a churned map to a new file,
+/// leaving the original file untouched.
+#[tokio::test]
+async fn test_compact_to() {
+    let file = temp_file();
+    let map = HashMap::<u32, String>::new(file.clone(), vec![47]).unwrap();
+    for round in 0..5 {
+        for i in 0..50 {
+            map.insert(i, format!("value {} {}", i, round))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+    }
+    map.remove_batch((0..25).collect()).await.unwrap().unwrap();
+    let original = read_all(&file);
+
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("compacted.db");
+    map.compact_to(&dest).unwrap();
+    assert_eq!(read_all(&file), original);
+
+    let id = bincode::serialize(&vec![47u8]).unwrap();
+    let expected_len = (25..50u32)
+        .map(|i| {
+            let entry = DBEntry::HashMapEntry(
+                id.clone(),
+                bincode::serialize(&i).unwrap(),
+                bincode::serialize(&format!("value {} 4", i)).unwrap(),
+            );
+            bincode::serialized_size(&entry).unwrap()
+        })
+        .sum::<u64>();
+    assert_eq!(std::fs::metadata(&dest).unwrap().len(), expected_len);
+
+    let compacted_file = Arc::new(Mutex::new(
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&dest)
+            .unwrap(),
+    ));
+    let compacted = HashMap::<u32, String>::new(compacted_file, vec![47]).unwrap();
+    assert_eq!(compacted.len(), 25);
+    for i in 25..50 {
+        assert_eq!(
+            compacted.get(&i).unwrap().value(),
+            &format!("value {} 4", i)
+        );
+    }
+}
+
+/// Tests that the ordered debug view of a map is the same before and after reloading it.
+#[tokio::test]
+async fn test_debug_entries() {
+    let file = temp_file();
+    let map = HashMap::<u32, String>::new(file.clone(), vec![48]).unwrap();
+    for i in (0..20).rev() {
+        map.insert(i, i.to_string()).await.unwrap().unwrap();
+    }
+    map.remove(&7).unwrap().await.unwrap().unwrap();
+
+    let entries = map.debug_entries();
+    assert_eq!(entries.keys().copied().collect::<Vec<_>>(), {
+        let mut keys = (0..20).collect::<Vec<_>>();
+        keys.retain(|key| *key != 7);
+        keys
+    });
+    let reloaded = HashMap::<u32, String>::new(file, vec![48]).unwrap();
+    assert_eq!(reloaded.debug_entries(), entries);
+}
+
+/// Tests that inserts past `max_entries` evict the least recently used key, in memory and on
+/// disk, and that `RejectNew` refuses new keys instead.
+#[tokio::test]
+async fn test_max_entries_eviction() {
+    let file = temp_file();
+    let config = || {
+        HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .max_entries(3)
+            .build()
+            .unwrap()
+    };
+    let map = HashMap::<u32, u32>::with_config(file.clone(), vec![49], config()).unwrap();
+    for i in 0..3 {
+        map.insert(i, i).await.unwrap().unwrap();
+    }
+    // Reading 0 makes 1 the least recently used key.
+    assert_eq!(*map.get(&0).unwrap().value(), 0);
+    map.insert(3, 3).await.unwrap().unwrap();
+
+    assert_eq!(map.len(), 3);
+    assert!(map.get(&1).is_none());
+    let reloaded = HashMap::<u32, u32>::with_config(file, vec![49], config()).unwrap();
+    assert_eq!(
+        reloaded.debug_entries().into_keys().collect::<Vec<_>>(),
+        [0, 2, 3]
+    );
+
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .max_entries(2)
+        .eviction(EvictionPolicy::RejectNew)
+        .build()
+        .unwrap();
+    let map = HashMap::<u32, u32>::with_config(temp_file(), vec![49], config).unwrap();
+    map.insert_batch(vec![(0, 0), (1, 1)])
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        map.insert(2, 2).await.unwrap(),
+        Err(StructureError::MapFull { limit: 2 })
+    ));
+    assert_eq!(map.insert(1, 10).await.unwrap().unwrap(), Some(1));
+    assert_eq!(map.len(), 2);
+}
+
+/// Tests that when two tasks race to seed an empty map, exactly one of them does.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_init_once() {
+    let file = temp_file();
+    let map = Arc::new(HashMap::<u32, u32>::new(file.clone(), vec![50]).unwrap());
+    let seeders = (0..2)
+        .map(|i| {
+            let map = map.clone();
+            tokio::spawn(async move {
+                let seed = (0..10).map(|key| (key, i)).collect();
+                map.init_once(seed).await.unwrap().unwrap()
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut seeded = Vec::new();
+    for seeder in seeders {
+        seeded.push(seeder.await.unwrap());
+    }
+
+    assert_eq!(seeded.iter().filter(|seeded| **seeded).count(), 1);
+    let winner = seeded.iter().position(|seeded| *seeded).unwrap() as u32;
+    let reloaded = HashMap::<u32, u32>::new(file, vec![50]).unwrap();
+    assert_eq!(reloaded.len(), 10);
+    assert!(reloaded
+        .debug_entries()
+        .values()
+        .all(|value| *value == winner));
+    assert!(!map.init_once(vec![(99, 0)]).await.unwrap().unwrap());
+}
+
+/// Tests that removing a key before its insert was written leaves no record of either in the
+/// file, while removals of written keys still append a tombstone.
+#[tokio::test]
+async fn test_skip_unwritten_tombstones() {
+    let file = temp_file();
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .skip_unwritten_tombstones(true)
+        .build()
+        .unwrap();
+    let map = HashMap::<u32, String>::with_config(file.clone(), vec![51], config).unwrap();
+    map.insert(1, "written".to_string()).await.unwrap().unwrap();
+    let before = read_all(&file);
+
+    let insert = map.insert(2, "short-lived".to_string());
+    let remove = map.remove(&2).unwrap();
+    assert_eq!(insert.await.unwrap().unwrap(), None);
+    assert_eq!(
+        remove.await.unwrap().unwrap().as_deref(),
+        Some("short-lived")
+    );
+    assert_eq!(read_all(&file), before);
+
+    map.remove(&1).unwrap().await.unwrap().unwrap();
+    assert!(read_all(&file).len() > before.len());
+    let reloaded = HashMap::<u32, String>::with_config(
+        file,
+        vec![51],
+        HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    assert!(reloaded.is_empty());
+}
+
+/// Tests that a conflict resolver decides between two records of the same key, and that the
+/// winner is written back so it survives a reload without the resolver.
+#[tokio::test]
+async fn test_conflict_resolver() {
+    let file = temp_file();
+    {
+        let mut file = file.lock().unwrap();
+        for (key, value) in [(7u32, 30u32), (8, 1), (7, 10), (8, 2)] {
+            let entry = DBEntry::HashMapEntry(
+                vec![52],
+                bincode::serialize(&key).unwrap(),
+                bincode::serialize(&value).unwrap(),
+            );
+            file.write_all(&bincode::serialize(&entry).unwrap())
+                .unwrap();
+        }
+    }
+    let config = || {
+        HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .build()
+            .unwrap()
+    };
+    let max = |_: &u32, old: u32, new: u32| old.max(new);
+
+    let map =
+        HashMap::<u32, u32>::with_conflict_resolver(file.clone(), vec![52], config(), max).unwrap();
+    assert_eq!(*map.get(&7).unwrap().value(), 30);
+    assert_eq!(*map.get(&8).unwrap().value(), 2);
+
+    let reloaded = HashMap::<u32, u32>::with_config(file.clone(), vec![52], config()).unwrap();
+    assert_eq!(*reloaded.get(&7).unwrap().value(), 30);
+    // The written-back winner is now the last record, so loading again writes nothing.
+    let size = read_all(&file).len();
+    HashMap::<u32, u32>::with_conflict_resolver(file.clone(), vec![52], config(), max).unwrap();
+    assert_eq!(read_all(&file).len(), size);
+}
+
+/// Tests that a checksum-verified read detects a value corrupted on disk, while the cached
+/// value is still returned from memory.
+#[tokio::test]
+async fn test_get_verified() {
+    let file = temp_file();
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .checksums(true)
+        .build()
+        .unwrap();
+    let map = HashMap::<u32, String>::with_config(file.clone(), vec![53], config).unwrap();
+    map.insert(1, "important value".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    map.insert(2, "other value".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        map.get_verified(&1).unwrap().as_deref(),
+        Some("important value")
+    );
+    assert_eq!(map.get_verified(&3).unwrap(), None);
+
+    let contents = read_all(&file);
+    let position = contents
+        .windows(9)
+        .position(|window| window == b"important")
+        .unwrap();
+    {
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::Start(position as u64)).unwrap();
+        file.write_all(b"I").unwrap();
+    }
+
+    assert!(matches!(
+        map.get_verified(&1),
+        Err(StructureError::ChecksumMismatch { .. })
+    ));
+    assert_eq!(map.get(&1).unwrap().value(), "important value");
+    // The corrupted record's frame no longer matches either, which fails every verified read.
+    assert!(matches!(
+        map.get_verified(&2),
+        Err(StructureError::ChecksumMismatch { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_failed_append_rolls_back_memory() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("rollback.db");
+    let writable = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    let writable = Arc::new(Mutex::new(writable));
+    let hashmap = HashMap::<u32, String>::new(writable, vec![54]).unwrap();
+    let entries = vec![(1, "one".to_string()), (2, "two".to_string())];
+    hashmap
+        .insert_batch(entries.clone())
+        .await
+        .unwrap()
+        .unwrap();
+    drop(hashmap);
+
+    // The map can load from a read-only file, but every append to it fails.
+    let read_only = Arc::new(Mutex::new(File::open(&path).unwrap()));
+    let hashmap = HashMap::<u32, String>::new(read_only, vec![54]).unwrap();
+    assert!(hashmap.insert(1, "uno".to_string()).await.unwrap().is_err());
+    assert!(hashmap
+        .insert(3, "three".to_string())
+        .await
+        .unwrap()
+        .is_err());
+    let batch = vec![(2, "dos".to_string()), (4, "four".to_string())];
+    assert!(hashmap.insert_batch(batch).await.unwrap().is_err());
+    assert!(hashmap.remove(&1).unwrap().await.unwrap().is_err());
+    assert!(hashmap.remove_batch(vec![1, 2]).await.unwrap().is_err());
+    assert_eq!(
+        hashmap.debug_entries(),
+        entries
+            .into_iter()
+            .collect::<std::collections::BTreeMap<_, _>>()
+    );
+}
+
+#[tokio::test]
+async fn test_load_stops_at_truncated_tail() {
+    let file = temp_file();
+    let hashmap = HashMap::<u32, String>::new(file.clone(), vec![55]).unwrap();
+    let entries = (0..1000).map(|i| (i, i.to_string())).collect::<Vec<_>>();
+    hashmap.insert_batch(entries).await.unwrap().unwrap();
+    drop(hashmap);
+
+    // Append the first half of another entry, as if a write was cut short.
+    let entry = DBEntry::HashMapEntry(vec![55], vec![1, 2, 3, 4], vec![5; 64]);
+    let bytes = bincode::serialize(&entry).unwrap();
+    {
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(&bytes[..bytes.len() / 2]).unwrap();
+    }
+
+    for load_concurrency in [1, 4] {
+        let config = HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .load_concurrency(load_concurrency)
+            .build()
+            .unwrap();
+        let id = vec![55u8];
+        let hashmap = HashMap::<u32, String>::with_config(file.clone(), id, config).unwrap();
+        assert_eq!(hashmap.len(), 1000);
+        assert_eq!(hashmap.get(&999).unwrap().value(), "999");
+    }
+}
+
+/// Tests that `iter` yields every live pair of the map, and only those.
+#[tokio::test]
+async fn test_iter() {
+    let map = HashMap::<u32, String>::new(temp_file(), vec![56]).unwrap();
+    for i in 0..10 {
+        map.insert(i, format!("value{}", i)).await.unwrap().unwrap();
+    }
+    map.remove(&3).unwrap().await.unwrap().unwrap();
+    let mut pairs = map.iter().map(|pair| pair.into_owned()).collect::<Vec<_>>();
+    pairs.sort();
+    let expected = (0..10)
+        .filter(|&i| i != 3)
+        .map(|i| (i, format!("value{}", i)))
+        .collect::<Vec<_>>();
+    assert_eq!(pairs, expected);
+}
+
+/// Tests that `keys` and `values` return owned copies of every live key and value.
+#[tokio::test]
+async fn test_keys_and_values() {
+    let map = HashMap::<u32, String>::new(temp_file(), vec![57]).unwrap();
+    for i in 0..10 {
+        map.insert(i, format!("value{}", i)).await.unwrap().unwrap();
+    }
+    map.remove(&3).unwrap().await.unwrap().unwrap();
+    let expected = (0..10).filter(|&i| i != 3).collect::<Vec<_>>();
+
+    let keys = map.keys().collect::<std::collections::BTreeSet<_>>();
+    assert_eq!(keys, expected.iter().copied().collect());
+    let mut values = map.values().collect::<Vec<_>>();
+    values.sort();
+    let mut expected_values = expected
+        .iter()
+        .map(|i| format!("value{}", i))
+        .collect::<Vec<_>>();
+    expected_values.sort();
+    assert_eq!(values, expected_values);
+
+    // The iterators hold no locks, so the map can be written to while they are consumed.
+    for key in map.keys() {
+        map.insert(key, "updated".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    assert!(map.values().all(|value| value == "updated"));
+}
+
+/// Tests that `contains_key` reports present keys and not absent or removed ones.
+#[tokio::test]
+async fn test_contains_key() {
+    let map = HashMap::<u32, String>::new(temp_file(), vec![58]).unwrap();
+    map.insert(1, "one".to_string()).await.unwrap().unwrap();
+    map.insert(2, "two".to_string()).await.unwrap().unwrap();
+    map.remove(&2).unwrap().await.unwrap().unwrap();
+    assert!(map.contains_key(&1));
+    assert!(!map.contains_key(&2));
+    assert!(!map.contains_key(&3));
+}
+
+/// Tests that a value read with `get_cloned` can be held across an insert into the same key.
+#[tokio::test]
+async fn test_get_cloned() {
+    let map = HashMap::<u32, String>::new(temp_file(), vec![59]).unwrap();
+    assert_eq!(map.get_cloned(&1), None);
+    map.insert(1, "one".to_string()).await.unwrap().unwrap();
+    let before = map.get_cloned(&1).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        map.insert(1, "uno".to_string()).await.unwrap().unwrap();
+    })
+    .await
+    .unwrap();
+    assert_eq!(before, "one");
+    assert_eq!(map.get_cloned(&1).unwrap(), "uno");
+}
+
+/// Tests that `get_or_insert_with` computes and writes a value only for an absent key.
+#[tokio::test]
+async fn test_get_or_insert_with() {
+    let file = temp_file();
+    let map = HashMap::<u32, String>::new(file.clone(), vec![60]).unwrap();
+    let value = map
+        .get_or_insert_with(1, || "computed".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(value, "computed");
+    let written = read_all(&file);
+    assert!(!written.is_empty());
+
+    let value = map
+        .get_or_insert_with(1, || panic!("the key already exists"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(value, "computed");
+    assert_eq!(read_all(&file), written);
+
+    let reloaded = HashMap::<u32, String>::new(file, vec![60]).unwrap();
+    assert_eq!(reloaded.get_cloned(&1).unwrap(), "computed");
+}
+
+/// Tests that concurrent increments of the same counter are all applied and persisted.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_increment() {
+    let file = temp_file();
+    let map = Arc::new(HashMap::<String, u64>::new(file.clone(), vec![61]).unwrap());
+    let tasks = (0..8)
+        .map(|_| {
+            let map = map.clone();
+            tokio::spawn(async move {
+                let key = "counter".to_string();
+                let increments = (0..100).map(|_| map.increment(&key, 3)).collect::<Vec<_>>();
+                for increment in increments {
+                    increment.await.unwrap().unwrap();
+                }
+                map.increment(&key, -1).await.unwrap().unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    assert_eq!(map.get_cloned(&"counter".to_string()), Some(8 * 299));
+    assert_eq!(
+        map.increment(&"floor".to_string(), -5)
+            .await
+            .unwrap()
+            .unwrap(),
+        0
+    );
+
+    let reloaded = HashMap::<String, u64>::new(file, vec![61]).unwrap();
+    assert_eq!(reloaded.get_cloned(&"counter".to_string()), Some(8 * 299));
+    assert_eq!(reloaded.get_cloned(&"floor".to_string()), Some(0));
+}
+
+/// Tests that `compare_and_swap` writes only when the current value matches the expected one.
+#[tokio::test]
+async fn test_compare_and_swap() {
+    let file = temp_file();
+    let map = HashMap::<u32, String>::new(file.clone(), vec![62]).unwrap();
+
+    // An absent key matches only `None`.
+    let one = "one".to_string();
+    assert!(!map
+        .compare_and_swap(&1, Some(&one), "uno".to_string())
+        .await
+        .unwrap()
+        .unwrap());
+    assert!(read_all(&file).is_empty());
+    assert!(map
+        .compare_and_swap(&1, None, one.clone())
+        .await
+        .unwrap()
+        .unwrap());
+
+    // A present key matches only its current value.
+    let written = read_all(&file);
+    assert!(!map
+        .compare_and_swap(&1, None, "uno".to_string())
+        .await
+        .unwrap()
+        .unwrap());
+    assert!(!map
+        .compare_and_swap(&1, Some(&"two".to_string()), "uno".to_string())
+        .await
+        .unwrap()
+        .unwrap());
+    assert_eq!(read_all(&file), written);
+    assert!(map
+        .compare_and_swap(&1, Some(&one), "uno".to_string())
+        .await
+        .unwrap()
+        .unwrap());
+    assert_eq!(map.get_cloned(&1).unwrap(), "uno");
+
+    let reloaded = HashMap::<u32, String>::new(file, vec![62]).unwrap();
+    assert_eq!(reloaded.get_cloned(&1).unwrap(), "uno");
+}
+
+/// Tests that `retain` removes the entries failing the predicate, in memory and on disk.
+#[tokio::test]
+async fn test_retain() {
+    let file = temp_file();
+    let map = HashMap::<u32, u32>::new(file.clone(), vec![63]).unwrap();
+    map.insert_batch((0..20).map(|i| (i, i * 3)).collect())
+        .await
+        .unwrap()
+        .unwrap();
+    map.retain(|_, value| value % 2 == 0)
+        .await
+        .unwrap()
+        .unwrap();
+    let expected = (0..20)
+        .filter(|i| i * 3 % 2 == 0)
+        .map(|i| (i, i * 3))
+        .collect::<std::collections::BTreeMap<_, _>>();
+    assert_eq!(map.debug_entries(), expected);
+
+    let reloaded = HashMap::<u32, u32>::new(file, vec![63]).unwrap();
+    assert_eq!(reloaded.debug_entries(), expected);
+}
+
+/// Tests that `extend` inserts and persists every pair of an iterator.
+#[tokio::test]
+async fn test_extend() {
+    let file = temp_file();
+    let map = HashMap::<u32, String>::new(file.clone(), vec![64]).unwrap();
+    map.extend((0..50).map(|i| (i, i.to_string())))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(map.len(), 50);
+
+    let reloaded = HashMap::<u32, String>::new(file, vec![64]).unwrap();
+    let expected = (0..50)
+        .map(|i| (i, i.to_string()))
+        .collect::<std::collections::BTreeMap<_, _>>();
+    assert_eq!(reloaded.debug_entries(), expected);
+}
+
+/// Tests that `drain` returns every pair of the map and leaves it empty on disk.
+#[tokio::test]
+async fn test_drain() {
+    let file = temp_file();
+    let map = HashMap::<u32, String>::new(file.clone(), vec![65]).unwrap();
+    let entries = (0..20).map(|i| (i, i.to_string())).collect::<Vec<_>>();
+    map.insert_batch(entries.clone()).await.unwrap().unwrap();
+
+    let mut drained = map.drain().await.unwrap().unwrap();
+    drained.sort();
+    assert_eq!(drained, entries);
+    assert!(map.is_empty());
+
+    let reloaded = HashMap::<u32, String>::new(file, vec![65]).unwrap();
+    assert!(reloaded.is_empty());
+}
+
+/// Tests that a final entry torn by a crash, whose frame checksum doesn't match, is ignored
+/// when loading.
+#[tokio::test]
+async fn test_load_ignores_torn_final_entry() {
+    let file = temp_file();
+    let hashmap = HashMap::<u32, String>::new(file.clone(), vec![66]).unwrap();
+    hashmap.insert(1, "one".to_string()).await.unwrap().unwrap();
+    hashmap.insert(2, "two".to_string()).await.unwrap().unwrap();
+    drop(hashmap);
+
+    {
+        let mut file = file.lock().unwrap();
+        let len = file.seek(SeekFrom::End(0)).unwrap();
+        file.seek(SeekFrom::Start(len - 1)).unwrap();
+        file.write_all(b"X").unwrap();
+    }
+
+    let hashmap = HashMap::<u32, String>::new(file, vec![66]).unwrap();
+    assert_eq!(hashmap.len(), 1);
+    assert_eq!(hashmap.get(&1).unwrap().value(), "one");
+    assert!(hashmap.get(&2).is_none());
+}
+
+/// Tests that an entry whose frame checksum doesn't match fails loading when it isn't the
+/// final entry, rather than dropping the entries after it.
+#[tokio::test]
+async fn test_load_rejects_corrupted_entry() {
+    let file = temp_file();
+    let hashmap = HashMap::<u32, String>::new(file.clone(), vec![67]).unwrap();
+    hashmap
+        .insert(1, "first".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    hashmap
+        .insert(2, "second".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    drop(hashmap);
+
+    let contents = read_all(&file);
+    let position = contents
+        .windows(5)
+        .position(|window| window == b"first")
+        .unwrap();
+    {
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::Start(position as u64)).unwrap();
+        file.write_all(b"F").unwrap();
+    }
+
+    assert!(matches!(
+        HashMap::<u32, String>::new(file, vec![67]),
+        Err(StructureError::ChecksumMismatch { .. })
+    ));
+}
+
+/// Tests that lenient loading finds the next intact record after one whose length prefix was
+/// corrupted, which makes it look like it runs past the end of the file.
+#[tokio::test]
+async fn test_lenient_load_skips_corrupted_length() {
+    let file = temp_file();
+    let hashmap = HashMap::<u32, String>::new(file.clone(), vec![68]).unwrap();
+    for i in 0..3 {
+        hashmap.insert(i, i.to_string()).await.unwrap().unwrap();
+    }
+    drop(hashmap);
+
+    let record = bincode::serialize(&DBEntry::HashMapEntry(
+        bincode::serialize(&vec![68u8]).unwrap(),
+        bincode::serialize(&1u32).unwrap(),
+        bincode::serialize(&"1".to_string()).unwrap(),
+    ))
+    .unwrap();
+    let position = read_all(&file)
+        .windows(record.len())
+        .position(|window| window == record)
+        .unwrap();
+    {
+        let mut file = file.lock().unwrap();
+        // The last byte of the length of the frame's payload.
+        file.seek(SeekFrom::Start(position as u64 + 8)).unwrap();
+        file.write_all(&[0x7F]).unwrap();
+    }
+
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .recovery(RecoveryMode::Lenient)
+        .build()
+        .unwrap();
+    let id = vec![68u8];
+    let hashmap = HashMap::<u32, String>::with_config(file, id, config).unwrap();
+    assert_eq!(hashmap.len(), 2);
+    assert_eq!(hashmap.get(&0).unwrap().value(), "0");
+    assert!(hashmap.get(&1).is_none());
+    assert_eq!(hashmap.get(&2).unwrap().value(), "2");
+    let skipped = hashmap.skipped_records();
+    assert_eq!(skipped.len(), 1);
+    assert!(skipped[0].offset <= position as u64);
+    assert!(skipped[0].error.is_none());
+}
+
+/// Tests that the same data round-trips through both serialization formats, and that JSON
+/// leaves it readable in the file.
+#[tokio::test]
+async fn test_serialization_formats_round_trip() {
+    let id = vec![69u8];
+    for format in [SerializationFormat::Bincode, SerializationFormat::Json] {
+        let file = temp_file();
+        let config = || {
+            HashMapConfigBuilder::default()
+                .shard_amount(8)
+                .format(format)
+                .build()
+                .unwrap()
+        };
+        let hashmap =
+            HashMap::<String, Vec<u32>>::with_config(file.clone(), id.clone(), config()).unwrap();
+        hashmap
+            .insert("first".to_string(), vec![1, 2])
+            .await
+            .unwrap()
+            .unwrap();
+        hashmap
+            .insert("second".to_string(), vec![3])
+            .await
+            .unwrap()
+            .unwrap();
+        hashmap
+            .remove(&"second".to_string())
+            .unwrap()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(hashmap.config().format, format);
+        drop(hashmap);
+
+        let contents = read_all(&file);
+        let readable = contents.windows(7).any(|window| window == b"\"first\"");
+        assert_eq!(readable, format == SerializationFormat::Json);
+
+        let hashmap =
+            HashMap::<String, Vec<u32>>::with_config(file.clone(), id.clone(), config()).unwrap();
+        assert_eq!(hashmap.len(), 1);
+        assert_eq!(
+            hashmap.get(&"first".to_string()).unwrap().value(),
+            &vec![1, 2]
+        );
+        assert!(hashmap.get(&"second".to_string()).is_none());
+    }
+
+    let canonical_json = HashMapConfigBuilder::default()
+        .canonical_keys(true)
+        .format(SerializationFormat::Json)
+        .build();
+    assert!(canonical_json.is_err());
+}
+
+/// Tests that loading enforces the size limit on stored values, and that an entry with a
+/// crafted length prefix far beyond the end of the file is treated as a truncated tail.
+#[tokio::test]
+async fn test_max_value_bytes_on_load() {
+    let file = temp_file();
+    let hashmap = HashMap::<u32, String>::new(file.clone(), vec![70]).unwrap();
+    hashmap
+        .insert(1, "small".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    hashmap.insert(2, "x".repeat(100)).await.unwrap().unwrap();
+    drop(hashmap);
+
+    let id = vec![70u8];
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .max_value_bytes(16)
+        .build()
+        .unwrap();
+    let result = HashMap::<u32, String>::with_config(file.clone(), id.clone(), config);
+    assert!(matches!(
+        result,
+        Err(StructureError::ValueTooLarge {
+            size: 108,
+            limit: 16
+        })
+    ));
+
+    {
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        // A frame tag followed by a payload length of 1 TiB.
+        let mut crafted = vec![135];
+        crafted.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        crafted.extend_from_slice(&[0; 16]);
+        file.write_all(&crafted).unwrap();
+    }
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .build()
+        .unwrap();
+    let hashmap = HashMap::<u32, String>::with_config(file, id, config).unwrap();
+    assert_eq!(hashmap.len(), 2);
+    assert_eq!(hashmap.get(&1).unwrap().value(), "small");
+}
+
+/// Tests that a map with `auto_compact_ratio` keeps its file from growing without bound under
+/// repeated overwrites and removals.
+#[tokio::test]
+async fn test_auto_compact_ratio() {
+    let file = temp_file();
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .auto_compact_ratio(2.0)
+        .build()
+        .unwrap();
+    let id = vec![71u8];
+    let hashmap = HashMap::<u32, String>::with_config(file.clone(), id.clone(), config).unwrap();
+    let mut sizes = Vec::new();
+    for round in 0..200 {
+        for key in 0..10 {
+            hashmap
+                .insert(key, format!("value {} of round {}", key, round))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        for key in 0..5 {
+            hashmap.remove(&key).unwrap().await.unwrap().unwrap();
+        }
+        sizes.push(read_all(&file).len());
+    }
+    // Without compaction the file would grow by about a round's worth every round.
+    let (first, second) = sizes.split_at(100);
+    let first_max = *first.iter().max().unwrap();
+    let second_max = *second.iter().max().unwrap();
+    assert!(
+        second_max < first_max * 5 / 4,
+        "{} > {}",
+        second_max,
+        first_max
+    );
+    assert!(second_max < sizes[0] * 10);
+    drop(hashmap);
+
+    let hashmap = HashMap::<u32, String>::new(file, vec![71]).unwrap();
+    assert_eq!(hashmap.len(), 5);
+    assert_eq!(hashmap.get(&7).unwrap().value(), "value 7 of round 199");
+
+    let invalid = HashMapConfigBuilder::default()
+        .auto_compact_ratio(1.0)
+        .build();
+    assert!(invalid.is_err());
+}
+
+/// Tests that the dead record count rises with overwrites and removals and drops to zero once
+/// the map is compacted.
+#[tokio::test]
+async fn test_stats_and_needs_compaction() {
+    let file = temp_file();
+    let hashmap = HashMap::<u32, String>::new(file.clone(), vec![72]).unwrap();
+    let other = HashMap::<u32, String>::new(file.clone(), vec![73]).unwrap();
+    for key in 0..4 {
+        hashmap
+            .insert(key, "first".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    other.insert(0, "other".to_string()).await.unwrap().unwrap();
+    let stats = hashmap.stats().unwrap();
+    assert_eq!(stats.live_entries, 4);
+    assert_eq!(stats.total_records, 4);
+    assert_eq!(stats.dead_records, 0);
+    assert_eq!(stats.dead_bytes, 0);
+    assert!(!hashmap.needs_compaction(1.5).unwrap());
+
+    for key in 0..4 {
+        hashmap
+            .insert(key, "second".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    hashmap.remove(&3).unwrap().await.unwrap().unwrap();
+    let stats = hashmap.stats().unwrap();
+    assert_eq!(stats.live_entries, 3);
+    assert_eq!(stats.total_records, 9);
+    // The four first writes, the second write of the removed key and its tombstone.
+    assert_eq!(stats.dead_records, 6);
+    assert!(stats.dead_bytes > 0 && stats.dead_bytes < stats.disk_bytes);
+    assert!(hashmap.needs_compaction(1.5).unwrap());
+    assert!(!hashmap.needs_compaction(10.0).unwrap());
+
+    hashmap.compact().unwrap();
+    let stats = hashmap.stats().unwrap();
+    assert_eq!(stats.live_entries, 3);
+    assert_eq!(stats.total_records, 3);
+    assert_eq!(stats.dead_records, 0);
+    assert_eq!(stats.dead_bytes, 0);
+    assert!(!hashmap.needs_compaction(1.5).unwrap());
+    assert_eq!(other.stats().unwrap().live_entries, 1);
+}
+
+/// Tests that a standalone map's synchronous writes, including its synced ones, work without a
+/// tokio runtime.
+#[test]
+fn test_sync_writes_on_file() {
+    let file = temp_file();
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .durability(Durability::Sync)
+        .build()
+        .unwrap();
+    let id = vec![74u8];
+    let hashmap = HashMap::<u32, u32>::with_config(file.clone(), id, config).unwrap();
+    for i in 0..10 {
+        assert_eq!(hashmap.insert_sync(i, i * 2).unwrap(), None);
+    }
+    assert_eq!(hashmap.remove_sync(&0).unwrap(), Some(0));
+    drop(hashmap);
+
+    let hashmap = HashMap::<u32, u32>::new(file, vec![74]).unwrap();
+    assert_eq!(hashmap.len(), 9);
+    assert_eq!(hashmap.get(&9).unwrap().value(), &18);
+}
+
+#[test]
+fn test_concurrent_inserts_on_current_thread_runtime() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let file = temp_file();
+        let config = HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .durability(Durability::Sync)
+            .build()
+            .unwrap();
+        let id = vec![75u8];
+        let hashmap = HashMap::<u32, u32>::with_config(file.clone(), id, config).unwrap();
+
+        let ticks = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let ticker = {
+            let ticks = ticks.clone();
+            tokio::spawn(async move {
+                loop {
+                    ticks.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        let writes: Vec<_> = (0..200).map(|i| hashmap.insert(i, i * 2)).collect();
+        for write in writes {
+            assert_eq!(write.await.unwrap().unwrap(), None);
+        }
+        ticker.abort();
+        assert!(ticks.load(std::sync::atomic::Ordering::Relaxed) > 0);
+        drop(hashmap);
+
+        let hashmap = HashMap::<u32, u32>::new(file, vec![75]).unwrap();
+        assert_eq!(hashmap.len(), 200);
+        assert_eq!(hashmap.get(&199).unwrap().value(), &398);
+    });
+}
+
+#[tokio::test]
+async fn test_write_queue_depth_bounds_queued_writes() {
+    let file = temp_file();
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .durability(Durability::Sync)
+        .write_queue_depth(4)
+        .build()
+        .unwrap();
+    let id = vec![76u8];
+    let hashmap = HashMap::<u32, u32>::with_config(file.clone(), id, config).unwrap();
+
+    let mut writes = Vec::new();
+    let mut most_queued = 0;
+    for i in 0..500 {
+        writes.push(hashmap.insert(i, i * 2));
+        most_queued = most_queued.max(hashmap.queued_writes());
+    }
+    assert!(most_queued <= 4);
+    for write in writes {
+        assert_eq!(write.await.unwrap().unwrap(), None);
+    }
+    assert_eq!(hashmap.queued_writes(), 0);
+    drop(hashmap);
+
+    let hashmap = HashMap::<u32, u32>::new(file, vec![76]).unwrap();
+    assert_eq!(hashmap.len(), 500);
+    assert_eq!(hashmap.get(&499).unwrap().value(), &998);
+
+    let invalid = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .write_queue_depth(0)
+        .build();
+    assert!(invalid.is_err());
+}
+
+/// Tests that a write made while the write queue is full waits in its JoinHandle instead of
+/// blocking the calling thread, even on a single-threaded runtime.
+#[tokio::test]
+async fn test_full_write_queue_does_not_block_caller() {
+    let file = temp_file();
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .write_queue_depth(1)
+        .build()
+        .unwrap();
+    let hashmap = HashMap::<u32, SlowValue>::with_config(file.clone(), vec![82], config).unwrap();
+
+    let started = std::time::Instant::now();
+    let writes = (0..4)
+        .map(|i| hashmap.insert(i % 2, SlowValue(i as u64)))
+        .collect::<Vec<_>>();
+    assert!(started.elapsed() < Duration::from_millis(250));
+    assert_eq!(hashmap.queued_writes(), 1);
+    for write in writes {
+        write.await.unwrap().unwrap();
+    }
+    assert!(started.elapsed() >= Duration::from_millis(1200));
+    assert_eq!(hashmap.queued_writes(), 0);
+    drop(hashmap);
+
+    let hashmap = HashMap::<u32, u64>::new(file, vec![82]).unwrap();
+    assert_eq!(hashmap.get_cloned(&0), Some(2));
+    assert_eq!(hashmap.get_cloned(&1), Some(3));
+}
+
+#[tokio::test]
+async fn test_recovers_from_panic_during_write() {
+    let file = temp_file();
+    let hashmap = HashMap::<u32, u32>::new(file.clone(), vec![77]).unwrap();
+    hashmap.insert(1, 10).await.unwrap().unwrap();
+
+    let panicking = std::thread::spawn({
+        let file = file.clone();
+        move || {
+            let mut file = file.lock().unwrap();
+            file.seek(SeekFrom::End(0)).unwrap();
+            file.write_all(&[135, 40, 0, 0]).unwrap();
+            panic!("write interrupted");
+        }
+    });
+    assert!(panicking.join().is_err());
+    assert!(file.is_poisoned());
+
+    hashmap.insert(2, 20).await.unwrap().unwrap();
+    assert!(!file.is_poisoned());
+    drop(hashmap);
+
+    let hashmap = HashMap::<u32, u32>::new(file, vec![77]).unwrap();
+    assert_eq!(hashmap.len(), 2);
+    assert_eq!(hashmap.get(&2).unwrap().value(), &20);
+}
+
+#[tokio::test]
+async fn test_insert_with_ttl_expires() {
+    let file = temp_file();
+    let config = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .expiry_interval(Duration::from_millis(20))
+        .build()
+        .unwrap();
+    let id = vec![78u8];
+    let hashmap = HashMap::<u32, u32>::with_config(file.clone(), id, config).unwrap();
+    hashmap
+        .insert_with_ttl(1, 10, Duration::from_millis(100))
+        .await
+        .unwrap()
+        .unwrap();
+    hashmap.insert(2, 20).await.unwrap().unwrap();
+    assert_eq!(hashmap.get_cloned(&1), Some(10));
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(hashmap.get(&1).is_none());
+    assert!(!hashmap.contains_key(&1));
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while hashmap.len() > 1 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(hashmap.len(), 1);
+    drop(hashmap);
+
+    let hashmap = HashMap::<u32, u32>::new(file, vec![78]).unwrap();
+    assert_eq!(hashmap.len(), 1);
+    assert_eq!(hashmap.get(&2).unwrap().value(), &20);
+    // The expired entry and its expiry time, the other entry, and the removal.
+    let stats = hashmap.stats().unwrap();
+    assert_eq!((stats.live_entries, stats.total_records), (1, 4));
+}
+
+#[tokio::test]
+async fn test_ttl_survives_reopen() {
+    let file = temp_file();
+    let hashmap = HashMap::<u32, u32>::new(file.clone(), vec![79]).unwrap();
+    hashmap
+        .insert_with_ttl(1, 10, Duration::from_secs(3600))
+        .await
+        .unwrap()
+        .unwrap();
+    hashmap
+        .insert_with_ttl(2, 20, Duration::from_millis(50))
+        .await
+        .unwrap()
+        .unwrap();
+    hashmap
+        .insert_with_ttl(3, 30, Duration::from_millis(50))
+        .await
+        .unwrap()
+        .unwrap();
+    hashmap.insert(3, 31).await.unwrap().unwrap();
+    drop(hashmap);
+
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    let hashmap = HashMap::<u32, u32>::new(file, vec![79]).unwrap();
+    assert_eq!(hashmap.len(), 2);
+    assert_eq!(hashmap.get_cloned(&1), Some(10));
+    assert!(hashmap.get(&2).is_none());
+    assert_eq!(hashmap.get_cloned(&3), Some(31));
+}
+
+#[tokio::test]
+async fn test_max_in_memory_reloads_evicted_values() {
+    let file = temp_file();
+    let config = || {
+        HashMapConfigBuilder::default()
+            .shard_amount(8)
+            .max_in_memory(10)
+            .build()
+            .unwrap()
+    };
+    let id = vec![80u8];
+    let hashmap = HashMap::<u32, String>::with_config(file.clone(), id.clone(), config()).unwrap();
+    for i in 0..100 {
+        hashmap
+            .insert(i, format!("value {}", i))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    assert_eq!(hashmap.len(), 100);
+    assert!(hashmap.in_memory_len() <= 11);
+    for i in 0..100 {
+        assert_eq!(hashmap.get_cloned(&i), Some(format!("value {}", i)));
+        assert!(hashmap.in_memory_len() <= 11);
+    }
+
+    // Overwrites and removals of evicted keys return their values from the log.
+    assert_eq!(
+        hashmap.insert(3, "new".to_string()).await.unwrap().unwrap(),
+        Some("value 3".to_string())
+    );
+    assert_eq!(
+        hashmap.remove(&4).unwrap().await.unwrap().unwrap(),
+        Some("value 4".to_string())
+    );
+    hashmap.compact().unwrap();
+    assert_eq!(hashmap.get_cloned(&3), Some("new".to_string()));
+    assert_eq!(hashmap.get_cloned(&5), Some("value 5".to_string()));
+    drop(hashmap);
+
+    let hashmap = HashMap::<u32, String>::with_config(file, id, config()).unwrap();
+    assert_eq!(hashmap.len(), 99);
+    assert!(hashmap.in_memory_len() <= 10);
+    assert_eq!(hashmap.get_cloned(&4), None);
+    for i in (0..100).filter(|i| ![3, 4].contains(i)) {
+        assert_eq!(hashmap.get_cloned(&i), Some(format!("value {}", i)));
+    }
+
+    let invalid = HashMapConfigBuilder::default()
+        .shard_amount(8)
+        .max_in_memory(0)
+        .build();
+    assert!(invalid.is_err());
+}
+
+/// Utility function to create a `HashMap` with a given id.
+fn create<K, V>(filename: &str, id: &str) -> HashMap<K, V>
+where
+    K: Hash + Eq + Serialize + for<'de> Deserialize<'de> + Clone + Send + 'static,
+    V: Serialize + for<'de> Deserialize<'de> + Clone + Send + 'static,
+{
+    let db = DBMaker::file_db(PathBuf::from(filename)).make().unwrap();
+    db.hash_map(id.to_string()).unwrap()
+}
+
+/// Utility function to read the whole contents of a shared file.
+fn read_all(file: &Arc<Mutex<File>>) -> Vec<u8> {
+    let mut file = file.lock().unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer).unwrap();
+    buffer
 }
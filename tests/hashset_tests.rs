@@ -3,10 +3,11 @@
 //! This module contains tests to validate the functionality of the `HashSet` data structure,
 //! ensuring its correctness and reliability in various scenarios.
 
-use rustmap_db::{DBMaker, HashSet};
+use rustmap_db::{db::db_entry::DBEntry, DBMaker, HashSet};
 use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::hash::Hash;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use tempfile::tempfile;
@@ -51,6 +52,25 @@ async fn test_remove_batch() {
     }
 }
 
+/// Tests that batch removals are still applied after reopening the `HashSet`.
+#[tokio::test]
+async fn test_remove_batch_persists() {
+    let file = temp_file();
+    let hashset = HashSet::<String>::new(file.clone(), vec![12]).unwrap();
+    let keys = vec!["key5".to_string(), "key6".to_string(), "key7".to_string()];
+    hashset.insert_batch(keys.clone()).await.unwrap().unwrap();
+    hashset
+        .remove_batch(keys[..2].to_vec())
+        .await
+        .unwrap()
+        .unwrap();
+    drop(hashset);
+
+    let hashset = HashSet::<String>::new(file, vec![12]).unwrap();
+    assert_eq!(hashset.len(), 1);
+    assert!(hashset.get(&keys[2]).is_some());
+}
+
 /// Tests clearing the `HashSet`.
 #[tokio::test]
 async fn test_clear() {
@@ -61,9 +81,40 @@ async fn test_clear() {
         .await
         .unwrap()
         .unwrap();
-    assert_eq!(hashset.is_empty(), false);
+    assert!(!hashset.is_empty());
     hashset.clear().unwrap();
-    assert_eq!(hashset.is_empty(), true);
+    assert!(hashset.is_empty());
+}
+
+/// Tests that clearing a `HashSet` opened through a `Database` swaps in a new log file, leaving
+/// the old log whole on disk, and keeps the records of other structures.
+#[tokio::test]
+async fn test_clear_swaps_log_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("clear.db");
+    let db = DBMaker::file_db(path.clone()).make().unwrap();
+    let hashset = db.hash_set::<u32>("set".to_string()).unwrap();
+    let other = db.hash_set::<u32>("other".to_string()).unwrap();
+    for i in 0..10 {
+        hashset.insert(i).await.unwrap().unwrap();
+    }
+    other.insert(1).await.unwrap().unwrap();
+    db.sync().unwrap();
+
+    let old_contents = std::fs::read(&path).unwrap();
+    let mut old_log = File::open(&path).unwrap();
+    hashset.clear().unwrap();
+    let mut contents = Vec::new();
+    old_log.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, old_contents);
+
+    drop((hashset, other, db));
+    let db = DBMaker::file_db(path).make().unwrap();
+    assert!(db.hash_set::<u32>("set".to_string()).unwrap().is_empty());
+    assert!(db
+        .hash_set::<u32>("other".to_string())
+        .unwrap()
+        .contains(&1));
 }
 
 /// Tests concurrent inserts to ensure thread safety.
@@ -108,7 +159,7 @@ async fn test_error_handling_nonexistent_key() {
 #[tokio::test]
 async fn test_serialization() {
     let filename = "test_hashset_serialization.db";
-    let hashset = create::<String, String>(filename, "test_serialization");
+    let hashset = create::<String>(filename, "test_serialization");
 
     let key = "serial_key".to_string();
     hashset.insert(key.clone()).await.unwrap().unwrap();
@@ -166,8 +217,109 @@ async fn test_concurrent_insert_remove_hashset() {
     }
 }
 
+/// Tests that `drain_filter` removes and returns the matching elements, and that the removals
+/// are still applied after reopening the `HashSet`.
+#[tokio::test]
+async fn test_drain_filter() {
+    let file = temp_file();
+    let hashset = HashSet::<String>::new(file.clone(), vec![13]).unwrap();
+    let keys = ["tmp_a", "tmp_b", "keep_a", "tmp_c", "keep_b"]
+        .iter()
+        .map(|key| key.to_string())
+        .collect::<Vec<_>>();
+    hashset.insert_batch(keys).await.unwrap().unwrap();
+
+    let mut drained = hashset
+        .drain_filter(|key| key.starts_with("tmp_"))
+        .await
+        .unwrap()
+        .unwrap();
+    drained.sort();
+    assert_eq!(drained, vec!["tmp_a", "tmp_b", "tmp_c"]);
+    assert_eq!(hashset.len(), 2);
+    drop(hashset);
+
+    let hashset = HashSet::<String>::new(file, vec![13]).unwrap();
+    assert_eq!(hashset.len(), 2);
+    assert!(hashset.get(&"keep_a".to_string()).is_some());
+    assert!(hashset.get(&"keep_b".to_string()).is_some());
+}
+
+/// Tests that a partially written final entry ends loading without losing the elements
+/// before it.
+#[tokio::test]
+async fn test_load_stops_at_truncated_tail() {
+    let file = temp_file();
+    let hashset = HashSet::<u32>::new(file.clone(), vec![14]).unwrap();
+    hashset
+        .insert_batch((0..1000).collect())
+        .await
+        .unwrap()
+        .unwrap();
+    drop(hashset);
+
+    let entry = DBEntry::HashSetEntry(vec![14], bincode::serialize(&1000u32).unwrap());
+    let bytes = bincode::serialize(&entry).unwrap();
+    {
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(&bytes[..bytes.len() - 1]).unwrap();
+    }
+
+    let hashset = HashSet::<u32>::new(file, vec![14]).unwrap();
+    assert_eq!(hashset.len(), 1000);
+    assert!(hashset.get(&1000).is_none());
+}
+
+/// Tests that `contains` reports present elements and not absent or removed ones.
+#[tokio::test]
+async fn test_contains() {
+    let hashset = HashSet::<u32>::new(temp_file(), vec![15]).unwrap();
+    hashset.insert_batch(vec![1, 2]).await.unwrap().unwrap();
+    hashset.remove(&2).unwrap().await.unwrap().unwrap();
+    assert!(hashset.contains(&1));
+    assert!(!hashset.contains(&2));
+    assert!(!hashset.contains(&3));
+}
+
+/// Tests that `extend` inserts and persists every element of an iterator.
+#[tokio::test]
+async fn test_extend() {
+    let file = temp_file();
+    let hashset = HashSet::<u32>::new(file.clone(), vec![16]).unwrap();
+    hashset.extend(0..50).await.unwrap().unwrap();
+    assert_eq!(hashset.len(), 50);
+
+    let reloaded = HashSet::<u32>::new(file, vec![16]).unwrap();
+    assert_eq!(reloaded.len(), 50);
+    assert!((0..50).all(|i| reloaded.contains(&i)));
+}
+
+/// Tests that a final entry torn by a crash, whose frame checksum doesn't match, is ignored
+/// when loading.
+#[tokio::test]
+async fn test_load_ignores_torn_final_entry() {
+    let file = temp_file();
+    let hashset = HashSet::<u32>::new(file.clone(), vec![17]).unwrap();
+    hashset.insert(1).await.unwrap().unwrap();
+    hashset.insert(2).await.unwrap().unwrap();
+    drop(hashset);
+
+    {
+        let mut file = file.lock().unwrap();
+        let len = file.seek(SeekFrom::End(0)).unwrap();
+        file.seek(SeekFrom::Start(len - 1)).unwrap();
+        file.write_all(&[0xFF]).unwrap();
+    }
+
+    let hashset = HashSet::<u32>::new(file, vec![17]).unwrap();
+    assert_eq!(hashset.len(), 1);
+    assert!(hashset.contains(&1));
+    assert!(!hashset.contains(&2));
+}
+
 /// Utility function to create a `HashSet` with a given id.
-fn create<K, V>(filename: &str, id: &str) -> HashSet<K>
+fn create<K>(filename: &str, id: &str) -> HashSet<K>
 where
     K: Hash + Eq + Serialize + for<'de> Deserialize<'de> + Clone + Send + 'static + std::fmt::Debug,
 {
@@ -1,3 +1,8 @@
+mod async_map_tests;
 mod db_tests;
 mod hashmap_tests;
 mod hashset_tests;
+mod multimap_tests;
+mod persistent_tests;
+mod snapshot_map_tests;
+mod transaction_tests;
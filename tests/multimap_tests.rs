@@ -0,0 +1,81 @@
+//! Test suite for the `MultiMap` in rustmap-db.
+//!
+//! This module contains tests to validate the functionality of the `MultiMap` data structure,
+//! in particular that the values of each key survive a reload in the order they were inserted.
+
+use rustmap_db::{DBMaker, MultiMap};
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+use tempfile::tempfile;
+
+fn temp_file() -> Arc<Mutex<File>> {
+    Arc::new(Mutex::new(tempfile().unwrap()))
+}
+
+/// Tests that every value inserted for a key is present, in order, after reloading.
+#[tokio::test]
+async fn test_insert_and_reload() {
+    let file = temp_file();
+    let map = MultiMap::<String, u32>::new(file.clone(), vec![1]).unwrap();
+    for value in [3, 1, 2, 1] {
+        map.insert("a".to_string(), value).await.unwrap().unwrap();
+    }
+    assert_eq!(map.insert("b".to_string(), 9).await.unwrap().unwrap(), 1);
+    assert_eq!(map.get(&"a".to_string()), Some(vec![3, 1, 2, 1]));
+
+    let reloaded = MultiMap::<String, u32>::new(file, vec![1]).unwrap();
+    assert_eq!(reloaded.len(), 2);
+    assert_eq!(reloaded.get(&"a".to_string()), Some(vec![3, 1, 2, 1]));
+    assert_eq!(reloaded.get(&"b".to_string()), Some(vec![9]));
+    assert_eq!(reloaded.get(&"c".to_string()), None);
+}
+
+/// Tests that `remove_value` removes only the first occurrence of a value, and the key along
+/// with its last value.
+#[tokio::test]
+async fn test_remove_value() {
+    let file = temp_file();
+    let map = MultiMap::<u32, String>::new(file.clone(), vec![2]).unwrap();
+    for value in ["x", "y", "x"] {
+        map.insert(1, value.to_string()).await.unwrap().unwrap();
+    }
+    map.insert(2, "z".to_string()).await.unwrap().unwrap();
+
+    let removed = map.remove_value(&1, &"x".to_string()).unwrap();
+    assert_eq!(removed.await.unwrap().unwrap(), "x");
+    assert!(map.remove_value(&1, &"missing".to_string()).is_none());
+    map.remove_value(&2, &"z".to_string())
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!map.contains_key(&2));
+
+    let reloaded = MultiMap::<u32, String>::new(file, vec![2]).unwrap();
+    assert_eq!(
+        reloaded.get(&1),
+        Some(vec!["y".to_string(), "x".to_string()])
+    );
+    assert!(!reloaded.contains_key(&2));
+}
+
+/// Tests that a multimap created through a `Database` shares its file with other structures.
+#[tokio::test]
+async fn test_database_multi_map() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("multimap.db");
+    {
+        let db = DBMaker::file_db(path.clone()).make().unwrap();
+        let tags = db.multi_map::<u32, String>("tags".to_string()).unwrap();
+        let names = db.hash_map::<u32, String>("names".to_string()).unwrap();
+        tags.insert(1, "red".to_string()).await.unwrap().unwrap();
+        tags.insert(1, "blue".to_string()).await.unwrap().unwrap();
+        names.insert(1, "one".to_string()).await.unwrap().unwrap();
+    }
+    let db = DBMaker::file_db(path).make().unwrap();
+    let tags = db.multi_map::<u32, String>("tags".to_string()).unwrap();
+    assert_eq!(
+        tags.get(&1),
+        Some(vec!["red".to_string(), "blue".to_string()])
+    );
+}
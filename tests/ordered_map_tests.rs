@@ -0,0 +1,129 @@
+//! Test suite for the `OrderedMap` in rustmap-db.
+//!
+//! This module contains tests to validate the functionality of the `OrderedMap` data structure,
+//! in particular that its pairs are kept in key order however they were inserted.
+
+use rustmap_db::{DBMaker, OrderedMap};
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use tempfile::tempfile;
+
+fn temp_file() -> Arc<Mutex<File>> {
+    Arc::new(Mutex::new(tempfile().unwrap()))
+}
+
+/// Tests that the same pairs inserted in different orders iterate identically, in key order,
+/// before and after a reload.
+#[tokio::test]
+async fn test_insertion_order_independence() {
+    let orders = [
+        vec![5, 1, 4, 2, 3],
+        vec![1, 2, 3, 4, 5],
+        vec![3, 5, 2, 1, 4],
+    ];
+    let expected = (1..=5).map(|i| (i, i * 10)).collect::<Vec<_>>();
+    for (id, order) in orders.into_iter().enumerate() {
+        let file = temp_file();
+        let map = OrderedMap::<u32, u32>::new(file.clone(), vec![id as u8]).unwrap();
+        for key in order {
+            assert_eq!(map.insert(key, key * 10).await.unwrap().unwrap(), None);
+        }
+        assert_eq!(map.iter().collect::<Vec<_>>(), expected);
+
+        let reloaded = OrderedMap::<u32, u32>::new(file, vec![id as u8]).unwrap();
+        assert_eq!(reloaded.iter().collect::<Vec<_>>(), expected);
+    }
+}
+
+/// Tests sorted iteration, range scans and the first and last pairs, including after
+/// overwrites and removals are reloaded.
+#[tokio::test]
+async fn test_sorted_iteration_and_ranges() {
+    let file = temp_file();
+    let map = OrderedMap::<String, u32>::new(file.clone(), vec![1]).unwrap();
+    for (key, value) in [("pear", 3), ("apple", 1), ("fig", 2), ("kiwi", 4)] {
+        map.insert(key.to_string(), value).await.unwrap().unwrap();
+    }
+    assert_eq!(
+        map.insert("fig".to_string(), 20).await.unwrap().unwrap(),
+        Some(2)
+    );
+    assert_eq!(
+        map.remove(&"kiwi".to_string())
+            .unwrap()
+            .await
+            .unwrap()
+            .unwrap(),
+        Some(4)
+    );
+    assert!(map.remove(&"kiwi".to_string()).is_none());
+
+    let reloaded = OrderedMap::<String, u32>::new(file, vec![1]).unwrap();
+    assert_eq!(reloaded.len(), 3);
+    assert_eq!(reloaded.get(&"fig".to_string()), Some(20));
+    assert_eq!(
+        reloaded.keys().collect::<Vec<_>>(),
+        vec!["apple".to_string(), "fig".to_string(), "pear".to_string()]
+    );
+    assert_eq!(
+        reloaded
+            .iter()
+            .rev()
+            .map(|(_, value)| value)
+            .collect::<Vec<_>>(),
+        vec![3, 20, 1]
+    );
+    assert_eq!(
+        reloaded
+            .range("b".to_string().."p".to_string())
+            .collect::<Vec<_>>(),
+        vec![("fig".to_string(), 20)]
+    );
+    assert_eq!(reloaded.first(), Some(("apple".to_string(), 1)));
+    assert_eq!(reloaded.last(), Some(("pear".to_string(), 3)));
+}
+
+/// Tests that an ordered map created through a `Database` shares its records with a hashmap
+/// of the same id, and that clearing it swaps in a new log file that keeps the records of
+/// other structures.
+#[tokio::test]
+async fn test_database_ordered_map() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("ordered.db");
+    {
+        let db = DBMaker::file_db(path.clone()).make().unwrap();
+        let scores = db.ordered_map::<u32, String>("scores".to_string()).unwrap();
+        let names = db.ordered_map::<u32, String>("names".to_string()).unwrap();
+        for i in [30, 10, 20] {
+            scores.insert(i, i.to_string()).await.unwrap().unwrap();
+            names
+                .insert(i, format!("name {}", i))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        db.close_async().await.unwrap();
+    }
+
+    let db = DBMaker::file_db(path.clone()).make().unwrap();
+    let hashmap = db.hash_map::<u32, String>("scores".to_string()).unwrap();
+    assert_eq!(hashmap.len(), 3);
+    assert_eq!(hashmap.get(&20).unwrap().value(), "20");
+    drop(hashmap);
+
+    let names = db.ordered_map::<u32, String>("names".to_string()).unwrap();
+    let old_contents = std::fs::read(&path).unwrap();
+    let mut old_log = File::open(&path).unwrap();
+    names.clear().unwrap();
+    assert!(names.is_empty());
+    let mut contents = Vec::new();
+    old_log.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, old_contents);
+    assert!(db
+        .ordered_map::<u32, String>("names".to_string())
+        .unwrap()
+        .is_empty());
+    let scores = db.ordered_map::<u32, String>("scores".to_string()).unwrap();
+    assert_eq!(scores.keys().collect::<Vec<_>>(), vec![10, 20, 30]);
+}
@@ -0,0 +1,86 @@
+//! Test suite for the `PersistentStructure` trait in rustmap-db.
+//!
+//! These tests drive hashmaps and hashsets through `dyn PersistentStructure`, as code that
+//! maintains structures without knowing their element types would.
+
+use std::{
+    fs::File,
+    sync::{Arc, Mutex},
+};
+
+use rustmap_db::{db::db_entry::DBEntry, HashMap, HashSet, PersistentStructure, StructureError};
+
+fn temp_file() -> Arc<Mutex<File>> {
+    Arc::new(Mutex::new(tempfile::tempfile().unwrap()))
+}
+
+fn file_len(file: &Arc<Mutex<File>>) -> u64 {
+    file.lock().unwrap().metadata().unwrap().len()
+}
+
+/// Tests compacting a map and a set that share a file through trait objects.
+#[tokio::test]
+async fn test_compact_through_trait_objects() {
+    let file = temp_file();
+    let map = HashMap::<u32, u32>::new(file.clone(), vec![1]).unwrap();
+    let set = HashSet::<u32>::new(file.clone(), vec![2]).unwrap();
+    for i in 0..50 {
+        map.insert(i % 10, i).await.unwrap().unwrap();
+        set.insert(i % 5).await.unwrap().unwrap();
+    }
+    map.remove(&0).unwrap().await.unwrap().unwrap();
+    set.remove(&0).unwrap().await.unwrap().unwrap();
+
+    let structures: [&dyn PersistentStructure; 2] = [&map, &set];
+    for structure in structures {
+        let before = file_len(&file);
+        let stats = structure.stats().unwrap();
+        assert_eq!(stats.live_entries, structure.len());
+        assert!(stats.reclaimable_bytes > 0);
+        structure.compact().unwrap();
+        assert_eq!(file_len(&file), before - stats.reclaimable_bytes);
+        assert_eq!(structure.stats().unwrap().reclaimable_bytes, 0);
+        structure.load_from_file().unwrap();
+    }
+    assert_eq!(map.len(), 9);
+    assert_eq!(set.len(), 4);
+
+    let map = HashMap::<u32, u32>::new(file.clone(), vec![1]).unwrap();
+    let set = HashSet::<u32>::new(file.clone(), vec![2]).unwrap();
+    assert_eq!(map.len(), 9);
+    assert_eq!(map.get(&3).unwrap().value(), &43);
+    assert!(map.get(&0).is_none());
+    assert_eq!(set.len(), 4);
+    assert!(set.get(&0).is_none());
+}
+
+/// Tests appending raw entries and reloading them through a trait object.
+#[tokio::test]
+async fn test_append_entry_and_reload() {
+    let file = temp_file();
+    let map = HashMap::<u32, u32>::new(file.clone(), vec![3]).unwrap();
+    map.insert(1, 10).await.unwrap().unwrap();
+    let structure: &dyn PersistentStructure = &map;
+    let id = structure.id().to_vec();
+
+    let key = bincode::serialize(&2u32).unwrap();
+    let value = bincode::serialize(&20u32).unwrap();
+    structure
+        .append_entry(&DBEntry::HashMapEntry(id.clone(), key, value))
+        .unwrap();
+    assert_eq!(structure.len(), 1);
+    structure.load_from_file().unwrap();
+    assert_eq!(structure.len(), 2);
+    assert_eq!(map.get(&2).unwrap().value(), &20);
+
+    let foreign = DBEntry::HashSetEntry(id, bincode::serialize(&3u32).unwrap());
+    assert!(matches!(
+        structure.append_entry(&foreign),
+        Err(StructureError::ForeignEntry)
+    ));
+
+    structure.clear().unwrap();
+    assert!(structure.is_empty());
+    structure.load_from_file().unwrap();
+    assert!(structure.is_empty());
+}
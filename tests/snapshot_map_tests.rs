@@ -0,0 +1,108 @@
+//! Test suite for the `SnapshotHashMap` in rustmap-db.
+//!
+//! These tests check that readers see consistent, immutable versions of the map while writers
+//! publish new ones, and that the contents persist like a `HashMap`.
+
+use std::{
+    fs::File,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use rustmap_db::{HashMap, SnapshotHashMap};
+
+fn temp_file() -> Arc<Mutex<File>> {
+    Arc::new(Mutex::new(tempfile::tempfile().unwrap()))
+}
+
+/// Tests basic operations and that the contents survive a reopen.
+#[tokio::test]
+async fn test_insert_get_remove_persist() {
+    let file = temp_file();
+    let map = SnapshotHashMap::<String, u32>::new(file.clone(), vec![1]).unwrap();
+    assert_eq!(map.insert("a".to_string(), 1).await.unwrap().unwrap(), None);
+    assert_eq!(
+        map.insert("a".to_string(), 2).await.unwrap().unwrap(),
+        Some(1)
+    );
+    map.insert("b".to_string(), 3).await.unwrap().unwrap();
+    let removed = map.remove(&"b".to_string()).unwrap().await.unwrap();
+    assert_eq!(removed.unwrap(), Some(3));
+    assert!(map.remove(&"b".to_string()).is_none());
+    drop(map);
+
+    let map = SnapshotHashMap::<String, u32>::new(file.clone(), vec![1]).unwrap();
+    assert_eq!(map.len(), 1);
+    assert_eq!(map.get(&"a".to_string()), Some(2));
+
+    let map = HashMap::<String, u32>::new(file, vec![1]).unwrap();
+    assert_eq!(map.get(&"a".to_string()).unwrap().value(), &2);
+}
+
+/// Tests that a held snapshot doesn't hold up writers and never changes.
+#[tokio::test]
+async fn test_snapshot_is_immutable() {
+    let map = SnapshotHashMap::<u32, u32>::new(temp_file(), vec![2]).unwrap();
+    map.insert(1, 1).await.unwrap().unwrap();
+    let snapshot = map.snapshot();
+
+    for i in 2..100 {
+        map.insert(i, i).await.unwrap().unwrap();
+    }
+    map.insert(1, 100).await.unwrap().unwrap();
+
+    assert_eq!(snapshot.len(), 1);
+    assert_eq!(snapshot.get(&1), Some(&1));
+    assert_eq!(map.len(), 99);
+    assert_eq!(map.get(&1), Some(100));
+}
+
+/// Tests many concurrent readers alongside a few writers.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_concurrent_readers_and_writers() {
+    let map = Arc::new(SnapshotHashMap::<u32, u32>::new(temp_file(), vec![3]).unwrap());
+    let done = Arc::new(AtomicBool::new(false));
+    let reads = Arc::new(AtomicUsize::new(0));
+
+    let readers = (0..8)
+        .map(|_| {
+            let map = map.clone();
+            let done = done.clone();
+            let reads = reads.clone();
+            thread::spawn(move || loop {
+                // Read at least once, even if the writers finish before the thread starts.
+                let snapshot = map.snapshot();
+                assert!(snapshot.iter().all(|(key, value)| *value == key * 2));
+                let _ = map.get(&0);
+                reads.fetch_add(1, Ordering::Relaxed);
+                if done.load(Ordering::Relaxed) {
+                    break;
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let writers = (0..2u32)
+        .map(|writer| {
+            let map = map.clone();
+            tokio::spawn(async move {
+                for key in (writer * 100)..(writer * 100 + 100) {
+                    map.insert(key, key * 2).await.unwrap().unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.await.unwrap();
+    }
+
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert!(reads.load(Ordering::Relaxed) > 0);
+    assert_eq!(map.len(), 200);
+}
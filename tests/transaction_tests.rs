@@ -0,0 +1,116 @@
+//! Test suite for `MultiDbTransaction` in rustmap-db.
+//!
+//! These tests stage writes across two databases in a temporary directory and check that
+//! they are applied to both or to neither, including after a simulated crash.
+
+use std::path::Path;
+
+use rustmap_db::{DBMaker, Database, MultiDbTransaction};
+
+fn open(path: &Path) -> Database {
+    DBMaker::file_db(path.to_path_buf()).make().unwrap()
+}
+
+/// Opens a database with a shared lock, so it can be reopened while a handle leaked by a
+/// simulated crash still holds the file.
+fn open_shared(path: &Path) -> Database {
+    DBMaker::file_db(path.to_path_buf())
+        .allow_shared()
+        .make()
+        .unwrap()
+}
+
+fn file_count(dir: &Path) -> usize {
+    std::fs::read_dir(dir).unwrap().count()
+}
+
+/// Tests that a committed transaction applies its writes to every database.
+#[tokio::test]
+async fn test_commit_applies_to_all_databases() {
+    let dir = tempfile::tempdir().unwrap();
+    let first = open(&dir.path().join("first.db"));
+    let second = open(&dir.path().join("second.db"));
+    let accounts = first
+        .hash_map::<String, i64>("accounts".to_string())
+        .unwrap();
+    accounts
+        .insert("bob".to_string(), 5)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut transaction = MultiDbTransaction::new();
+    transaction
+        .insert(&first, "accounts", &"alice".to_string(), &-10i64)
+        .unwrap();
+    transaction
+        .remove(&first, "accounts", &"bob".to_string())
+        .unwrap();
+    transaction
+        .insert(&second, "accounts", &"alice".to_string(), &10i64)
+        .unwrap();
+    transaction.prepare().unwrap().commit().unwrap();
+    assert_eq!(file_count(dir.path()), 2);
+
+    let accounts = first
+        .hash_map::<String, i64>("accounts".to_string())
+        .unwrap();
+    assert_eq!(*accounts.get(&"alice".to_string()).unwrap().value(), -10);
+    assert!(accounts.get(&"bob".to_string()).is_none());
+    let accounts = second
+        .hash_map::<String, i64>("accounts".to_string())
+        .unwrap();
+    assert_eq!(*accounts.get(&"alice".to_string()).unwrap().value(), 10);
+}
+
+/// Tests that a transaction interrupted between prepare and commit is applied to neither
+/// database once they are reopened, and that its prepared logs are cleaned up.
+#[tokio::test]
+async fn test_crash_between_prepare_and_commit_applies_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let first_path = dir.path().join("first.db");
+    let second_path = dir.path().join("second.db");
+    let first = open_shared(&first_path);
+    let second = open_shared(&second_path);
+
+    let mut transaction = MultiDbTransaction::new();
+    transaction.insert(&first, "map", &1u32, &1u32).unwrap();
+    transaction.insert(&second, "map", &2u32, &2u32).unwrap();
+    let prepared = transaction.prepare().unwrap();
+    assert_eq!(file_count(dir.path()), 4);
+    // Simulate a crash: the prepared logs stay on disk and commit never runs.
+    std::mem::forget(prepared);
+    drop((first, second));
+
+    let first = open_shared(&first_path);
+    let second = open_shared(&second_path);
+    assert_eq!(file_count(dir.path()), 2);
+    assert!(first
+        .hash_map::<u32, u32>("map".to_string())
+        .unwrap()
+        .is_empty());
+    assert!(second
+        .hash_map::<u32, u32>("map".to_string())
+        .unwrap()
+        .is_empty());
+}
+
+/// Tests that aborting, or dropping, a prepared transaction leaves the databases unchanged.
+#[tokio::test]
+async fn test_abort_removes_prepared_logs() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = open(&dir.path().join("abort.db"));
+    let size = db.file_size().unwrap();
+    for abort in [true, false] {
+        let mut transaction = MultiDbTransaction::new();
+        transaction.insert(&db, "map", &1u32, &1u32).unwrap();
+        let prepared = transaction.prepare().unwrap();
+        if abort {
+            prepared.abort().unwrap();
+        } else {
+            drop(prepared);
+        }
+        assert_eq!(file_count(dir.path()), 1);
+    }
+    assert_eq!(db.file_size().unwrap(), size);
+}